@@ -0,0 +1,91 @@
+//! Measures the code editor's syntax highlighting on files of increasing length, up
+//! to the size of generated peripheral access crates. Only the lines on screen are
+//! highlighted, so opening a file at its end has to parse the whole file once (over
+//! several frames), but a keystroke should take about the same time at any length,
+//! since only the edited lines on screen are re-highlighted.
+//!
+//! Run with `cargo bench --bench highlighting`.
+
+// the modules are shared with the editor, which uses more of them
+#![allow(dead_code)]
+
+#[path = "../src/app/rope.rs"]
+mod rope;
+#[path = "../src/app/highlighting.rs"]
+mod highlighting;
+
+use std::time::{Duration, Instant};
+
+use highlighting::{HighlightCache, Highlighter, ViewHighlight};
+use rope::Rope;
+
+const NUM_KEYSTROKES: u32 = 50;
+
+/// The number of lines on screen
+const SCREEN_LINES: usize = 60;
+
+/// Build a file of roughly `num_lines` lines by repeating one of our own sources
+fn make_source(num_lines: usize) -> String {
+    let chunk = include_str!("../src/project/serial_plotter.rs");
+    let mut source = String::new();
+    while source.lines().count() < num_lines {
+        source += chunk;
+    }
+    source
+}
+
+/// The text of the screen starting at `first_line`
+fn screen(code: &Rope, first_line: usize) -> String {
+    code.slice(code.line_to_byte(first_line)..code.line_to_byte(first_line + SCREEN_LINES))
+}
+
+fn main() {
+    println!(
+        "{:>8} {:>14} {:>8} {:>14} {:>14} {:>14}",
+        "lines", "open at end", "frames", "edit", "keystroke", "rehighlighted"
+    );
+    for num_lines in [1000, 4000, 16000, 64000, 100000] {
+        let mut code = Rope::from(make_source(num_lines).as_str());
+        let mut highlighter = Highlighter::default();
+        let mut cache = HighlightCache::default();
+        let mut view = ViewHighlight::default();
+
+        // show the end of the file, which takes a frame per catch-up step
+        let last_screen = code.len_lines().saturating_sub(SCREEN_LINES);
+        let text = screen(&code, last_screen);
+        let mut frames = 1;
+        let start = Instant::now();
+        while highlighter.highlight(&mut cache, &mut view, &code, &[(0, last_screen)], &text, "Rust").is_none() {
+            frames += 1;
+        }
+        let open = start.elapsed();
+
+        // type characters into the middle of the file, one at a time
+        let first_line = code.len_lines() / 2;
+        highlighter.highlight(&mut cache, &mut view, &code, &[(0, first_line)], &screen(&code, first_line), "Rust");
+        let mut insert_at = code.line_to_byte(first_line + SCREEN_LINES / 2) + 4;
+        let mut edit = Duration::ZERO;
+        let mut keystroke = Duration::ZERO;
+        let mut rehighlighted = 0;
+        for _ in 0..NUM_KEYSTROKES {
+            let start = Instant::now();
+            code.replace(insert_at..insert_at, "x");
+            edit += start.elapsed();
+            insert_at += 1;
+            let text = screen(&code, first_line);
+            let start = Instant::now();
+            highlighter.highlight(&mut cache, &mut view, &code, &[(0, first_line)], &text, "Rust");
+            keystroke += start.elapsed();
+            rehighlighted += highlighter.get_last_highlighted();
+        }
+        println!(
+            "{:>8} {:>14?} {:>8} {:>14?} {:>14?} {:>14}",
+            code.len_lines(),
+            open,
+            frames,
+            edit / NUM_KEYSTROKES,
+            keystroke / NUM_KEYSTROKES,
+            rehighlighted / NUM_KEYSTROKES as usize,
+        );
+    }
+}
@@ -1,27 +1,41 @@
 use std::string::String;
 use std::fmt;
+use std::ops::Range;
 
-use log::info;
+use log::{debug, info, warn};
 
 use egui::Ui;
 use egui::containers::scroll_area::ScrollArea;
-use egui::text::LayoutJob;
 use egui::Label;
 use egui::Sense;
+use egui::{Key, Modifiers};
 use egui::widget_text::RichText;
 
-use syntect::easy::HighlightLines;
-use syntect::parsing::SyntaxSet;
-use syntect::highlighting::{ThemeSet, FontStyle};
-use syntect::util::LinesWithEndings;
+use serde::{Serialize, Deserialize};
 
 // Imports for reading & writing to/from files and opening via Path
 use std::path::{Path, PathBuf};
 use std::fs;
-use std::io::{Read, Write, Seek};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::SystemTime;
 
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
 use crate::app::icons::IconSet;
+use crate::app::edit_history::{EditHistory, EditKind};
+use crate::app::find_replace::{self, FindAction, FindReplace};
+use crate::app::formatter::{self, Diagnostic};
+use crate::app::recovery::{self, RecoveryFile};
+use crate::app::diff::{self, DiffKind};
+use crate::app::file_watcher::FileWatcher;
+use crate::app::folding::{self, FoldRange, Folds};
+use crate::app::highlighting::{self, HighlightCache, Highlighter, ViewHighlight};
+use crate::app::keymap::{self, Command};
+use crate::app::rope::{self, Rope};
+use crate::app::rust_analyzer::{
+    self, AnalysisError, Completion, Location, OutlineItem, Pending, Refactoring, RustAnalyzer,
+    SourceEdit, WorkspaceStatus,
+};
 // use crate::app::colorscheme::ColorScheme;
 
 /// This module contains functionality for the code editor.
@@ -33,23 +47,55 @@ use crate::app::icons::IconSet;
 /// the egui demo app: https://github.com/emilk/egui/blob/master/crates/egui_demo_lib/src/syntax_highlighting.rs
 
 
-// A CodeFile is some code in memory, its path in the filesystem,
-// and its file descriptor.
+/// Lines put in the text edit beyond the ones in view, so the cursor can move off screen
+const WINDOW_MARGIN: usize = 32;
+
+/// The lines in the text edit are taken in blocks of this many lines, so that the
+/// window doesn't change with every line that is scrolled
+const WINDOW_BLOCK: usize = 64;
+
+/// The most lines that a selection can keep in the text edit
+const MAX_WINDOW_LINES: usize = 5000;
+
+/// The width of the column in the gutter with the fold markers
+const FOLD_MARKER_WIDTH: f32 = 14.0;
+
+// A CodeFile is some code in memory, and its path in the filesystem.
+// The file is written by path rather than through a kept-open handle, so
+// a file that was replaced on disk is saved to the new file.
 #[derive(Debug)]
 struct CodeFile {
-    code: String,
+    code: Rope,
     path: Option<PathBuf>,
-    file: Option<fs::File>,
     synced: bool,   // represents whether the code buffer is synced to the filesystem
+    language: String,   // the name of the syntax used to highlight the code
+    highlight: HighlightCache,
+    history: EditHistory,
+    cursor: usize,  // the char index of the cursor
+    anchor: usize,  // the char index of the other end of the selection, or the cursor
+    scroll: f32,    // the vertical scroll offset of the code
+    folds: Folds,
+    diagnostics: Vec<Diagnostic>,
+    conflict: Option<String>,   // the code on disk, if it changed outside the editor while there were unsaved changes
+    written: Option<(SystemTime, u64)>,    // the modification time and hash of the code last saved to the file
 }
 
 impl Default for CodeFile {
     fn default() -> Self {
         Self {
-            code: String::new(),
+            code: Rope::default(),
             path: None,
-            file: None,
             synced: false,
+            language: highlighting::PLAIN_TEXT.to_string(),
+            highlight: HighlightCache::default(),
+            history: EditHistory::default(),
+            cursor: 0,
+            anchor: 0,
+            scroll: 0.0,
+            folds: Folds::default(),
+            diagnostics: Vec::new(),
+            conflict: None,
+            written: None,
         }
     }
 }
@@ -57,45 +103,158 @@ impl Default for CodeFile {
 impl CodeFile {
     // Load some code from a path
     fn load_from_file(&mut self, file_path: &Path) -> std::io::Result<()> {
-        let CodeFile { code, .. } = self;
-        code.clear();
         self.path = Some(file_path.canonicalize()?);
-        self.file = Some(fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(file_path)?
-        );
-        if let Some(file) = &mut self.file {
-            file.read_to_string(code)?;
-            self.synced = true;
-        }
+        self.code = Rope::from(fs::read_to_string(file_path)?.as_str());
+        self.synced = true;
         Ok(())
     }
 
     pub fn save(&mut self) -> std::io::Result<()> {
-        if let Some(file) = &mut self.file {
-            file.rewind()?;
-            file.set_len(0)?;
-            file.write(self.code.as_bytes())?;
-            file.sync_all()?;
+        if let Some(path) = &self.path {
+            let code = self.code.to_string();
+            fs::write(path, &code)?;
             self.synced = true;
+            self.conflict = None;
+            // the watcher reports this write as well, which isn't a change from outside
+            self.written = fs::metadata(path).and_then(|m| m.modified()).ok()
+                .map(|modified| (modified, hash_code(&code)));
         }
         Ok(())
     }
+
+    /// Whether the file holds what the tab last saved to it, i.e. it hasn't been
+    /// changed outside of the editor since
+    fn wrote(&self, path: &Path, disk_code: &str) -> bool {
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        self.written.is_some_and(|written| Some(written.0) == modified && written.1 == hash_code(disk_code))
+    }
+
+    /// Replace the code, which is `before`, with `after` as a single edit
+    fn replace_code(&mut self, before: &str, after: &str, kind: EditKind) {
+        self.history.record(before, after, kind);
+        self.code.replace_changed(0, before, after);
+    }
+
+    /// Move a cursor that was just folded away to the end of the line the fold starts at
+    fn cursor_out_of_folds(&mut self) {
+        let line = self.code.byte_to_line(self.code.char_to_byte(self.cursor));
+        let visible = folding::visible_line(line, &self.folds.hidden_lines());
+        if visible != line {
+            self.cursor = self.code.byte_to_char(self.code.line_to_byte(visible + 1) - 1);
+            self.anchor = self.cursor;
+        }
+    }
+}
+
+/// How the panes of the editor are laid out
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum SplitDirection {
+    /// Side by side
+    #[default]
+    Horizontal,
+    /// One above the other
+    Vertical,
+}
+
+/// A part of the editor with its own strip of tabs. The tabs are indices into the
+/// editor's buffers, so panes that show the same file share its code (and cursor).
+#[derive(Debug, Default)]
+struct Pane {
+    tabs: Vec<usize>,
+    active_tab: Option<usize>,
+    /// The file shown on the last frame, to restore a tab's scroll position when
+    /// switching to it
+    shown_path: Option<PathBuf>,
+    /// The highlighting of the lines shown, while the buffer's parser states are shared
+    /// with the other panes
+    highlight: ViewHighlight,
+}
+
+/// The panes and tabs of the editor, as they are kept between sessions
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct EditorState {
+    split: SplitDirection,
+    focused_pane: usize,
+    panes: Vec<PaneState>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+struct PaneState {
+    active_tab: Option<usize>,
+    tabs: Vec<TabState>,
+}
+
+/// The file of a tab (relative to the project folder if it's in there), and where
+/// the user was in it
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+struct TabState {
+    path: PathBuf,
+    cursor: usize,
+    anchor: usize,
+    scroll: f32,
+    folds: Vec<FoldRange>,
 }
 
 pub struct CodeEditor {
+    /// The open files, which the panes show in their tabs
     tabs: Vec<CodeFile>,
-    active_tab: Option<usize>,
-    ps: SyntaxSet,
-    ts: ThemeSet,
+    /// There is always at least one pane, and the focused one is where files open
+    panes: Vec<Pane>,
+    focused_pane: usize,
+    split: SplitDirection,
+    /// A tab being dragged to another pane: the pane it's in, and its buffer
+    dragged_tab: Option<(usize, usize)>,
+    /// Where each pane was on the last frame, to find the pane a tab is dropped on
+    pane_rects: Vec<egui::Rect>,
+    /// A line (1-based) of the active tab to move the cursor to on the next frame
+    pending_goto_line: Option<usize>,
+    /// A char range of the active tab to select (or, if empty, to move the cursor to)
+    /// and scroll into view on the next frame
+    pending_selection: Option<Range<usize>>,
+    highlighter: Highlighter,
+    rust_analyzer: RustAnalyzer,
+    /// The completions shown in the popup, and the index of the selected one
+    completions: Vec<Completion>,
+    selected_completion: usize,
+    /// The completions being computed in the background, and the file and revision
+    /// of its code that they are for
+    pending_completions: Option<(PathBuf, u64, Pending<Vec<Completion>>)>,
+    /// The hover text at a (file, byte offset), cached until the code changes
+    hover: Option<(PathBuf, usize, Option<String>)>,
+    /// The hover text being computed in the background, and where it is for
+    pending_hover: Option<(PathBuf, usize, Pending<Option<String>>)>,
+    /// The results of the last find-references query
+    references: Vec<Location>,
+    show_references: bool,
+    /// A rename in progress: the file and byte offset of the symbol, and its new name
+    rename: Option<(PathBuf, usize, String)>,
+    /// A refactoring waiting to be confirmed, or the reason it couldn't be done
+    refactor_preview: Option<Result<SourceEdit, String>>,
+    /// The outline of the active tab, and the revision of the code it was computed from
+    outline: Option<(u64, Vec<OutlineItem>)>,
+    show_line_numbers: bool,
+    /// Whether to format Rust tabs with unsaved changes when they are saved
+    format_on_save: bool,
+    /// Where copies of the unsaved tabs are kept, to recover them after a crash
+    recovery_dir: Option<PathBuf>,
+    /// A tab (pane and buffer) with unsaved changes that the user asked to close
+    pending_close: Option<(usize, usize)>,
+    /// Notices files changed outside of the editor
+    watcher: FileWatcher,
+    /// Keystrokes held back until the cursor they go to is scrolled into view
+    deferred_input: Vec<egui::Event>,
+    find: FindReplace,
     // cs: ColorScheme,
 }
 
 impl fmt::Debug for CodeEditor {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "num tabs: {:?}", self.tabs.len())?;
-        write!(f, "active_tab: {:?}", self.active_tab)?;
+        write!(f, "num panes: {:?}", self.panes.len())?;
+        write!(f, "active_tab: {:?}", self.active_tab())?;
         Ok(())
     }
 }
@@ -104,9 +263,32 @@ impl Default for CodeEditor {
     fn default() -> Self {
         Self {
             tabs: Vec::new(),
-            active_tab: None,
-            ps: SyntaxSet::load_defaults_newlines(),
-            ts: ThemeSet::load_defaults(),
+            panes: vec![Pane::default()],
+            focused_pane: 0,
+            split: SplitDirection::default(),
+            dragged_tab: None,
+            pane_rects: Vec::new(),
+            pending_goto_line: None,
+            pending_selection: None,
+            highlighter: Highlighter::default(),
+            rust_analyzer: RustAnalyzer::default(),
+            completions: Vec::new(),
+            selected_completion: 0,
+            pending_completions: None,
+            hover: None,
+            pending_hover: None,
+            references: Vec::new(),
+            show_references: false,
+            rename: None,
+            refactor_preview: None,
+            outline: None,
+            show_line_numbers: true,
+            format_on_save: false,
+            recovery_dir: None,
+            pending_close: None,
+            watcher: FileWatcher::default(),
+            deferred_input: Vec::new(),
+            find: FindReplace::default(),
             // cs: ColorScheme::default(),
         }
     }
@@ -118,228 +300,2440 @@ impl CodeEditor {
     // unless file_path already exists in one of the tabs, in which case
     // the active tab is switched to that tab
     pub fn load_from_file(&mut self, file_path: &Path) -> std::io::Result<()> {
+        // use canonicalize() here to keep things consistent; a file that doesn't
+        // exist can't be opened
+        let canonical_path = file_path.canonicalize()?;
         let predicate = |elem: &CodeFile| {
             // elem is a CodeFile, see it the path matches the arg to this method
-            if let Some(path) = &elem.path {
-                // use canonicalize() here to keep things consistent
-                return path.as_path() == file_path.canonicalize().unwrap();
-            } else {
-                return false;
-            }
+            elem.path.as_deref() == Some(canonical_path.as_path())
         };
         if let Some(i) = self.tabs.iter().position(predicate) {
-            self.active_tab = Some(i);
+            self.show_in_pane(self.focused_pane, i);
         } else {
             let mut code_file = CodeFile::default();
             code_file.load_from_file(file_path)?;
+            let first_line = code_file.code.lines_at(0).next().unwrap_or_default();
+            code_file.language = self.highlighter.detect_language(file_path, first_line);
+            if let Some(path) = &code_file.path {
+                self.watcher.watch_file(path);
+            }
             self.tabs.push(code_file);
-            self.active_tab = Some(self.tabs.len() - 1);
+            self.show_in_pane(self.focused_pane, self.tabs.len() - 1);
         }
         Ok(())
     }
 
-    // iterates through and saves all open tabs
-    pub fn save_all(&mut self) -> std::io::Result<()> {
-        for tab in self.tabs.iter_mut() {
-            tab.save()?;
+    /// The buffer of the tab shown in the focused pane
+    fn active_tab(&self) -> Option<usize> {
+        self.panes[self.focused_pane].active_tab
+    }
+
+    /// Show a buffer in a pane, adding a tab for it if the pane doesn't have one
+    fn show_in_pane(&mut self, p: usize, i: usize) {
+        let pane = &mut self.panes[p];
+        if !pane.tabs.contains(&i) {
+            pane.tabs.push(i);
         }
-        Ok(())
+        pane.active_tab = Some(i);
     }
 
-    // pub fn set_colorscheme(&mut self, cs: ColorScheme) {
-    //     self.cs = cs;
-    // }
+    /// Open the active tab in a new pane, splitting the editor in `direction`. All of
+    /// the panes are laid out in the same direction.
+    pub fn split(&mut self, direction: SplitDirection) {
+        let Some(i) = self.active_tab() else {
+            return;
+        };
+        self.split = direction;
+        self.panes.insert(self.focused_pane + 1, Pane::default());
+        self.focused_pane += 1;
+        self.show_in_pane(self.focused_pane, i);
+    }
 
-    // This method computes the syntax highlighting.
-    // The module function `highlight` caches the result and should
-    // only call this method if the code changes
-    fn highlight(&mut self, text: &str, language: &str) -> LayoutJob {
-        // Destructure, and do the highlighting
-        let CodeEditor {
-            ps,
-            ts,
-            ..
-        } = self;
+    /// Move a tab from one pane to another. The tab's buffer stays open.
+    fn move_tab(&mut self, from: usize, to: usize, i: usize) {
+        self.show_in_pane(to, i);
+        self.focused_pane = to;
+        self.remove_from_pane(from, i);
+        self.remove_empty_panes();
+    }
 
-        let syntax = ps.find_syntax_by_extension(language).unwrap();
+    /// Take the tab of a buffer out of a pane, showing the next tab in its place
+    fn remove_from_pane(&mut self, p: usize, i: usize) {
+        let pane = &mut self.panes[p];
+        let Some(at) = pane.tabs.iter().position(|tab| *tab == i) else {
+            return;
+        };
+        pane.tabs.remove(at);
+        if pane.active_tab == Some(i) {
+            pane.active_tab = pane.tabs.get(at.min(pane.tabs.len().saturating_sub(1))).copied();
+        }
+    }
 
-        // Choose the syntext colorscheme by parsing the current GUI colorscheme
-        // let theme = match 
+    /// Remove the panes without tabs, unless there's only one pane left
+    fn remove_empty_panes(&mut self) {
+        let mut p = 0;
+        while p < self.panes.len() && self.panes.len() > 1 {
+            if self.panes[p].tabs.is_empty() {
+                self.panes.remove(p);
+                if self.focused_pane > p {
+                    self.focused_pane -= 1;
+                }
+                // the panes after the removed one move down by one
+                let shift = |(pane, i): (usize, usize)| match pane > p {
+                    true => (pane - 1, i),
+                    false => (pane, i),
+                };
+                self.pending_close = self.pending_close.filter(|(pane, _)| *pane != p).map(shift);
+                self.dragged_tab = self.dragged_tab.filter(|(pane, _)| *pane != p).map(shift);
+            } else {
+                p += 1;
+            }
+        }
+        self.focused_pane = self.focused_pane.min(self.panes.len() - 1);
+    }
 
-        let mut h = HighlightLines::new(syntax, &ts.themes["Solarized (dark)"]);
+    /// Open the file at `file_path` (or switch to its tab), and move the cursor
+    /// to the start of `line`, which is 1-based.
+    pub fn goto_line(&mut self, file_path: &Path, line: usize) -> std::io::Result<()> {
+        self.load_from_file(file_path)?;
+        self.pending_goto_line = Some(line);
+        Ok(())
+    }
 
-        use egui::text::{LayoutSection, TextFormat};
+    /// Load the Cargo workspace at `root` for code analysis
+    pub fn load_workspace(&mut self, root: &Path) {
+        self.rust_analyzer.load_workspace(root);
+        self.watcher.watch_tree(root);
+    }
 
-        let mut job = LayoutJob {
-            text: text.to_string(),
-            ..Default::default()
-        };
+    /// The panes and their tabs, with the paths of the files in `root` relative to it
+    pub fn get_state(&self, root: &Path) -> EditorState {
+        let root = root.canonicalize().unwrap_or(root.to_path_buf());
+        let panes = self.panes.iter()
+            .map(|pane| {
+                let tabs: Vec<usize> = pane.tabs.iter()
+                    .copied()
+                    .filter(|i| self.tabs[*i].path.is_some())
+                    .collect();
+                let tab_states = tabs.iter()
+                    .filter_map(|i| {
+                        let tab = &self.tabs[*i];
+                        let path = tab.path.as_ref()?;
+                        Some(TabState {
+                            path: path.strip_prefix(&root).unwrap_or(path).to_path_buf(),
+                            cursor: tab.cursor,
+                            anchor: tab.anchor,
+                            scroll: tab.scroll,
+                            folds: tab.folds.get_folded(),
+                        })
+                    })
+                    .collect();
+                PaneState {
+                    active_tab: pane.active_tab.and_then(|i| tabs.iter().position(|tab| *tab == i)),
+                    tabs: tab_states,
+                }
+            })
+            .collect();
+        EditorState { split: self.split, focused_pane: self.focused_pane, panes }
+    }
 
-        for line in LinesWithEndings::from(text) {
-            for (style, range) in h.highlight_line(line, &ps).ok().unwrap() {
-                let fg = style.foreground;
-                let text_color = egui::Color32::from_rgb(fg.r, fg.g, fg.b);
-                let italics = style.font_style.contains(FontStyle::ITALIC);
-                let underline = style.font_style.contains(FontStyle::ITALIC);
-                let underline = if underline {
-                    egui::Stroke::new(1.0, text_color)
-                } else {
-                    egui::Stroke::NONE
+    /// Open the panes and tabs of a former session, with relative paths resolved
+    /// against `root`. Files that no longer exist are left out.
+    pub fn restore_state(&mut self, state: &EditorState, root: &Path) {
+        for (p, pane_state) in state.panes.iter().enumerate() {
+            if p >= self.panes.len() {
+                self.panes.push(Pane::default());
+            }
+            self.focused_pane = p;
+            let mut active_tab = None;
+            for (n, tab_state) in pane_state.tabs.iter().enumerate() {
+                let path = root.join(&tab_state.path);
+                if let Err(e) = self.load_from_file(&path) {
+                    warn!("couldn't reopen {}: {:?}", path.display(), e);
+                    continue;
+                }
+                let Some(i) = self.active_tab() else {
+                    continue;
                 };
-                job.sections.push(LayoutSection {
-                    leading_space: 0.0,
-                    byte_range: as_byte_range(text, range),
-                    format: TextFormat {
-                        font_id: egui::FontId::new(12.0, egui::FontFamily::Name("EditorFont".into())),
-                        color: text_color,
-                        italics,
-                        underline,
-                        ..Default::default()
-                    },
+                let tab = &mut self.tabs[i];
+                // the cursor is clamped to the code when the tab is shown
+                tab.cursor = tab_state.cursor;
+                tab.anchor = tab_state.anchor;
+                tab.scroll = tab_state.scroll;
+                tab.folds.set_folded(tab_state.folds.clone());
+                if pane_state.active_tab == Some(n) {
+                    active_tab = Some(i);
+                }
+            }
+            let pane = &mut self.panes[p];
+            if active_tab.is_some() {
+                pane.active_tab = active_tab;
+            }
+            pane.shown_path = None;
+        }
+        self.split = state.split;
+        self.focused_pane = state.focused_pane;
+        self.remove_empty_panes();
+    }
+
+    /// Reload the analysis workspace, i.e. after crates were added to the project
+    pub fn reload_workspace(&mut self) {
+        self.rust_analyzer.reload_workspace();
+    }
+
+    /// Move the cursor of the active tab to the start of `line`, which is 1-based.
+    pub fn scroll_to_line(&mut self, line: usize) {
+        self.pending_goto_line = Some(line);
+    }
+
+    /// Show the outline of the active tab, if it is Rust code. It is recomputed whenever
+    /// the code changes, and clicking an item scrolls the editor to it.
+    pub fn display_outline(&mut self, ui: &mut Ui) {
+        let code = match self.active_tab().and_then(|i| self.tabs.get(i)) {
+            Some(tab) if tab.language == "Rust" => &tab.code,
+            Some(_) => {
+                ui.label("no outline for this file");
+                return;
+            },
+            None => {
+                ui.label("no file open");
+                return;
+            },
+        };
+        let stale = match &self.outline {
+            Some((revision, _)) => *revision != code.revision(),
+            None => true,
+        };
+        if stale {
+            self.outline = Some((code.revision(), rust_analyzer::file_outline(&code.to_string())));
+        }
+        let mut clicked_line: Option<usize> = None;
+        if let Some((_, items)) = &self.outline {
+            if items.is_empty() {
+                ui.label("no items");
+            }
+            for item in items.iter() {
+                ui.horizontal(|ui| {
+                    ui.add_space(item.depth as f32 * 12.0);
+                    ui.label(RichText::new(item.kind).weak());
+                    let label = Label::new(item.label.as_str()).sense(Sense::click());
+                    let mut response = ui.add(label);
+                    if let Some(detail) = &item.detail {
+                        response = response.on_hover_text(detail.as_str());
+                    }
+                    if response.clicked() {
+                        clicked_line = Some(item.line);
+                    }
                 });
             }
         }
+        if let Some(line) = clicked_line {
+            self.scroll_to_line(line);
+        }
+    }
+
+    // iterates through and saves all open tabs, formatting the
+    // changed Rust tabs first if format on save is on
+    pub fn save_all(&mut self) -> std::io::Result<()> {
+        for i in 0..self.tabs.len() {
+            let tab = &self.tabs[i];
+            if self.format_on_save && !tab.synced && tab.language == "Rust" {
+                self.format_tab(i);
+            }
+            let was_synced = self.tabs[i].synced;
+            self.tabs[i].save()?;
+            if let (false, Some(dir), Some(path)) = (was_synced, &self.recovery_dir, &self.tabs[i].path) {
+                recovery::remove(dir, path);
+            }
+        }
+        Ok(())
+    }
 
-        return job;
+    /// Whether any of the tabs have changes that haven't been saved
+    pub fn has_unsaved_changes(&self) -> bool {
+        self.tabs.iter().any(|tab| !tab.synced)
     }
 
-    // TODO -- optimizations (and opportunities for benchmarking) 
-    // regarding the syntax highlighting, as well as error checking and 
-    // bug fixes
-    pub fn display_code(&mut self, ctx: &egui::Context, ui: &mut Ui) {
+    /// The files of the tabs that have unsaved changes
+    pub fn get_unsaved_files(&self) -> Vec<PathBuf> {
+        self.tabs.iter()
+            .filter(|tab| !tab.synced)
+            .filter_map(|tab| tab.path.clone())
+            .collect()
+    }
 
-        // First, get some data from the object, and detect if any tabs are open
-        // (if not, just return)
-        let CodeEditor { tabs, active_tab, .. } = self;
-        let i: usize;
-        if *active_tab == None {
+    /// Keep copies of the unsaved tabs in `dir`
+    pub fn set_recovery_directory(&mut self, dir: PathBuf) {
+        self.recovery_dir = Some(dir);
+    }
+
+    /// Write the code of the unsaved tabs to the recovery directory
+    pub fn autosave(&self) {
+        let Some(dir) = &self.recovery_dir else {
             return;
+        };
+        for tab in self.tabs.iter().filter(|tab| !tab.synced) {
+            if let Some(path) = &tab.path {
+                recovery::save(dir, path, &tab.code.to_string());
+            }
+        }
+    }
+
+    /// Close the tabs that have unsaved changes, dropping the changes (and their
+    /// recovery copies)
+    pub fn discard_unsaved_changes(&mut self) {
+        while let Some(i) = self.tabs.iter().position(|tab| !tab.synced) {
+            self.close_buffer(i);
+        }
+        self.remove_empty_panes();
+        self.pending_close = None;
+    }
+
+    /// Open a file with the unsaved code that was recovered for it. Restoring the code
+    /// is an edit of the tab, so it can be undone to get back the saved code. A file
+    /// that was deleted gets an empty tab at its path, and saving it creates it again.
+    pub fn restore(&mut self, file: &RecoveryFile) -> std::io::Result<()> {
+        if file.path.exists() {
+            self.load_from_file(&file.path)?;
         } else {
-            i = active_tab.unwrap();
+            let mut code_file = CodeFile::default();
+            let first_line = file.code.lines().next().unwrap_or_default();
+            code_file.language = self.highlighter.detect_language(&file.path, first_line);
+            code_file.path = Some(file.path.clone());
+            self.watcher.watch_file(&file.path);
+            self.tabs.push(code_file);
+            self.show_in_pane(self.focused_pane, self.tabs.len() - 1);
+        }
+        let Some(i) = self.active_tab() else {
+            return Ok(());
+        };
+        let tab = &mut self.tabs[i];
+        let before = tab.code.to_string();
+        tab.replace_code(&before, &file.code, EditKind::Recovery);
+        self.code_changed(i);
+        Ok(())
+    }
+
+    /// Format the code of a Rust tab, keeping its cursor in place. Code that can't be
+    /// formatted is left as it is, with the reason shown in the editor.
+    fn format_tab(&mut self, i: usize) {
+        let tab = &mut self.tabs[i];
+        tab.diagnostics.clear();
+        let code = tab.code.to_string();
+        match formatter::format_rust(&code, tab.path.as_deref()) {
+            Ok(formatted) if formatted != code => {
+                let cursor = formatter::map_cursor(&code, &formatted, tab.cursor);
+                tab.history.close_group();
+                tab.replace_code(&code, &formatted, EditKind::Format);
+                tab.cursor = cursor;
+                tab.anchor = cursor;
+                if self.active_tab() == Some(i) {
+                    self.pending_selection = Some(cursor..cursor);
+                }
+                self.code_changed(i);
+            },
+            Ok(_) => (),
+            Err(diagnostic) => {
+                warn!("couldn't format {:?}: {}", tab.path, diagnostic.message);
+                tab.diagnostics.push(diagnostic);
+            },
         }
+    }
+
+    pub fn set_format_on_save(&mut self, format: bool) {
+        self.format_on_save = format;
+    }
+
+    /// Make the custom .tmTheme files in `dir` available for highlighting
+    pub fn load_syntax_themes(&mut self, dir: &Path) {
+        self.highlighter.load_themes(dir);
+    }
+
+    /// The names of the themes that code can be highlighted with
+    pub fn get_syntax_themes(&self) -> Vec<String> {
+        self.highlighter.get_themes().into_iter().map(String::from).collect()
+    }
+
+    /// Highlight code with the named theme from now on
+    pub fn set_syntax_theme(&mut self, theme: &str) {
+        self.highlighter.set_theme(theme);
+    }
+
+    /// Show or hide the line-number gutter
+    pub fn set_show_line_numbers(&mut self, show: bool) {
+        self.show_line_numbers = show;
+    }
+
+    /// Undo the last edit in the active tab
+    pub fn undo(&mut self) {
+        self.step_history(false);
+    }
+
+    /// Redo the last undone edit in the active tab
+    pub fn redo(&mut self) {
+        self.step_history(true);
+    }
+
+    /// The kind of edit that undo would revert in the active tab, if any
+    pub fn get_undo_kind(&self) -> Option<EditKind> {
+        self.active_tab().and_then(|i| self.tabs[i].history.get_undo_kind())
+    }
+
+    /// The kind of edit that redo would reapply in the active tab, if any
+    pub fn get_redo_kind(&self) -> Option<EditKind> {
+        self.active_tab().and_then(|i| self.tabs[i].history.get_redo_kind())
+    }
 
-        let mut layouter = |ui: &egui::Ui, string: &str, _wrap_width: f32| {
-            // Call the highlight function (below), which is a memoized version
-            // of this struct's highlight method
-            let layout_job = highlight(ctx, string, "rs");
-            ui.fonts(|f| f.layout_job(layout_job))
+    fn step_history(&mut self, redo: bool) {
+        let Some(i) = self.active_tab() else {
+            return;
         };
+        let tab = &mut self.tabs[i];
+        let offset = match redo {
+            true => tab.history.redo(&mut tab.code),
+            false => tab.history.undo(&mut tab.code),
+        };
+        if let Some(offset) = offset {
+            self.select(i, offset..offset);
+            self.code_changed(i);
+        }
+    }
+
+    /// Select a byte range of a tab on the next frame
+    fn select(&mut self, tab: usize, range: Range<usize>) {
+        let code = &self.tabs[tab].code;
+        self.pending_selection = Some(code.byte_to_char(range.start)..code.byte_to_char(range.end));
+    }
+
+    /// Mark a tab as changed after editing its code outside of the text edit
+    fn code_changed(&mut self, tab: usize) {
+        let tab = &mut self.tabs[tab];
+        tab.synced = false;
+        if let Some(path) = &tab.path {
+            self.rust_analyzer.set_file_contents(path, &tab.code.to_string());
+        }
+        self.hover = None;
+        self.pending_hover = None;
+        self.completions.clear();
+        self.pending_completions = None;
+    }
 
-        ScrollArea::both().auto_shrink([false; 2]).show(ui, |ui| {
-            let former_contents = tabs[i].code.clone();
-            let resp = ui.add(
-                egui::TextEdit::multiline(&mut tabs[i].code)
-                    .font(egui::TextStyle::Name("EditorFont".into()))
-                    .code_editor()
-                    .lock_focus(true)
-                    .desired_width(f32::INFINITY)
-                    .frame(false)
-                    .layouter(&mut layouter),
-            );
-            // check if the code has changed, so we can set the synced flag
-            if tabs[i].synced && tabs[i].code != former_contents {
-                tabs[i].synced = false;
+    /// Pick up changes made to the files of the tabs outside of the editor. Tabs
+    /// without unsaved changes are reloaded, the others get a conflict to resolve.
+    fn check_external_changes(&mut self, ctx: &egui::Context) {
+        for path in self.watcher.poll(ctx) {
+            let Some(i) = self.tabs.iter().position(|tab| tab.path.as_deref() == Some(path.as_path())) else {
+                continue;
+            };
+            let tab = &mut self.tabs[i];
+            match fs::read_to_string(&path) {
+                Ok(disk_code) if tab.code == *disk_code => {
+                    tab.synced = true;
+                    tab.conflict = None;
+                },
+                // the tab was saved, and edited again before the change was picked up
+                Ok(disk_code) if tab.wrote(&path, &disk_code) => {
+                    debug!("{} was saved by the editor", path.display());
+                },
+                Ok(disk_code) if tab.synced => {
+                    info!("reloading {}, which changed on disk", path.display());
+                    self.reload_tab(i, disk_code);
+                },
+                Ok(disk_code) => {
+                    info!("{} changed on disk, and has unsaved changes", path.display());
+                    tab.conflict = Some(disk_code);
+                },
+                Err(_) if !path.exists() => {
+                    // saving will create the file again
+                    info!("{} was removed", path.display());
+                    tab.synced = false;
+                },
+                Err(e) => warn!("couldn't read {} after it changed: {:?}", path.display(), e),
             }
-            // See if a code snippet was released over the editor.
-            // TODO -- if so, insert it on the proper line
-            ctx.memory_mut(|mem| {
-                let id = egui::Id::new("released_code_snippet");
-                let data: Option<String> = mem.data.get_temp(id);
-                if let Some(value) = data {
-                    if resp.hovered() {
-                        info!("found a released code snippet!");
-                        mem.data.remove::<String>(id);
-                        tabs[i].code += &value;
+        }
+    }
+
+    /// Replace the code of a tab with what is on disk. This is an edit of the tab, so
+    /// it can be undone.
+    fn reload_tab(&mut self, i: usize, disk_code: String) {
+        let tab = &mut self.tabs[i];
+        let before = tab.code.to_string();
+        tab.history.close_group();
+        tab.replace_code(&before, &disk_code, EditKind::Reload);
+        tab.conflict = None;
+        self.code_changed(i);
+        self.tabs[i].synced = true;
+        self.outline = None;
+    }
+
+    /// Show how the code on disk differs from a tab with unsaved changes, and let the
+    /// user pick which to keep
+    fn display_conflict_prompt(&mut self, ctx: &egui::Context) {
+        let Some(i) = self.tabs.iter().position(|tab| tab.conflict.is_some()) else {
+            return;
+        };
+        let tab = &self.tabs[i];
+        let file_name = tab.path.as_ref()
+            .and_then(|path| path.file_name())
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let disk_code = tab.conflict.clone().unwrap_or_default();
+        let mut choice = None;
+        egui::Window::new("file changed on disk")
+            .id(egui::Id::new("file_conflict_prompt"))
+            .collapsible(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.label(format!("{} was changed outside of the editor, but has unsaved changes here.", file_name));
+                ui.label(RichText::new("lines removed from, and added to, the code on disk:").weak());
+                let diff = diff::diff_lines(&tab.code.to_string(), &disk_code);
+                ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+                    for line in diff::with_context(&diff) {
+                        let text = match line {
+                            Some(line) => RichText::new(line.text.as_str()).monospace(),
+                            None => {
+                                ui.label(RichText::new("...").weak());
+                                continue;
+                            },
+                        };
+                        match line.map(|line| line.kind) {
+                            Some(DiffKind::Removed) => ui.label(text.strikethrough().color(egui::Color32::RED)),
+                            Some(DiffKind::Added) => ui.label(text.color(egui::Color32::GREEN)),
+                            _ => ui.label(text),
+                        };
                     }
-                }
+                });
+                ui.horizontal(|ui| {
+                    if ui.button("load from disk").clicked() {
+                        choice = Some(true);
+                    }
+                    if ui.button("keep my changes").clicked() {
+                        choice = Some(false);
+                    }
+                });
             });
-        });
+        match choice {
+            Some(true) => self.reload_tab(i, disk_code),
+            Some(false) => self.tabs[i].conflict = None,
+            None => (),
+        }
     }
 
-    pub fn display_editor_tabs(&mut self, ctx: &egui::Context, ui: &mut Ui) {
-        let icons_ref: Arc<IconSet> = ctx.data_mut(|data| {
-            data.get_temp("icons".into()).expect("error loading shared icon map!")
-        });
-        let icons = icons_ref.clone();
-        ui.horizontal(|ui| {
-            let mut idx_to_remove: Option<usize> = None;
-            for (i, code_file) in self.tabs.iter().enumerate() {
-                // display the close icon
-                let x_icon = egui::widgets::ImageButton::new(
-                    icons.get("quit_icon").unwrap().texture_id(ctx),
-                    egui::Vec2::new(6.0, 6.0),
-                ).frame(true);
-                if ui.add(x_icon).clicked() {
-                    // we'll remove the tab right after this loop
-                    idx_to_remove = Some(i);
+    /// Show the problems found in the active tab above the code. Clicking one moves
+    /// the cursor to its line.
+    fn display_diagnostics(&mut self, ui: &mut Ui) {
+        let Some(i) = self.active_tab() else {
+            return;
+        };
+        if self.tabs[i].diagnostics.is_empty() {
+            return;
+        }
+        let color = ui.visuals().error_fg_color;
+        for diagnostic in self.tabs[i].diagnostics.iter() {
+            let text = match diagnostic.line {
+                Some(line) => format!("line {}: {}", line, diagnostic.message),
+                None => diagnostic.message.clone(),
+            };
+            let label = egui::Label::new(RichText::new(text).color(color)).sense(Sense::click());
+            if ui.add(label).clicked() && diagnostic.line.is_some() {
+                self.pending_goto_line = diagnostic.line;
+            }
+        }
+        ui.separator();
+    }
+
+    /// Show the find/replace bar above the code, and carry out what it asks for
+    fn display_find_bar(&mut self, ctx: &egui::Context, ui: &mut Ui) {
+        let id = text_edit_id(self.focused_pane);
+        let Some(i) = self.active_tab() else {
+            return;
+        };
+        // the find shortcut opens the bar, searching for the selected text
+        let tab = &self.tabs[i];
+        let selection = tab.code.char_to_byte(tab.anchor.min(tab.cursor))..tab.code.char_to_byte(tab.anchor.max(tab.cursor));
+        let keymap = keymap::get_keymap(ctx);
+        if ui.input_mut(|input| keymap.consume(input, Command::Find)) {
+            let query = Some(tab.code.slice(selection.clone()))
+                .filter(|text| !text.is_empty() && !text.contains('\n'));
+            self.find.open(ctx, query.as_deref());
+        }
+        if !self.find.visible {
+            return;
+        }
+
+        self.find.update(&self.tabs[i].code, selection.start);
+        match self.find.display(ui) {
+            FindAction::Next => {
+                if let Some(range) = self.find.step(true) {
+                    self.select(i, range);
                 }
-                // extract the file name for the tab and display it
-                // as a clickable label
-                let p = code_file.path.clone().unwrap();
-                let fname = p.as_path().file_name().unwrap();
-                let fname = fname.to_str().unwrap();
-                let mut text = RichText::new(fname);
-                // active tab should be hightlighted
-                if let Some(at) = self.active_tab {
-                    if at == i {
-                        text = text.underline();
-                    }
+            },
+            FindAction::Previous => {
+                if let Some(range) = self.find.step(false) {
+                    self.select(i, range);
                 }
-                if !self.tabs[i].synced {
-                    text = text.color(egui::Color32::RED);
+            },
+            FindAction::Replace => {
+                let before = self.tabs[i].code.to_string();
+                let mut after = before.clone();
+                if let Some(range) = self.find.replace_current(&mut after) {
+                    self.tabs[i].replace_code(&before, &after, EditKind::Replace);
+                    self.select(i, range.end..range.end);
+                    self.code_changed(i);
                 }
-                let label = Label::new(text).sense(Sense::click());
-                if ui.add(label).clicked() {
-                    self.active_tab = Some(i);
+            },
+            FindAction::ReplaceAll => {
+                let before = self.tabs[i].code.to_string();
+                let mut after = before.clone();
+                let count = self.find.replace_all(&mut after);
+                if count > 0 {
+                    info!("replaced {} matches", count);
+                    // all of the replacements are a single step in the history
+                    self.tabs[i].replace_code(&before, &after, EditKind::Replace);
+                    self.code_changed(i);
+                }
+            },
+            FindAction::Close => {
+                self.find.visible = false;
+                ctx.memory_mut(|mem| mem.request_focus(id));
+            },
+            FindAction::None => (),
+        }
+        ui.separator();
+    }
+
+    /// Show the panes of the editor, with the find bar and diagnostics of the active
+    /// tab above them
+    pub fn display_code(&mut self, ctx: &egui::Context, ui: &mut Ui) {
+
+        // replay the keystrokes that were held back on the last frame
+        if !self.deferred_input.is_empty() {
+            let deferred = std::mem::take(&mut self.deferred_input);
+            ui.input_mut(|input| {
+                input.events.splice(0..0, deferred);
+            });
+        }
+
+        // pick up a workspace that finished loading, and give it any unsaved edits
+        if self.rust_analyzer.update() {
+            for tab in self.tabs.iter().filter(|tab| !tab.synced) {
+                if let Some(path) = &tab.path {
+                    self.rust_analyzer.set_file_contents(path, &tab.code.to_string());
                 }
-                ui.separator();
             }
-            // Remove a tab if necessary
-            // TODO -- make it so that the active tab is changed only if
-            //   the closed tab was the active tab.
-            if let Some(i) = idx_to_remove {
-                let _ = self.tabs.remove(i);
-                let mut at = i;
-                if self.tabs.len() == 0 {
-                    self.active_tab = None;
-                } else {
-                    if at >= self.tabs.len() {
-                        at -= 1;
+            self.hover = None;
+            self.pending_hover = None;
+        }
+        if *self.rust_analyzer.get_status() == WorkspaceStatus::Loading {
+            ctx.request_repaint_after(std::time::Duration::from_millis(500));
+        }
+        self.check_external_changes(ctx);
+        self.display_conflict_prompt(ctx);
+
+        // the pane that has the keyboard is where files open and shortcuts go
+        if let Some(p) = (0..self.panes.len()).find(|p| ctx.memory(|mem| mem.has_focus(text_edit_id(*p)))) {
+            self.focused_pane = p;
+        }
+
+        // our own edit history replaces the text edit's undo, which doesn't survive
+        // switching tabs and misses edits made outside of the text edit. Selecting all,
+        // and going to the start or end, cover the whole buffer rather than the window.
+        let id = text_edit_id(self.focused_pane);
+        if ctx.memory(|mem| mem.has_focus(id)) {
+            let keymap = keymap::get_keymap(ctx);
+            let (undo, redo, select_all, to_start, to_end) = ui.input_mut(|input| {
+                let undo = keymap.consume(input, Command::Undo);
+                let redo = keymap.consume(input, Command::Redo);
+                let select_all = keymap.consume(input, Command::SelectAll);
+                let to_start = keymap.consume(input, Command::GoToStart);
+                let to_end = keymap.consume(input, Command::GoToEnd);
+                (undo, redo, select_all, to_start, to_end)
+            });
+            if undo {
+                self.undo();
+            }
+            if redo {
+                self.redo();
+            }
+            if let Some(tab) = self.active_tab().and_then(|i| self.tabs.get_mut(i)) {
+                let end = tab.code.len_chars();
+                if select_all {
+                    tab.anchor = 0;
+                    tab.cursor = end;
+                }
+                if to_start {
+                    self.pending_selection = Some(0..0);
+                }
+                if to_end {
+                    self.pending_selection = Some(end..end);
+                }
+            }
+        }
+        self.display_find_bar(ctx, ui);
+        self.display_diagnostics(ui);
+        self.display_panes(ctx, ui);
+
+        // list the results of the last find-references query
+        let mut goto = None;
+        egui::Window::new("references")
+            .open(&mut self.show_references)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                ScrollArea::vertical().show(ui, |ui| {
+                    for location in self.references.iter() {
+                        let fname = location.path.file_name().unwrap_or_default().to_string_lossy();
+                        let text = format!("{}:{}  {}", fname, location.line, location.preview);
+                        let label = Label::new(RichText::new(text).monospace()).sense(Sense::click());
+                        if ui.add(label).on_hover_text(location.path.display().to_string()).clicked() {
+                            goto = Some(location.clone());
+                        }
                     }
-                    self.active_tab = Some(at);
+                });
+            });
+        if let Some(location) = goto {
+            self.goto_line(&location.path, location.line).unwrap_or_else(|e| {
+                warn!("couldn't open {}: {:?}", location.path.display(), e);
+            });
+        }
+
+        self.display_close_prompt(ctx);
+        self.display_rename_window(ctx);
+        self.display_refactor_preview(ctx);
+    }
+
+    /// Lay out the panes side by side (or one above the other), and move a tab that
+    /// was dragged onto another pane
+    fn display_panes(&mut self, ctx: &egui::Context, ui: &mut Ui) {
+        let rect = ui.available_rect_before_wrap();
+        let n = self.panes.len() as f32;
+        self.pane_rects = (0..self.panes.len())
+            .map(|p| {
+                let p = p as f32;
+                match self.split {
+                    SplitDirection::Horizontal => {
+                        let width = rect.width() / n;
+                        let left = rect.left() + p * width;
+                        egui::Rect::from_x_y_ranges(left..=(left + width), rect.y_range())
+                    },
+                    SplitDirection::Vertical => {
+                        let height = rect.height() / n;
+                        let top = rect.top() + p * height;
+                        egui::Rect::from_x_y_ranges(rect.x_range(), top..=(top + height))
+                    },
                 }
+            })
+            .collect();
+        let mut to_close: Option<(usize, usize)> = None;
+        for p in 0..self.panes.len() {
+            let pane_rect = self.pane_rects[p];
+            let mut pane_ui = ui.child_ui(pane_rect.shrink(2.0), egui::Layout::top_down(egui::Align::Min));
+            pane_ui.set_clip_rect(pane_rect.intersect(ui.clip_rect()));
+            let closed = pane_ui.push_id(p, |ui| self.display_pane(ctx, ui, p)).inner;
+            if let Some(i) = closed {
+                to_close = Some((p, i));
             }
-        });
+            if p > 0 {
+                let stroke = ui.visuals().widgets.noninteractive.bg_stroke;
+                match self.split {
+                    SplitDirection::Horizontal => ui.painter().vline(pane_rect.left(), pane_rect.y_range(), stroke),
+                    SplitDirection::Vertical => ui.painter().hline(pane_rect.x_range(), pane_rect.top(), stroke),
+                };
+            }
+        }
+        ui.expand_to_include_rect(rect);
+
+        // close a tab, or ask first if it has unsaved changes that would be lost. Tabs
+        // can't be closed while the prompt for another one is open.
+        if let Some((p, i)) = to_close.filter(|_| self.pending_close.is_none()) {
+            let last_view = self.panes.iter().filter(|pane| pane.tabs.contains(&i)).count() == 1;
+            match last_view && !self.tabs[i].synced {
+                true => self.pending_close = Some((p, i)),
+                false => self.close_tab(p, i),
+            }
+        }
+
+        // a dragged tab follows the pointer, and moves to the pane it's dropped on
+        let Some((from, i)) = self.dragged_tab else {
+            return;
+        };
+        let pointer = ctx.pointer_interact_pos();
+        let target = pointer.and_then(|pos| self.pane_rects.iter().position(|rect| rect.contains(pos)));
+        if ctx.input(|input| input.pointer.any_down()) {
+            if let Some(to) = target.filter(|to| *to != from) {
+                ui.painter().rect_stroke(self.pane_rects[to].shrink(1.0), 0.0, (2.0, ui.visuals().selection.bg_fill));
+            }
+            if let (Some(pos), Some(path)) = (pointer, &self.tabs[i].path) {
+                let fname = path.file_name().unwrap_or_default().to_string_lossy();
+                egui::Area::new("dragged_editor_tab")
+                    .order(egui::Order::Tooltip)
+                    .fixed_pos(pos)
+                    .show(ctx, |ui| {
+                        egui::Frame::popup(ui.style()).show(ui, |ui| ui.label(fname.as_ref()));
+                    });
+            }
+        } else {
+            self.dragged_tab = None;
+            if let Some(to) = target.filter(|to| *to != from) {
+                self.move_tab(from, to, i);
+            }
+        }
     }
 
-}
+    /// Show the tabs of a pane, and the code of its active tab. The scroll area is sized
+    /// for the whole buffer, but only a window of lines (the ones in view, and the
+    /// selection) goes into the text edit, and the edits made there are put back into
+    /// the tab's rope. Returns the buffer of a tab that the user asked to close.
+    fn display_pane(&mut self, ctx: &egui::Context, ui: &mut Ui, p: usize) -> Option<usize> {
+        let closed = self.display_pane_tabs(ctx, ui, p);
+        ui.separator();
+        let id = text_edit_id(p);
 
-fn as_byte_range(whole: &str, range: &str) -> std::ops::Range<usize> {
-    let whole_start = whole.as_ptr() as usize;
-    let range_start = range.as_ptr() as usize;
-    assert!(whole_start <= range_start);
-    assert!(range_start + range.len() <= whole_start + whole.len());
-    let offset = range_start - whole_start;
-    offset..(offset + range.len())
-}
+        // First, get some data from the object, and detect if the pane has a tab open
+        // (if not, just return)
+        let CodeEditor {
+            tabs,
+            panes,
+            focused_pane,
+            pending_goto_line,
+            pending_selection,
+            highlighter,
+            rust_analyzer,
+            completions,
+            selected_completion,
+            pending_completions,
+            hover,
+            pending_hover,
+            references,
+            show_references,
+            rename,
+            refactor_preview,
+            show_line_numbers,
+            deferred_input,
+            find,
+            ..
+        } = self;
+        let Some(i) = panes[p].active_tab else {
+            return closed;
+        };
+        let tab = &mut tabs[i];
+        let focused = p == *focused_pane;
+
+        // select a search match, or put the cursor where an undo left off, or move it
+        // to a requested line; either way it is scrolled into view. Those requests are
+        // for the focused pane.
+        let mut scroll_to_cursor = false;
+        let (pending_selection, pending_goto_line) = match focused {
+            true => (pending_selection.take(), pending_goto_line.take()),
+            false => (None, None),
+        };
+        if let Some(range) = pending_selection {
+            tab.anchor = range.start;
+            tab.cursor = range.end;
+            scroll_to_cursor = true;
+        }
+        if let Some(line) = pending_goto_line {
+            tab.cursor = tab.code.line_to_char(line.saturating_sub(1));
+            tab.anchor = tab.cursor;
+            scroll_to_cursor = true;
+            ctx.memory_mut(|mem| mem.request_focus(id));
+        }
+        // the code may have gotten shorter since the cursor was placed
+        tab.cursor = tab.cursor.min(tab.code.len_chars());
+        tab.anchor = tab.anchor.min(tab.code.len_chars());
+        // the cursor never hides in folded code
+        tab.folds.update(ctx, &tab.code, &tab.language);
+        let cursor_line = tab.code.byte_to_line(tab.code.char_to_byte(tab.cursor));
+        tab.folds.unfold_lines(cursor_line..(cursor_line + 1));
+
+        // the search matches are drawn over the highlighted code
+        let mut find_ranges = Vec::new();
+        if find.visible && panes[*focused_pane].active_tab == Some(i) {
+            let (matches, current) = find.get_matches();
+            let color = ui.visuals().selection.bg_fill;
+            for (idx, range) in matches.iter().enumerate() {
+                let color = if Some(idx) == current { color } else { color.gamma_multiply(0.4) };
+                find_ranges.push((range.clone(), color));
+            }
+        }
+
+        let language = tab.language.clone();
 
-// Implementation of egui's ComputerMut trait to cache the syntax highlighting.
-// This function will only call the compute function if the  (code, lang) tuple
-// changes (i.e. the code was modified), or the language type changed (not  yet
-// supported in Iron Coder). Otherwise, the cached LayoutJob will be returned.
-pub fn highlight(ctx: &egui::Context, code: &str, language: &str) -> LayoutJob {
-    // ComputerMut trait
-    impl egui::util::cache::ComputerMut<(&str, &str), LayoutJob> for CodeEditor {
-        fn compute(&mut self, (code, lang): (&str, &str)) -> LayoutJob {
-            self.highlight(code, lang)
-        }
-    }
-    type HighlightCache = egui::util::cache::FrameCache<LayoutJob, CodeEditor>;
-    // either return cached LayoutJob, or compute the new one
-    ctx.memory_mut(|mem| {
-        mem.caches
-            .cache::<HighlightCache>()
-            .get((code, language))
-    })
-    
-}
\ No newline at end of file
+        // Handle the code analysis shortcuts. While the completion popup is open,
+        // it takes the navigation keys before the text edit sees them.
+        let mut request_completions = false;
+        let mut accept_completion = false;
+        let mut goto_definition = false;
+        let mut find_references = false;
+        let mut start_rename = false;
+        let mut undo = false;
+        let mut redo = false;
+        let mut refactoring: Option<Refactoring> = None;
+        let mut fold = false;
+        let mut unfold = false;
+        let keymap = keymap::get_keymap(ctx);
+        if ctx.memory(|mem| mem.has_focus(id)) {
+            ui.input_mut(|input| {
+                request_completions = keymap.consume(input, Command::Complete);
+                fold = keymap.consume(input, Command::Fold);
+                unfold = keymap.consume(input, Command::Unfold);
+                goto_definition = keymap.consume(input, Command::GoToDefinition);
+                find_references = keymap.consume(input, Command::FindReferences);
+                start_rename = keymap.consume(input, Command::RenameSymbol);
+                if !completions.is_empty() {
+                    let n = completions.len();
+                    if input.consume_key(Modifiers::NONE, Key::ArrowDown) {
+                        *selected_completion = (*selected_completion + 1) % n;
+                    }
+                    if input.consume_key(Modifiers::NONE, Key::ArrowUp) {
+                        *selected_completion = (*selected_completion + n - 1) % n;
+                    }
+                    accept_completion = input.consume_key(Modifiers::NONE, Key::Enter)
+                        || input.consume_key(Modifiers::NONE, Key::Tab);
+                    if input.consume_key(Modifiers::NONE, Key::Escape) {
+                        completions.clear();
+                        *pending_completions = None;
+                    }
+                }
+            });
+        }
+
+        if fold {
+            tab.folds.fold_at(cursor_line);
+            tab.cursor_out_of_folds();
+        }
+        if unfold {
+            tab.folds.unfold_at(cursor_line);
+        }
+        let revision = tab.code.revision();
+        let hidden = tab.folds.hidden_lines();
+        // copying or cutting across folded lines takes the lines along, which the text
+        // edit can't do as it doesn't have them
+        let selection = tab.anchor.min(tab.cursor)..tab.anchor.max(tab.cursor);
+        let selection = tab.code.char_to_byte(selection.start)..tab.code.char_to_byte(selection.end);
+        let selected_lines = tab.code.byte_to_line(selection.start)..(tab.code.byte_to_line(selection.end) + 1);
+        let selects_hidden = hidden.iter().any(|lines| lines.start < selected_lines.end && selected_lines.start < lines.end);
+        if selects_hidden && ctx.memory(|mem| mem.has_focus(id)) {
+            let (copy, cut) = ui.input_mut(|input| {
+                let copy = input.events.iter().any(|event| matches!(event, egui::Event::Copy));
+                let cut = input.events.iter().any(|event| matches!(event, egui::Event::Cut));
+                input.events.retain(|event| !matches!(event, egui::Event::Copy | egui::Event::Cut));
+                (copy, cut)
+            });
+            if copy || cut {
+                let text = tab.code.slice(selection.clone());
+                if cut {
+                    tab.history.record_at(selection.start, &text, "", EditKind::Typing);
+                    tab.code.replace(selection.clone(), "");
+                    tab.folds.unfold_lines(selected_lines);
+                    tab.cursor = tab.code.byte_to_char(selection.start);
+                    tab.anchor = tab.cursor;
+                    tab.synced = false;
+                }
+                ui.output_mut(|output| output.copied_text = text);
+            }
+        }
+        let hidden = tab.folds.hidden_lines();
+
+        let row_height = ui.fonts(|f| f.row_height(&line_number_font()));
+        let num_lines = tab.code.len_lines();
+        let mut scroll_area = ScrollArea::both().auto_shrink([false; 2]);
+        // each tab keeps its own scroll position
+        let shown_path = &mut panes[p].shown_path;
+        if *shown_path != tab.path {
+            shown_path.clone_from(&tab.path);
+            scroll_area = scroll_area.vertical_scroll_offset(tab.scroll);
+        }
+        let scroll_output = scroll_area.show_viewport(ui, |ui, viewport| {
+            let mut goto: Option<Location> = None;
+            let content_top = ui.max_rect().top();
+            ui.set_height(row_height * folding::num_visible(num_lines, &hidden) as f32);
+
+            // take the lines to edit out of the rope, leaving out the folded ones
+            let selection = tab.anchor.min(tab.cursor)..tab.anchor.max(tab.cursor);
+            let lines = window_lines(&tab.code, selection, viewport, row_height, &hidden);
+            let mut window = TextWindow::new(&tab.code, lines.clone(), &hidden);
+            let runs = window.runs.clone();
+
+            // give the text edit the tab's cursor, relative to the window
+            let text_cursor = window.char_to_window(&tab.code, tab.cursor);
+            let cursor_in_window = text_cursor.is_some();
+            let mut state = egui::TextEdit::load_state(ctx, id).unwrap_or_default();
+            let cursor_range = text_cursor.map(|cursor| {
+                let anchor = match window.char_to_window(&tab.code, tab.anchor) {
+                    Some(anchor) => anchor,
+                    // the selection goes on past the window
+                    None if tab.anchor < tab.cursor => 0,
+                    None => window.former.chars().count(),
+                };
+                let anchor = egui::text::CCursor::new(anchor);
+                let cursor = egui::text::CCursor::new(cursor);
+                egui::text_edit::CCursorRange::two(anchor, cursor)
+            });
+            state.set_ccursor_range(cursor_range);
+            state.store(ctx, id);
+            // keys pressed while the cursor is out of the window would land in the wrong
+            // place, so they are held back until the cursor is scrolled into view
+            if !cursor_in_window && ctx.memory(|mem| mem.has_focus(id)) {
+                let held: Vec<egui::Event> = ui.input_mut(|input| {
+                    let (held, rest) = std::mem::take(&mut input.events).into_iter().partition(|event| {
+                        matches!(event, egui::Event::Text(_) | egui::Event::Paste(_) | egui::Event::Cut
+                            | egui::Event::Copy | egui::Event::Key { pressed: true, .. })
+                    });
+                    input.events = rest;
+                    held
+                });
+                if !held.is_empty() {
+                    deferred_input.extend(held);
+                    scroll_to_cursor = true;
+                }
+            }
+
+            // the matches in folded lines aren't shown
+            let find_ranges: Vec<_> = find_ranges.iter()
+                .filter_map(|(range, color)| {
+                    let start = window.to_window(range.start)?;
+                    let end = window.to_window(range.end)?;
+                    (end - start == range.len()).then_some((start..end, *color))
+                })
+                .collect();
+            let view_highlight = &mut panes[p].highlight;
+            let mut layouter = |ui: &egui::Ui, string: &str, _wrap_width: f32| {
+                // the highlighter only re-highlights the lines that changed since the last call
+                let highlighted = highlighter.highlight(&mut tab.highlight, view_highlight, &tab.code, &runs, string, &language);
+                debug!("highlighted {} lines", highlighter.get_last_highlighted());
+                let mut layout_job = highlighted.unwrap_or_else(|| {
+                    // the lines above are still being parsed
+                    ui.ctx().request_repaint();
+                    highlighter.plain(string)
+                });
+                // the matches may be out of date if the code was just edited
+                let up_to_date = find_ranges.iter().all(|(range, _)| {
+                    string.is_char_boundary(range.start) && string.is_char_boundary(range.end)
+                });
+                if up_to_date {
+                    find_replace::highlight_ranges(&mut layout_job, &find_ranges);
+                }
+                ui.fonts(|f| f.layout_job(layout_job))
+            };
+
+            // leave room for the line numbers and fold markers, and reserve a shape behind
+            // the text for the current line and bracket highlights, which are painted once
+            // the text is laid out
+            let fold_width = match language.as_str() {
+                "Rust" => FOLD_MARKER_WIDTH,
+                _ => 0.0,
+            };
+            let gutter_width = fold_width + match show_line_numbers {
+                true => gutter_width(ui, num_lines),
+                false => 0.0,
+            };
+            let background = ui.painter().add(egui::Shape::Noop);
+            let first_row = folding::line_to_row(lines.start, &hidden);
+            let num_rows = folding::line_to_row(lines.end, &hidden) - first_row;
+            let top = content_top + first_row as f32 * row_height;
+            let rect = egui::Rect::from_x_y_ranges(ui.max_rect().x_range(), top..=(top + num_rows as f32 * row_height));
+            let output = ui.allocate_ui_at_rect(rect, |ui| {
+                ui.horizontal_top(|ui| {
+                    ui.add_space(gutter_width);
+                    egui::TextEdit::multiline(&mut window.text)
+                        .id(id)
+                        .font(egui::TextStyle::Name("EditorFont".into()))
+                        .code_editor()
+                        .lock_focus(true)
+                        .desired_width(f32::INFINITY)
+                        .frame(false)
+                        .margin(egui::vec2(4.0, 0.0))
+                        .layouter(&mut layouter)
+                        .show(ui)
+                }).inner
+            }).inner;
+            let cursor_rect = |char_idx: usize| {
+                let ccursor = egui::text::CCursor::new(char_idx);
+                output.galley
+                    .pos_from_cursor(&output.galley.from_ccursor(ccursor))
+                    .translate(output.text_draw_pos.to_vec2())
+            };
+            let resp = output.response.clone().context_menu(|ui| {
+                if ui.add(egui::Button::new("go to definition").shortcut_text(keymap.get_shortcut_text(Command::GoToDefinition))).clicked() {
+                    goto_definition = true;
+                    ui.close_menu();
+                }
+                if ui.add(egui::Button::new("find references").shortcut_text(keymap.get_shortcut_text(Command::FindReferences))).clicked() {
+                    find_references = true;
+                    ui.close_menu();
+                }
+                if ui.add(egui::Button::new("complete").shortcut_text(keymap.get_shortcut_text(Command::Complete))).clicked() {
+                    request_completions = true;
+                    ui.close_menu();
+                }
+                ui.separator();
+                let undo_kind = tab.history.get_undo_kind();
+                let undo_button = egui::Button::new("undo").shortcut_text(keymap.get_shortcut_text(Command::Undo));
+                if ui.add_enabled(undo_kind.is_some(), undo_button).clicked() {
+                    undo = true;
+                    ui.close_menu();
+                }
+                let redo_kind = tab.history.get_redo_kind();
+                let redo_button = egui::Button::new("redo").shortcut_text(keymap.get_shortcut_text(Command::Redo));
+                if ui.add_enabled(redo_kind.is_some(), redo_button).clicked() {
+                    redo = true;
+                    ui.close_menu();
+                }
+                ui.separator();
+                if ui.add(egui::Button::new("rename symbol").shortcut_text(keymap.get_shortcut_text(Command::RenameSymbol))).clicked() {
+                    start_rename = true;
+                    ui.close_menu();
+                }
+                for r in Refactoring::ALL {
+                    if ui.button(r.to_string()).clicked() {
+                        refactoring = Some(r);
+                        ui.close_menu();
+                    }
+                }
+                if fold_width > 0.0 {
+                    ui.separator();
+                    if ui.button("fold all").clicked() {
+                        tab.folds.fold_all();
+                        ui.close_menu();
+                    }
+                    if ui.button("unfold all").clicked() {
+                        tab.folds.unfold_all();
+                        ui.close_menu();
+                    }
+                }
+            });
+            let path = tab.path.clone();
+            // clicking in a pane moves the focus to it
+            if resp.clicked() || resp.dragged() || resp.secondary_clicked() {
+                *focused_pane = p;
+            }
+            let focused = p == *focused_pane;
+            // take the cursor back from the text edit of the focused pane, unless it made
+            // one up because the tab's cursor is out of the window
+            let has_cursor = output.cursor_range.is_some() && focused;
+            let mut window_cursor = output.cursor_range
+                .filter(|_| focused && (cursor_in_window || resp.clicked() || resp.dragged()))
+                .map(|range| (range.primary.ccursor.index, range.secondary.ccursor.index));
+            // indent a new line like the one before it
+            if let Some((cursor, anchor)) = &mut window_cursor {
+                if let Some(n) = auto_indent(&mut window.text, &window.former, *cursor) {
+                    *cursor += n;
+                    *anchor = *cursor;
+                }
+            }
+            // put the edits made in the window back into the code, and take the cursor
+            // back from the text edit
+            let mut positions = window_cursor.map_or(Vec::new(), |(cursor, anchor)| vec![cursor, anchor]);
+            window.apply(tab, &mut positions);
+            if let [cursor, anchor] = positions[..] {
+                tab.cursor = cursor;
+                tab.anchor = anchor;
+            }
+            let error_lines: Vec<usize> = tab.diagnostics.iter().filter_map(|d| d.line).collect();
+            let cursor = window_cursor.map(|(cursor, _)| cursor);
+            paint_decorations(ui, &output, &window.text, cursor, background, gutter_width, *show_line_numbers, &error_lines, &runs);
+            if fold_width > 0.0 {
+                display_fold_markers(ui, &output, &runs, gutter_width, &mut tab.folds);
+            }
+            tab.cursor_out_of_folds();
+
+            if scroll_to_cursor {
+                match cursor {
+                    Some(cursor) => ui.scroll_to_rect(cursor_rect(cursor), Some(egui::Align::Center)),
+                    None => {
+                        // the window catches up with the cursor on the next frame
+                        let line = tab.code.byte_to_line(tab.code.char_to_byte(tab.cursor));
+                        let top = content_top + folding::line_to_row(line, &hidden) as f32 * row_height;
+                        let rect = egui::Rect::from_x_y_ranges(output.text_draw_pos.x..=output.text_draw_pos.x, top..=(top + row_height));
+                        ui.scroll_to_rect(rect, Some(egui::Align::Center));
+                        ctx.request_repaint();
+                    },
+                }
+            }
+
+            if resp.clicked() && ui.input(|input| input.modifiers.command) {
+                goto_definition = true;
+            }
+            // moving the cursor away ends the current group of keystrokes
+            if resp.clicked() || resp.lost_focus() {
+                tab.history.close_group();
+            }
+
+            // show the completion popup under the cursor
+            if let (Some(cursor), false) = (cursor, completions.is_empty()) {
+                egui::Area::new("completion_popup")
+                    .order(egui::Order::Foreground)
+                    .fixed_pos(cursor_rect(cursor).left_bottom())
+                    .show(ctx, |ui| {
+                        egui::Frame::popup(ui.style()).show(ui, |ui| {
+                            ScrollArea::vertical().max_height(200.0).show(ui, |ui| {
+                                for (idx, completion) in completions.iter().enumerate() {
+                                    let mut text = completion.label.clone();
+                                    if let Some(detail) = &completion.detail {
+                                        text = format!("{}  {}", text, detail);
+                                    }
+                                    let label = ui.selectable_label(idx == *selected_completion, RichText::new(text).monospace());
+                                    if idx == *selected_completion {
+                                        label.scroll_to_me(None);
+                                    }
+                                    if label.clicked() {
+                                        *selected_completion = idx;
+                                        accept_completion = true;
+                                    }
+                                }
+                            });
+                        });
+                    });
+            }
+
+            if let (Some(path), true) = (&path, has_cursor) {
+                // pick up the completions computed in the background, if they are still
+                // for the code. Until then, the ones shown are out of date and can't be accepted.
+                if let Some((pending_path, pending_revision, rx)) = pending_completions {
+                    match rx.try_recv() {
+                        Ok(result) => {
+                            if pending_path == path && *pending_revision == tab.code.revision() {
+                                *completions = result.unwrap_or_else(|e| {
+                                    warn!("couldn't get completions: {:?}", e);
+                                    Vec::new()
+                                });
+                                *selected_completion = 0;
+                            }
+                            *pending_completions = None;
+                        },
+                        Err(mpsc::TryRecvError::Empty) => {
+                            accept_completion = false;
+                            ctx.request_repaint();
+                        },
+                        Err(mpsc::TryRecvError::Disconnected) => *pending_completions = None,
+                    }
+                }
+                let mut offset = tab.code.char_to_byte(tab.cursor);
+                if accept_completion {
+                    if let Some(completion) = completions.get(*selected_completion) {
+                        let before = tab.code.to_string();
+                        let mut after = before.clone();
+                        offset = completion.apply(&mut after, offset);
+                        tab.replace_code(&before, &after, EditKind::Completion);
+                        tab.cursor = tab.code.byte_to_char(offset);
+                        tab.anchor = tab.cursor;
+                        resp.request_focus();
+                    }
+                    completions.clear();
+                }
+                let (line_start, line) = line_at(&tab.code, offset);
+                let code_changed = tab.code.revision() != revision;
+                if code_changed {
+                    rust_analyzer.set_file_contents(path, &tab.code.to_string());
+                    *hover = None;
+                    *pending_hover = None;
+                    // typing after a `.` or `::`, or while the popup is open, updates the completions
+                    let before = &line[..(offset - line_start)];
+                    if !accept_completion && (before.ends_with('.') || before.ends_with("::")) {
+                        request_completions = true;
+                    } else if !completions.is_empty() {
+                        if before.ends_with(|c: char| c.is_alphanumeric() || c == '_') {
+                            request_completions = true;
+                        } else {
+                            completions.clear();
+                        }
+                    }
+                }
+                if request_completions {
+                    match rust_analyzer.completions(path, offset) {
+                        Ok(rx) => {
+                            *pending_completions = Some((path.clone(), tab.code.revision(), rx));
+                            ctx.request_repaint();
+                        },
+                        Err(e) => {
+                            warn!("couldn't get completions: {:?}", e);
+                            completions.clear();
+                            *pending_completions = None;
+                        },
+                    }
+                }
+                if goto_definition {
+                    match rust_analyzer.goto_definition(path, offset) {
+                        Ok(locations) if locations.is_empty() => info!("no definition found"),
+                        Ok(locations) => goto = locations.into_iter().next(),
+                        Err(e) => warn!("couldn't go to definition: {:?}", e),
+                    }
+                }
+                if find_references {
+                    match rust_analyzer.find_references(path, offset) {
+                        Ok(locations) => {
+                            *references = locations;
+                            *show_references = true;
+                        },
+                        Err(e) => warn!("couldn't find references: {:?}", e),
+                    }
+                }
+                if start_rename {
+                    *rename = Some((path.clone(), offset, word_at(&line, offset - line_start)));
+                }
+                if let Some(r) = refactoring {
+                    let selection = tab.code.char_to_byte(tab.anchor.min(tab.cursor))..tab.code.char_to_byte(tab.anchor.max(tab.cursor));
+                    let result = rust_analyzer.refactor(path, selection, r);
+                    *refactor_preview = Some(result.map_err(error_message));
+                }
+            }
+
+            // show the type and docs of whatever the pointer rests on
+            if resp.hovered() && completions.is_empty() && ui.input(|input| input.pointer.is_still()) {
+                if let (Some(path), Some(pos)) = (&path, resp.hover_pos()) {
+                    let ccursor = output.galley.cursor_from_pos(pos - output.text_draw_pos).ccursor;
+                    let offset = window.to_code(byte_offset(&window.text, ccursor.index), false);
+                    let cached = matches!(hover, Some((p, o, _)) if p == path && *o == offset);
+                    let pending = matches!(pending_hover, Some((p, o, _)) if p == path && *o == offset);
+                    if !cached && !pending {
+                        *pending_hover = rust_analyzer.hover(path, offset).ok()
+                            .map(|rx| (path.clone(), offset, rx));
+                    }
+                    if let Some((p, o, rx)) = pending_hover {
+                        match rx.try_recv() {
+                            Ok(text) => {
+                                *hover = Some((p.clone(), *o, text.unwrap_or(None)));
+                                *pending_hover = None;
+                            },
+                            Err(mpsc::TryRecvError::Empty) => ctx.request_repaint(),
+                            Err(mpsc::TryRecvError::Disconnected) => *pending_hover = None,
+                        }
+                    }
+                    // the text for where the pointer was before isn't shown while it's pending
+                    if let Some((_, _, Some(text))) = hover.as_ref().filter(|(p, o, _)| p == path && *o == offset) {
+                        egui::show_tooltip_at_pointer(ctx, egui::Id::new("code_editor_hover"), |ui| {
+                            ui.label(RichText::new(text.as_str()).monospace());
+                        });
+                    }
+                }
+            }
+
+            // See if a code snippet was released over the editor.
+            // TODO -- if so, insert it on the proper line
+            ctx.memory_mut(|mem| {
+                let id = egui::Id::new("released_code_snippet");
+                let data: Option<String> = mem.data.get_temp(id);
+                if let Some(value) = data {
+                    if resp.hovered() {
+                        info!("found a released code snippet!");
+                        mem.data.remove::<String>(id);
+                        let end = tab.code.len();
+                        tab.history.record_at(end, "", &value, EditKind::Snippet);
+                        tab.code.replace(end..end, &value);
+                    }
+                }
+            });
+            // any edit leaves the tab with unsaved changes
+            if tab.code.revision() != revision {
+                tab.synced = false;
+            }
+            goto
+        });
+        tab.scroll = scroll_output.state.offset.y;
+
+        if let Some(location) = scroll_output.inner {
+            self.goto_line(&location.path, location.line).unwrap_or_else(|e| {
+                warn!("couldn't open {}: {:?}", location.path.display(), e);
+            });
+        }
+
+        if undo {
+            self.undo();
+        }
+        if redo {
+            self.redo();
+        }
+        closed
+    }
+
+    /// Ask for the new name of the symbol being renamed
+    fn display_rename_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.rename.is_some();
+        let mut preview = false;
+        if let Some((_, _, new_name)) = &mut self.rename {
+            egui::Window::new("rename symbol")
+                .open(&mut open)
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    let response = ui.text_edit_singleline(new_name);
+                    response.request_focus();
+                    if response.lost_focus() && ui.input(|input| input.key_pressed(Key::Enter)) {
+                        preview = true;
+                    }
+                    if ui.button("preview").clicked() {
+                        preview = true;
+                    }
+                });
+        }
+        if preview {
+            if let Some((path, offset, new_name)) = self.rename.take() {
+                let result = self.rust_analyzer.rename(&path, offset, &new_name);
+                self.refactor_preview = Some(result.map_err(error_message));
+            }
+        }
+        if !open {
+            self.rename = None;
+        }
+    }
+
+    /// Show the edits of a refactoring, and apply them if confirmed
+    fn display_refactor_preview(&mut self, ctx: &egui::Context) {
+        let mut open = self.refactor_preview.is_some();
+        let mut apply = false;
+        let title = match &self.refactor_preview {
+            Some(Ok(edit)) => edit.title.clone(),
+            _ => "refactoring".to_string(),
+        };
+        if let Some(preview) = &self.refactor_preview {
+            egui::Window::new(title)
+                .id(egui::Id::new("refactor_preview"))
+                .open(&mut open)
+                .collapsible(false)
+                .show(ctx, |ui| {
+                    let edit = match preview {
+                        Ok(edit) => edit,
+                        Err(e) => {
+                            ui.label(e.as_str());
+                            return;
+                        },
+                    };
+                    ScrollArea::vertical().max_height(400.0).show(ui, |ui| {
+                        for (path, previews) in edit.previews.iter() {
+                            ui.label(RichText::new(path.display().to_string()).underline());
+                            for p in previews.iter() {
+                                ui.label(RichText::new(format!("line {}", p.line)).weak());
+                                if !p.old.is_empty() {
+                                    ui.label(RichText::new(p.old.as_str()).monospace().strikethrough().color(egui::Color32::RED));
+                                }
+                                if !p.new.is_empty() {
+                                    ui.label(RichText::new(p.new.as_str()).monospace().color(egui::Color32::GREEN));
+                                }
+                            }
+                            ui.separator();
+                        }
+                        for (path, _) in edit.created_files.iter() {
+                            ui.label(format!("create {}", path.display()));
+                        }
+                        for (from, to) in edit.moved_files.iter() {
+                            ui.label(format!("move {} to {}", from.display(), to.display()));
+                        }
+                    });
+                    if ui.button("apply").clicked() {
+                        apply = true;
+                    }
+                });
+        }
+        if apply {
+            if let Some(Ok(edit)) = self.refactor_preview.take() {
+                self.apply_source_edit(&edit);
+            }
+        }
+        if !open {
+            self.refactor_preview = None;
+        }
+    }
+
+    /// The code of the tab that has `file_path` open, if there is one
+    pub fn get_open_code(&self, file_path: &Path) -> Option<String> {
+        self.tabs.iter()
+            .find(|tab| tab.path.as_deref() == Some(file_path))
+            .map(|tab| tab.code.to_string())
+    }
+
+    /// Replace the whole contents of a file. If the file is open the change goes to its
+    /// tab (where it can be undone), otherwise the file is written directly.
+    pub fn replace_file_contents(&mut self, file_path: &Path, code: String, kind: EditKind) -> std::io::Result<()> {
+        if let Some(tab) = self.tabs.iter_mut().find(|tab| tab.path.as_deref() == Some(file_path)) {
+            let before = tab.code.to_string();
+            tab.history.close_group();
+            tab.replace_code(&before, &code, kind);
+            tab.synced = false;
+            self.rust_analyzer.set_file_contents(file_path, &code);
+        } else {
+            fs::write(file_path, &code)?;
+            self.rust_analyzer.set_file_contents(file_path, &code);
+        }
+        self.outline = None;
+        Ok(())
+    }
+
+    /// Apply a SourceEdit to the open tabs, and to the files on disk that aren't open
+    pub fn apply_source_edit(&mut self, edit: &SourceEdit) {
+        for path in edit.edited_files() {
+            let tab = self.tabs.iter_mut().find(|tab| tab.path.as_deref() == Some(path));
+            if let Some(tab) = tab {
+                let before = tab.code.to_string();
+                let mut after = before.clone();
+                edit.apply_to(path, &mut after);
+                tab.replace_code(&before, &after, EditKind::Refactor);
+                tab.synced = false;
+                self.rust_analyzer.set_file_contents(path, &after);
+                continue;
+            }
+            let result = fs::read_to_string(path).and_then(|mut code| {
+                edit.apply_to(path, &mut code);
+                fs::write(path, &code)?;
+                Ok(code)
+            });
+            match result {
+                Ok(code) => self.rust_analyzer.set_file_contents(path, &code),
+                Err(e) => warn!("couldn't edit {}: {:?}", path.display(), e),
+            }
+        }
+        for (path, contents) in edit.created_files.iter() {
+            let result = path.parent().map(fs::create_dir_all).unwrap_or(Ok(()))
+                .and_then(|_| fs::write(path, contents));
+            if let Err(e) = result {
+                warn!("couldn't create {}: {:?}", path.display(), e);
+            }
+        }
+        for (from, to) in edit.moved_files.iter() {
+            if let Err(e) = fs::rename(from, to) {
+                warn!("couldn't move {} to {}: {:?}", from.display(), to.display(), e);
+                continue;
+            }
+            // keep an open tab pointing at the moved file
+            if let Some(tab) = self.tabs.iter_mut().find(|tab| tab.path.as_deref() == Some(from.as_path())) {
+                tab.path = Some(to.clone());
+            }
+        }
+        // the analysis doesn't know about new or moved files until it is reloaded
+        if !edit.created_files.is_empty() || !edit.moved_files.is_empty() {
+            self.rust_analyzer.reload_workspace();
+        }
+        self.outline = None;
+    }
+
+    /// Show the buttons that split the editor, and the state of the code analysis
+    pub fn display_editor_toolbar(&mut self, _ctx: &egui::Context, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            let can_split = self.active_tab().is_some();
+            if ui.add_enabled(can_split, egui::Button::new("split right").frame(false)).clicked() {
+                self.split(SplitDirection::Horizontal);
+            }
+            if ui.add_enabled(can_split, egui::Button::new("split down").frame(false)).clicked() {
+                self.split(SplitDirection::Vertical);
+            }
+            // show the state of the code analysis on the right
+            ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                let status = self.rust_analyzer.get_status();
+                let label = ui.label(RichText::new(format!("rust-analyzer: {}", status)).weak());
+                if let WorkspaceStatus::Failed(e) = status {
+                    label.on_hover_text(e.as_str());
+                }
+            });
+        });
+    }
+
+    /// Show the tabs of a pane. Returns the buffer of a tab that the user asked to close.
+    fn display_pane_tabs(&mut self, ctx: &egui::Context, ui: &mut Ui, p: usize) -> Option<usize> {
+        let icons_ref: Arc<IconSet> = ctx.data_mut(|data| {
+            data.get_temp("icons".into()).expect("error loading shared icon map!")
+        });
+        let icons = icons_ref.clone();
+        let languages = self.highlighter.get_languages();
+        ui.horizontal(|ui| {
+            let mut idx_to_remove: Option<usize> = None;
+            let mut language_override: Option<(usize, String)> = None;
+            let mut clicked: Option<usize> = None;
+            for &i in self.panes[p].tabs.iter() {
+                let code_file = &self.tabs[i];
+                // display the close icon
+                let x_icon = egui::widgets::ImageButton::new(
+                    icons.get("quit_icon").unwrap().texture_id(ctx),
+                    egui::Vec2::new(6.0, 6.0),
+                ).frame(true);
+                if ui.add(x_icon).clicked() {
+                    // we'll remove the tab right after the panes are shown, or ask
+                    // first if it has unsaved changes
+                    idx_to_remove = Some(i);
+                }
+                // extract the file name for the tab and display it
+                // as a label, which can be clicked, or dragged to another pane
+                let p_buf = code_file.path.clone().unwrap();
+                let fname = p_buf.as_path().file_name().unwrap();
+                let fname = fname.to_str().unwrap();
+                let mut text = RichText::new(fname);
+                // active tab should be hightlighted
+                if self.panes[p].active_tab == Some(i) {
+                    text = text.underline();
+                }
+                if !code_file.synced {
+                    text = text.color(egui::Color32::RED);
+                }
+                let label = ui.add(Label::new(text).sense(Sense::click_and_drag()));
+                if label.clicked() {
+                    clicked = Some(i);
+                }
+                if label.drag_started() {
+                    self.dragged_tab = Some((p, i));
+                }
+                // show the detected language, which can be overridden manually
+                let language = RichText::new(&code_file.language).small().weak();
+                ui.menu_button(language, |ui| {
+                    ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+                        for &name in languages.iter() {
+                            if ui.selectable_label(name == code_file.language, name).clicked() {
+                                language_override = Some((i, name.to_string()));
+                                ui.close_menu();
+                            }
+                        }
+                    });
+                });
+                ui.separator();
+            }
+            if let Some(i) = clicked {
+                self.panes[p].active_tab = Some(i);
+                self.focused_pane = p;
+            }
+            if let Some((i, language)) = language_override {
+                info!("highlighting {:?} as {}", self.tabs[i].path, language);
+                self.tabs[i].language = language;
+            }
+            idx_to_remove
+        }).inner
+    }
+
+    /// Close the tab of a buffer in a pane. The buffer is dropped, with any unsaved
+    /// changes, unless another pane shows it too.
+    fn close_tab(&mut self, p: usize, i: usize) {
+        self.remove_from_pane(p, i);
+        if !self.panes.iter().any(|pane| pane.tabs.contains(&i)) {
+            self.close_buffer(i);
+        }
+        self.remove_empty_panes();
+    }
+
+    /// Drop a buffer, with any unsaved changes, and its tabs in all of the panes
+    fn close_buffer(&mut self, i: usize) {
+        for p in 0..self.panes.len() {
+            self.remove_from_pane(p, i);
+        }
+        let tab = self.tabs.remove(i);
+        // discard the tab's unsaved edits from the analysis
+        if let (false, Some(path)) = (tab.synced, &tab.path) {
+            if let Ok(code) = fs::read_to_string(path) {
+                self.rust_analyzer.set_file_contents(path, &code);
+            }
+            if let Some(dir) = &self.recovery_dir {
+                recovery::remove(dir, path);
+            }
+        }
+        // the buffers after the dropped one move down by one
+        let shift = |idx: &mut usize| {
+            if *idx > i {
+                *idx -= 1;
+            }
+        };
+        for pane in self.panes.iter_mut() {
+            pane.tabs.iter_mut().for_each(&shift);
+            if let Some(idx) = pane.active_tab.as_mut() {
+                shift(idx);
+            }
+        }
+        // so do the prompt and the drag of another tab, and those of this one are moot
+        let follow = |(p, mut idx): (usize, usize)| {
+            shift(&mut idx);
+            (p, idx)
+        };
+        self.pending_close = self.pending_close.filter(|(_, idx)| *idx != i).map(follow);
+        self.dragged_tab = self.dragged_tab.filter(|(_, idx)| *idx != i).map(follow);
+    }
+
+    /// Ask whether to save the changes of a tab that is being closed
+    fn display_close_prompt(&mut self, ctx: &egui::Context) {
+        let Some((p, i)) = self.pending_close else {
+            return;
+        };
+        if !self.panes.get(p).is_some_and(|pane| pane.tabs.contains(&i)) {
+            self.pending_close = None;
+            return;
+        }
+        let file_name = self.tabs[i].path.as_ref()
+            .and_then(|path| path.file_name())
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let mut choice = None;
+        egui::Window::new("unsaved changes")
+            .id(egui::Id::new("close_tab_prompt"))
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.label(format!("{} has unsaved changes. Save them before closing it?", file_name));
+                ui.horizontal(|ui| {
+                    if ui.button("save").clicked() {
+                        choice = Some(true);
+                    }
+                    if ui.button("discard").clicked() {
+                        choice = Some(false);
+                    }
+                    if ui.button("cancel").clicked() {
+                        self.pending_close = None;
+                    }
+                });
+            });
+        match choice {
+            Some(true) => {
+                self.pending_close = None;
+                match self.tabs[i].save() {
+                    Ok(_) => self.close_tab(p, i),
+                    Err(e) => warn!("couldn't save {}: {:?}", file_name, e),
+                }
+            },
+            Some(false) => {
+                self.pending_close = None;
+                self.close_tab(p, i);
+            },
+            None => (),
+        }
+    }
+
+}
+
+/// The id of the text edit of a pane
+fn text_edit_id(pane: usize) -> egui::Id {
+    egui::Id::new("code_editor_text_edit").with(pane)
+}
+
+/// The font of the line numbers, which matches the highlighted code
+fn line_number_font() -> egui::FontId {
+    egui::FontId::new(12.0, egui::FontFamily::Name("EditorFont".into()))
+}
+
+/// The width of the line-number gutter, which fits the largest line number
+fn gutter_width(ui: &Ui, num_lines: usize) -> f32 {
+    let digits = (num_lines.max(1) as f32).log10() as usize + 1;
+    let digit_width = ui.fonts(|f| f.glyph_width(&line_number_font(), '0'));
+    digits.max(3) as f32 * digit_width + 12.0
+}
+
+/// The line of the tab shown in a line of the text edit, given the runs of lines that
+/// the text is made of
+fn code_line(runs: &[(usize, usize)], text_line: usize) -> usize {
+    let (first, line) = runs.iter()
+        .rev()
+        .find(|(first, _)| *first <= text_line)
+        .copied()
+        .unwrap_or_default();
+    line + text_line - first
+}
+
+/// Paint the line numbers in the gutter left of the text, and highlight the line of
+/// the cursor (a char index of `code`) and the brackets around it in the reserved
+/// `background` shape. The text is made of `runs` of lines of the tab.
+#[allow(clippy::too_many_arguments)]
+fn paint_decorations(
+    ui: &Ui,
+    output: &egui::text_edit::TextEditOutput,
+    code: &str,
+    cursor: Option<usize>,
+    background: egui::layers::ShapeIdx,
+    gutter_width: f32,
+    show_line_numbers: bool,
+    error_lines: &[usize],
+    runs: &[(usize, usize)],
+) {
+    let visuals = ui.visuals();
+    let galley = &output.galley;
+    let origin = output.text_draw_pos;
+    let cursor = cursor.map(egui::text::CCursor::new);
+    let char_rect = |idx: usize| {
+        let cursor = galley.from_ccursor(egui::text::CCursor::new(idx));
+        galley.pos_from_cursor(&cursor).translate(origin.to_vec2())
+    };
+
+    let mut shapes = Vec::new();
+    let mut current_line = None;
+    if let Some(ccursor) = cursor {
+        current_line = Some(code_line(runs, galley.from_ccursor(ccursor).pcursor.paragraph));
+        let row = char_rect(ccursor.index);
+        let left = output.response.rect.left() - gutter_width;
+        let line_rect = egui::Rect::from_x_y_ranges(left..=ui.clip_rect().right(), row.y_range());
+        shapes.push(egui::Shape::rect_filled(line_rect, 0.0, visuals.faint_bg_color));
+        if let Some((a, b)) = matching_bracket(code, ccursor.index) {
+            for idx in [a, b] {
+                let rect = char_rect(idx).union(char_rect(idx + 1));
+                shapes.push(egui::Shape::rect_stroke(rect, 1.0, (1.0, visuals.selection.bg_fill)));
+            }
+        }
+    }
+    // tint the lines that have diagnostics (error_lines are 1-based)
+    if !error_lines.is_empty() {
+        let mut text_line = 0;
+        let mut line_start = true;
+        for row in galley.rows.iter() {
+            if line_start && error_lines.contains(&(code_line(runs, text_line) + 1)) {
+                let top = origin.y + row.rect.top();
+                let left = output.response.rect.left() - gutter_width;
+                let line_rect = egui::Rect::from_x_y_ranges(left..=ui.clip_rect().right(), top..=(top + row.rect.height()));
+                shapes.push(egui::Shape::rect_filled(line_rect, 0.0, visuals.error_fg_color.gamma_multiply(0.2)));
+            }
+            line_start = row.ends_with_newline;
+            if line_start {
+                text_line += 1;
+            }
+        }
+    }
+    ui.painter().set(background, egui::Shape::Vec(shapes));
+
+    if !show_line_numbers {
+        return;
+    }
+    let clip = ui.clip_rect();
+    let right = output.response.rect.left() - 6.0;
+    let mut text_line = 0;
+    let mut line_start = true;
+    for row in galley.rows.iter() {
+        let top = origin.y + row.rect.top();
+        if top > clip.bottom() {
+            break;
+        }
+        if line_start && top + row.rect.height() >= clip.top() {
+            let line = code_line(runs, text_line);
+            let color = match current_line == Some(line) {
+                true => visuals.strong_text_color(),
+                false => visuals.weak_text_color(),
+            };
+            let pos = egui::Pos2::new(right, top);
+            ui.painter().text(pos, egui::Align2::RIGHT_TOP, line + 1, line_number_font(), color);
+        }
+        line_start = row.ends_with_newline;
+        if line_start {
+            text_line += 1;
+        }
+    }
+}
+
+/// Show a marker in the gutter next to the lines that start a range that can be
+/// folded, and a placeholder after the folded ones. Clicking either toggles the fold.
+fn display_fold_markers(
+    ui: &mut Ui,
+    output: &egui::text_edit::TextEditOutput,
+    runs: &[(usize, usize)],
+    gutter_width: f32,
+    folds: &mut Folds,
+) {
+    let clip = ui.clip_rect();
+    let left = output.response.rect.left() - gutter_width;
+    let origin = output.text_draw_pos;
+    let mut toggled = None;
+    let mut text_line = 0;
+    let mut line_start = true;
+    let num_rows = output.galley.rows.len();
+    for (n, row) in output.galley.rows.iter().enumerate() {
+        let top = origin.y + row.rect.top();
+        if top > clip.bottom() {
+            break;
+        }
+        let line = code_line(runs, text_line);
+        if line_start && top + row.rect.height() >= clip.top() && folds.get_range(line).is_some() {
+            let folded = folds.is_folded(line);
+            let rect = egui::Rect::from_min_size(egui::pos2(left, top), egui::vec2(FOLD_MARKER_WIDTH, row.rect.height()));
+            let response = ui.interact(rect, ui.id().with(("fold_marker", line)), Sense::click());
+            // the marker of an unfolded range only shows while the pointer is near it
+            if folded || ui.rect_contains_pointer(rect.expand2(egui::vec2(gutter_width, 0.0))) {
+                let icon = response.clone().with_new_rect(rect.shrink(3.0));
+                egui::collapsing_header::paint_default_icon(ui, if folded { 0.0 } else { 1.0 }, &icon);
+            }
+            if response.clicked() {
+                toggled = Some(line);
+            }
+        }
+        // the rest of a folded range is shown as a placeholder at the end of its first line
+        if (row.ends_with_newline || n + 1 == num_rows) && folds.is_folded(line) {
+            let pos = egui::pos2(origin.x + row.rect.right() + 8.0, top);
+            let text = egui::WidgetText::from(RichText::new("⋯").monospace());
+            let galley = text.into_galley(ui, Some(false), f32::INFINITY, egui::TextStyle::Monospace);
+            let rect = egui::Rect::from_min_size(pos, galley.size()).expand2(egui::vec2(4.0, 0.0));
+            let response = ui.interact(rect, ui.id().with(("fold_placeholder", line)), Sense::click())
+                .on_hover_cursor(egui::CursorIcon::PointingHand);
+            let visuals = ui.style().interact(&response);
+            ui.painter().rect(rect, 2.0, ui.visuals().faint_bg_color, visuals.bg_stroke);
+            galley.paint_with_visuals(ui.painter(), pos, visuals);
+            if response.clicked() {
+                toggled = Some(line);
+            }
+        }
+        line_start = row.ends_with_newline;
+        if line_start {
+            text_line += 1;
+        }
+    }
+    if let Some(line) = toggled {
+        folds.toggle(line);
+    }
+}
+
+/// If there's a bracket right after or before the char index, the char indices of
+/// that bracket and the one matching it. Brackets in literals and comments don't count.
+fn matching_bracket(text: &str, char_idx: usize) -> Option<(usize, usize)> {
+    let offset = byte_offset(text, char_idx);
+    let after = text[offset..].chars().next().map(|c| (char_idx, offset, c));
+    let before = text[..offset].chars().next_back()
+        .map(|c| (char_idx.wrapping_sub(1), offset - c.len_utf8(), c));
+    let mut is_code: Option<Vec<bool>> = None;
+    for (idx, byte, c) in after.into_iter().chain(before) {
+        let (open, close) = match c {
+            '(' | ')' => ('(', ')'),
+            '[' | ']' => ('[', ']'),
+            '{' | '}' => ('{', '}'),
+            _ => continue,
+        };
+        let is_code = is_code.get_or_insert_with(|| code_chars(text));
+        if !is_code[idx] {
+            continue;
+        }
+        let mut depth = 0;
+        if c == open {
+            for (n, ch) in text[byte..].chars().enumerate().filter(|(n, _)| is_code[idx + n]) {
+                if ch == open {
+                    depth += 1;
+                } else if ch == close {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some((idx, idx + n));
+                    }
+                }
+            }
+        } else {
+            for (n, ch) in text[..(byte + 1)].chars().rev().enumerate().filter(|(n, _)| is_code[idx - n]) {
+                if ch == close {
+                    depth += 1;
+                } else if ch == open {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some((idx, idx - n));
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Whether each char of the text is code, as opposed to part of a string or char
+/// literal or a comment, going by Rust's syntax
+fn code_chars(text: &str) -> Vec<bool> {
+    let chars: Vec<char> = text.chars().collect();
+    let find = |from: usize, pattern: &[char]| {
+        (from..chars.len()).find(|i| chars[*i..].starts_with(pattern)).map_or(chars.len(), |i| i + pattern.len())
+    };
+    let is_ident = |i: usize| chars[i].is_alphanumeric() || chars[i] == '_';
+    let mut is_code = vec![true; chars.len()];
+    let mut i = 0;
+    while i < chars.len() {
+        // an `r` at the end of an identifier (other than `br`) doesn't start a raw string
+        let raw_prefix = i == 0 || !is_ident(i - 1) || (chars[i - 1] == 'b' && (i == 1 || !is_ident(i - 2)));
+        let end = match &chars[i..] {
+            ['/', '/', ..] => (i..chars.len()).find(|j| chars[*j] == '\n').unwrap_or(chars.len()),
+            ['/', '*', ..] => {
+                // block comments nest
+                let (mut depth, mut j) = (0, i);
+                while j < chars.len() {
+                    match &chars[j..] {
+                        ['/', '*', ..] => {
+                            depth += 1;
+                            j += 2;
+                        },
+                        ['*', '/', ..] => {
+                            depth -= 1;
+                            j += 2;
+                        },
+                        _ => j += 1,
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                j
+            },
+            ['"', ..] => {
+                let mut j = i + 1;
+                while j < chars.len() && chars[j] != '"' {
+                    j += if chars[j] == '\\' { 2 } else { 1 };
+                }
+                (j + 1).min(chars.len())
+            },
+            ['r', rest @ ..] if raw_prefix && rest.iter().find(|c| **c != '#') == Some(&'"') => {
+                let hashes = rest.iter().take_while(|c| **c == '#').count();
+                let mut closing = vec!['#'; hashes + 1];
+                closing[0] = '"';
+                find(i + hashes + 2, &closing)
+            },
+            ['\'', '\\', ..] => find(i + 3, &['\'']),
+            // a lifetime has no closing quote
+            ['\'', _, '\'', ..] => i + 3,
+            _ => {
+                i += 1;
+                continue;
+            },
+        };
+        is_code[i..end].fill(false);
+        i = end.max(i + 1);
+    }
+    is_code
+}
+
+/// If a single newline was just typed before the char index, indent the new line like
+/// the one before it (one level deeper after an opening bracket). Returns the number
+/// of chars that were inserted.
+fn auto_indent(code: &mut String, former: &str, char_idx: usize) -> Option<usize> {
+    if code.len() != former.len() + 1 {
+        return None;
+    }
+    let offset = byte_offset(code, char_idx);
+    if !code[..offset].ends_with('\n') {
+        return None;
+    }
+    let newline = offset - 1;
+    if former.get(..newline) != Some(&code[..newline]) || former.get(newline..) != Some(&code[offset..]) {
+        return None;
+    }
+    let line_start = code[..newline].rfind('\n').map_or(0, |i| i + 1);
+    let line = &code[line_start..newline];
+    let mut indent: String = line.chars().take_while(|c| *c == ' ' || *c == '\t').collect();
+    if line.trim_end().ends_with(['{', '(', '[']) {
+        indent += "    ";
+    }
+    if indent.is_empty() {
+        return None;
+    }
+    code.insert_str(offset, &indent);
+    Some(indent.chars().count())
+}
+
+/// The lines of a tab to put in the text edit: the ones in view with a margin, rounded
+/// out to whole blocks, and the lines of the selection (a char range) if it isn't too long.
+/// The rows in view are counted without the `hidden` lines, and the window doesn't
+/// start or end within them.
+fn window_lines(
+    code: &Rope,
+    selection: Range<usize>,
+    viewport: egui::Rect,
+    row_height: f32,
+    hidden: &[Range<usize>],
+) -> Range<usize> {
+    let top = (viewport.min.y / row_height).max(0.0) as usize;
+    let bottom = (viewport.max.y / row_height).max(0.0).ceil() as usize;
+    let first_row = top.saturating_sub(WINDOW_MARGIN) / WINDOW_BLOCK * WINDOW_BLOCK;
+    let last_row = (bottom + WINDOW_MARGIN).div_ceil(WINDOW_BLOCK) * WINDOW_BLOCK;
+    let mut first = folding::row_to_line(first_row, hidden);
+    let mut last = folding::row_to_line(last_row, hidden);
+    if !selection.is_empty() {
+        let selection_first = code.byte_to_line(code.char_to_byte(selection.start));
+        let selection_last = code.byte_to_line(code.char_to_byte(selection.end)) + 1;
+        if last.max(selection_last) - first.min(selection_first) <= MAX_WINDOW_LINES {
+            first = first.min(folding::visible_line(selection_first, hidden));
+            last = last.max(selection_last);
+        }
+    }
+    let mut last = last.min(code.len_lines());
+    if let Some(lines) = hidden.iter().find(|lines| lines.contains(&(last - 1))) {
+        last = lines.end;
+    }
+    folding::visible_line(first.min(last - 1), hidden)..last
+}
+
+/// The code in the text edit: some lines of a tab, without the folded lines in them.
+/// The first line of a folded range is followed by the line break after the range.
+struct TextWindow {
+    /// The byte ranges of the code that make up the window
+    segments: Vec<Range<usize>>,
+    /// The line of the text and the line of the code that each run of lines starts at
+    runs: Vec<(usize, usize)>,
+    /// The text as it was taken from the code, and as the text edit left it
+    former: String,
+    text: String,
+}
+
+impl TextWindow {
+    /// Take `lines` of the code, which don't start or end within the `hidden` lines
+    fn new(code: &Rope, lines: Range<usize>, hidden: &[Range<usize>]) -> Self {
+        // the line break after a line, or the end of the code
+        let line_end = |line: usize| match line + 1 < code.len_lines() {
+            true => code.line_to_byte(line + 1) - 1,
+            false => code.len(),
+        };
+        let mut segments = Vec::new();
+        let mut runs = vec![(0, lines.start)];
+        let mut start = code.line_to_byte(lines.start);
+        let (mut text_line, mut line) = (0, lines.start);
+        for folded in hidden.iter().filter(|folded| folded.start > lines.start && folded.end <= lines.end) {
+            segments.push(start..line_end(folded.start - 1));
+            start = line_end(folded.end - 1);
+            text_line += folded.start - line;
+            line = folded.end;
+            if line < lines.end {
+                runs.push((text_line, line));
+            }
+        }
+        segments.push(start..line_end(lines.end - 1));
+        let text: String = segments.iter().map(|segment| code.slice(segment.clone())).collect();
+        Self { segments, runs, former: text.clone(), text }
+    }
+
+    /// The byte offset in the code of a byte offset of the former text. An offset
+    /// where folded lines were left out is before them, or after them if `after`.
+    fn to_code(&self, offset: usize, after: bool) -> usize {
+        let mut segment_start = 0;
+        for segment in self.segments.iter() {
+            let segment_end = segment_start + segment.len();
+            if offset < segment_end || (offset == segment_end && !after) {
+                return segment.start + offset - segment_start;
+            }
+            segment_start = segment_end;
+        }
+        self.segments.last().map_or(0, |segment| segment.end)
+    }
+
+    /// The byte offset in the former text of a byte offset of the code, if it's in the
+    /// window. An offset in folded lines is where they were left out.
+    fn to_window(&self, offset: usize) -> Option<usize> {
+        let first = self.segments.first()?;
+        let last = self.segments.last()?;
+        if offset < first.start || offset > last.end {
+            return None;
+        }
+        let mut segment_start = 0;
+        for segment in self.segments.iter() {
+            if offset <= segment.end {
+                return Some(segment_start + offset.saturating_sub(segment.start));
+            }
+            segment_start += segment.len();
+        }
+        None
+    }
+
+    /// The char index in the former text of a char index of the code, if it's in the window
+    fn char_to_window(&self, code: &Rope, char_idx: usize) -> Option<usize> {
+        let offset = self.to_window(code.char_to_byte(char_idx))?;
+        Some(self.former[..offset].chars().count())
+    }
+
+    /// The edit made to the text, as the byte range of the code it replaces and the text
+    /// to put there, and the byte offsets in the edited code of the char indices of the
+    /// `positions` in the text
+    fn edit(&self, positions: &[usize]) -> (Range<usize>, &str, Vec<usize>) {
+        let (former, text) = (&self.former, &self.text);
+        let prefix = rope::common_prefix(former, text);
+        let suffix = rope::common_suffix(&former[prefix..], &text[prefix..]);
+        let inserted = &text[prefix..(text.len() - suffix)];
+        let removed_end = former.len() - suffix;
+        // typing at the end of the first line of a folded range goes on that line, but
+        // deleting the line break after it leaves the folded lines be
+        let start = self.to_code(prefix, prefix != removed_end);
+        let end = self.to_code(removed_end, false).max(start);
+        let offsets = positions.iter()
+            .map(|idx| {
+                let offset = byte_offset(text, *idx);
+                if offset == prefix {
+                    start
+                } else if offset < prefix {
+                    self.to_code(offset, false)
+                } else if offset >= text.len() - suffix {
+                    self.to_code(offset + former.len() - text.len(), false) + start + inserted.len() - end
+                } else {
+                    start + offset - prefix
+                }
+            })
+            .collect();
+        (start..end, inserted, offsets)
+    }
+
+    /// Put the edit made to the text back into the code, and turn the char indices of
+    /// the `positions` in the text into char indices of the code
+    fn apply(&self, tab: &mut CodeFile, positions: &mut [usize]) {
+        let (range, inserted, offsets) = self.edit(positions);
+        if self.former != self.text {
+            // editing folded lines unfolds them
+            if !range.is_empty() {
+                let lines = tab.code.byte_to_line(range.start)..(tab.code.byte_to_line(range.end) + 1);
+                tab.folds.unfold_lines(lines);
+            }
+            let before = tab.code.slice(range.clone());
+            tab.history.record_at(range.start, &before, inserted, EditKind::Typing);
+            tab.code.replace(range, inserted);
+        }
+        for (position, offset) in positions.iter_mut().zip(offsets) {
+            *position = tab.code.byte_to_char(offset);
+        }
+    }
+}
+
+/// The line around a byte offset of the code, and the byte offset it starts at
+fn line_at(code: &Rope, offset: usize) -> (usize, String) {
+    let line = code.byte_to_line(offset);
+    let start = code.line_to_byte(line);
+    (start, code.slice(start..code.line_to_byte(line + 1)))
+}
+
+/// The identifier around a byte offset in `text`
+fn word_at(text: &str, offset: usize) -> String {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    let start = text[..offset].rfind(|c: char| !is_ident(c)).map(|i| i + 1).unwrap_or(0);
+    let end = text[offset..].find(|c: char| !is_ident(c)).map(|i| offset + i).unwrap_or(text.len());
+    text[start..end].to_string()
+}
+
+/// A message for the user about why an analysis query failed
+fn error_message(e: AnalysisError) -> String {
+    match e {
+        AnalysisError::RefactorError(msg) => msg,
+        e => format!("{:?}", e),
+    }
+}
+
+/// A hash of some code, to recognize it later without keeping a copy
+fn hash_code(code: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    code.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Convert a char index (as used by egui's cursors) into a byte offset in `text`
+fn byte_offset(text: &str, char_idx: usize) -> usize {
+    text.char_indices().nth(char_idx).map(|(b, _)| b).unwrap_or(text.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODE: &str = "fn a() {\n    one\n    two\n}\nfn b() {}\n";
+
+    /// Make the edit `edit` to the text of a window on `lines` of `code`, put it back
+    /// into a tab, and check the tab's code and the `positions` (char indices of the
+    /// edited text) in it
+    fn check(
+        code: &str,
+        lines: Range<usize>,
+        hidden: &[Range<usize>],
+        edit: impl FnOnce(&mut String),
+        positions: &[usize],
+        expected: &str,
+        expected_positions: &[usize],
+    ) {
+        let mut tab = CodeFile { code: Rope::from(code), ..CodeFile::default() };
+        let mut window = TextWindow::new(&tab.code, lines, hidden);
+        edit(&mut window.text);
+        let mut positions = positions.to_vec();
+        window.apply(&mut tab, &mut positions);
+        assert_eq!(tab.code.to_string(), expected);
+        assert_eq!(positions, expected_positions);
+    }
+
+    #[test]
+    fn editor_state_round_trip() {
+        let root = std::env::temp_dir().join(format!("iron_coder_editor_state_{}", std::process::id()));
+        let outside = std::env::temp_dir().join(format!("iron_coder_editor_state_outside_{}", std::process::id()));
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        let root = root.canonicalize().unwrap();
+        for path in [root.join("src/main.rs"), root.join("Cargo.toml"), outside.join("lib.rs")] {
+            fs::write(path, "fn main() {\n    run();\n}\n").unwrap();
+        }
+        let lib = outside.canonicalize().unwrap().join("lib.rs");
+        let tab = |path: &Path, cursor: usize| TabState { path: path.to_path_buf(), cursor, anchor: cursor, ..Default::default() };
+        let state = EditorState {
+            split: SplitDirection::Vertical,
+            focused_pane: 2,
+            panes: vec![
+                PaneState {
+                    active_tab: Some(0),
+                    tabs: vec![
+                        TabState { folds: vec![FoldRange { start: 0, end: 2 }], ..tab(Path::new("src/main.rs"), 5) },
+                        tab(Path::new("src/gone.rs"), 0),
+                    ],
+                },
+                // a pane whose files are all gone is dropped
+                PaneState { active_tab: Some(0), tabs: vec![tab(Path::new("gone.rs"), 0)] },
+                PaneState {
+                    active_tab: Some(2),
+                    tabs: vec![tab(Path::new("missing.rs"), 0), tab(Path::new("Cargo.toml"), 1), tab(&lib, 2)],
+                },
+            ],
+        };
+        // the state goes through the state file
+        let state: EditorState = toml::from_str(&toml::to_string(&state).unwrap()).unwrap();
+        let mut editor = CodeEditor::default();
+        editor.restore_state(&state, &root);
+        assert_eq!(editor.panes.len(), 2);
+        assert_eq!(editor.focused_pane, 1);
+        let active_path = |p: usize| editor.panes[p].active_tab.and_then(|i| editor.tabs[i].path.clone());
+        assert_eq!(active_path(0), Some(root.join("src/main.rs")));
+        assert_eq!(active_path(1), Some(lib.clone()));
+
+        let saved = editor.get_state(&root);
+        assert_eq!(saved.split, SplitDirection::Vertical);
+        assert_eq!(saved.focused_pane, 1);
+        let panes: Vec<_> = saved.panes.iter()
+            .map(|pane| {
+                let tabs: Vec<_> = pane.tabs.iter().map(|tab| (tab.path.clone(), tab.cursor)).collect();
+                (pane.active_tab, tabs)
+            })
+            .collect();
+        assert_eq!(panes, [
+            (Some(0), vec![(PathBuf::from("src/main.rs"), 5)]),
+            (Some(1), vec![(PathBuf::from("Cargo.toml"), 1), (lib, 2)]),
+        ]);
+        assert_eq!(saved.panes[0].tabs[0].folds, [FoldRange { start: 0, end: 2 }]);
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn own_saves_are_recognized() {
+        let dir = std::env::temp_dir().join(format!("iron_coder_code_editor_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("main.rs");
+        fs::write(&path, "fn main() {}\n").unwrap();
+        let mut tab = CodeFile::default();
+        tab.load_from_file(&path).unwrap();
+        let path = tab.path.clone().unwrap();
+        assert!(!tab.wrote(&path, "fn main() {}\n"));
+        tab.code = Rope::from("fn main() { run(); }\n");
+        tab.save().unwrap();
+        // the tab is edited again before the watcher reports the save
+        tab.code = Rope::from("fn main() { run(); stop(); }\n");
+        assert!(tab.wrote(&path, &fs::read_to_string(&path).unwrap()));
+        // but other changes to the file are noticed, even if they write the same code
+        fs::write(&path, "fn main() {}\n").unwrap();
+        assert!(!tab.wrote(&path, &fs::read_to_string(&path).unwrap()));
+        fs::write(&path, "fn main() { run(); }\n").unwrap();
+        fs::File::options().write(true).open(&path).unwrap()
+            .set_modified(SystemTime::now() + std::time::Duration::from_secs(60)).unwrap();
+        assert!(!tab.wrote(&path, &fs::read_to_string(&path).unwrap()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn brackets_are_matched() {
+        let text = "f(a[1], {b})";
+        assert_eq!(matching_bracket(text, 1), Some((1, 11)));
+        assert_eq!(matching_bracket(text, 12), Some((11, 1)));
+        assert_eq!(matching_bracket(text, 3), Some((3, 5)));
+        assert_eq!(matching_bracket(text, 10), Some((10, 8)));
+        // the bracket after the index comes first
+        assert_eq!(matching_bracket("(())", 1), Some((1, 2)));
+        assert_eq!(matching_bracket("é(ü)", 1), Some((1, 3)));
+        assert_eq!(matching_bracket("(a", 0), None);
+        assert_eq!(matching_bracket("a b", 1), None);
+        assert_eq!(matching_bracket("", 0), None);
+    }
+
+    #[test]
+    fn brackets_in_literals_and_comments_are_skipped() {
+        let text = "f(\")\\\"(\", ')', '\\'', r#\")\"#, /* ) /* ( */ */ x) // )";
+        let close = text.chars().count() - 6;
+        assert_eq!(matching_bracket(text, 1), Some((1, close)));
+        assert_eq!(matching_bracket(text, close + 1), Some((close, 1)));
+        // a bracket that is in a literal has no match
+        assert_eq!(matching_bracket("\"(\" ()", 1), None);
+        assert_eq!(matching_bracket("// {\n{}", 3), None);
+        assert_eq!(matching_bracket("// {\n{}", 5), Some((5, 6)));
+        // lifetimes aren't char literals
+        assert_eq!(matching_bracket("fn f<'a>(x: &'a u8) {}", 8), Some((8, 18)));
+        // and identifiers that end in `r` don't start raw strings
+        assert_eq!(matching_bracket("for\"(\"", 4), None);
+        assert_eq!(matching_bracket("br\"(\" ()", 6), Some((6, 7)));
+    }
+
+    #[test]
+    fn new_lines_are_indented_like_the_line_before() {
+        let indent = |former: &str, typed: &str, char_idx: usize| {
+            let mut code = typed.to_string();
+            let n = auto_indent(&mut code, former, char_idx);
+            (code, n)
+        };
+        assert_eq!(indent("fn f() {", "fn f() {\n", 9), ("fn f() {\n    ".to_string(), Some(4)));
+        assert_eq!(indent("    let x = 1;", "    let x = 1;\n", 15), ("    let x = 1;\n    ".to_string(), Some(4)));
+        assert_eq!(indent("\tcall(", "\tcall(\n", 7), ("\tcall(\n\t    ".to_string(), Some(5)));
+        // a line broken in the middle
+        assert_eq!(indent("    a\n    b", "    a\n\n    b", 6), ("    a\n    \n    b".to_string(), Some(4)));
+        assert_eq!(indent("  é", "  é\n", 4), ("  é\n  ".to_string(), Some(2)));
+        // only a single typed newline is indented
+        assert_eq!(indent("x", "x\n", 2), ("x\n".to_string(), None));
+        assert_eq!(indent("  x", "  xy", 4), ("  xy".to_string(), None));
+        assert_eq!(indent("  x", "  x\n\n", 5), ("  x\n\n".to_string(), None));
+        assert_eq!(indent("  ab", "  a\nc", 4), ("  a\nc".to_string(), None));
+    }
+
+    #[test]
+    fn window_leaves_out_folded_lines() {
+        let code = Rope::from(CODE);
+        let window = TextWindow::new(&code, 0..6, &[1..3]);
+        assert_eq!(window.former, "fn a() {\n}\nfn b() {}\n");
+        assert_eq!(window.runs, [(0, 0), (1, 3)]);
+        // the end of the first line of the folded range is before the folded lines,
+        // and the line break after it is the one after them
+        assert_eq!(window.to_code(8, false), 8);
+        assert_eq!(window.to_code(8, true), 24);
+        assert_eq!(window.to_window(12), Some(8));
+        assert_eq!(window.to_window(25), Some(9));
+        assert_eq!(window.char_to_window(&code, 27), Some(11));
+        let window = TextWindow::new(&code, 1..3, &[]);
+        assert_eq!(window.former, "    one\n    two");
+        assert_eq!(window.char_to_window(&code, 3), None);
+        assert_eq!(window.char_to_window(&code, 26), None);
+    }
+
+    #[test]
+    fn edits_at_segment_boundaries() {
+        // typing at the end of the first line of a folded range goes on that line
+        check(CODE, 0..6, &[1..3], |text| text.insert(8, 'x'), &[9],
+            "fn a() {x\n    one\n    two\n}\nfn b() {}\n", &[9]);
+        // and at the start of the line after it goes on that line
+        check(CODE, 0..6, &[1..3], |text| text.insert(9, 'x'), &[10],
+            "fn a() {\n    one\n    two\nx}\nfn b() {}\n", &[26]);
+        // the end of a window that stops before the end of the code
+        check(CODE, 0..2, &[], |text| text.push('x'), &[17],
+            "fn a() {\n    onex\n    two\n}\nfn b() {}\n", &[17]);
+        check(CODE, 2..4, &[], |text| text.insert(0, 'x'), &[1, 0],
+            "fn a() {\n    one\nx    two\n}\nfn b() {}\n", &[18, 17]);
+    }
+
+    #[test]
+    fn deleting_the_line_break_after_a_folded_range() {
+        check(CODE, 0..6, &[1..3], |text| { text.remove(8); }, &[8],
+            "fn a() {\n    one\n    two}\nfn b() {}\n", &[24]);
+        // deleting across the folded range takes its lines with it
+        check(CODE, 0..6, &[1..3], |text| text.replace_range(7..10, ""), &[7],
+            "fn a() \nfn b() {}\n", &[7]);
+    }
+
+    #[test]
+    fn selections_past_the_window() {
+        // a selection from before the window is taken from the start of the window
+        check(CODE, 1..3, &[], |text| *text = "x".to_string(), &[1, 0],
+            "fn a() {\nx\n}\nfn b() {}\n", &[10, 9]);
+        // and one to after it, to the end of the window
+        check(CODE, 2..4, &[], |text| text.replace_range(4.., ""), &[4, 4],
+            "fn a() {\n    one\n    \nfn b() {}\n", &[21, 21]);
+        // the lines of a selection are put in the window
+        let code = Rope::from("line\n".repeat(1000).as_str());
+        let viewport = egui::Rect::from_min_max(egui::pos2(0.0, 0.0), egui::pos2(100.0, 100.0));
+        let selection = code.line_to_char(10)..code.line_to_char(500);
+        assert_eq!(window_lines(&code, selection, viewport, 10.0, &[]), 0..501);
+        let selection = code.line_to_char(500)..code.line_to_char(600);
+        assert_eq!(window_lines(&code, selection, viewport, 10.0, &[]), 0..601);
+        assert_eq!(window_lines(&code, 0..0, viewport, 10.0, &[]), 0..64);
+        // the window doesn't end within folded lines
+        let selection = code.line_to_char(10)..code.line_to_char(499);
+        assert_eq!(window_lines(&code, selection, viewport, 10.0, &[495..510]), 0..510);
+    }
+
+    #[test]
+    fn multibyte_text() {
+        let code = "é ü {\nαβ\nγ\n}\n→ ok\n";
+        let window = TextWindow::new(&Rope::from(code), 0..6, &[1..3]);
+        assert_eq!(window.former, "é ü {\n}\n→ ok\n");
+        check(code, 0..6, &[1..3], |text| text.insert(6, 'ö'), &[5],
+            "é ü ö{\nαβ\nγ\n}\n→ ok\n", &[5]);
+        check(code, 0..6, &[1..3], |text| text.insert(14, 'ß'), &[10],
+            "é ü {\nαβ\nγ\n}\n→ ßok\n", &[15]);
+        check(code, 0..6, &[1..3], |text| { text.remove(7); }, &[5],
+            "é ü {\nαβ\nγ}\n→ ok\n", &[10]);
+    }
+}
@@ -1,14 +1,42 @@
 use egui::Color32;
 use serde::{Serialize, Deserialize};
+use log::{info, warn};
 
 use std::borrow::Cow;
+use std::fs;
+use std::path::{Path, PathBuf};
 
+/// A colorscheme for the whole app. Besides the built-in ones, colorschemes are loaded
+/// from TOML files in the themes directory. Any colors missing from such a file are
+/// taken from the default colorscheme.
 #[derive(PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ColorScheme {
     // pub name: &'static str,
     pub name: Cow<'static, str>,
-    is_dark: bool,
-    colors: [Color32; 4],
+    pub is_dark: bool,
+    /// The background, background highlights, secondary text and body text
+    pub colors: [Color32; 4],
+    /// The name of the syntect theme used to highlight code, which can be one of
+    /// syntect's defaults or a custom .tmTheme in the config directory
+    #[serde(default)]
+    pub syntax_theme: Cow<'static, str>,
+    /// The background of the code editor
+    pub editor_background: Color32,
+    /// The background of selected text and widgets
+    pub selection: Color32,
+    /// The background of widgets that aren't being interacted with
+    pub widget_inactive: Color32,
+    /// The background of hovered widgets
+    pub widget_hovered: Color32,
+    /// The background of clicked or open widgets
+    pub widget_active: Color32,
+    pub error: Color32,
+    pub warn: Color32,
+    /// The connection wires between pins in the system editor
+    pub wire: Color32,
+    /// The wire drawn while a new connection is being made
+    pub wire_in_progress: Color32,
 }
 
 impl Default for ColorScheme {
@@ -23,28 +51,131 @@ pub fn set_colorscheme(ctx: &egui::Context, cs: ColorScheme) {
 
     style.visuals.dark_mode = cs.is_dark;
 
-    // TODO style.dark_mode = false;
-    // TODO style.widgets.... all widget styling
-
     // Background colors
     style.visuals.extreme_bg_color = cs.colors[0];
     style.visuals.faint_bg_color = cs.colors[1];
-    style.visuals.code_bg_color = cs.colors[1];
+    style.visuals.code_bg_color = cs.editor_background;
     style.visuals.panel_fill = cs.colors[1];
     style.visuals.window_fill = cs.colors[1];
 
     // Foreground colors
-    // TODO style.selection = ;
+    style.visuals.selection.bg_fill = cs.selection;
+    style.visuals.selection.stroke.color = cs.colors[3];
     style.visuals.hyperlink_color = cs.colors[3];
     style.visuals.window_stroke.color = cs.colors[2];
-    style.visuals.warn_fg_color = cs.colors[3];
-    style.visuals.error_fg_color = cs.colors[3];
+    style.visuals.warn_fg_color = cs.warn;
+    style.visuals.error_fg_color = cs.error;
+
+    // Widget states
+    let widgets = &mut style.visuals.widgets;
+    widgets.noninteractive.bg_fill = cs.colors[1];
+    widgets.noninteractive.weak_bg_fill = cs.colors[1];
+    widgets.noninteractive.bg_stroke.color = cs.colors[2];
+    widgets.noninteractive.fg_stroke.color = cs.colors[3];
+    for (widget, fill) in [
+        (&mut widgets.inactive, cs.widget_inactive),
+        (&mut widgets.hovered, cs.widget_hovered),
+        (&mut widgets.active, cs.widget_active),
+        (&mut widgets.open, cs.widget_active),
+    ] {
+        widget.bg_fill = fill;
+        widget.weak_bg_fill = fill;
+        widget.fg_stroke.color = cs.colors[3];
+    }
+    widgets.hovered.bg_stroke.color = cs.colors[2];
+    widgets.active.bg_stroke.color = cs.colors[3];
 
     ctx.set_style(style);
+    // keep the colorscheme around for the parts of the app that draw their own colors
+    ctx.data_mut(|data| data.insert_temp(egui::Id::new("colorscheme"), cs));
+}
+
+/// Get the colorscheme that was last set, for drawing custom colors
+pub fn get_colorscheme(ctx: &egui::Context) -> ColorScheme {
+    ctx.data(|data| data.get_temp(egui::Id::new("colorscheme"))).unwrap_or_default()
+}
+
+impl ColorScheme {
+    /// The syntax theme to highlight code with, falling back to a default one that
+    /// matches the brightness of the colorscheme if none is set
+    pub fn get_syntax_theme(&self) -> &str {
+        match (&*self.syntax_theme, self.is_dark) {
+            ("", true) => "Solarized (dark)",
+            ("", false) => "Solarized (light)",
+            (theme, _) => theme,
+        }
+    }
+
+    /// Show color pickers for each color of the scheme, returning true if any changed
+    pub fn display_editor(&mut self, ui: &mut egui::Ui) -> bool {
+        let mut changed = false;
+        let base_names = ["background", "background highlights", "secondary text", "body text"];
+        egui::Grid::new("colorscheme_editor").num_columns(2).show(ui, |ui| {
+            for (name, color) in base_names.iter().zip(self.colors.iter_mut()) {
+                ui.label(*name);
+                changed |= ui.color_edit_button_srgba(color).changed();
+                ui.end_row();
+            }
+            for (name, color) in [
+                ("editor background", &mut self.editor_background),
+                ("selection", &mut self.selection),
+                ("inactive widgets", &mut self.widget_inactive),
+                ("hovered widgets", &mut self.widget_hovered),
+                ("active widgets", &mut self.widget_active),
+                ("errors", &mut self.error),
+                ("warnings", &mut self.warn),
+                ("connection wires", &mut self.wire),
+                ("new connection wire", &mut self.wire_in_progress),
+            ] {
+                ui.label(name);
+                changed |= ui.color_edit_button_srgba(color).changed();
+                ui.end_row();
+            }
+            ui.label("dark mode");
+            changed |= ui.checkbox(&mut self.is_dark, "").changed();
+            ui.end_row();
+        });
+        changed
+    }
+
+    /// Write the colorscheme to a TOML file in `dir`, named after the scheme
+    pub fn export(&self, dir: &Path) -> std::io::Result<PathBuf> {
+        fs::create_dir_all(dir)?;
+        let file_name = self.name.to_lowercase().replace(|c: char| !c.is_alphanumeric(), "_");
+        let path = dir.join(file_name).with_extension("toml");
+        let contents = toml::to_string(self).map_err(std::io::Error::other)?;
+        fs::write(&path, contents)?;
+        info!("exported colorscheme to {}", path.display());
+        Ok(path)
+    }
+}
+
+/// Load all of the colorschemes from the TOML files in `dir`
+pub fn load_colorschemes(dir: &Path) -> Vec<ColorScheme> {
+    let mut colorschemes = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return colorschemes,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "toml") {
+            continue;
+        }
+        let cs = fs::read_to_string(&path).map_err(|e| e.to_string())
+            .and_then(|contents| toml::from_str::<ColorScheme>(&contents).map_err(|e| e.to_string()));
+        match cs {
+            Ok(cs) => {
+                info!("loaded colorscheme {} from {}", cs.name, path.display());
+                colorschemes.push(cs);
+            },
+            Err(e) => warn!("couldn't load colorscheme from {}: {}", path.display(), e),
+        }
+    }
+    colorschemes.sort_by(|a, b| a.name.cmp(&b.name));
+    colorschemes
 }
 
-// TODO -- make these serializable in a toml file for addition of new ones
-//         without re-complilation (but maybe there also are some built-in ones)
 pub const SOLARIZED_DARK: ColorScheme = ColorScheme {
     name: Cow::Borrowed("Solarized Dark"),
     is_dark: true,
@@ -54,6 +185,16 @@ pub const SOLARIZED_DARK: ColorScheme = ColorScheme {
         Color32::from_rgb( 88, 110, 117),   // Base 01 (secondary text)
         Color32::from_rgb(131, 148, 150),   // Base 0 (body text)
     ],
+    syntax_theme: Cow::Borrowed("Solarized (dark)"),
+    editor_background: Color32::from_rgb(  0,  43,  54),    // Base 03
+    selection: Color32::from_rgb( 38, 139, 210),            // blue
+    widget_inactive: Color32::from_rgb(  7,  54,  66),      // Base 02
+    widget_hovered: Color32::from_rgb( 88, 110, 117),       // Base 01
+    widget_active: Color32::from_rgb(101, 123, 131),        // Base 00
+    error: Color32::from_rgb(220,  50,  47),                // red
+    warn: Color32::from_rgb(181, 137,   0),                 // yellow
+    wire: Color32::from_rgb(220,  50,  47),                 // red
+    wire_in_progress: Color32::from_rgb(133, 153,   0),     // green
 };
 
 pub const SOLARIZED_LIGHT: ColorScheme = ColorScheme {
@@ -65,6 +206,16 @@ pub const SOLARIZED_LIGHT: ColorScheme = ColorScheme {
         Color32::from_rgb(147, 161, 161),   // Base 1 (secondary text)
         Color32::from_rgb(101, 123, 131),   // Base 00 (body text)
     ],
+    syntax_theme: Cow::Borrowed("Solarized (light)"),
+    editor_background: Color32::from_rgb(253, 246, 227),    // Base 3
+    selection: Color32::from_rgb(147, 161, 161),            // Base 1
+    widget_inactive: Color32::from_rgb(238, 232, 213),      // Base 2
+    widget_hovered: Color32::from_rgb(220, 214, 195),
+    widget_active: Color32::from_rgb(147, 161, 161),        // Base 1
+    error: Color32::from_rgb(220,  50,  47),                // red
+    warn: Color32::from_rgb(203,  75,  22),                 // orange
+    wire: Color32::from_rgb(220,  50,  47),                 // red
+    wire_in_progress: Color32::from_rgb(133, 153,   0),     // green
 };
 
 pub const INDUSTRIAL_DARK: ColorScheme = ColorScheme {
@@ -75,11 +226,21 @@ pub const INDUSTRIAL_DARK: ColorScheme = ColorScheme {
         Color32::from_rgb(42,   42,  42),   // Base 2 (background highlights)
         Color32::from_rgb(204, 204, 204),   // Base 1 (secondary text)
         Color32::from_rgb(248,  81,  73),   // Base 00 (body text)
-    ]
+    ],
+    syntax_theme: Cow::Borrowed("base16-eighties.dark"),
+    editor_background: Color32::from_rgb(31,   31,  31),
+    selection: Color32::from_rgb(110,  40,  36),
+    widget_inactive: Color32::from_rgb(60,   60,  60),
+    widget_hovered: Color32::from_rgb(80,   80,  80),
+    widget_active: Color32::from_rgb(100, 100, 100),
+    error: Color32::from_rgb(248,  81,  73),
+    warn: Color32::from_rgb(230, 180,  80),
+    wire: Color32::from_rgb(248,  81,  73),
+    wire_in_progress: Color32::from_rgb(120, 200, 120),
 };
 
 pub const SYSTEM_COLORSCHEMES: [ColorScheme; 3] = [
     SOLARIZED_DARK,
     SOLARIZED_LIGHT,
     INDUSTRIAL_DARK,
-];
\ No newline at end of file
+];
@@ -0,0 +1,159 @@
+//! This module compares two versions of a file line by line, for showing what changed.
+//! The diff itself is done by `dissimilar`, which compares chars, so each distinct
+//! line is first stood in for by a single char.
+
+use std::collections::HashMap;
+
+/// Lines around a change that are shown for context
+const CONTEXT_LINES: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiffKind {
+    Unchanged,
+    Removed,
+    Added,
+}
+
+/// A line of the old or new text
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffLine {
+    pub kind: DiffKind,
+    pub text: String,
+}
+
+/// Compare `old` and `new` line by line
+pub fn diff_lines(old: &str, new: &str) -> Vec<DiffLine> {
+    let mut lines: Vec<&str> = Vec::new();
+    let mut ids: HashMap<&str, char> = HashMap::new();
+    let old = encode(old, &mut ids, &mut lines);
+    let new = encode(new, &mut ids, &mut lines);
+    let mut diff = Vec::new();
+    for chunk in dissimilar::diff(&old, &new) {
+        let (kind, chars) = match chunk {
+            dissimilar::Chunk::Equal(chars) => (DiffKind::Unchanged, chars),
+            dissimilar::Chunk::Delete(chars) => (DiffKind::Removed, chars),
+            dissimilar::Chunk::Insert(chars) => (DiffKind::Added, chars),
+        };
+        diff.extend(chars.chars().map(|c| DiffLine {
+            kind,
+            text: lines[line_index(c)].to_string(),
+        }));
+    }
+    diff
+}
+
+/// Drop the unchanged lines that are far from any change, leaving None in place of
+/// each run of dropped lines
+pub fn with_context(diff: &[DiffLine]) -> Vec<Option<&DiffLine>> {
+    let changed: Vec<usize> = diff.iter()
+        .enumerate()
+        .filter(|(_, line)| line.kind != DiffKind::Unchanged)
+        .map(|(i, _)| i)
+        .collect();
+    let near_change = |i: usize| {
+        changed.iter().any(|&c| c.abs_diff(i) <= CONTEXT_LINES)
+    };
+    let mut shown = Vec::new();
+    for (i, line) in diff.iter().enumerate() {
+        if near_change(i) {
+            shown.push(Some(line));
+        } else if shown.last().is_none_or(|last| last.is_some()) {
+            shown.push(None);
+        }
+    }
+    shown
+}
+
+/// Turn each line of the text into the char that stands in for it
+fn encode<'a>(text: &'a str, ids: &mut HashMap<&'a str, char>, lines: &mut Vec<&'a str>) -> String {
+    text.lines().map(|line| {
+        *ids.entry(line).or_insert_with(|| {
+            lines.push(line);
+            line_char(lines.len() - 1)
+        })
+    }).collect()
+}
+
+/// The char that stands in for the line at an index, skipping the surrogate range
+fn line_char(index: usize) -> char {
+    let code = match index < 0xD800 {
+        true => index as u32,
+        false => index as u32 + 0x800,
+    };
+    char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+}
+
+fn line_index(c: char) -> usize {
+    match c as u32 {
+        code if code < 0xD800 => code as usize,
+        code => (code - 0x800) as usize,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(kind: DiffKind, text: &str) -> DiffLine {
+        DiffLine { kind, text: text.to_string() }
+    }
+
+    #[test]
+    fn lines_are_compared() {
+        use DiffKind::*;
+        assert_eq!(diff_lines("a\nb\nc\nd\ne\n", "a\nB\nc\nd\ne\nf"), [
+            line(Unchanged, "a"),
+            line(Removed, "b"),
+            line(Added, "B"),
+            line(Unchanged, "c"),
+            line(Unchanged, "d"),
+            line(Unchanged, "e"),
+            line(Added, "f"),
+        ]);
+        // a line that appears twice stands for itself both times
+        assert_eq!(diff_lines("x\nx\ny\n", "x\ny\n"), [
+            line(Removed, "x"),
+            line(Unchanged, "x"),
+            line(Unchanged, "y"),
+        ]);
+        assert_eq!(diff_lines("", "new\n"), [line(Added, "new")]);
+        assert!(diff_lines("", "").is_empty());
+    }
+
+    /// The texts of the lines of a diff that are shown, with None for the gaps
+    fn shown_lines(old: &str, new: &str) -> Vec<Option<String>> {
+        with_context(&diff_lines(old, new)).into_iter()
+            .map(|line| line.map(|line| line.text.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn far_unchanged_lines_are_left_out() {
+        let old: String = (0..10).map(|n| format!("{}\n", n)).collect();
+        let new = old.replace("5\n", "five\n");
+        let shown = shown_lines(&old, &new);
+        assert_eq!(shown, [None, Some("3"), Some("4"), Some("5"), Some("five"), Some("6"), Some("7"), None].map(|line| line.map(String::from)));
+        // there's no gap before a change at the start, or after one at the end
+        let new = old.replace("0\n", "").replace("9\n", "nine\n");
+        let shown = shown_lines(&old, &new);
+        assert_eq!(shown, [Some("0"), Some("1"), Some("2"), None, Some("7"), Some("8"), Some("9"), Some("nine")].map(|line| line.map(String::from)));
+        assert!(with_context(&diff_lines(&old, &old)).iter().all(|line| line.is_none()));
+    }
+
+    #[test]
+    fn line_chars_skip_the_surrogates() {
+        for index in [0, 1, 0xD7FF, 0xD800, 0xDFFF, 0xF000, 0x10_0000] {
+            let c = line_char(index);
+            assert_ne!(c, char::REPLACEMENT_CHARACTER);
+            assert_eq!(line_index(c), index);
+        }
+        assert_eq!(line_char(0xD800), '\u{E000}');
+        // more distinct lines than there are chars below the surrogates
+        let old: String = (0..0xD900).map(|n| format!("{}\n", n)).collect();
+        let new = format!("{}end\n", old);
+        let diff = diff_lines(&old, &new);
+        assert_eq!(diff.len(), 0xD901);
+        assert_eq!(diff[0xD850], line(DiffKind::Unchanged, &0xD850.to_string()));
+        assert_eq!(diff[0xD900], line(DiffKind::Added, "end"));
+    }
+}
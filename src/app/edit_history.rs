@@ -0,0 +1,300 @@
+//! This module contains the undo/redo history of a code editor tab. Every change to
+//! the code is recorded as a single edit (the text replaced at a byte offset), and
+//! consecutive keystrokes are grouped into one edit per word, so that undo steps
+//! back through whole words rather than single characters.
+
+use std::fmt;
+
+use crate::app::rope::{self, Rope};
+
+/// The way the code of a tab was changed, which decides how edits are grouped
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EditKind {
+    /// Typing or deleting text in the editor, which is grouped into words
+    Typing,
+    /// Accepting a completion
+    Completion,
+    /// Dropping a code snippet into the editor
+    Snippet,
+    /// Applying a rename, refactoring or quick-fix
+    Refactor,
+    /// Replacing one or all matches of a search
+    Replace,
+    /// Reformatting the code when it is saved
+    Format,
+    /// Restoring unsaved changes from the recovery directory
+    Recovery,
+    /// Reloading a file that was changed outside of the editor
+    Reload,
+}
+
+impl fmt::Display for EditKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EditKind::Typing => write!(f, "typing"),
+            EditKind::Completion => write!(f, "completion"),
+            EditKind::Snippet => write!(f, "snippet"),
+            EditKind::Refactor => write!(f, "refactoring"),
+            EditKind::Replace => write!(f, "replace"),
+            EditKind::Format => write!(f, "formatting"),
+            EditKind::Recovery => write!(f, "recovery"),
+            EditKind::Reload => write!(f, "reload"),
+        }
+    }
+}
+
+/// A replacement of `removed` by `inserted` at a byte offset of the code
+#[derive(Debug, Clone)]
+struct Edit {
+    kind: EditKind,
+    offset: usize,
+    removed: String,
+    inserted: String,
+}
+
+impl Edit {
+    /// Whether a keystroke can be merged into this edit, i.e. it continues the same
+    /// word right where this edit left off
+    fn continues_with(&self, next: &Edit) -> bool {
+        if self.kind != EditKind::Typing || next.kind != EditKind::Typing {
+            return false;
+        }
+        let is_word = |s: &str| s.chars().all(|c| c.is_alphanumeric() || c == '_');
+        if self.removed.is_empty() && next.removed.is_empty() {
+            // typing forwards, where a word may follow the whitespace before it
+            next.offset == self.offset + self.inserted.len()
+                && is_word(&next.inserted)
+                && is_word(self.inserted.trim_start())
+        } else if self.inserted.is_empty() && next.inserted.is_empty() {
+            // deleting backwards or forwards
+            next.offset + next.removed.len() == self.offset || next.offset == self.offset
+        } else {
+            false
+        }
+    }
+
+    /// Merge a following keystroke into this edit
+    fn merge(&mut self, next: Edit) {
+        if next.removed.is_empty() {
+            self.inserted += &next.inserted;
+        } else if next.offset < self.offset {
+            self.removed = next.removed + &self.removed;
+            self.offset = next.offset;
+        } else {
+            self.removed += &next.removed;
+        }
+    }
+}
+
+/// The undo and redo stacks of one tab
+#[derive(Debug, Default)]
+pub struct EditHistory {
+    undo: Vec<Edit>,
+    redo: Vec<Edit>,
+    /// Whether the last edit is closed to further keystrokes
+    closed: bool,
+}
+
+impl EditHistory {
+
+    /// Record the change from `before` to `after`. Only the differing middle part of
+    /// the code is stored.
+    pub fn record(&mut self, before: &str, after: &str, kind: EditKind) {
+        self.record_at(0, before, after, kind);
+    }
+
+    /// Record the change from `before` to `after`, which is the part of the code that
+    /// starts at byte `offset`
+    pub fn record_at(&mut self, offset: usize, before: &str, after: &str, kind: EditKind) {
+        if before == after {
+            return;
+        }
+        let prefix = rope::common_prefix(before, after);
+        let suffix = rope::common_suffix(&before[prefix..], &after[prefix..]);
+        let edit = Edit {
+            kind,
+            offset: offset + prefix,
+            removed: before[prefix..(before.len() - suffix)].to_string(),
+            inserted: after[prefix..(after.len() - suffix)].to_string(),
+        };
+        self.redo.clear();
+        match self.undo.last_mut() {
+            Some(last) if !self.closed && last.continues_with(&edit) => last.merge(edit),
+            _ => self.undo.push(edit),
+        }
+        self.closed = false;
+    }
+
+    /// Stop grouping keystrokes into the last edit, so the next one starts a new step
+    pub fn close_group(&mut self) {
+        self.closed = true;
+    }
+
+    /// The kind of edit that would be undone next, if any
+    pub fn get_undo_kind(&self) -> Option<EditKind> {
+        self.undo.last().map(|edit| edit.kind)
+    }
+
+    /// The kind of edit that would be redone next, if any
+    pub fn get_redo_kind(&self) -> Option<EditKind> {
+        self.redo.last().map(|edit| edit.kind)
+    }
+
+    /// Undo the last edit to `code`, returning the byte offset to put the cursor at
+    pub fn undo(&mut self, code: &mut Rope) -> Option<usize> {
+        let edit = self.undo.pop()?;
+        let end = edit.offset + edit.inserted.len();
+        code.replace(edit.offset..end, &edit.removed);
+        let cursor = edit.offset + edit.removed.len();
+        self.redo.push(edit);
+        self.closed = true;
+        Some(cursor)
+    }
+
+    /// Redo the last undone edit to `code`, returning the byte offset to put the cursor at
+    pub fn redo(&mut self, code: &mut Rope) -> Option<usize> {
+        let edit = self.redo.pop()?;
+        let end = edit.offset + edit.removed.len();
+        code.replace(edit.offset..end, &edit.inserted);
+        let cursor = edit.offset + edit.inserted.len();
+        self.undo.push(edit);
+        self.closed = true;
+        Some(cursor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tab's code and history, edited the way the editor records keystrokes
+    #[derive(Default)]
+    struct Tab {
+        code: Rope,
+        history: EditHistory,
+    }
+
+    impl Tab {
+        fn new(code: &str) -> Self {
+            Self { code: Rope::from(code), ..Default::default() }
+        }
+
+        fn replace(&mut self, start: usize, end: usize, text: &str) {
+            let before = self.code.slice(start..end);
+            self.history.record_at(start, &before, text, EditKind::Typing);
+            self.code.replace(start..end, text);
+        }
+
+        /// Type `text` one char at a time at byte `offset`
+        fn type_at(&mut self, mut offset: usize, text: &str) {
+            for c in text.chars() {
+                self.replace(offset, offset, c.encode_utf8(&mut [0; 4]));
+                offset += c.len_utf8();
+            }
+        }
+
+        /// Undo every step, checking the text and cursor after each one, then redo them all
+        fn check_steps(&mut self, steps: &[(&str, usize)]) {
+            let last = self.code.to_string();
+            for (text, cursor) in steps {
+                assert_eq!(self.history.undo(&mut self.code), Some(*cursor));
+                assert_eq!(self.code.to_string(), *text);
+            }
+            assert_eq!(self.history.undo(&mut self.code), None);
+            while self.history.redo(&mut self.code).is_some() {}
+            assert_eq!(self.code.to_string(), last);
+        }
+    }
+
+    #[test]
+    fn typing_is_grouped_into_words() {
+        let mut tab = Tab::default();
+        tab.type_at(0, "let x = 1;");
+        tab.check_steps(&[
+            ("let x = 1", 9),
+            ("let x =", 7),
+            ("let x ", 6),
+            ("let x", 5),
+            ("let", 3),
+            ("", 0),
+        ]);
+        // words with multibyte chars are grouped too
+        let mut tab = Tab::new("fn ");
+        tab.type_at(3, "größe");
+        tab.check_steps(&[("fn ", 3)]);
+    }
+
+    #[test]
+    fn deletes_are_grouped_by_direction() {
+        // backspacing, which removes the char before the one removed last
+        let mut tab = Tab::new("hello world");
+        for end in (7..=11).rev() {
+            tab.replace(end - 1, end, "");
+        }
+        assert_eq!(tab.code.to_string(), "hello ");
+        tab.check_steps(&[("hello world", 11)]);
+        // deleting forwards, which removes the char after it
+        let mut tab = Tab::new("hello world");
+        for _ in 0..6 {
+            tab.replace(0, 1, "");
+        }
+        assert_eq!(tab.code.to_string(), "world");
+        tab.check_steps(&[("hello world", 6)]);
+        // deleting isn't merged with the typing before it
+        let mut tab = Tab::new("ab");
+        tab.type_at(2, "cd");
+        tab.replace(3, 4, "");
+        tab.check_steps(&[("abcd", 4), ("ab", 2)]);
+    }
+
+    #[test]
+    fn closing_a_group_starts_a_new_step() {
+        let mut tab = Tab::default();
+        tab.type_at(0, "ab");
+        tab.history.close_group();
+        tab.type_at(2, "cd");
+        tab.check_steps(&[("ab", 2), ("", 0)]);
+        // and so does undoing
+        let mut tab = Tab::default();
+        tab.type_at(0, "ab");
+        tab.history.undo(&mut tab.code);
+        tab.history.redo(&mut tab.code);
+        tab.type_at(2, "cd");
+        tab.check_steps(&[("ab", 2), ("", 0)]);
+    }
+
+    #[test]
+    fn an_edit_clears_the_redo_stack() {
+        let mut tab = Tab::default();
+        tab.type_at(0, "one");
+        tab.history.close_group();
+        tab.type_at(3, "two");
+        assert_eq!(tab.history.undo(&mut tab.code), Some(3));
+        assert_eq!(tab.history.get_redo_kind(), Some(EditKind::Typing));
+        tab.type_at(3, "three");
+        assert_eq!(tab.history.get_redo_kind(), None);
+        assert_eq!(tab.history.redo(&mut tab.code), None);
+        tab.check_steps(&[("one", 3), ("", 0)]);
+    }
+
+    #[test]
+    fn undo_and_redo_put_the_cursor_after_the_text() {
+        let mut tab = Tab::new("let x = 1;\n");
+        tab.history.record("let x = 1;\n", "let value = 1;\n", EditKind::Refactor);
+        tab.code = Rope::from("let value = 1;\n");
+        // other kinds of edits aren't merged with typing
+        tab.type_at(15, "a");
+        assert_eq!(tab.history.get_undo_kind(), Some(EditKind::Typing));
+        assert_eq!(tab.history.undo(&mut tab.code), Some(15));
+        assert_eq!(tab.history.get_undo_kind(), Some(EditKind::Refactor));
+        // only the changed part of the code is replaced
+        assert_eq!(tab.history.undo(&mut tab.code), Some(5));
+        assert_eq!(tab.code.to_string(), "let x = 1;\n");
+        assert_eq!(tab.history.redo(&mut tab.code), Some(9));
+        assert_eq!(tab.code.to_string(), "let value = 1;\n");
+        assert_eq!(tab.history.redo(&mut tab.code), Some(16));
+        assert_eq!(tab.code.to_string(), "let value = 1;\na");
+        assert_eq!(tab.history.redo(&mut tab.code), None);
+    }
+}
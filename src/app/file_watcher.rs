@@ -0,0 +1,131 @@
+//! This module watches the project directory, and the directories of any other open
+//! files, for changes made outside of the editor (i.e. by generating the system
+//! module, or by another program). The watcher wakes the GUI up when something
+//! changes, so the changes show without waiting for any input.
+
+use log::{info, warn};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+
+/// Directories of a project tree that aren't watched, since they change all the time
+const IGNORED_DIRS: [&str; 2] = ["target", ".git"];
+
+#[derive(Default)]
+pub struct FileWatcher {
+    watcher: Option<RecommendedWatcher>,
+    events: Option<Receiver<PathBuf>>,
+    /// The watched directories, and whether their subdirectories are watched too
+    dirs: Vec<(PathBuf, bool)>,
+    /// The roots of the watched project trees, whose new subdirectories get watched
+    roots: Vec<PathBuf>,
+}
+
+impl FileWatcher {
+
+    /// Watch a project directory and its subdirectories, except for build output
+    pub fn watch_tree(&mut self, root: &Path) {
+        let Ok(root) = root.canonicalize() else {
+            return;
+        };
+        // the top level isn't watched recursively, to leave out the ignored directories
+        self.watch(&root, false);
+        if let Ok(entries) = fs::read_dir(&root) {
+            for entry in entries.flatten() {
+                if entry.file_type().is_ok_and(|t| t.is_dir()) && !is_ignored(&entry.path()) {
+                    self.watch(&entry.path(), true);
+                }
+            }
+        }
+        if !self.roots.contains(&root) {
+            self.roots.push(root);
+        }
+    }
+
+    /// Watch the directory of an open file, unless it already is
+    pub fn watch_file(&mut self, path: &Path) {
+        if let (false, Some(dir)) = (self.is_watched(path), path.parent()) {
+            self.watch(dir, false);
+        }
+    }
+
+    fn is_watched(&self, path: &Path) -> bool {
+        self.dirs.iter().any(|(dir, recursive)| {
+            path.parent() == Some(dir.as_path()) || (*recursive && path.starts_with(dir))
+        })
+    }
+
+    fn watch(&mut self, dir: &Path, recursive: bool) {
+        if self.dirs.iter().any(|(d, r)| d == dir && *r == recursive) {
+            return;
+        }
+        self.dirs.push((dir.to_path_buf(), recursive));
+        if let Some(watcher) = &mut self.watcher {
+            let mode = if recursive { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive };
+            if let Err(e) = watcher.watch(dir, mode) {
+                warn!("couldn't watch {}: {:?}", dir.display(), e);
+            }
+        }
+    }
+
+    /// Get the paths that changed since the last call. The watcher is started by the
+    /// first call, since it needs the context to wake up the GUI.
+    pub fn poll(&mut self, ctx: &egui::Context) -> BTreeSet<PathBuf> {
+        if self.events.is_none() {
+            self.start(ctx);
+        }
+        let changed: BTreeSet<PathBuf> = match &self.events {
+            Some(events) => events.try_iter().collect(),
+            None => BTreeSet::new(),
+        };
+        // watch the new top-level directories of the project trees
+        let new_dirs: Vec<PathBuf> = changed.iter()
+            .filter(|path| path.parent().is_some_and(|parent| self.roots.iter().any(|root| root == parent)))
+            .filter(|path| path.is_dir() && !is_ignored(path))
+            .cloned()
+            .collect();
+        for dir in new_dirs {
+            self.watch(&dir, true);
+        }
+        changed
+    }
+
+    fn start(&mut self, ctx: &egui::Context) {
+        let (tx, rx) = mpsc::channel();
+        let ctx = ctx.clone();
+        let handler = move |result: notify::Result<notify::Event>| {
+            match result {
+                Ok(event) if matches!(event.kind, EventKind::Access(_)) => (),
+                Ok(event) => {
+                    for path in event.paths {
+                        let _ = tx.send(path);
+                    }
+                    ctx.request_repaint();
+                },
+                Err(e) => warn!("file watcher error: {:?}", e),
+            }
+        };
+        // the receiver is kept even if the watcher couldn't start, so it isn't retried
+        self.events = Some(rx);
+        match notify::recommended_watcher(handler) {
+            Ok(mut watcher) => {
+                for (dir, recursive) in self.dirs.iter() {
+                    let mode = if *recursive { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive };
+                    if let Err(e) = watcher.watch(dir, mode) {
+                        warn!("couldn't watch {}: {:?}", dir.display(), e);
+                    }
+                }
+                info!("watching {} directories for changes", self.dirs.len());
+                self.watcher = Some(watcher);
+            },
+            Err(e) => warn!("couldn't start the file watcher: {:?}", e),
+        }
+    }
+}
+
+fn is_ignored(dir: &Path) -> bool {
+    dir.file_name().is_some_and(|name| IGNORED_DIRS.iter().any(|ignored| name == *ignored))
+}
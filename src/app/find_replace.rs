@@ -0,0 +1,381 @@
+//! This module contains the find/replace bar of the code editor. Searches can be
+//! plain text or regular expressions (using Oniguruma, the same engine that syntect
+//! uses), optionally case-sensitive or limited to whole words.
+
+use std::ops::Range;
+
+use egui::text::LayoutJob;
+use egui::{Color32, Key, RichText};
+use onig::{Captures, Regex, RegexOptions, Syntax};
+
+use crate::app::rope::Rope;
+
+/// What the user asked the find bar to do this frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FindAction {
+    None,
+    /// Select the next or previous match
+    Next,
+    Previous,
+    /// Replace the selected match, or every match
+    Replace,
+    ReplaceAll,
+    Close,
+}
+
+/// The text to search for, and how to match it
+#[derive(Default, Clone)]
+pub struct SearchQuery {
+    pub text: String,
+    pub case_sensitive: bool,
+    pub whole_word: bool,
+    pub use_regex: bool,
+}
+
+impl SearchQuery {
+
+    /// Compile the query, or say why it couldn't be compiled
+    pub fn build_regex(&self) -> Result<Regex, String> {
+        let mut pattern = match self.use_regex {
+            true => self.text.clone(),
+            false => escape(&self.text),
+        };
+        if self.whole_word {
+            pattern = format!(r"\b(?:{})\b", pattern);
+        }
+        let options = match self.case_sensitive {
+            true => RegexOptions::REGEX_OPTION_NONE,
+            false => RegexOptions::REGEX_OPTION_IGNORECASE,
+        };
+        Regex::with_options(&pattern, options, Syntax::default())
+            .map_err(|e| e.description().to_string())
+    }
+
+    /// The text that replaces a match, where `$n` or `${n}` inserts the nth group of a
+    /// regex, and `$$` is a dollar sign. All of the digits after a `$` are the group
+    /// number, so `${1}0` puts a zero after the first group.
+    pub fn expand(&self, replacement: &str, captures: &Captures) -> String {
+        if !self.use_regex {
+            return replacement.to_string();
+        }
+        let mut expanded = String::new();
+        let mut rest = replacement;
+        while let Some(at) = rest.find('$') {
+            expanded += &rest[..at];
+            rest = &rest[(at + 1)..];
+            let (group, len) = match rest.strip_prefix('{').and_then(|r| r.split_once('}')) {
+                Some((digits, _)) => (digits, digits.len() + 2),
+                None => {
+                    let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+                    (&rest[..digits], digits)
+                },
+            };
+            match group.parse::<usize>() {
+                Ok(group) => {
+                    expanded += captures.at(group).unwrap_or("");
+                    rest = &rest[len..];
+                },
+                _ if rest.starts_with('$') => {
+                    expanded.push('$');
+                    rest = &rest[1..];
+                },
+                _ => expanded.push('$'),
+            }
+        }
+        expanded += rest;
+        expanded
+    }
+
+    /// Replace every (non-empty) match of `regex` in `text`, returning the new text
+    /// and the number of replacements
+    pub fn replace_all(&self, regex: &Regex, text: &str, replacement: &str) -> (String, usize) {
+        let mut replaced = String::with_capacity(text.len());
+        let mut last = 0;
+        let mut count = 0;
+        for captures in regex.captures_iter(text) {
+            let (start, end) = match captures.pos(0) {
+                Some((start, end)) if start != end => (start, end),
+                _ => continue,
+            };
+            replaced += &text[last..start];
+            replaced += &self.expand(replacement, &captures);
+            last = end;
+            count += 1;
+        }
+        replaced += &text[last..];
+        (replaced, count)
+    }
+
+    /// Show toggles for the case, whole-word and regex options
+    pub fn display_options(&mut self, ui: &mut egui::Ui) {
+        ui.toggle_value(&mut self.case_sensitive, "Aa").on_hover_text("match case");
+        ui.toggle_value(&mut self.whole_word, "W").on_hover_text("match whole words");
+        ui.toggle_value(&mut self.use_regex, ".*").on_hover_text("use a regular expression");
+    }
+
+    /// Everything about the query that decides what it matches
+    fn key(&self) -> String {
+        format!("{}{}{}{}", self.case_sensitive, self.whole_word, self.use_regex, self.text)
+    }
+}
+
+#[derive(Default)]
+pub struct FindReplace {
+    pub visible: bool,
+    query: SearchQuery,
+    replacement: String,
+    /// The byte ranges of the matches, and the revision of the code and the query
+    /// they were found for
+    matches: Vec<Range<usize>>,
+    searched: Option<(u64, String)>,
+    /// The index of the selected match
+    current: Option<usize>,
+    /// Why the query couldn't be compiled, if it couldn't
+    error: Option<String>,
+}
+
+impl FindReplace {
+
+    /// Show the find bar with `query` in it, and focus it
+    pub fn open(&mut self, ctx: &egui::Context, query: Option<&str>) {
+        self.visible = true;
+        if let Some(query) = query {
+            self.query.text = query.to_string();
+        }
+        ctx.memory_mut(|mem| mem.request_focus(egui::Id::new("find_query")));
+    }
+
+    /// The byte ranges of all the matches, and the index of the selected one
+    pub fn get_matches(&self) -> (&[Range<usize>], Option<usize>) {
+        (&self.matches, self.current)
+    }
+
+    /// The byte range of the selected match
+    pub fn get_current(&self) -> Option<Range<usize>> {
+        self.current.and_then(|i| self.matches.get(i)).cloned()
+    }
+
+    /// Search `code` again if it or the query changed since the last search. The
+    /// selected match becomes the first one at or after `cursor` (a byte offset).
+    pub fn update(&mut self, code: &Rope, cursor: usize) {
+        let key = self.query.key();
+        if let Some((searched_revision, searched_key)) = &self.searched {
+            if *searched_revision == code.revision() && *searched_key == key {
+                return;
+            }
+        }
+        self.matches.clear();
+        self.error = None;
+        if !self.query.text.is_empty() {
+            match self.query.build_regex() {
+                Ok(regex) => {
+                    self.matches = regex.find_iter(&code.to_string())
+                        .filter(|(start, end)| start != end)
+                        .map(|(start, end)| start..end)
+                        .collect();
+                },
+                Err(e) => self.error = Some(e),
+            }
+        }
+        self.current = match self.matches.is_empty() {
+            true => None,
+            false => Some(self.matches.iter().position(|m| m.start >= cursor).unwrap_or(0)),
+        };
+        self.searched = Some((code.revision(), key));
+    }
+
+    /// Select the next (or previous) match, returning its byte range
+    pub fn step(&mut self, forward: bool) -> Option<Range<usize>> {
+        let n = self.matches.len();
+        if n == 0 {
+            return None;
+        }
+        self.current = Some(match (self.current, forward) {
+            (None, _) => 0,
+            (Some(i), true) => (i + 1) % n,
+            (Some(i), false) => (i + n - 1) % n,
+        });
+        self.get_current()
+    }
+
+    /// Replace the selected match in `code`, returning the byte range of the replacement
+    pub fn replace_current(&self, code: &mut String) -> Option<Range<usize>> {
+        let current = self.get_current()?;
+        let regex = self.query.build_regex().ok()?;
+        let captures = regex.captures_iter(code)
+            .find(|caps| caps.pos(0) == Some((current.start, current.end)))?;
+        let replacement = self.query.expand(&self.replacement, &captures);
+        code.replace_range(current.clone(), &replacement);
+        Some(current.start..(current.start + replacement.len()))
+    }
+
+    /// Replace every match in `code`, returning how many were replaced
+    pub fn replace_all(&self, code: &mut String) -> usize {
+        let regex = match self.query.build_regex() {
+            Ok(regex) => regex,
+            Err(_) => return 0,
+        };
+        let (replaced, count) = self.query.replace_all(&regex, code, &self.replacement);
+        *code = replaced;
+        count
+    }
+
+    /// Show the find bar, and return what the user asked for
+    pub fn display(&mut self, ui: &mut egui::Ui) -> FindAction {
+        let mut action = FindAction::None;
+        ui.horizontal(|ui| {
+            let query = egui::TextEdit::singleline(&mut self.query.text)
+                .id(egui::Id::new("find_query"))
+                .hint_text("find")
+                .desired_width(200.0);
+            let response = ui.add(query);
+            if response.lost_focus() && ui.input(|input| input.key_pressed(Key::Enter)) {
+                let back = ui.input(|input| input.modifiers.shift);
+                action = if back { FindAction::Previous } else { FindAction::Next };
+                response.request_focus();
+            }
+            if response.has_focus() && ui.input(|input| input.key_pressed(Key::Escape)) {
+                action = FindAction::Close;
+            }
+            self.query.display_options(ui);
+            if ui.button("previous").clicked() {
+                action = FindAction::Previous;
+            }
+            if ui.button("next").clicked() {
+                action = FindAction::Next;
+            }
+            match (&self.error, self.current) {
+                (Some(e), _) => {
+                    ui.label(RichText::new(e.as_str()).color(ui.visuals().error_fg_color));
+                },
+                (None, Some(i)) => {
+                    ui.label(format!("{} of {}", i + 1, self.matches.len()));
+                },
+                (None, None) if !self.query.text.is_empty() => {
+                    ui.label("no matches");
+                },
+                _ => (),
+            }
+            ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                if ui.button("close").clicked() {
+                    action = FindAction::Close;
+                }
+            });
+        });
+        ui.horizontal(|ui| {
+            let replacement = egui::TextEdit::singleline(&mut self.replacement)
+                .hint_text("replace")
+                .desired_width(200.0);
+            ui.add(replacement).on_hover_text("$1, $2... insert the groups of a regular expression");
+            if ui.add_enabled(self.current.is_some(), egui::Button::new("replace")).clicked() {
+                action = FindAction::Replace;
+            }
+            if ui.add_enabled(self.current.is_some(), egui::Button::new("replace all")).clicked() {
+                action = FindAction::ReplaceAll;
+            }
+        });
+        action
+    }
+}
+
+/// Escape the characters of `text` that have a meaning in a regex
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_ascii_punctuation() {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Give the text of a layout job in each of the (sorted, non-overlapping) byte ranges
+/// a background color, by splitting the highlighted sections at the range boundaries.
+pub fn highlight_ranges(job: &mut LayoutJob, ranges: &[(Range<usize>, Color32)]) {
+    if ranges.is_empty() {
+        return;
+    }
+    let mut sections = Vec::with_capacity(job.sections.len() + 2 * ranges.len());
+    let mut r = 0;
+    for section in job.sections.drain(..) {
+        let mut start = section.byte_range.start;
+        let end = section.byte_range.end;
+        while start < end {
+            while r < ranges.len() && ranges[r].0.end <= start {
+                r += 1;
+            }
+            let (stop, background) = match ranges.get(r) {
+                Some((range, _)) if range.start > start => (range.start.min(end), None),
+                Some((range, color)) if range.start < end => (range.end.min(end), Some(*color)),
+                _ => (end, None),
+            };
+            let mut part = section.clone();
+            part.byte_range = start..stop;
+            if let Some(color) = background {
+                part.format.background = color;
+            }
+            sections.push(part);
+            start = stop;
+        }
+    }
+    job.sections = sections;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn regex_query(text: &str) -> SearchQuery {
+        SearchQuery {
+            text: text.to_string(),
+            case_sensitive: true,
+            use_regex: true,
+            ..Default::default()
+        }
+    }
+
+    /// Replace every match of a regex in `text`
+    fn replaced(pattern: &str, text: &str, replacement: &str) -> String {
+        let query = regex_query(pattern);
+        query.replace_all(&query.build_regex().unwrap(), text, replacement).0
+    }
+
+    #[test]
+    fn escaped_text_matches_itself() {
+        let punctuation: String = (0..128u8).map(char::from).filter(char::is_ascii_punctuation).collect();
+        for text in [punctuation.as_str(), "a.b*c", "fn main() { println!(\"{}\", x[0]); }", "\\d+ $1 ^é$", "(?i)"] {
+            let query = SearchQuery {
+                text: text.to_string(),
+                case_sensitive: true,
+                ..Default::default()
+            };
+            let regex = query.build_regex().unwrap();
+            assert_eq!(regex.find(text), Some((0, text.len())), "{}", text);
+            assert_eq!(regex.find(&text[1..]), None, "{}", text);
+        }
+        assert_eq!(escape("a.b"), "a\\.b");
+        assert_eq!(escape("é_1"), "é\\_1");
+    }
+
+    #[test]
+    fn groups_are_expanded() {
+        assert_eq!(replaced(r"(\w+)=(\w+)", "a=b, cd=ef", "$2=$1"), "b=a, ef=cd");
+        assert_eq!(replaced(r"(\w+)=(\w+)", "a=b", "${2}0 ${1}"), "b0 a");
+        assert_eq!(replaced(r"(\w+)", "a", "$0$0 $9"), "aa ");
+        let ten_groups = "(a)(b)(c)(d)(e)(f)(g)(h)(i)(j)";
+        assert_eq!(replaced(ten_groups, "abcdefghij", "$10$1"), "ja");
+        assert_eq!(replaced(ten_groups, "abcdefghij", "${1}0"), "a0");
+    }
+
+    #[test]
+    fn dollars_without_groups_are_kept() {
+        assert_eq!(replaced("(x)", "x", "$$1"), "$1");
+        assert_eq!(replaced("(x)", "x", "$"), "$");
+        assert_eq!(replaced("(x)", "x", "a$ $b ${x} ${} ${1"), "a$ $b ${x} ${} ${1");
+        assert_eq!(replaced("(x)", "x", "€$1€"), "€x€");
+        // a plain search replaces matches with the text as it is
+        let query = SearchQuery { text: "x".to_string(), ..Default::default() };
+        assert_eq!(query.replace_all(&query.build_regex().unwrap(), "xXx", "$1").0, "$1$1$1");
+    }
+}
@@ -0,0 +1,341 @@
+//! This module keeps track of the folded code of an editor tab. The ranges that can
+//! be folded come from rust-analyzer's parser, which runs on a background thread
+//! whenever the code changed, so that typing in a long file doesn't wait for it.
+//!
+//! Folds are kept by line. An edit moves the folds below it with their lines right
+//! away, and once the ranges of the edited code are known, a fold is kept only if a
+//! range still starts at its first line.
+
+use std::ops::Range;
+use std::sync::mpsc;
+use std::thread;
+
+use serde::{Serialize, Deserialize};
+
+use crate::app::rope::Rope;
+use crate::app::rust_analyzer;
+
+/// A range of lines that can be folded. The first line stays in view, followed by a
+/// marker in place of the lines after it, up to and including `end`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct FoldRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl FoldRange {
+    /// The lines that are hidden when the range is folded
+    fn hidden(&self) -> Range<usize> {
+        (self.start + 1)..(self.end + 1)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Folds {
+    /// The ranges that can be folded, at most one starting at each line, sorted
+    ranges: Vec<FoldRange>,
+    /// The revision of the code that the ranges were computed for
+    ranges_revision: u64,
+    /// The folded ranges, sorted by their first line
+    folded: Vec<FoldRange>,
+    /// The revision and number of lines of the code that the folded lines are for
+    revision: u64,
+    num_lines: usize,
+    /// The ranges being computed on a background thread, and the revision they're for
+    pending: Option<(u64, mpsc::Receiver<Vec<FoldRange>>)>,
+}
+
+impl Folds {
+
+    /// Keep the folds in step with the code, and pick up (or start computing) the
+    /// ranges of the current code. Only Rust code can be folded.
+    pub fn update(&mut self, ctx: &egui::Context, code: &Rope, language: &str) {
+        if code.revision() != self.revision {
+            // the folds of a tab that was just opened are already for its code
+            let changed = match self.revision {
+                0 => None,
+                revision => code.changed_since(revision),
+            };
+            if let Some(line) = changed {
+                let delta = code.len_lines() as isize - self.num_lines as isize;
+                self.shift(line, delta);
+            }
+            self.revision = code.revision();
+            self.num_lines = code.len_lines();
+        }
+        if language != "Rust" {
+            self.ranges.clear();
+            return;
+        }
+
+        if let Some((revision, rx)) = &self.pending {
+            match rx.try_recv() {
+                Ok(ranges) => {
+                    self.ranges = ranges;
+                    self.ranges_revision = *revision;
+                    self.pending = None;
+                    if self.ranges_revision == code.revision() {
+                        self.validate();
+                    }
+                },
+                Err(mpsc::TryRecvError::Empty) => return,
+                Err(mpsc::TryRecvError::Disconnected) => self.pending = None,
+            }
+        }
+        // only one computation runs at a time, so fast typing doesn't pile them up
+        if self.ranges_revision != code.revision() && self.pending.is_none() {
+            let (tx, rx) = mpsc::channel();
+            let text = code.to_string();
+            let ctx = ctx.clone();
+            thread::spawn(move || {
+                // the receiver is gone if the tab was closed in the meantime
+                if tx.send(fold_ranges(&text)).is_ok() {
+                    ctx.request_repaint();
+                }
+            });
+            self.pending = Some((code.revision(), rx));
+        }
+    }
+
+    /// Move the folds after the first changed line by the number of lines added (or
+    /// removed), and resize the folds around it
+    fn shift(&mut self, changed_line: usize, delta: isize) {
+        let moved = |line: usize| line.saturating_add_signed(delta);
+        for fold in self.folded.iter_mut() {
+            if fold.start > changed_line {
+                fold.start = moved(fold.start);
+                fold.end = moved(fold.end);
+            } else if fold.end >= changed_line {
+                fold.end = moved(fold.end);
+            }
+        }
+        self.folded.retain(|fold| fold.end > fold.start);
+    }
+
+    /// Keep the folds that a range still starts at, and give them its end
+    fn validate(&mut self) {
+        let ranges = &self.ranges;
+        self.folded.retain_mut(|fold| match ranges.iter().find(|range| range.start == fold.start) {
+            Some(range) => {
+                fold.end = range.end;
+                true
+            },
+            None => false,
+        });
+    }
+
+    /// The range that can be folded at a line, if there is one
+    pub fn get_range(&self, line: usize) -> Option<FoldRange> {
+        self.ranges.iter().find(|range| range.start == line).copied()
+    }
+
+    pub fn is_folded(&self, line: usize) -> bool {
+        self.folded.iter().any(|fold| fold.start == line)
+    }
+
+    /// Fold the range at a line, or unfold it if it is folded
+    pub fn toggle(&mut self, line: usize) {
+        if self.is_folded(line) {
+            self.folded.retain(|fold| fold.start != line);
+        } else if let Some(range) = self.get_range(line) {
+            let at = self.folded.partition_point(|fold| fold.start < line);
+            self.folded.insert(at, range);
+        }
+    }
+
+    /// Fold the innermost range around a line
+    pub fn fold_at(&mut self, line: usize) {
+        let innermost = self.ranges.iter()
+            .filter(|range| range.start <= line && line <= range.end)
+            .max_by_key(|range| range.start);
+        if let Some(range) = innermost {
+            if !self.is_folded(range.start) {
+                self.toggle(range.start);
+            }
+        }
+    }
+
+    /// Unfold the folds that hide a line, or that start at it
+    pub fn unfold_at(&mut self, line: usize) {
+        self.folded.retain(|fold| !(fold.start..=fold.end).contains(&line));
+    }
+
+    /// Unfold the folds that hide any of the lines
+    pub fn unfold_lines(&mut self, lines: Range<usize>) {
+        self.folded.retain(|fold| {
+            let hidden = fold.hidden();
+            hidden.start >= lines.end || lines.start >= hidden.end
+        });
+    }
+
+    pub fn fold_all(&mut self) {
+        self.folded = self.ranges.clone();
+    }
+
+    pub fn unfold_all(&mut self) {
+        self.folded.clear();
+    }
+
+    /// The folded ranges, to keep them between sessions
+    pub fn get_folded(&self) -> Vec<FoldRange> {
+        self.folded.clone()
+    }
+
+    /// Fold the ranges of a former session. They are checked against the ranges of
+    /// the code once those are known.
+    pub fn set_folded(&mut self, mut folded: Vec<FoldRange>) {
+        folded.sort_by_key(|fold| fold.start);
+        self.folded = folded;
+    }
+
+    /// The hidden lines, as sorted ranges that don't overlap
+    pub fn hidden_lines(&self) -> Vec<Range<usize>> {
+        let mut hidden: Vec<Range<usize>> = Vec::new();
+        for fold in self.folded.iter() {
+            let lines = fold.hidden();
+            match hidden.last_mut() {
+                // a fold inside a folded range is already hidden
+                Some(last) if lines.start <= last.end => last.end = last.end.max(lines.end),
+                _ => hidden.push(lines),
+            }
+        }
+        hidden
+    }
+}
+
+/// The ranges of lines that can be folded in Rust code, at most one per first line
+fn fold_ranges(code: &str) -> Vec<FoldRange> {
+    let line_of = |offset: usize| code.as_bytes()[..offset].iter().filter(|b| **b == b'\n').count();
+    let mut ranges: Vec<FoldRange> = Vec::new();
+    for range in rust_analyzer::folding_ranges(code) {
+        let start = line_of(range.start);
+        let mut end = line_of(range.end);
+        // a closing line that goes on, like `} else {`, stays in view
+        let rest = code[range.end..].split('\n').next().unwrap_or_default();
+        if !rest.trim_start_matches([')', ',', ';']).trim().is_empty() {
+            end -= 1;
+        }
+        if end > start {
+            ranges.push(FoldRange { start, end });
+        }
+    }
+    // of the ranges that start at the same line, the longest is the one to fold
+    ranges.sort_by_key(|range| (range.start, std::cmp::Reverse(range.end)));
+    ranges.dedup_by_key(|range| range.start);
+    ranges
+}
+
+/// The number of lines in view when the hidden lines are folded away
+pub fn num_visible(num_lines: usize, hidden: &[Range<usize>]) -> usize {
+    num_lines - hidden.iter().map(|lines| lines.len()).sum::<usize>()
+}
+
+/// The line shown in the `row`-th row of the editor
+pub fn row_to_line(row: usize, hidden: &[Range<usize>]) -> usize {
+    let mut line = row;
+    for lines in hidden.iter() {
+        if lines.start > line {
+            break;
+        }
+        line += lines.len();
+    }
+    line
+}
+
+/// The row that a line is shown in. A hidden line is in the row of its fold.
+pub fn line_to_row(line: usize, hidden: &[Range<usize>]) -> usize {
+    let mut row = line;
+    for lines in hidden.iter() {
+        if lines.start > line {
+            break;
+        }
+        row -= lines.len().min(line + 1 - lines.start);
+    }
+    row
+}
+
+/// The first line of the fold that hides a line, or the line itself if it isn't hidden
+pub fn visible_line(line: usize, hidden: &[Range<usize>]) -> usize {
+    match hidden.iter().find(|lines| lines.contains(&line)) {
+        Some(lines) => lines.start - 1,
+        None => line,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn folds(folded: &[(usize, usize)]) -> Folds {
+        let mut folds = Folds::default();
+        folds.set_folded(folded.iter().map(|&(start, end)| FoldRange { start, end }).collect());
+        folds
+    }
+
+    fn folded(folds: &Folds) -> Vec<(usize, usize)> {
+        folds.get_folded().iter().map(|fold| (fold.start, fold.end)).collect()
+    }
+
+    #[test]
+    fn nested_and_adjacent_folds_hide_lines_once() {
+        // a fold inside another, then one that starts on the line after it
+        let hidden = folds(&[(1, 6), (2, 4), (7, 9), (12, 13)]).hidden_lines();
+        assert_eq!(hidden, [2..7, 8..10, 13..14]);
+        // a fold that starts on the last line of another
+        let hidden = folds(&[(3, 5), (0, 3)]).hidden_lines();
+        assert_eq!(hidden, [1..6]);
+        assert_eq!(folds(&[]).hidden_lines(), []);
+    }
+
+    #[test]
+    fn rows_and_lines_round_trip() {
+        let num_lines = 16;
+        let hidden = folds(&[(1, 6), (2, 4), (7, 9), (12, 13)]).hidden_lines();
+        let visible: Vec<usize> = (0..num_lines)
+            .filter(|line| !hidden.iter().any(|lines| lines.contains(line)))
+            .collect();
+        assert_eq!(visible, [0, 1, 7, 10, 11, 12, 14, 15]);
+        assert_eq!(num_visible(num_lines, &hidden), visible.len());
+        for (row, line) in visible.iter().enumerate() {
+            assert_eq!(row_to_line(row, &hidden), *line);
+            assert_eq!(line_to_row(*line, &hidden), row);
+            assert_eq!(visible_line(*line, &hidden), *line);
+        }
+        // a hidden line is shown in the row of the fold that hides it
+        for line in 0..num_lines {
+            let shown = visible_line(line, &hidden);
+            assert!(visible.contains(&shown));
+            assert!(shown <= line);
+            assert_eq!(line_to_row(line, &hidden), line_to_row(shown, &hidden));
+            assert_eq!(row_to_line(line_to_row(line, &hidden), &hidden), shown);
+        }
+        assert_eq!(visible_line(5, &hidden), 1);
+        assert_eq!(visible_line(9, &hidden), 7);
+        // with nothing folded, rows are lines
+        assert_eq!(row_to_line(7, &[]), 7);
+        assert_eq!(line_to_row(7, &[]), 7);
+    }
+
+    #[test]
+    fn folds_shift_with_edits() {
+        let before = [(2, 5), (10, 12)];
+        let shifted = |changed_line: usize, delta: isize| {
+            let mut folds = folds(&before);
+            folds.shift(changed_line, delta);
+            folded(&folds)
+        };
+        // above the folds, they all move
+        assert_eq!(shifted(0, 2), [(4, 7), (12, 14)]);
+        assert_eq!(shifted(1, -1), [(1, 4), (9, 11)]);
+        // inside a fold, it grows or shrinks, and the ones below it move
+        assert_eq!(shifted(3, 2), [(2, 7), (12, 14)]);
+        assert_eq!(shifted(5, 1), [(2, 6), (11, 13)]);
+        assert_eq!(shifted(3, -2), [(2, 3), (8, 10)]);
+        // a fold whose lines were all removed is gone
+        assert_eq!(shifted(2, -3), [(7, 9)]);
+        // below a fold, it stays where it is
+        assert_eq!(shifted(7, 4), [(2, 5), (14, 16)]);
+        assert_eq!(shifted(13, -1), before);
+        assert_eq!(shifted(8, 0), before);
+    }
+}
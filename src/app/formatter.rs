@@ -0,0 +1,343 @@
+//! This module formats Rust code for the format-on-save option of the code editor.
+//! Code is piped through `rustfmt`, using the `rustfmt.toml` of the project it is in.
+//! When rustfmt isn't installed, `prettyplease` is used instead, as long as that
+//! wouldn't lose any comments (prettyplease only keeps doc comments).
+
+use log::{debug, warn};
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const RUSTFMT_CONFIG_FILES: [&str; 2] = ["rustfmt.toml", ".rustfmt.toml"];
+
+/// The edition of code that isn't in a crate
+const DEFAULT_EDITION: &str = "2021";
+
+/// A problem found in the code of a tab, shown in the editor
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// The line the problem is on (1-based), if it is known
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+/// Format Rust code that lives at `path`, returning the formatted code, or the reason
+/// it couldn't be formatted
+pub fn format_rust(code: &str, path: Option<&Path>) -> Result<String, Diagnostic> {
+    let dir = path.and_then(Path::parent);
+    let config = dir.and_then(find_rustfmt_config);
+    let edition = dir.and_then(find_edition).unwrap_or(DEFAULT_EDITION.to_string());
+    match run_rustfmt(code, config.as_deref(), &edition) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!("rustfmt wasn't found, formatting with prettyplease");
+            format_with_prettyplease(code)
+        },
+        Err(e) => Err(Diagnostic {
+            line: None,
+            message: format!("couldn't run rustfmt: {}", e),
+        }),
+        Ok(result) => result,
+    }
+}
+
+/// Find the rustfmt config of the crate that `dir` is in, by looking in it and in each
+/// of its ancestors
+fn find_rustfmt_config(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .flat_map(|dir| RUSTFMT_CONFIG_FILES.iter().map(move |name| dir.join(name)))
+        .find(|path| path.is_file())
+}
+
+/// The edition of the crate that `dir` is in, from the nearest Cargo.toml with a
+/// package. An edition inherited from the workspace is read from the workspace's
+/// Cargo.toml, and a package without one is of the 2015 edition, like cargo has it.
+fn find_edition(dir: &Path) -> Option<String> {
+    let workspace_edition = |manifest: &toml::Table| {
+        let edition = manifest.get("workspace")?.get("package")?.get("edition")?;
+        edition.as_str().map(String::from)
+    };
+    let mut manifests = dir.ancestors()
+        .filter_map(|dir| fs::read_to_string(dir.join("Cargo.toml")).ok())
+        .filter_map(|contents| contents.parse::<toml::Table>().ok());
+    let (manifest, edition) = manifests.by_ref()
+        .find_map(|manifest| {
+            let edition = manifest.get("package")?.get("edition").cloned();
+            Some((manifest, edition))
+        })?;
+    match edition {
+        Some(toml::Value::String(edition)) => Some(edition),
+        // the workspace's Cargo.toml may be the package's one too
+        Some(edition) if edition.get("workspace").and_then(toml::Value::as_bool) == Some(true) => {
+            std::iter::once(manifest).chain(manifests).find_map(|manifest| workspace_edition(&manifest))
+        },
+        _ => Some("2015".to_string()),
+    }
+}
+
+/// Pipe the code through rustfmt. The outer error is for when rustfmt couldn't be run
+/// at all, and the inner one for when it rejected the code. The edition is always
+/// given, as rustfmt can't read it from the Cargo.toml of code on stdin (and would
+/// take it to be 2015), and it overrides the edition of the config, like `cargo fmt`.
+fn run_rustfmt(code: &str, config: Option<&Path>, edition: &str) -> io::Result<Result<String, Diagnostic>> {
+    let mut cmd = Command::new("rustfmt");
+    cmd.args(["--emit", "stdout", "--edition", edition]);
+    if let Some(config) = config {
+        cmd.arg("--config-path").arg(config);
+    }
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // write on another thread, so rustfmt can't block on a full stdout pipe meanwhile
+    let mut stdin = child.stdin.take().expect("rustfmt stdin is piped");
+    let input = code.to_string();
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output()?;
+    if let Ok(Err(e)) = writer.join() {
+        warn!("couldn't write code to rustfmt: {:?}", e);
+    }
+    if output.status.success() {
+        return Ok(String::from_utf8(output.stdout).map_err(|_| Diagnostic {
+            line: None,
+            message: "rustfmt returned invalid UTF-8".to_string(),
+        }));
+    }
+    Ok(Err(parse_rustfmt_error(&String::from_utf8_lossy(&output.stderr))))
+}
+
+/// Turn the error output of rustfmt into a diagnostic: the first error message, and
+/// the line from its `--> <stdin>:line:column` location
+fn parse_rustfmt_error(stderr: &str) -> Diagnostic {
+    let message = stderr.lines()
+        .find(|line| line.starts_with("error"))
+        .or_else(|| stderr.lines().find(|line| !line.trim().is_empty()))
+        .unwrap_or("rustfmt failed")
+        .trim_start_matches("error: ")
+        .to_string();
+    let line = stderr.lines()
+        .filter_map(|line| line.trim_start().strip_prefix("--> <stdin>:"))
+        .find_map(|location| location.split(':').next()?.parse().ok());
+    Diagnostic { line, message }
+}
+
+/// Format the code with prettyplease, which only keeps doc comments
+fn format_with_prettyplease(code: &str) -> Result<String, Diagnostic> {
+    if has_plain_comments(code) {
+        return Err(Diagnostic {
+            line: None,
+            message: "rustfmt isn't installed, and formatting with prettyplease would remove the comments".to_string(),
+        });
+    }
+    let file = syn::parse_file(code).map_err(|e| Diagnostic {
+        line: Some(e.span().start().line),
+        message: e.to_string(),
+    })?;
+    Ok(prettyplease::unparse(&file))
+}
+
+/// Whether the code has any comments besides doc comments, skipping over string and
+/// char literals
+fn has_plain_comments(code: &str) -> bool {
+    let chars: Vec<char> = code.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '/' if chars.get(i + 1) == Some(&'/') => {
+                let doc = matches!(chars.get(i + 2), Some('!'))
+                    || (chars.get(i + 2) == Some(&'/') && chars.get(i + 3) != Some(&'/'));
+                if !doc {
+                    return true;
+                }
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            },
+            '/' if chars.get(i + 1) == Some(&'*') => {
+                let doc = matches!(chars.get(i + 2), Some('!'))
+                    || (chars.get(i + 2) == Some(&'*') && !matches!(chars.get(i + 3), Some('*') | Some('/')));
+                if !doc {
+                    return true;
+                }
+                i += 2;
+                while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                    i += 1;
+                }
+                i += 1;
+            },
+            'r' if matches!(chars.get(i + 1), Some('"') | Some('#')) => {
+                // a raw string ends at a quote followed by as many #s as it started with
+                let hashes = chars[(i + 1)..].iter().take_while(|c| **c == '#').count();
+                if chars.get(i + 1 + hashes) != Some(&'"') {
+                    i += 1;
+                    continue;
+                }
+                i += hashes + 2;
+                while i < chars.len()
+                    && !(chars[i] == '"' && chars[(i + 1)..].iter().take(hashes).filter(|c| **c == '#').count() == hashes)
+                {
+                    i += 1;
+                }
+                i += hashes;
+            },
+            '"' => {
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    if chars[i] == '\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+            },
+            '\'' => {
+                // a char literal, rather than a lifetime
+                if chars.get(i + 1) == Some(&'\\') {
+                    i += 3;
+                    while i < chars.len() && chars[i] != '\'' {
+                        i += 1;
+                    }
+                } else if chars.get(i + 2) == Some(&'\'') {
+                    i += 2;
+                }
+            },
+            _ => (),
+        }
+        i += 1;
+    }
+    false
+}
+
+/// Find where a char index of some code ends up after the code is reformatted, by
+/// keeping the same number of non-whitespace chars before it. A cursor right before
+/// a token stays before that token.
+pub fn map_cursor(before: &str, after: &str, cursor: usize) -> usize {
+    let n = before.chars().take(cursor).filter(|c| !c.is_whitespace()).count();
+    let at_token = before.chars().nth(cursor).is_some_and(|c| !c.is_whitespace());
+    let mut tokens = after.chars().enumerate().filter(|(_, c)| !c.is_whitespace());
+    match (at_token, n) {
+        (true, n) => tokens.nth(n).map(|(i, _)| i),
+        (false, 0) => Some(0),
+        (false, n) => tokens.nth(n - 1).map(|(i, _)| i + 1),
+    }.unwrap_or(after.chars().count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_comments_are_found() {
+        assert!(has_plain_comments("fn main() {} // a comment"));
+        assert!(has_plain_comments("fn main() { /* a comment */ }"));
+        // four slashes, or a block with three stars, aren't doc comments
+        assert!(has_plain_comments("//// a separator\nfn main() {}"));
+        assert!(has_plain_comments("/*** a banner */\nfn main() {}"));
+        assert!(has_plain_comments("/**/ fn main() {}"));
+    }
+
+    #[test]
+    fn doc_comments_are_kept() {
+        assert!(!has_plain_comments("//! a crate\n/// a function\nfn main() {}"));
+        assert!(!has_plain_comments("/*! a crate */\n/** a function */\nfn main() {}"));
+        assert!(has_plain_comments("/// a function\nfn main() {} // but this isn't"));
+    }
+
+    #[test]
+    fn comments_in_literals_are_skipped() {
+        assert!(!has_plain_comments(r#"const URL: &str = "http://example.com";"#));
+        assert!(!has_plain_comments(r#"const S: &str = "a \" // quote";"#));
+        assert!(!has_plain_comments(r###"const S: &str = r#"a "// quote" /* too */"#;"###));
+        assert!(!has_plain_comments(r###"const S: &[u8] = br##"a "# // quote"##;"###));
+        assert!(has_plain_comments(r###"const S: &str = r#"a"#; // after"###));
+        // raw identifiers aren't raw strings
+        assert!(has_plain_comments("fn r#match() {} // a comment"));
+    }
+
+    #[test]
+    fn char_literals_and_lifetimes() {
+        assert!(!has_plain_comments(r#"const C: [char; 4] = ['"', '/', '\'', '\u{1F980}'];"#));
+        assert!(!has_plain_comments("const Q: char = '\"'; const S: &str = \"//\";"));
+        // the quote of a lifetime doesn't start a char literal that hides the comment
+        assert!(has_plain_comments("fn f<'a>(s: &'a str) -> &'a str { s } // a comment"));
+        assert!(has_plain_comments("struct S<'a, 'b>(&'a u8, &'b u8); // a comment"));
+        assert!(!has_plain_comments("fn f<'a>(s: &'a str) -> char { '/' }"));
+    }
+
+    #[test]
+    fn cursor_keeps_its_token() {
+        let before = "fn  main(){\nlet x=1;}";
+        let after = "fn main() {\n    let x = 1;\n}\n";
+        let at = |text: &str, pattern: &str| text[..text.find(pattern).unwrap()].chars().count();
+        // before a token, the cursor stays before it
+        assert_eq!(map_cursor(before, after, at(before, "main")), at(after, "main"));
+        assert_eq!(map_cursor(before, after, at(before, "=1")), at(after, "= 1"));
+        assert_eq!(map_cursor(before, after, at(before, "1;")), at(after, "1;"));
+        // after a token, it stays right after it
+        assert_eq!(map_cursor(before, after, at(before, "\nlet")), at(after, "\n    let"));
+        assert_eq!(map_cursor(before, after, 0), 0);
+        assert_eq!(map_cursor(before, after, before.chars().count()), at(after, "}") + 1);
+        // chars are counted, not bytes
+        assert_eq!(map_cursor("let é=1;", "let é = 1;", 4), 4);
+        assert_eq!(map_cursor("let é=1;", "let é = 1;", 5), 6);
+        assert_eq!(map_cursor("let é=1;", "let é = 1;", 6), 8);
+    }
+
+    /// An empty directory for a test to write to
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("iron_coder_formatter_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("member").join("src")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn edition_is_read_from_the_crate() {
+        let dir = test_dir("edition");
+        let src = dir.join("member").join("src");
+        assert_eq!(find_edition(&src), None);
+        fs::write(dir.join("member").join("Cargo.toml"), "[package]\nname = \"member\"\nedition = \"2018\"\n").unwrap();
+        assert_eq!(find_edition(&src).as_deref(), Some("2018"));
+        fs::write(dir.join("member").join("Cargo.toml"), "[package]\nname = \"member\"\n").unwrap();
+        assert_eq!(find_edition(&src).as_deref(), Some("2015"));
+        // an edition inherited from the workspace
+        fs::write(dir.join("member").join("Cargo.toml"), "[package]\nname = \"member\"\nedition.workspace = true\n").unwrap();
+        fs::write(dir.join("Cargo.toml"), "[workspace]\nmembers = [\"member\"]\n[workspace.package]\nedition = \"2021\"\n").unwrap();
+        assert_eq!(find_edition(&src).as_deref(), Some("2021"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn config_without_edition_formats_newer_code() {
+        let dir = test_dir("config");
+        fs::write(dir.join("member").join("Cargo.toml"), "[package]\nname = \"member\"\nedition = \"2021\"\n").unwrap();
+        fs::write(dir.join("rustfmt.toml"), "tab_spaces = 2\n").unwrap();
+        let path = dir.join("member").join("src").join("lib.rs");
+        let formatted = format_rust("async fn f()->u8{async{1}.await}", Some(&path)).unwrap();
+        assert!(formatted.starts_with("async fn f() -> u8 {\n"), "{}", formatted);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rustfmt_errors_are_parsed() {
+        let stderr = "error: expected one of `!` or `::`, found `}`\n --> <stdin>:3:1\n  |\n3 | }\n  | ^ expected one of `!` or `::`\n\n";
+        assert_eq!(parse_rustfmt_error(stderr), Diagnostic {
+            line: Some(3),
+            message: "expected one of `!` or `::`, found `}`".to_string(),
+        });
+        let stderr = "warning: unknown configuration option `foo`\nerror[E0001]: bad\n  --> <stdin>:12:5\n";
+        assert_eq!(parse_rustfmt_error(stderr), Diagnostic {
+            line: Some(12),
+            message: "error[E0001]: bad".to_string(),
+        });
+        assert_eq!(parse_rustfmt_error("\nsomething went wrong\n"), Diagnostic {
+            line: None,
+            message: "something went wrong".to_string(),
+        });
+        assert_eq!(parse_rustfmt_error(""), Diagnostic {
+            line: None,
+            message: "rustfmt failed".to_string(),
+        });
+    }
+}
@@ -0,0 +1,547 @@
+//! This module contains the syntax highlighter used by the code editor. Syntect can
+//! only highlight a line after parsing all of the lines before it, so each buffer has
+//! a `HighlightCache` that keeps the parser state at checkpoints through the buffer, as
+//! far down as the buffer has been shown. Only the lines on screen are highlighted,
+//! starting from the nearest checkpoint, and an edit drops the checkpoints after it.
+//!
+//! The lines on screen are highlighted incrementally: each view of a buffer has a
+//! `ViewHighlight`, which caches the highlighted sections of each line it shows along
+//! with the syntect parser states before and after it. When the
+//! code changes, only the lines from the first edited line onward are re-highlighted,
+//! stopping as soon as the parser state matches the cached state of the following
+//! line again. Folded lines are left out of the text on screen, so the line after a
+//! fold starts over from the parser state before it in the buffer.
+//!
+//! Besides the syntaxes that ship with syntect, the highlighter knows about a few file
+//! types that are common in embedded Rust projects, which are bundled in `assets/syntaxes`.
+
+use std::ops::Range;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+use egui::text::{LayoutJob, LayoutSection, TextFormat};
+
+use syntect::highlighting::{
+    FontStyle, HighlightIterator, HighlightState, Highlighter as ThemeHighlighter, ThemeSet,
+};
+use syntect::parsing::{ParseState, ScopeStack, SyntaxDefinition, SyntaxSet};
+use syntect::util::LinesWithEndings;
+
+// imported through `super`, so that the benchmark can include this module with the rope
+use super::rope::Rope;
+
+/// The theme used until another one is set, and whenever a theme can't be found
+const DEFAULT_THEME: &str = "Solarized (dark)";
+
+/// The name of the syntax used for files of an unknown type
+pub const PLAIN_TEXT: &str = "Plain Text";
+
+/// Syntaxes that are not among syntect's defaults: TOML, linker scripts and RON
+const EXTRA_SYNTAXES: [&str; 3] = [
+    include_str!("../../assets/syntaxes/TOML.sublime-syntax"),
+    include_str!("../../assets/syntaxes/LinkerScript.sublime-syntax"),
+    include_str!("../../assets/syntaxes/RON.sublime-syntax"),
+];
+
+/// Load syntect's default syntaxes along with our extra ones
+fn load_syntaxes() -> SyntaxSet {
+    let mut builder = SyntaxSet::load_defaults_newlines().into_builder();
+    for syntax in EXTRA_SYNTAXES {
+        match SyntaxDefinition::load_from_str(syntax, true, None) {
+            Ok(syntax) => builder.add(syntax),
+            Err(e) => warn!("couldn't load bundled syntax: {:?}", e),
+        }
+    }
+    builder.build()
+}
+
+/// The syntect parser and highlighter states at the boundary between two lines
+type LineState = (ParseState, HighlightState);
+
+/// The number of lines between the checkpoints of the parser state
+const CHECKPOINT_INTERVAL: usize = 128;
+
+/// How long a single call may spend parsing the lines above the ones on screen. The
+/// rest is parsed by the following calls, and the lines are shown plain until then.
+const CATCH_UP_TIME: Duration = Duration::from_millis(20);
+
+/// A highlighted line of code
+#[derive(Debug)]
+struct HighlightedLine {
+    /// The text of the line, including its line ending
+    text: String,
+    /// The state before the line, which decides how it is highlighted
+    start: LineState,
+    /// The state after the line, which the next line starts from
+    end: LineState,
+    /// The number of sections that the line adds to the layout job
+    num_sections: usize,
+}
+
+/// The parsing of one buffer, which all of its views are highlighted from
+#[derive(Default, Debug)]
+pub struct HighlightCache {
+    /// The syntax and theme that the cache was built with
+    language: String,
+    theme: String,
+    /// The revision of the buffer that the checkpoints are up to date with
+    revision: u64,
+    /// The state at the start of every CHECKPOINT_INTERVAL-th line
+    checkpoints: Vec<LineState>,
+}
+
+/// The highlighting of the text that one view shows of a buffer
+#[derive(Default, Debug)]
+pub struct ViewHighlight {
+    /// The syntax and theme that the text was highlighted with
+    language: String,
+    theme: String,
+    /// The revision of the buffer that the start states are up to date with
+    revision: u64,
+    /// The runs of lines that the highlighted text is made of, as the line of the text
+    /// and the line of the buffer that each run starts at, and the states before them
+    runs: Vec<(usize, usize)>,
+    starts: Option<Vec<LineState>>,
+    lines: Vec<HighlightedLine>,
+    /// The highlighted text, whose sections are kept in sync with `lines`
+    job: LayoutJob,
+}
+
+pub struct Highlighter {
+    ps: SyntaxSet,
+    ts: ThemeSet,
+    /// The name of the theme that code is highlighted with
+    theme: String,
+    /// The number of lines that were highlighted by the last update
+    last_highlighted: usize,
+}
+
+impl Default for Highlighter {
+    fn default() -> Self {
+        Self {
+            ps: load_syntaxes(),
+            ts: ThemeSet::load_defaults(),
+            theme: DEFAULT_THEME.to_string(),
+            last_highlighted: 0,
+        }
+    }
+}
+
+impl Highlighter {
+
+    /// Add the custom .tmTheme files found in `dir` to the available themes
+    pub fn load_themes(&mut self, dir: &Path) {
+        if !dir.is_dir() {
+            return;
+        }
+        match self.ts.add_from_folder(dir) {
+            Ok(_) => info!("loaded syntax themes from {}", dir.display()),
+            Err(e) => warn!("couldn't load syntax themes from {}: {:?}", dir.display(), e),
+        }
+    }
+
+    /// The names of all the available themes, sorted
+    pub fn get_themes(&self) -> Vec<&str> {
+        self.ts.themes.keys().map(|name| name.as_str()).collect()
+    }
+
+    /// Switch to the theme with the given name. The caches of the buffers and views
+    /// notice the change, and re-highlight their code with the new colors.
+    pub fn set_theme(&mut self, theme: &str) {
+        if theme == self.theme {
+            return;
+        }
+        if !self.ts.themes.contains_key(theme) {
+            warn!("unknown syntax theme {}, using {}", theme, DEFAULT_THEME);
+        }
+        self.theme = theme.to_string();
+    }
+
+    /// The number of lines that were (re-)highlighted by the last update, as opposed
+    /// to being reused from the cache.
+    pub fn get_last_highlighted(&self) -> usize {
+        self.last_highlighted
+    }
+
+    /// Pick the syntax of a file from its name or extension, or failing that from
+    /// the first line of its code. Unknown file types are treated as plain text.
+    pub fn detect_language(&self, path: &Path, code: &str) -> String {
+        let by_name = path.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| self.ps.find_syntax_by_extension(name));
+        let by_extension = path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| self.ps.find_syntax_by_extension(ext));
+        let by_first_line = code.lines().next()
+            .and_then(|line| self.ps.find_syntax_by_first_line(line));
+        by_name.or(by_extension).or(by_first_line)
+            .map(|syntax| syntax.name.clone())
+            .unwrap_or_else(|| PLAIN_TEXT.to_string())
+    }
+
+    /// The names of all the languages that can be picked for a file, sorted
+    pub fn get_languages(&self) -> Vec<&str> {
+        let mut languages: Vec<&str> = self.ps.syntaxes().iter()
+            .filter(|syntax| !syntax.hidden && !syntax.file_extensions.is_empty())
+            .map(|syntax| syntax.name.as_str())
+            .collect();
+        languages.sort_by_key(|name| name.to_lowercase());
+        languages
+    }
+
+    /// Highlight `text` as the language with the given syntax name, for the view whose
+    /// highlighting is kept in `view`. The text is made of runs of lines of `code`, though
+    /// it may have been edited since: `runs` has the line of the text and the line of the
+    /// code that each run starts at, the first one at the start of the text. Returns None
+    /// while the lines above it are still being parsed.
+    pub fn highlight(
+        &mut self,
+        cache: &mut HighlightCache,
+        view: &mut ViewHighlight,
+        code: &Rope,
+        runs: &[(usize, usize)],
+        text: &str,
+        language: &str,
+    ) -> Option<LayoutJob> {
+        if cache.language != language || cache.theme != self.theme {
+            *cache = HighlightCache {
+                language: language.to_string(),
+                theme: self.theme.clone(),
+                ..Default::default()
+            };
+        }
+        if view.language != language || view.theme != self.theme {
+            *view = ViewHighlight {
+                language: language.to_string(),
+                theme: self.theme.clone(),
+                ..Default::default()
+            };
+        }
+        // drop the states that an edit of the code made out of date
+        if let Some(line) = code.changed_since(cache.revision) {
+            cache.checkpoints.truncate(line / CHECKPOINT_INTERVAL + 1);
+            cache.revision = code.revision();
+        }
+        if let Some(line) = code.changed_since(view.revision) {
+            if view.runs.iter().any(|(_, first_line)| line < *first_line) {
+                view.starts = None;
+            }
+            view.revision = code.revision();
+        }
+        if runs != view.runs {
+            view.runs = runs.to_vec();
+            view.starts = None;
+        }
+
+        let Highlighter { ps, ts, .. } = self;
+        let syntax = ps.find_syntax_by_name(language)
+            .unwrap_or_else(|| ps.find_syntax_plain_text());
+        let theme = ts.themes.get(&self.theme).unwrap_or(&ts.themes[DEFAULT_THEME]);
+        let highlighter = ThemeHighlighter::new(theme);
+        if cache.checkpoints.is_empty() {
+            let state = (ParseState::new(syntax), HighlightState::new(&highlighter, ScopeStack::new()));
+            cache.checkpoints.push(state);
+        }
+        if view.starts.is_none() {
+            let mut starts = Vec::with_capacity(runs.len());
+            for (_, first_line) in runs.iter() {
+                starts.push(state_at(cache, code, *first_line, ps, &highlighter)?);
+            }
+            // the cached lines can only be reused if they were highlighted from the same states
+            let same_starts = runs.iter()
+                .zip(starts.iter())
+                .all(|((line, _), start)| view.lines.get(*line).map(|l| &l.start) == Some(start));
+            if !same_starts {
+                view.lines.clear();
+                view.job = LayoutJob::default();
+            }
+            view.starts = Some(starts);
+        }
+        self.last_highlighted = update_lines(view, text, ps, &highlighter);
+        Some(view.job.clone())
+    }
+
+    /// Lay out `text` without highlighting, in the color of the theme
+    pub fn plain(&self, text: &str) -> LayoutJob {
+        let theme = self.ts.themes.get(&self.theme).unwrap_or(&self.ts.themes[DEFAULT_THEME]);
+        let color = theme.settings.foreground
+            .map(|fg| egui::Color32::from_rgb(fg.r, fg.g, fg.b))
+            .unwrap_or(egui::Color32::GRAY);
+        LayoutJob::single_section(text.to_string(), TextFormat {
+            font_id: egui::FontId::new(12.0, egui::FontFamily::Name("EditorFont".into())),
+            color,
+            ..Default::default()
+        })
+    }
+
+}
+
+/// The state at the start of a line, parsing on from the last checkpoint before it.
+/// Returns None if the checkpoints couldn't be caught up with the line in time.
+fn state_at(
+    cache: &mut HighlightCache,
+    code: &Rope,
+    line: usize,
+    ps: &SyntaxSet,
+    highlighter: &ThemeHighlighter,
+) -> Option<LineState> {
+    let checkpoint = line / CHECKPOINT_INTERVAL;
+    let deadline = Instant::now() + CATCH_UP_TIME;
+    while cache.checkpoints.len() <= checkpoint {
+        if Instant::now() > deadline {
+            debug!("parsed up to line {} of {}", (cache.checkpoints.len() - 1) * CHECKPOINT_INTERVAL, line);
+            return None;
+        }
+        let from = (cache.checkpoints.len() - 1) * CHECKPOINT_INTERVAL;
+        let mut state = cache.checkpoints[cache.checkpoints.len() - 1].clone();
+        for text in code.lines_at(from).take(CHECKPOINT_INTERVAL) {
+            parse_line(text, &mut state, ps, highlighter);
+        }
+        cache.checkpoints.push(state);
+    }
+    let from = checkpoint * CHECKPOINT_INTERVAL;
+    let mut state = cache.checkpoints[checkpoint].clone();
+    for text in code.lines_at(from).take(line - from) {
+        parse_line(text, &mut state, ps, highlighter);
+    }
+    Some(state)
+}
+
+/// Move the state past a line, without keeping its highlighting
+fn parse_line(line: &str, state: &mut LineState, ps: &SyntaxSet, highlighter: &ThemeHighlighter) {
+    let (parse_state, highlight_state) = state;
+    let ops = parse_state.parse_line(line, ps).unwrap_or_default();
+    HighlightIterator::new(highlight_state, &ops, line, highlighter).for_each(drop);
+}
+
+/// Re-highlight the lines of `text` that changed since the last update of the view,
+/// returning how many lines were highlighted
+fn update_lines(view: &mut ViewHighlight, text: &str, ps: &SyntaxSet, highlighter: &ThemeHighlighter) -> usize {
+    let ViewHighlight { lines, job, runs, starts, .. } = view;
+    let starts = starts.as_ref().expect("the start states are known before updating");
+    if text == job.text && !lines.is_empty() {
+        return 0;
+    }
+
+    // find the unchanged lines at the start and end of the text
+    let new_lines: Vec<&str> = LinesWithEndings::from(text).collect();
+    let prefix = lines.iter()
+        .zip(new_lines.iter())
+        .take_while(|(old, new)| old.text == **new)
+        .count();
+    let max_suffix = (lines.len() - prefix).min(new_lines.len() - prefix);
+    let suffix = lines.iter().rev()
+        .zip(new_lines.iter().rev())
+        .take(max_suffix)
+        .take_while(|(old, new)| old.text == **new)
+        .count();
+
+    // where the unchanged prefix ends, in bytes and in sections of the job
+    let prefix_bytes: usize = lines[..prefix].iter().map(|l| l.text.len()).sum();
+    let prefix_sections: usize = lines[..prefix].iter().map(|l| l.num_sections).sum();
+
+    // highlight lines until the parser state is back in sync with the cached
+    // state of an unchanged line, or the end of the text
+    let mut state = match prefix {
+        0 => starts[0].clone(),
+        _ => lines[prefix - 1].end.clone(),
+    };
+    let first_new_suffix = new_lines.len() - suffix;
+    let first_old_suffix = lines.len() - suffix;
+    let mut new_highlighted: Vec<HighlightedLine> = Vec::new();
+    let mut new_sections: Vec<LayoutSection> = Vec::new();
+    let mut offset = prefix_bytes;
+    // the index of the first old line that is reused after the highlighted ones
+    let mut resync_at = lines.len();
+    for (i, line) in new_lines.iter().enumerate().skip(prefix) {
+        // a run after folded lines starts from the state before it in the buffer
+        if let Some(run) = runs.iter().position(|(first, _)| *first == i) {
+            state = starts[run].clone();
+        }
+        if i >= first_new_suffix {
+            let old_idx = first_old_suffix + (i - first_new_suffix);
+            if lines[old_idx].start == state {
+                resync_at = old_idx;
+                break;
+            }
+        }
+        let highlighted = highlight_line(line, state, offset, ps, highlighter, &mut new_sections);
+        state = highlighted.end.clone();
+        offset += line.len();
+        new_highlighted.push(highlighted);
+    }
+    let num_highlighted = new_highlighted.len();
+
+    // replace the changed lines and their sections, and shift the sections after them
+    let old_bytes: usize = lines[prefix..resync_at].iter().map(|l| l.text.len()).sum();
+    let old_sections: usize = lines[prefix..resync_at].iter().map(|l| l.num_sections).sum();
+    let num_new_sections = new_sections.len();
+    lines.splice(prefix..resync_at, new_highlighted);
+    job.sections.splice(prefix_sections..(prefix_sections + old_sections), new_sections);
+    let new_bytes = offset - prefix_bytes;
+    if new_bytes != old_bytes {
+        for section in job.sections[(prefix_sections + num_new_sections)..].iter_mut() {
+            let start = section.byte_range.start + new_bytes - old_bytes;
+            let end = section.byte_range.end + new_bytes - old_bytes;
+            section.byte_range = start..end;
+        }
+    }
+    job.text.clear();
+    job.text.push_str(text);
+    num_highlighted
+}
+
+/// Highlight a single line that starts at byte `offset` of the text, starting from
+/// the given state, and push its sections onto `sections`.
+fn highlight_line(
+    line: &str,
+    start: LineState,
+    offset: usize,
+    ps: &SyntaxSet,
+    highlighter: &ThemeHighlighter,
+    sections: &mut Vec<LayoutSection>,
+) -> HighlightedLine {
+    let (mut parse_state, mut highlight_state) = start.clone();
+    let ops = parse_state.parse_line(line, ps).unwrap_or_default();
+    let num_sections_before = sections.len();
+    for (style, range) in HighlightIterator::new(&mut highlight_state, &ops, line, highlighter) {
+        let fg = style.foreground;
+        let text_color = egui::Color32::from_rgb(fg.r, fg.g, fg.b);
+        let italics = style.font_style.contains(FontStyle::ITALIC);
+        let underline = if style.font_style.contains(FontStyle::UNDERLINE) {
+            egui::Stroke::new(1.0, text_color)
+        } else {
+            egui::Stroke::NONE
+        };
+        let range = as_byte_range(line, range);
+        sections.push(LayoutSection {
+            leading_space: 0.0,
+            byte_range: (offset + range.start)..(offset + range.end),
+            format: TextFormat {
+                font_id: egui::FontId::new(12.0, egui::FontFamily::Name("EditorFont".into())),
+                color: text_color,
+                italics,
+                underline,
+                ..Default::default()
+            },
+        });
+    }
+    HighlightedLine {
+        text: line.to_string(),
+        start,
+        end: (parse_state, highlight_state),
+        num_sections: sections.len() - num_sections_before,
+    }
+}
+
+fn as_byte_range(whole: &str, range: &str) -> Range<usize> {
+    let whole_start = whole.as_ptr() as usize;
+    let range_start = range.as_ptr() as usize;
+    assert!(whole_start <= range_start);
+    assert!(range_start + range.len() <= whole_start + whole.len());
+    let offset = range_start - whole_start;
+    offset..(offset + range.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The text of `num` lines of the code from `first`
+    fn lines(code: &Rope, first: usize, num: usize) -> String {
+        code.lines_at(first).take(num).collect()
+    }
+
+    const SNIPPET: &str = "\
+/// A point
+#[derive(Debug)]
+struct Point {
+    x: f32, // across
+    y: f32,
+}
+
+impl Point {
+    fn new(x: f32, y: f32) -> Self {
+        let name = \"origin\";
+        let c = 'c';
+        Self { x, y }
+    }
+}
+
+fn main() {
+    let p = Point::new(1.0, 2.0);
+    println!(\"{:?}\", p);
+}
+";
+
+    /// A pseudo-random number generator, so that the test runs the same every time
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((self.0 >> 33) as usize) % n.max(1)
+        }
+    }
+
+    /// Highlight the lines of the code from `first` on without any cached state
+    fn from_scratch(highlighter: &mut Highlighter, code: &Rope, first: usize) -> LayoutJob {
+        let text = lines(code, first, usize::MAX);
+        let (mut cache, mut view) = (HighlightCache::default(), ViewHighlight::default());
+        highlighter.highlight(&mut cache, &mut view, code, &[(0, first)], &text, "Rust").unwrap()
+    }
+
+    #[test]
+    fn incremental_highlighting_matches_highlighting_from_scratch() {
+        let mut highlighter = Highlighter::default();
+        let mut code = Rope::from(SNIPPET);
+        let mut cache = HighlightCache::default();
+        // a view of the whole code, and one below the top lines
+        let mut views = [(ViewHighlight::default(), 0), (ViewHighlight::default(), 8)];
+        let pieces = ["/*", "*/", "\"", "'", "//", "\n", "x", "{", "}", " ", "r#\"", "\"#"];
+        let mut rng = Lcg(7);
+        for step in 0..150 {
+            // the code is ASCII, so any offset is a char boundary
+            let start = rng.below(code.len() + 1);
+            // the first edits open and close a block comment, the rest are random
+            match step {
+                0 => code.replace(code.line_to_byte(3)..code.line_to_byte(3), "/* "),
+                1 => code.replace(code.line_to_byte(12)..code.line_to_byte(12), " */"),
+                _ if rng.below(3) == 0 => {
+                    let end = (start + rng.below(4)).min(code.len());
+                    code.replace(start..end, "");
+                }
+                _ => code.replace(start..start, pieces[rng.below(pieces.len())]),
+            }
+            for (view, first) in views.iter_mut() {
+                let first = (*first).min(code.len_lines() - 1);
+                let text = lines(&code, first, usize::MAX);
+                let job = highlighter.highlight(&mut cache, view, &code, &[(0, first)], &text, "Rust").unwrap();
+                let expected = from_scratch(&mut highlighter, &code, first);
+                assert_eq!(job.text, expected.text, "after step {}", step);
+                assert_eq!(job.sections, expected.sections, "after step {} of {:?}", step, code.to_string());
+            }
+        }
+    }
+
+    #[test]
+    fn views_of_a_buffer_keep_their_own_lines() {
+        let code = Rope::from("fn main() {\n    let x = 1;\n}\n".repeat(100).as_str());
+        let mut highlighter = Highlighter::default();
+        let mut cache = HighlightCache::default();
+        let (mut top, mut bottom) = (ViewHighlight::default(), ViewHighlight::default());
+        let (top_text, bottom_text) = (lines(&code, 0, 20), lines(&code, 200, 20));
+        for _ in 0..2 {
+            highlighter.highlight(&mut cache, &mut top, &code, &[(0, 0)], &top_text, "Rust").unwrap();
+            highlighter.highlight(&mut cache, &mut bottom, &code, &[(0, 200)], &bottom_text, "Rust").unwrap();
+        }
+        // showing the buffer in two places doesn't re-highlight either of them
+        highlighter.highlight(&mut cache, &mut top, &code, &[(0, 0)], &top_text, "Rust").unwrap();
+        assert_eq!(highlighter.get_last_highlighted(), 0);
+        highlighter.highlight(&mut cache, &mut bottom, &code, &[(0, 200)], &bottom_text, "Rust").unwrap();
+        assert_eq!(highlighter.get_last_highlighted(), 0);
+        assert_eq!(cache.checkpoints.len(), 2);
+    }
+}
@@ -0,0 +1,576 @@
+//! This module has the commands that can be run from the keyboard, and the keymap
+//! that binds them to shortcuts. Each command belongs to a scope, so the same keys
+//! can do different things in the code editor and in the system editor.
+//!
+//! The keymap is kept in a TOML file in the config directory, which only needs the
+//! bindings that differ from the defaults, i.e.
+//!
+//! ```toml
+//! [bindings]
+//! save_project = "ctrl+s"
+//! redo = ["ctrl+shift+z", "ctrl+y"]
+//! zoom_in = []
+//! ```
+
+use log::{info, warn};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use egui::{Key, Modifiers, RichText};
+
+use serde::{Serialize, Deserialize};
+use serde::de::IntoDeserializer;
+
+/// The name of the keymap file in the config directory
+pub const KEYMAP_FILE_NAME: &str = "keymap.toml";
+
+/// The part of the app where a command runs. Global commands run anywhere.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    Global,
+    Editor,
+    SystemEditor,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 3] = [Scope::Global, Scope::Editor, Scope::SystemEditor];
+
+    /// Whether a shortcut could run commands of both scopes at the same time
+    fn overlaps(&self, other: Scope) -> bool {
+        *self == other || *self == Scope::Global || other == Scope::Global
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Scope::Global => "global",
+            Scope::Editor => "code editor",
+            Scope::SystemEditor => "system editor",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Something the user can do with a keyboard shortcut
+#[non_exhaustive]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Command {
+    SaveProject,
+    OpenProject,
+    NewProject,
+    Quit,
+    Undo,
+    Redo,
+    SelectAll,
+    GoToStart,
+    GoToEnd,
+    Find,
+    Complete,
+    GoToDefinition,
+    FindReferences,
+    RenameSymbol,
+    Fold,
+    Unfold,
+    ZoomIn,
+    ZoomOut,
+    CancelConnection,
+}
+
+impl Command {
+    pub const ALL: [Command; 19] = [
+        Command::SaveProject,
+        Command::OpenProject,
+        Command::NewProject,
+        Command::Quit,
+        Command::Undo,
+        Command::Redo,
+        Command::SelectAll,
+        Command::GoToStart,
+        Command::GoToEnd,
+        Command::Find,
+        Command::Complete,
+        Command::GoToDefinition,
+        Command::FindReferences,
+        Command::RenameSymbol,
+        Command::Fold,
+        Command::Unfold,
+        Command::ZoomIn,
+        Command::ZoomOut,
+        Command::CancelConnection,
+    ];
+
+    pub fn get_scope(&self) -> Scope {
+        match self {
+            Command::SaveProject | Command::OpenProject | Command::NewProject | Command::Quit => Scope::Global,
+            Command::ZoomIn | Command::ZoomOut | Command::CancelConnection => Scope::SystemEditor,
+            _ => Scope::Editor,
+        }
+    }
+
+    /// The shortcuts that run the command unless the keymap file says otherwise
+    fn default_shortcuts(&self) -> Vec<Shortcut> {
+        let command = Modifiers::COMMAND;
+        let shift = Modifiers::SHIFT;
+        let none = Modifiers::NONE;
+        let shortcuts: &[(Modifiers, Key)] = match self {
+            Command::SaveProject => &[(command, Key::S)],
+            Command::OpenProject => &[(command, Key::O)],
+            Command::NewProject => &[(command, Key::N)],
+            Command::Quit => &[(command, Key::Q)],
+            Command::Undo => &[(command, Key::Z)],
+            Command::Redo => &[(command | shift, Key::Z), (command, Key::Y)],
+            Command::SelectAll => &[(command, Key::A)],
+            Command::GoToStart => &[(command, Key::Home)],
+            Command::GoToEnd => &[(command, Key::End)],
+            Command::Find => &[(command, Key::F)],
+            Command::Complete => &[(command, Key::Space)],
+            Command::GoToDefinition => &[(none, Key::F12)],
+            Command::FindReferences => &[(shift, Key::F12)],
+            Command::RenameSymbol => &[(none, Key::F2)],
+            Command::Fold => &[(command | shift, Key::Minus)],
+            Command::Unfold => &[(command | shift, Key::PlusEquals)],
+            Command::ZoomIn => &[(command, Key::PlusEquals)],
+            Command::ZoomOut => &[(command, Key::Minus)],
+            Command::CancelConnection => &[(none, Key::Escape)],
+        };
+        shortcuts.iter().map(|(modifiers, key)| Shortcut::new(*modifiers, *key)).collect()
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Command::SaveProject => "save project",
+            Command::OpenProject => "open project",
+            Command::NewProject => "new project",
+            Command::Quit => "quit",
+            Command::Undo => "undo",
+            Command::Redo => "redo",
+            Command::SelectAll => "select all",
+            Command::GoToStart => "go to start",
+            Command::GoToEnd => "go to end",
+            Command::Find => "find",
+            Command::Complete => "complete",
+            Command::GoToDefinition => "go to definition",
+            Command::FindReferences => "find references",
+            Command::RenameSymbol => "rename symbol",
+            Command::Fold => "fold",
+            Command::Unfold => "unfold",
+            Command::ZoomIn => "zoom in",
+            Command::ZoomOut => "zoom out",
+            Command::CancelConnection => "cancel connection",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum ShortcutError {
+    UnknownModifier(String),
+    UnknownKey(String),
+}
+
+impl fmt::Display for ShortcutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShortcutError::UnknownModifier(name) => write!(f, "unknown modifier `{}`", name),
+            ShortcutError::UnknownKey(name) => write!(f, "unknown key `{}`", name),
+        }
+    }
+}
+
+/// A key and the modifiers held with it. Ctrl stands for the command key on a Mac.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Shortcut {
+    modifiers: Modifiers,
+    key: Key,
+}
+
+impl Shortcut {
+    pub fn new(modifiers: Modifiers, key: Key) -> Self {
+        // ctrl and command are the same modifier as far as shortcuts go
+        let mut normalized = Modifiers::NONE;
+        if modifiers.ctrl || modifiers.command || modifiers.mac_cmd {
+            normalized = normalized | Modifiers::COMMAND;
+        }
+        if modifiers.alt {
+            normalized = normalized | Modifiers::ALT;
+        }
+        if modifiers.shift {
+            normalized = normalized | Modifiers::SHIFT;
+        }
+        Self { modifiers: normalized, key }
+    }
+}
+
+impl fmt::Display for Shortcut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let modifiers = [
+            (self.modifiers.command, "ctrl"),
+            (self.modifiers.alt, "alt"),
+            (self.modifiers.shift, "shift"),
+        ];
+        for (_, name) in modifiers.iter().filter(|(held, _)| *held) {
+            write!(f, "{}+", name)?;
+        }
+        write!(f, "{}", key_name(self.key))
+    }
+}
+
+impl FromStr for Shortcut {
+    type Err = ShortcutError;
+
+    /// Parse a shortcut like `ctrl+shift+z`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts: Vec<&str> = s.split('+').map(str::trim).collect();
+        // a trailing `+` is the plus key
+        if parts.len() > 1 && parts.last() == Some(&"") {
+            parts.pop();
+            *parts.last_mut().unwrap() = "plus";
+        }
+        let name = parts.pop().unwrap_or_default();
+        let key = KEYS.iter()
+            .find(|key| key_name(**key).eq_ignore_ascii_case(name))
+            .ok_or_else(|| ShortcutError::UnknownKey(name.to_string()))?;
+        let mut modifiers = Modifiers::NONE;
+        for part in parts {
+            modifiers = modifiers | match part.to_lowercase().as_str() {
+                "ctrl" | "cmd" | "command" => Modifiers::COMMAND,
+                "alt" | "option" => Modifiers::ALT,
+                "shift" => Modifiers::SHIFT,
+                _ => return Err(ShortcutError::UnknownModifier(part.to_string())),
+            };
+        }
+        Ok(Shortcut::new(modifiers, *key))
+    }
+}
+
+/// The name of a key in shortcuts: F-keys as they're printed on the keyboard, and the
+/// other keys in lower case
+fn key_name(key: Key) -> String {
+    let name = key.name();
+    match name.len() > 1 && name.starts_with('F') && name[1..].chars().all(|c| c.is_ascii_digit()) {
+        true => name.to_string(),
+        false => name.to_lowercase(),
+    }
+}
+
+/// The keys that can be bound
+const KEYS: [Key; 73] = [
+    Key::ArrowDown, Key::ArrowLeft, Key::ArrowRight, Key::ArrowUp,
+    Key::Escape, Key::Tab, Key::Backspace, Key::Enter, Key::Space,
+    Key::Insert, Key::Delete, Key::Home, Key::End, Key::PageUp, Key::PageDown,
+    Key::Minus, Key::PlusEquals,
+    Key::Num0, Key::Num1, Key::Num2, Key::Num3, Key::Num4,
+    Key::Num5, Key::Num6, Key::Num7, Key::Num8, Key::Num9,
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I,
+    Key::J, Key::K, Key::L, Key::M, Key::N, Key::O, Key::P, Key::Q, Key::R,
+    Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7, Key::F8, Key::F9, Key::F10,
+    Key::F11, Key::F12, Key::F13, Key::F14, Key::F15, Key::F16, Key::F17, Key::F18, Key::F19, Key::F20,
+];
+
+/// The shortcuts of a command in the keymap file, which can be a single one
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Binding {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct KeymapFile {
+    bindings: BTreeMap<String, Binding>,
+}
+
+/// The shortcuts bound to each command
+#[derive(Clone, Debug)]
+pub struct Keymap {
+    bindings: BTreeMap<Command, Vec<Shortcut>>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let bindings = Command::ALL.iter()
+            .map(|command| (*command, command.default_shortcuts()))
+            .collect();
+        Self { bindings }
+    }
+}
+
+impl Keymap {
+    /// Load the keymap from a file. Commands that aren't in the file keep their default
+    /// shortcuts, and so do the commands with shortcuts that can't be parsed.
+    pub fn load(path: &Path) -> Self {
+        let mut keymap = Keymap::default();
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) => return keymap,
+        };
+        let file: KeymapFile = match toml::from_str(&contents) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("couldn't load keymap from {}: {}", path.display(), e);
+                return keymap;
+            },
+        };
+        for (name, binding) in file.bindings {
+            let command: Result<Command, serde::de::value::Error> = Command::deserialize(name.as_str().into_deserializer());
+            let Ok(command) = command else {
+                warn!("unknown command `{}` in {}", name, path.display());
+                continue;
+            };
+            let texts = match binding {
+                Binding::One(text) => vec![text],
+                Binding::Many(texts) => texts,
+            };
+            let shortcuts: Result<Vec<Shortcut>, ShortcutError> = texts.iter().map(|text| text.parse()).collect();
+            match shortcuts {
+                Ok(shortcuts) => {
+                    keymap.bindings.insert(command, shortcuts);
+                },
+                Err(e) => warn!("couldn't bind {} in {}: {}", command, path.display(), e),
+            }
+        }
+        info!("loaded keymap from {}", path.display());
+        keymap
+    }
+
+    /// Write the bindings that differ from the defaults to a file
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let bindings = self.bindings.iter()
+            .filter(|(command, shortcuts)| **shortcuts != command.default_shortcuts())
+            .filter_map(|(command, shortcuts)| {
+                let name = toml::Value::try_from(command).ok()?.as_str()?.to_string();
+                Some((name, Binding::Many(shortcuts.iter().map(|s| s.to_string()).collect())))
+            })
+            .collect();
+        match toml::to_string(&KeymapFile { bindings }) {
+            Ok(contents) => {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                fs::write(path, contents)?;
+                info!("saved keymap to {}", path.display());
+            },
+            Err(e) => warn!("couldn't save keymap to toml! {:?}", e),
+        }
+        Ok(())
+    }
+
+    pub fn get_shortcuts(&self, command: Command) -> &[Shortcut] {
+        self.bindings.get(&command).map_or(&[], |shortcuts| shortcuts.as_slice())
+    }
+
+    /// The first shortcut of a command, to show next to it in menus
+    pub fn get_shortcut_text(&self, command: Command) -> String {
+        self.get_shortcuts(command).first().map(|s| s.to_string()).unwrap_or_default()
+    }
+
+    /// Bind a command to a single shortcut, or to none
+    pub fn set_shortcut(&mut self, command: Command, shortcut: Option<Shortcut>) {
+        self.bindings.insert(command, shortcut.into_iter().collect());
+    }
+
+    /// Whether one of the shortcuts of a command was pressed. The key press is
+    /// consumed, so it doesn't also go to a text field.
+    pub fn consume(&self, input: &mut egui::InputState, command: Command) -> bool {
+        // every shortcut is consumed, so that two of them pressed at once don't leave one behind
+        let mut pressed = false;
+        for shortcut in self.get_shortcuts(command) {
+            pressed |= input.consume_key(shortcut.modifiers, shortcut.key);
+        }
+        pressed
+    }
+
+    /// The other commands that share a shortcut with a command, in a scope where both can run
+    pub fn get_conflicts(&self, command: Command) -> Vec<Command> {
+        let shortcuts = self.get_shortcuts(command);
+        self.bindings.iter()
+            .filter(|(other, _)| **other != command && other.get_scope().overlaps(command.get_scope()))
+            .filter(|(_, other_shortcuts)| other_shortcuts.iter().any(|s| shortcuts.contains(s)))
+            .map(|(other, _)| *other)
+            .collect()
+    }
+
+    /// Show the shortcuts of the commands, to change them. Clicking a shortcut starts
+    /// `recording` a new one for its command. Returns whether the keymap was changed.
+    pub fn display_editor(&mut self, ui: &mut egui::Ui, recording: &mut Option<Command>) -> bool {
+        let mut changed = false;
+        for scope in Scope::ALL {
+            ui.label(RichText::new(scope.to_string()).strong());
+            egui::Grid::new(("keymap_editor", scope as usize)).striped(true).show(ui, |ui| {
+                for command in Command::ALL.iter().copied().filter(|c| c.get_scope() == scope) {
+                    ui.label(command.to_string());
+                    let text = match *recording == Some(command) {
+                        true => "press a shortcut (escape cancels)".to_string(),
+                        false => {
+                            let shortcuts: Vec<String> = self.get_shortcuts(command).iter().map(|s| s.to_string()).collect();
+                            match shortcuts.is_empty() {
+                                true => "none".to_string(),
+                                false => shortcuts.join(", "),
+                            }
+                        },
+                    };
+                    if ui.button(RichText::new(text).monospace()).on_hover_text("click to change").clicked() {
+                        *recording = Some(command);
+                    }
+                    if ui.add_enabled(!self.get_shortcuts(command).is_empty(), egui::Button::new("clear")).clicked() {
+                        self.set_shortcut(command, None);
+                        changed = true;
+                    }
+                    let is_default = self.get_shortcuts(command) == command.default_shortcuts().as_slice();
+                    if ui.add_enabled(!is_default, egui::Button::new("reset")).clicked() {
+                        self.bindings.insert(command, command.default_shortcuts());
+                        changed = true;
+                    }
+                    let conflicts = self.get_conflicts(command);
+                    if !conflicts.is_empty() {
+                        let names: Vec<String> = conflicts.iter().map(|c| c.to_string()).collect();
+                        ui.colored_label(ui.visuals().error_fg_color, format!("also {}", names.join(", ")));
+                    }
+                    ui.end_row();
+                }
+            });
+        }
+        if ui.button("reset all").clicked() {
+            *self = Keymap::default();
+            changed = true;
+        }
+        changed
+    }
+}
+
+/// Take the shortcut pressed this frame, if any, out of the input. Nothing else sees
+/// the keys pressed while a shortcut is being recorded.
+pub fn take_shortcut(input: &mut egui::InputState) -> Option<Shortcut> {
+    let shortcut = input.events.iter().find_map(|event| match event {
+        egui::Event::Key { key, pressed: true, modifiers, .. } => Some(Shortcut::new(*modifiers, *key)),
+        _ => None,
+    });
+    input.events.retain(|event| !matches!(event, egui::Event::Key { .. } | egui::Event::Text(_)
+        | egui::Event::Copy | egui::Event::Cut | egui::Event::Paste(_)));
+    shortcut
+}
+
+/// Share the keymap with the parts of the app that run commands
+pub fn set_keymap(ctx: &egui::Context, keymap: &Keymap) {
+    ctx.data_mut(|data| data.insert_temp("keymap".into(), Arc::new(keymap.clone())));
+}
+
+/// The keymap of the app, or the default one if it wasn't shared yet
+pub fn get_keymap(ctx: &egui::Context) -> Arc<Keymap> {
+    ctx.data_mut(|data| data.get_temp("keymap".into())).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shortcut(text: &str) -> Shortcut {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn shortcuts_round_trip() {
+        let modifiers = [
+            Modifiers::NONE,
+            Modifiers::COMMAND,
+            Modifiers::SHIFT,
+            Modifiers::COMMAND | Modifiers::ALT | Modifiers::SHIFT,
+        ];
+        for key in KEYS {
+            for modifiers in modifiers {
+                let shortcut = Shortcut::new(modifiers, key);
+                assert_eq!(shortcut.to_string().parse::<Shortcut>().unwrap(), shortcut);
+            }
+        }
+        assert_eq!(Shortcut::new(Modifiers::COMMAND | Modifiers::SHIFT, Key::Z).to_string(), "ctrl+shift+z");
+        assert_eq!(Shortcut::new(Modifiers::SHIFT, Key::F12).to_string(), "shift+F12");
+        assert_eq!(Shortcut::new(Modifiers::COMMAND, Key::PlusEquals).to_string(), "ctrl+plus");
+    }
+
+    #[test]
+    fn shortcuts_parse() {
+        assert_eq!(shortcut("ctrl++"), Shortcut::new(Modifiers::COMMAND, Key::PlusEquals));
+        assert_eq!(shortcut("+"), Shortcut::new(Modifiers::NONE, Key::PlusEquals));
+        assert_eq!(shortcut("ctrl+shift+minus"), Shortcut::new(Modifiers::COMMAND | Modifiers::SHIFT, Key::Minus));
+        assert_eq!(shortcut("f5"), Shortcut::new(Modifiers::NONE, Key::F5));
+        assert_eq!(shortcut("Shift + F12"), Shortcut::new(Modifiers::SHIFT, Key::F12));
+        // the modifiers can come in any order, and ctrl, cmd and command are the same
+        assert_eq!(shortcut("shift+cmd+Z"), shortcut("ctrl+shift+z"));
+        assert_eq!(shortcut("command+option+a"), shortcut("ctrl+alt+a"));
+        assert_eq!(Shortcut::new(Modifiers::CTRL, Key::A), Shortcut::new(Modifiers::MAC_CMD, Key::A));
+    }
+
+    #[test]
+    fn bad_shortcuts_are_rejected() {
+        assert!(matches!("ctrl+nope".parse::<Shortcut>(), Err(ShortcutError::UnknownKey(key)) if key == "nope"));
+        assert!(matches!("hyper+z".parse::<Shortcut>(), Err(ShortcutError::UnknownModifier(m)) if m == "hyper"));
+        assert!(matches!("".parse::<Shortcut>(), Err(ShortcutError::UnknownKey(_))));
+        assert!(matches!("F21".parse::<Shortcut>(), Err(ShortcutError::UnknownKey(_))));
+        assert!(matches!("ctrl+a+b".parse::<Shortcut>(), Err(ShortcutError::UnknownModifier(_))));
+    }
+
+    #[test]
+    fn keymap_file_overrides_defaults() {
+        let path = std::env::temp_dir().join(format!("iron_coder_keymap_{}.toml", std::process::id()));
+        fs::write(&path, concat!(
+            "[bindings]\n",
+            "find = \"ctrl+g\"\n",
+            "redo = [\"ctrl+shift+z\", \"F4\"]\n",
+            "zoom_in = []\n",
+            "undo = \"ctrl+nope\"\n",
+            "quit = [\"ctrl+q\", \"hyper+q\"]\n",
+            "launch_rockets = \"ctrl+l\"\n",
+        )).unwrap();
+        let keymap = Keymap::load(&path);
+        let defaults = Keymap::default();
+        assert_eq!(keymap.get_shortcuts(Command::Find), [shortcut("ctrl+g")]);
+        assert_eq!(keymap.get_shortcuts(Command::Redo), [shortcut("ctrl+shift+z"), shortcut("F4")]);
+        assert_eq!(keymap.get_shortcuts(Command::ZoomIn), []);
+        // bindings that can't be parsed keep the defaults
+        assert_eq!(keymap.get_shortcuts(Command::Undo), defaults.get_shortcuts(Command::Undo));
+        assert_eq!(keymap.get_shortcuts(Command::Quit), defaults.get_shortcuts(Command::Quit));
+        assert_eq!(keymap.get_shortcuts(Command::Fold), defaults.get_shortcuts(Command::Fold));
+
+        // only the changed bindings are saved, and they load back the same
+        keymap.save(&path).unwrap();
+        let saved = fs::read_to_string(&path).unwrap();
+        assert!(saved.contains("find") && !saved.contains("undo") && !saved.contains("launch_rockets"));
+        let reloaded = Keymap::load(&path);
+        for command in Command::ALL {
+            assert_eq!(reloaded.get_shortcuts(command), keymap.get_shortcuts(command));
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn conflicts_are_found_across_scopes() {
+        let mut keymap = Keymap::default();
+        for command in Command::ALL {
+            assert_eq!(keymap.get_conflicts(command), [], "{} conflicts by default", command);
+        }
+        // a global shortcut conflicts with the commands of every scope
+        keymap.set_shortcut(Command::Find, Some(shortcut("ctrl+s")));
+        keymap.set_shortcut(Command::ZoomIn, Some(shortcut("ctrl+s")));
+        assert_eq!(keymap.get_conflicts(Command::SaveProject), [Command::Find, Command::ZoomIn]);
+        assert_eq!(keymap.get_conflicts(Command::Find), [Command::SaveProject]);
+        assert_eq!(keymap.get_conflicts(Command::ZoomIn), [Command::SaveProject]);
+        // the code editor and the system editor never run at the same time
+        keymap.set_shortcut(Command::SaveProject, None);
+        assert_eq!(keymap.get_conflicts(Command::Find), []);
+        // commands of the same scope do conflict
+        keymap.set_shortcut(Command::Undo, Some(shortcut("ctrl+s")));
+        assert_eq!(keymap.get_conflicts(Command::Find), [Command::Undo]);
+    }
+}
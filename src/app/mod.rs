@@ -4,6 +4,7 @@ use log::{error, warn, info};
 
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Instant;
 
 use clap::Parser;
 
@@ -31,6 +32,19 @@ pub mod colorscheme;
 use colorscheme::ColorScheme;
 
 pub mod code_editor;
+pub mod diff;
+pub mod edit_history;
+pub mod file_watcher;
+pub mod find_replace;
+pub mod folding;
+pub mod formatter;
+pub mod highlighting;
+pub mod keymap;
+use keymap::{Command, Keymap};
+pub mod recovery;
+use recovery::RecoveryFile;
+pub mod rope;
+pub mod rust_analyzer;
 
 /// Iron Coder CLI configuration options...
 #[derive(Parser, Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
@@ -45,6 +59,20 @@ pub struct IronCoderOptions {
     /// Turn app persistence on or off. Default is off.
     #[arg(short, long)]
     pub persistence: bool,
+    /// An alternative path to look for user configuration, such as custom themes.
+    #[arg(short, long)]
+    pub config_directory: Option<PathBuf>,
+}
+
+impl IronCoderOptions {
+    /// The directory with the user's configuration, which defaults to the
+    /// platform's config directory for Iron Coder
+    pub fn get_config_directory(&self) -> Option<PathBuf> {
+        self.config_directory.clone().or_else(|| {
+            directories_next::ProjectDirs::from("", "", "Iron Coder")
+                .map(|dirs| dirs.config_dir().to_path_buf())
+        })
+    }
 }
 
 /// The current GUI mode
@@ -55,6 +83,15 @@ pub enum Mode {
     DevelopProject,
 }
 
+/// Something the user asked for that would lose unsaved changes, waiting for them
+/// to decide what to do with the changes
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PendingAction {
+    NewProject,
+    OpenProject,
+    Quit,
+}
+
 // derive Deserialize/Serialize so we can persist app state on powercycle.
 #[derive(serde::Deserialize, serde::Serialize)]
 #[serde(default)] // if we add new fields, give them default values when deserializing old state
@@ -67,6 +104,26 @@ pub struct IronCoderApp {
     // modal: Option<Modal>,
     mode: Mode,
     colorscheme: ColorScheme,
+    show_line_numbers: bool,
+    format_on_save: bool,
+    #[serde(skip)]
+    colorschemes: Vec<ColorScheme>,
+    #[serde(skip)]
+    pending_action: Option<PendingAction>,
+    /// Set once the user confirmed quitting, so closing the window isn't stopped again
+    #[serde(skip)]
+    quit_confirmed: bool,
+    #[serde(skip)]
+    last_autosave: Option<Instant>,
+    /// The shortcuts of the commands, which are kept in the config directory
+    #[serde(skip)]
+    keymap: Keymap,
+    /// A command that the settings window is waiting for a new shortcut for
+    #[serde(skip)]
+    recording_shortcut: Option<Command>,
+    /// Unsaved changes from the last run, waiting for the user to restore or discard them
+    #[serde(skip)]
+    recoverable: Vec<RecoveryFile>,
     #[serde(skip)]
     boards: Vec<board::Board>,
     options: IronCoderOptions,
@@ -86,6 +143,15 @@ impl Default for IronCoderApp {
             mode: Mode::EditProject,
             boards: boards,
             colorscheme: colorscheme::INDUSTRIAL_DARK,
+            colorschemes: colorscheme::SYSTEM_COLORSCHEMES.to_vec(),
+            show_line_numbers: true,
+            format_on_save: false,
+            pending_action: None,
+            quit_confirmed: false,
+            last_autosave: None,
+            keymap: Keymap::default(),
+            recording_shortcut: None,
+            recoverable: Vec::new(),
             options: IronCoderOptions::default(),
         }
     }
@@ -106,13 +172,22 @@ impl IronCoderApp {
             }
         }
         app.options = options;
+        if let Some(config_dir) = app.options.get_config_directory() {
+            app.colorschemes.extend(colorscheme::load_colorschemes(&config_dir.join("themes")));
+            app.keymap = Keymap::load(&config_dir.join(keymap::KEYMAP_FILE_NAME));
+        }
+        keymap::set_keymap(&cc.egui_ctx, &app.keymap);
         info!("Reloading current project and assets...");
         app.set_colorscheme(&cc.egui_ctx);
         app.project.known_boards = app.boards.clone();
+        app.setup_code_editor();
         match app.project.reload() {
             Ok(_) => (),
             Err(e) => warn!("error reloading project from disk! {:?}", e),
         }
+        if let Some(config_dir) = app.options.get_config_directory() {
+            app.recoverable = recovery::load_recoverable(&config_dir.join("recovery"));
+        }
 
         return app;
     }
@@ -121,6 +196,18 @@ impl IronCoderApp {
         colorscheme::set_colorscheme(ctx, self.colorscheme.clone());
     }
 
+    /// Load the custom syntax themes into the code editor, and highlight code
+    /// with the theme of the current colorscheme
+    fn setup_code_editor(&mut self) {
+        if let Some(config_dir) = self.options.get_config_directory() {
+            self.project.code_editor.load_syntax_themes(&config_dir.join("themes"));
+            self.project.code_editor.set_recovery_directory(config_dir.join("recovery"));
+        }
+        self.project.code_editor.set_syntax_theme(self.colorscheme.get_syntax_theme());
+        self.project.code_editor.set_show_line_numbers(self.show_line_numbers);
+        self.project.code_editor.set_format_on_save(self.format_on_save);
+    }
+
     /// Show the menu and app title
     pub fn display_title_and_menu(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
         let Self {
@@ -128,12 +215,14 @@ impl IronCoderApp {
             display_settings,
             mode,
             project,
+            keymap,
             ..
         } = self;
         let icons_ref: Arc<IconSet> = ctx.data_mut(|data| {
             data.get_temp("icons".into()).expect("couldn't load icons!")
         });
         let icons = icons_ref.clone();
+        let mut action: Option<PendingAction> = None;
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             egui::menu::bar(ui, |ui| {
                 // Create a NOTHING Rect so we can track where the header is drawn
@@ -154,7 +243,7 @@ impl IronCoderApp {
                             icons.get("save_icon").unwrap().texture_id(ctx),
                             SMALL_ICON_SIZE,
                             "save project"
-                        ).shortcut_text("ctrl+s");
+                        ).shortcut_text(keymap.get_shortcut_text(Command::SaveProject));
                         if ui.add(ib).clicked() {
                             if let Err(e) = project.save() {
                                 error!("error saving project: {:?}", e);
@@ -174,33 +263,39 @@ impl IronCoderApp {
                             icons.get("folder_icon").unwrap().texture_id(ctx),
                             SMALL_ICON_SIZE,
                             "open"
-                        ).shortcut_text("ctrl+o");
+                        ).shortcut_text(keymap.get_shortcut_text(Command::OpenProject));
                         if ui.add(ib).clicked() {
-                            match project.open() {
-                                Ok(_) => {
-                                    *mode = Mode::DevelopProject;
-                                },
-                                Err(e) => {
-                                    error!("error opening project: {:?}", e);
-                                },
-                            }
+                            action = Some(PendingAction::OpenProject);
+                        }
+
+                        // undo and redo edits in the active editor tab
+                        let undo_text = match project.code_editor.get_undo_kind() {
+                            Some(kind) => format!("undo {}", kind),
+                            None => "undo".to_string(),
+                        };
+                        let undo_button = egui::Button::new(undo_text).shortcut_text(keymap.get_shortcut_text(Command::Undo));
+                        if ui.add_enabled(project.code_editor.get_undo_kind().is_some(), undo_button).clicked() {
+                            project.code_editor.undo();
+                        }
+                        let redo_text = match project.code_editor.get_redo_kind() {
+                            Some(kind) => format!("redo {}", kind),
+                            None => "redo".to_string(),
+                        };
+                        let redo_button = egui::Button::new(redo_text).shortcut_text(keymap.get_shortcut_text(Command::Redo));
+                        if ui.add_enabled(project.code_editor.get_redo_kind().is_some(), redo_button).clicked() {
+                            project.code_editor.redo();
                         }
                         
                         let ib = egui::widgets::Button::image_and_text(
                             icons.get("boards_icon").unwrap().texture_id(ctx),
                             SMALL_ICON_SIZE,
                             "new project"
-                        ).shortcut_text("ctrl+n");
+                        ).shortcut_text(keymap.get_shortcut_text(Command::NewProject));
                         if ui.add(ib).clicked() {
                             match mode {
                                 Mode::EditProject      => (),
                                 Mode::DevelopProject => {
-                                    // TODO -- add a popup here confirming that user
-                                    // wants to leave the current project, and probably save
-                                    // the project in it's current state.
-                                    *project = Project::default();
-                                    project.known_boards = self.boards.clone();
-                                    *mode = Mode::EditProject;
+                                    action = Some(PendingAction::NewProject);
                                 },
                             }
                         }
@@ -227,16 +322,187 @@ impl IronCoderApp {
                             icons.get("quit_icon").unwrap().texture_id(ctx),
                             SMALL_ICON_SIZE,
                             "quit"
-                        ).shortcut_text("ctrl+q");
+                        ).shortcut_text(keymap.get_shortcut_text(Command::Quit));
                         //.tint(egui::Color32::WHITE);
                         // TODO: set tint to the appropriate value for the current colorscheme
                         if ui.add(ib).clicked() {
-                            frame.close();
+                            action = Some(PendingAction::Quit);
                         };
                     });
                 });
             });
         });
+        if let Some(action) = action {
+            self.request_action(action, frame);
+        }
+    }
+
+    /// Run the global commands whose shortcuts were pressed, or give the command that the
+    /// settings window is waiting for the shortcut that was pressed
+    fn handle_shortcuts(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        if let Some(command) = self.recording_shortcut {
+            let Some(shortcut) = ctx.input_mut(keymap::take_shortcut) else {
+                return;
+            };
+            self.recording_shortcut = None;
+            // escape leaves the shortcut as it was
+            if shortcut != keymap::Shortcut::new(egui::Modifiers::NONE, egui::Key::Escape) {
+                self.keymap.set_shortcut(command, Some(shortcut));
+                self.keymap_changed(ctx);
+            }
+            return;
+        }
+        let keymap = &self.keymap;
+        let (save, open, new, quit) = ctx.input_mut(|input| (
+            keymap.consume(input, Command::SaveProject),
+            keymap.consume(input, Command::OpenProject),
+            keymap.consume(input, Command::NewProject),
+            keymap.consume(input, Command::Quit),
+        ));
+        if save {
+            if let Err(e) = self.project.save() {
+                error!("error saving project: {:?}", e);
+            }
+        }
+        if open {
+            self.request_action(PendingAction::OpenProject, frame);
+        }
+        // like the menu item, a new project can only be started from the code editor
+        if new && matches!(self.mode, Mode::DevelopProject) {
+            self.request_action(PendingAction::NewProject, frame);
+        }
+        if quit {
+            self.request_action(PendingAction::Quit, frame);
+        }
+    }
+
+    /// Save the keymap to the config directory, and share it with the rest of the app
+    fn keymap_changed(&self, ctx: &egui::Context) {
+        if let Some(config_dir) = self.options.get_config_directory() {
+            let path = config_dir.join(keymap::KEYMAP_FILE_NAME);
+            self.keymap.save(&path).unwrap_or_else(|e| {
+                warn!("couldn't save keymap to {}: {:?}", path.display(), e);
+            });
+        }
+        keymap::set_keymap(ctx, &self.keymap);
+    }
+
+    /// Carry out an action that would lose unsaved changes, or ask the user what to do
+    /// with the changes first
+    fn request_action(&mut self, action: PendingAction, frame: &mut eframe::Frame) {
+        match self.project.code_editor.has_unsaved_changes() {
+            true => self.pending_action = Some(action),
+            false => self.perform_action(action, frame),
+        }
+    }
+
+    fn perform_action(&mut self, action: PendingAction, frame: &mut eframe::Frame) {
+        // remember what was open in the project that is being left
+        self.project.save_state();
+        match action {
+            PendingAction::NewProject => {
+                self.project = Project::default();
+                self.project.known_boards = self.boards.clone();
+                self.mode = Mode::EditProject;
+                // the new project has a fresh code editor
+                self.setup_code_editor();
+            },
+            PendingAction::OpenProject => {
+                match self.project.open() {
+                    Ok(_) => {
+                        self.mode = Mode::DevelopProject;
+                        // the project may have been opened in a fresh code editor
+                        self.setup_code_editor();
+                    },
+                    Err(e) => {
+                        error!("error opening project: {:?}", e);
+                    },
+                }
+            },
+            PendingAction::Quit => {
+                self.quit_confirmed = true;
+                frame.close();
+            },
+        }
+    }
+
+    /// Ask whether to save the unsaved changes before an action that would lose them
+    fn display_unsaved_changes_window(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        let Some(action) = self.pending_action else {
+            return;
+        };
+        let mut proceed = false;
+        egui::Window::new("Unsaved Changes")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+        .show(ctx, |ui| {
+            ui.label("These files have unsaved changes:");
+            for path in self.project.code_editor.get_unsaved_files() {
+                ui.label(RichText::new(path.display().to_string()).monospace());
+            }
+            ui.horizontal(|ui| {
+                if ui.button("save all").clicked() {
+                    match self.project.code_editor.save_all() {
+                        Ok(_) => proceed = true,
+                        Err(e) => error!("error saving tabs: {:?}", e),
+                    }
+                }
+                if ui.button("discard").clicked() {
+                    self.project.code_editor.discard_unsaved_changes();
+                    proceed = true;
+                }
+                if ui.button("cancel").clicked() {
+                    self.pending_action = None;
+                }
+            });
+        });
+        if proceed {
+            self.pending_action = None;
+            self.perform_action(action, frame);
+        }
+    }
+
+    /// Offer to restore the changes that weren't saved when the app last closed
+    fn display_recovery_window(&mut self, ctx: &egui::Context) {
+        if self.recoverable.is_empty() {
+            return;
+        }
+        let mut choice = None;
+        egui::Window::new("Recover Unsaved Changes")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+        .show(ctx, |ui| {
+            ui.label("These files had changes that weren't saved when Iron Coder last closed:");
+            for file in self.recoverable.iter() {
+                let mut text = file.path.display().to_string();
+                if !file.path.exists() {
+                    text += " (deleted)";
+                }
+                ui.label(RichText::new(text).monospace());
+            }
+            ui.horizontal(|ui| {
+                if ui.button("restore").clicked() {
+                    choice = Some(true);
+                }
+                if ui.button("discard").clicked() {
+                    choice = Some(false);
+                }
+            });
+        });
+        let Some(restore) = choice else {
+            return;
+        };
+        for file in std::mem::take(&mut self.recoverable) {
+            if restore {
+                self.project.code_editor.restore(&file).unwrap_or_else(|e| {
+                    warn!("couldn't restore {}: {:?}", file.path.display(), e);
+                });
+            } else if let Some(config_dir) = self.options.get_config_directory() {
+                recovery::remove(&config_dir.join("recovery"), &file.path);
+            }
+        }
     }
 
     /// Returns a copy of the list of available boards.
@@ -264,13 +530,18 @@ impl IronCoderApp {
             egui::TopBottomPanel::bottom("terminal_panel").resizable(true).show(ctx, |ui| {
                 project.display_terminal(ctx, ui);
             });
+            if project.serial_monitor.visible {
+                egui::TopBottomPanel::bottom("serial_monitor_panel").resizable(true).show(ctx, |ui| {
+                    project.display_serial_monitor(ctx, ui);
+                });
+            }
             egui::TopBottomPanel::bottom("editor_control_panel").show(ctx, |ui| {
                 project.display_project_toolbar(ctx, ui);
             });
-            egui::TopBottomPanel::top("editor_tabs").show(ctx, |ui| {
-                project.code_editor.display_editor_tabs(ctx, ui);
+            egui::TopBottomPanel::top("editor_toolbar").show(ctx, |ui| {
+                project.code_editor.display_editor_toolbar(ctx, ui);
             });
-            let frame = egui::Frame::canvas(&ctx.style());
+            let frame = egui::Frame::canvas(&ctx.style()).fill(ctx.style().visuals.code_bg_color);
             egui::CentralPanel::default().frame(frame).show(ctx, |ui| {
                 project.code_editor.display_code(ctx, ui);
             });
@@ -294,9 +565,17 @@ impl IronCoderApp {
         let Self {
             display_settings,
             colorscheme,
+            colorschemes,
+            show_line_numbers,
+            format_on_save,
+            project,
+            options,
+            keymap,
+            recording_shortcut,
             ..
         } = self;
 
+        let mut keymap_changed = false;
         if *display_settings {
             let window_response = egui::Window::new("App Settings")
             .open(display_settings)
@@ -322,16 +601,67 @@ impl IronCoderApp {
                     Err(_e) => (),
                 }
 
+                if ui.checkbox(show_line_numbers, "show line numbers").changed() {
+                    project.code_editor.set_show_line_numbers(*show_line_numbers);
+                }
+                if ui.checkbox(format_on_save, "format Rust code on save").changed() {
+                    project.code_editor.set_format_on_save(*format_on_save);
+                }
+
                 // Create radio buttons for colorscheme selection
-                for cs in colorscheme::SYSTEM_COLORSCHEMES.iter() {
+                for cs in colorschemes.iter() {
                     // ui.radio_value(&mut colorscheme, colorscheme::SOLARIZED_DARK, cs.name);
-                    let rb = egui::RadioButton::new(*colorscheme == cs.clone(), cs.name.clone());
+                    let rb = egui::RadioButton::new(colorscheme.name == cs.name, cs.name.clone());
                     if ui.add(rb).clicked() {
                         *colorscheme = cs.clone();
                         colorscheme::set_colorscheme(ctx, cs.clone());
+                        project.code_editor.set_syntax_theme(colorscheme.get_syntax_theme());
                     }
                 }
-               
+
+                // Create a selector for the theme used to highlight code
+                let current_theme = colorscheme.get_syntax_theme().to_string();
+                egui::ComboBox::from_label("code theme")
+                .selected_text(&current_theme)
+                .show_ui(ui, |ui| {
+                    for theme in project.code_editor.get_syntax_themes() {
+                        if ui.selectable_label(theme == current_theme, &theme).clicked() {
+                            colorscheme.syntax_theme = theme.into();
+                        }
+                    }
+                });
+                project.code_editor.set_syntax_theme(colorscheme.get_syntax_theme());
+
+                // Create an editor for the colors of the current colorscheme, which
+                // can be exported to the themes directory
+                egui::CollapsingHeader::new("theme editor").show(ui, |ui| {
+                    let mut name = colorscheme.name.to_string();
+                    if ui.text_edit_singleline(&mut name).changed() {
+                        colorscheme.name = name.into();
+                    }
+                    if colorscheme.display_editor(ui) {
+                        colorscheme::set_colorscheme(ctx, colorscheme.clone());
+                    }
+                    let themes_dir = options.get_config_directory().map(|dir| dir.join("themes"));
+                    let export_button = egui::Button::new("export");
+                    if ui.add_enabled(themes_dir.is_some(), export_button).clicked() {
+                        match colorscheme.export(&themes_dir.unwrap()) {
+                            Ok(_) => {
+                                // replace any scheme with the same name in the list
+                                colorschemes.retain(|cs| cs.name != colorscheme.name);
+                                colorschemes.push(colorscheme.clone());
+                            },
+                            Err(e) => warn!("couldn't export colorscheme: {:?}", e),
+                        }
+                    }
+                });
+
+                // Create an editor for the keyboard shortcuts, which warns about
+                // shortcuts that are bound to more than one command
+                egui::CollapsingHeader::new("keyboard shortcuts").show(ui, |ui| {
+                    keymap_changed = keymap.display_editor(ui, recording_shortcut);
+                });
+
                 // create a font selector:
                 for (text_style, font_id) in ctx.style().text_styles.iter() {
                     match text_style {
@@ -362,8 +692,12 @@ impl IronCoderApp {
             // unwrap ok here because window must be open for us to get here.
             // ctx.move_to_top(window_response.unwrap().response.layer_id);
             window_response.unwrap().response.layer_id.order = egui::Order::Foreground;
+        } else {
+            *recording_shortcut = None;
+        }
+        if keymap_changed {
+            self.keymap_changed(ctx);
         }
-        
     }
 
     /// This method will show or hide the "about" window
@@ -422,6 +756,8 @@ impl eframe::App for IronCoderApp {
     //   self in each of these method calls separately, vs once in the beginning of this
     //   method? But I can't do it the latter way while still having these as method calls.
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        // the global shortcuts go before the views get to the keys
+        self.handle_shortcuts(ctx, frame);
         // render the title bar with main menu
         self.display_title_and_menu(ctx, frame);
         // depending on the Mode, render the proper main view
@@ -436,6 +772,29 @@ impl eframe::App for IronCoderApp {
         // optionally render these popup windows
         self.display_settings_window(ctx);
         self.display_about_window(ctx);
+        self.display_unsaved_changes_window(ctx, frame);
+        self.display_recovery_window(ctx);
+        // keep copies of the unsaved tabs, in case the app crashes
+        let autosave_due = self.last_autosave.is_none_or(|t| t.elapsed() >= recovery::AUTOSAVE_INTERVAL);
+        if autosave_due {
+            self.project.code_editor.autosave();
+            self.project.save_state();
+            self.last_autosave = Some(Instant::now());
+        }
+    }
+
+    // Called when the window is about to close. Closing is held off while there
+    // are unsaved changes, until the user decides what to do with them.
+    fn on_close_event(&mut self) -> bool {
+        if self.quit_confirmed || !self.project.code_editor.has_unsaved_changes() {
+            return true;
+        }
+        self.pending_action = Some(PendingAction::Quit);
+        false
+    }
+
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        self.project.save_state();
     }
 }
 
@@ -0,0 +1,149 @@
+//! This module keeps copies of unsaved editor buffers in a recovery directory, so
+//! that edits survive a crash. Each buffer is written to its own TOML file (named
+//! after an FNV-1a hash of the buffer's path, which stays the same between builds
+//! of Iron Coder), which is removed again once the buffer is
+//! saved or its changes are discarded.
+
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How often the unsaved buffers are written to the recovery directory
+pub const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// An unsaved buffer, and the file it belongs to
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecoveryFile {
+    pub path: PathBuf,
+    pub code: String,
+}
+
+/// The recovery file of the buffer of `path`
+fn recovery_path(dir: &Path, path: &Path) -> PathBuf {
+    let hash = path.as_os_str().as_encoded_bytes().iter()
+        .fold(0xcbf29ce484222325_u64, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3));
+    dir.join(format!("{:016x}", hash)).with_extension("toml")
+}
+
+/// Write the unsaved code of the file at `path` to the recovery directory
+pub fn save(dir: &Path, path: &Path, code: &str) {
+    let file = RecoveryFile {
+        path: path.to_path_buf(),
+        code: code.to_string(),
+    };
+    let result = fs::create_dir_all(dir).map_err(|e| e.to_string())
+        .and_then(|_| toml::to_string(&file).map_err(|e| e.to_string()))
+        .and_then(|contents| fs::write(recovery_path(dir, path), contents).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        warn!("couldn't write recovery file for {}: {}", path.display(), e);
+    }
+}
+
+/// Remove the recovery file of the file at `path`, if there is one
+pub fn remove(dir: &Path, path: &Path) {
+    let recovery_path = recovery_path(dir, path);
+    if recovery_path.exists() {
+        if let Err(e) = fs::remove_file(&recovery_path) {
+            warn!("couldn't remove recovery file {}: {:?}", recovery_path.display(), e);
+        }
+    }
+}
+
+/// Load the recovery files that were written after their file was last saved, or
+/// whose file no longer exists. The others are out of date, and are removed.
+pub fn load_recoverable(dir: &Path) -> Vec<RecoveryFile> {
+    let mut recoverable = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return recoverable,
+    };
+    for entry in entries.flatten() {
+        let recovery_path = entry.path();
+        if recovery_path.extension().is_none_or(|ext| ext != "toml") {
+            continue;
+        }
+        let file = fs::read_to_string(&recovery_path).map_err(|e| e.to_string())
+            .and_then(|contents| toml::from_str::<RecoveryFile>(&contents).map_err(|e| e.to_string()));
+        let file = match file {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("couldn't read recovery file {}: {}", recovery_path.display(), e);
+                continue;
+            },
+        };
+        let recovered_at = entry.metadata().and_then(|m| m.modified()).ok();
+        let saved_at = fs::metadata(&file.path).and_then(|m| m.modified()).ok();
+        let unsaved = match (recovered_at, saved_at) {
+            (Some(recovered_at), Some(saved_at)) => recovered_at > saved_at,
+            // the buffer is all that is left of a file that was deleted
+            (_, None) => true,
+            (None, Some(_)) => false,
+        };
+        if unsaved {
+            info!("found unsaved changes to {}", file.path.display());
+            recoverable.push(file);
+        } else if let Err(e) = fs::remove_file(&recovery_path) {
+            warn!("couldn't remove recovery file {}: {:?}", recovery_path.display(), e);
+        }
+    }
+    recoverable.sort_by(|a, b| a.path.cmp(&b.path));
+    recoverable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    /// An empty directory for a test to write to
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("iron_coder_recovery_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn recovery_path_is_stable() {
+        let dir = Path::new("/recovery");
+        assert_eq!(recovery_path(dir, Path::new("")), dir.join("cbf29ce484222325.toml"));
+        assert_eq!(recovery_path(dir, Path::new("a")), dir.join("af63dc4c8601ec8c.toml"));
+        assert_ne!(recovery_path(dir, Path::new("/src/main.rs")), recovery_path(dir, Path::new("/src/lib.rs")));
+    }
+
+    #[test]
+    fn buffers_of_deleted_files_are_recoverable() {
+        let dir = test_dir("deleted");
+        let path = dir.join("deleted.rs");
+        save(&dir.join("recovery"), &path, "fn main() {}");
+        let recoverable = load_recoverable(&dir.join("recovery"));
+        assert_eq!(recoverable.len(), 1);
+        assert_eq!(recoverable[0].path, path);
+        assert_eq!(recoverable[0].code, "fn main() {}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn outdated_buffers_are_removed() {
+        let dir = test_dir("outdated");
+        let recovery_dir = dir.join("recovery");
+        let (saved, unsaved) = (dir.join("saved.rs"), dir.join("unsaved.rs"));
+        save(&recovery_dir, &saved, "old");
+        save(&recovery_dir, &unsaved, "new");
+        // the first file was saved after its recovery file was written, the second before
+        let now = SystemTime::now();
+        for (path, modified) in [(&saved, now + Duration::from_secs(60)), (&unsaved, now - Duration::from_secs(60))] {
+            fs::write(path, "").unwrap();
+            fs::File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+        }
+        let recoverable = load_recoverable(&recovery_dir);
+        assert_eq!(recoverable.len(), 1);
+        assert_eq!(recoverable[0].path, unsaved);
+        assert!(!recovery_path(&recovery_dir, &saved).exists());
+        assert!(recovery_path(&recovery_dir, &unsaved).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
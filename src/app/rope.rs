@@ -0,0 +1,513 @@
+//! This module contains the text buffer of the code editor tabs: a rope, which keeps
+//! the text in chunks of whole lines. An edit only rebuilds the chunks it touches, and
+//! each chunk knows its length in bytes, chars and lines, so converting between
+//! offsets and lines walks the (short) list of chunks rather than the text itself.
+//!
+//! Every edit gives the rope a new revision, and the first line that each of the last
+//! edits changed is kept in a log. Whatever is derived from the text (its highlighting,
+//! search matches, outline) keeps the revision it was derived from, and can tell from
+//! the log what is out of date without comparing any text.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The size chunks are filled up to, in bytes. A single longer line gets a chunk of its own.
+const CHUNK_SIZE: usize = 4096;
+
+/// Chunks left smaller than this by an edit are merged with the following chunk
+const MIN_CHUNK_SIZE: usize = CHUNK_SIZE / 4;
+
+/// The number of edits kept in the change log
+const CHANGE_LOG_SIZE: usize = 256;
+
+/// Revisions are unique across all ropes, so that a revision identifies a text
+static NEXT_REVISION: AtomicU64 = AtomicU64::new(1);
+
+fn next_revision() -> u64 {
+    NEXT_REVISION.fetch_add(1, Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone)]
+struct Chunk {
+    text: String,
+    chars: usize,
+    newlines: usize,
+}
+
+impl Chunk {
+    fn new(text: String) -> Self {
+        let chars = text.chars().count();
+        let newlines = text.bytes().filter(|b| *b == b'\n').count();
+        Self { text, chars, newlines }
+    }
+}
+
+/// Where a chunk is, and where it starts in the text
+#[derive(Debug, Clone, Copy, Default)]
+struct ChunkStart {
+    index: usize,
+    byte: usize,
+    char: usize,
+    line: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Rope {
+    /// Every chunk but the last ends with a line break, so lines never span chunks.
+    /// There is always at least one chunk.
+    chunks: Vec<Chunk>,
+    len: usize,
+    chars: usize,
+    newlines: usize,
+    revision: u64,
+    /// The revision before the oldest logged edit
+    log_start: u64,
+    /// The revision after each of the last edits, and the first line it changed
+    changes: VecDeque<(u64, usize)>,
+}
+
+impl Default for Rope {
+    fn default() -> Self {
+        Self::from("")
+    }
+}
+
+impl From<&str> for Rope {
+    fn from(text: &str) -> Self {
+        let mut chunks = split_chunks(text);
+        if chunks.is_empty() {
+            chunks.push(Chunk::new(String::new()));
+        }
+        let revision = next_revision();
+        Self {
+            len: text.len(),
+            chars: chunks.iter().map(|c| c.chars).sum(),
+            newlines: chunks.iter().map(|c| c.newlines).sum(),
+            chunks,
+            revision,
+            log_start: revision,
+            changes: VecDeque::new(),
+        }
+    }
+}
+
+impl fmt::Display for Rope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for chunk in self.chunks.iter() {
+            f.write_str(&chunk.text)?;
+        }
+        Ok(())
+    }
+}
+
+impl PartialEq<str> for Rope {
+    fn eq(&self, other: &str) -> bool {
+        if self.len != other.len() {
+            return false;
+        }
+        let mut offset = 0;
+        self.chunks.iter().all(|chunk| {
+            let end = offset + chunk.text.len();
+            let equal = other.as_bytes()[offset..end] == *chunk.text.as_bytes();
+            offset = end;
+            equal
+        })
+    }
+}
+
+impl Rope {
+
+    /// The length of the text in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The length of the text in chars
+    pub fn len_chars(&self) -> usize {
+        self.chars
+    }
+
+    /// The number of lines, counting the (possibly empty) line after the last line break
+    pub fn len_lines(&self) -> usize {
+        self.newlines + 1
+    }
+
+    /// The revision of the text, which changes with every edit
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// The first line that changed since the text was at `revision`, or None if it
+    /// hasn't changed. Line 0 is returned when the log doesn't go back that far.
+    pub fn changed_since(&self, revision: u64) -> Option<usize> {
+        if revision == self.revision {
+            return None;
+        }
+        let known = revision == self.log_start || self.changes.iter().any(|(r, _)| *r == revision);
+        if !known {
+            return Some(0);
+        }
+        self.changes.iter()
+            .filter(|(r, _)| *r > revision)
+            .map(|(_, line)| *line)
+            .min()
+    }
+
+    /// The lines from `line` on, each with its line break
+    pub fn lines_at(&self, line: usize) -> impl Iterator<Item = &str> {
+        let start = self.chunk_at_line(line);
+        self.chunks[start.index..].iter()
+            .flat_map(|chunk| chunk.text.split_inclusive('\n'))
+            .skip(line.saturating_sub(start.line))
+    }
+
+    /// The text in a byte range
+    pub fn slice(&self, range: Range<usize>) -> String {
+        let mut text = String::with_capacity(range.len());
+        let first = self.chunk_at_byte(range.start);
+        let mut offset = first.byte;
+        for chunk in self.chunks[first.index..].iter() {
+            if offset >= range.end {
+                break;
+            }
+            let start = range.start.saturating_sub(offset);
+            let end = (range.end - offset).min(chunk.text.len());
+            text.push_str(&chunk.text[start..end]);
+            offset += chunk.text.len();
+        }
+        text
+    }
+
+    /// The byte offset of the start of a line, or the end of the text for a line past it
+    pub fn line_to_byte(&self, line: usize) -> usize {
+        if line >= self.len_lines() {
+            return self.len;
+        }
+        let start = self.chunk_at_line(line);
+        let within = match line - start.line {
+            0 => 0,
+            n => self.chunks[start.index].text
+                .match_indices('\n')
+                .nth(n - 1)
+                .map_or(0, |(i, _)| i + 1),
+        };
+        start.byte + within
+    }
+
+    /// The line that a byte offset is on
+    pub fn byte_to_line(&self, byte: usize) -> usize {
+        let start = self.chunk_at_byte(byte);
+        let chunk = &self.chunks[start.index].text;
+        start.line + chunk[..(byte - start.byte)].bytes().filter(|b| *b == b'\n').count()
+    }
+
+    /// Convert a char index into a byte offset
+    pub fn char_to_byte(&self, char_idx: usize) -> usize {
+        let start = self.find_chunk(|start, chunk| char_idx < start.char + chunk.chars);
+        let chunk = &self.chunks[start.index].text;
+        let within = chunk.char_indices()
+            .nth(char_idx.saturating_sub(start.char))
+            .map_or(chunk.len(), |(i, _)| i);
+        start.byte + within
+    }
+
+    /// Convert a byte offset into a char index
+    pub fn byte_to_char(&self, byte: usize) -> usize {
+        let start = self.chunk_at_byte(byte);
+        let chunk = &self.chunks[start.index].text;
+        start.char + chunk[..(byte - start.byte)].chars().count()
+    }
+
+    /// The char index of the start of a line
+    pub fn line_to_char(&self, line: usize) -> usize {
+        self.byte_to_char(self.line_to_byte(line))
+    }
+
+    /// Replace a byte range of the text
+    pub fn replace(&mut self, range: Range<usize>, text: &str) {
+        assert!(range.start <= range.end && range.end <= self.len, "range out of bounds");
+        if range.is_empty() && text.is_empty() {
+            return;
+        }
+        let first = self.chunk_at_byte(range.start);
+        let changed_line = self.byte_to_line(range.start);
+        // the chunks the range touches are joined, edited and split up again. The
+        // range never ends at the end of a joined chunk (unless that is the end of the
+        // text), so the joined text still ends with a line break.
+        let mut last = self.chunk_at_byte(range.end).index;
+        let mut joined: String = self.chunks[first.index..=last].iter()
+            .map(|chunk| chunk.text.as_str())
+            .collect();
+        joined.replace_range((range.start - first.byte)..(range.end - first.byte), text);
+        while joined.len() < MIN_CHUNK_SIZE && last + 1 < self.chunks.len() {
+            last += 1;
+            joined.push_str(&self.chunks[last].text);
+        }
+        let removed = &self.chunks[first.index..=last];
+        self.chars -= removed.iter().map(|c| c.chars).sum::<usize>();
+        self.newlines -= removed.iter().map(|c| c.newlines).sum::<usize>();
+        let chunks = split_chunks(&joined);
+        self.chars += chunks.iter().map(|c| c.chars).sum::<usize>();
+        self.newlines += chunks.iter().map(|c| c.newlines).sum::<usize>();
+        self.len = self.len - range.len() + text.len();
+        self.chunks.splice(first.index..=last, chunks);
+        if self.chunks.is_empty() {
+            self.chunks.push(Chunk::new(String::new()));
+        }
+
+        self.revision = next_revision();
+        self.changes.push_back((self.revision, changed_line));
+        if self.changes.len() > CHANGE_LOG_SIZE {
+            if let Some((revision, _)) = self.changes.pop_front() {
+                self.log_start = revision;
+            }
+        }
+    }
+
+    /// Apply the change from `before` to `after`, which is the text at byte `offset`,
+    /// by replacing only the part in between their common start and end
+    pub fn replace_changed(&mut self, offset: usize, before: &str, after: &str) {
+        let prefix = common_prefix(before, after);
+        let suffix = common_suffix(&before[prefix..], &after[prefix..]);
+        let range = (offset + prefix)..(offset + before.len() - suffix);
+        self.replace(range, &after[prefix..(after.len() - suffix)]);
+    }
+
+    /// The chunk with the byte at `byte` in it, or the last chunk for the end of the text
+    fn chunk_at_byte(&self, byte: usize) -> ChunkStart {
+        self.find_chunk(|start, chunk| byte < start.byte + chunk.text.len())
+    }
+
+    /// The chunk that `line` starts in
+    fn chunk_at_line(&self, line: usize) -> ChunkStart {
+        self.find_chunk(|start, chunk| line < start.line + chunk.newlines)
+    }
+
+    /// The first chunk that `is_in` accepts, or the last chunk
+    fn find_chunk(&self, is_in: impl Fn(&ChunkStart, &Chunk) -> bool) -> ChunkStart {
+        let mut start = ChunkStart::default();
+        for (index, chunk) in self.chunks.iter().enumerate() {
+            start.index = index;
+            if index + 1 == self.chunks.len() || is_in(&start, chunk) {
+                break;
+            }
+            start.byte += chunk.text.len();
+            start.char += chunk.chars;
+            start.line += chunk.newlines;
+        }
+        start
+    }
+}
+
+/// Split text into chunks of whole lines
+fn split_chunks(text: &str) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    for line in text.split_inclusive('\n') {
+        if !current.is_empty() && current.len() + line.len() > CHUNK_SIZE {
+            chunks.push(Chunk::new(std::mem::take(&mut current)));
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        chunks.push(Chunk::new(current));
+    }
+    chunks
+}
+
+/// The length in bytes of the common start of two strings, on a char boundary
+pub fn common_prefix(a: &str, b: &str) -> usize {
+    a.char_indices()
+        .zip(b.chars())
+        .find(|((_, ca), cb)| ca != cb)
+        .map(|((i, _), _)| i)
+        .unwrap_or(a.len().min(b.len()))
+}
+
+/// The length in bytes of the common end of two strings, on a char boundary
+pub fn common_suffix(a: &str, b: &str) -> usize {
+    a.chars().rev()
+        .zip(b.chars().rev())
+        .take_while(|(ca, cb)| ca == cb)
+        .map(|(c, _)| c.len_utf8())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check every query of the rope against the same text in a String
+    fn check(rope: &Rope, model: &str) {
+        assert_eq!(rope.to_string(), model);
+        assert!(*rope == *model);
+        assert_eq!(rope.len(), model.len());
+        assert_eq!(rope.len_chars(), model.chars().count());
+        assert_eq!(rope.len_lines(), model.matches('\n').count() + 1);
+        assert!(rope.chunks[..rope.chunks.len() - 1].iter().all(|chunk| chunk.text.ends_with('\n')));
+
+        // the lines and offsets are sampled, as checking all of them would be slow
+        let line_starts: Vec<usize> = std::iter::once(0)
+            .chain(model.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        for (line, start) in line_starts.iter().enumerate() {
+            assert_eq!(rope.line_to_byte(line), *start);
+            if line % 50 == 0 || line + 1 == line_starts.len() {
+                assert_eq!(rope.line_to_char(line), model[..*start].chars().count());
+                assert!(rope.lines_at(line).take(60).eq(model[*start..].split_inclusive('\n').take(60)));
+            }
+        }
+        assert_eq!(rope.line_to_byte(line_starts.len()), model.len());
+        assert_eq!(rope.lines_at(line_starts.len()).count(), 0);
+
+        let boundaries: Vec<usize> = model.char_indices().map(|(i, _)| i).chain([model.len()]).collect();
+        let mut line = 0;
+        for (char_idx, byte) in boundaries.iter().enumerate() {
+            if char_idx % 13 == 0 {
+                assert_eq!(rope.char_to_byte(char_idx), *byte);
+                assert_eq!(rope.byte_to_char(*byte), char_idx);
+                assert_eq!(rope.byte_to_line(*byte), line);
+            }
+            if model[*byte..].starts_with('\n') {
+                line += 1;
+            }
+        }
+        let last = boundaries.len() - 1;
+        assert_eq!(rope.char_to_byte(last), model.len());
+        assert_eq!(rope.byte_to_line(model.len()), line_starts.len() - 1);
+        for (a, b) in [(0, last), (0, 0), (last, last), (last / 3, last / 2), (1.min(last), last)] {
+            assert_eq!(rope.slice(boundaries[a]..boundaries[b]), model[boundaries[a]..boundaries[b]]);
+        }
+    }
+
+    /// A line of text with some multibyte chars in it
+    fn line(n: usize) -> String {
+        format!("line {} é → 🦀 {}\n", n, "x".repeat(n % 50))
+    }
+
+    /// A pseudo-random number generator, so that the test runs the same every time
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((self.0 >> 33) as usize) % n.max(1)
+        }
+    }
+
+    /// A char boundary of the text near a random byte
+    fn boundary(text: &str, rng: &mut Lcg) -> usize {
+        let mut byte = rng.below(text.len() + 1);
+        while !text.is_char_boundary(byte) {
+            byte -= 1;
+        }
+        byte
+    }
+
+    #[test]
+    fn empty_rope() {
+        let rope = Rope::default();
+        check(&rope, "");
+        assert_eq!(rope.slice(0..0), "");
+        check(&Rope::from("\n"), "\n");
+        check(&Rope::from("no line break"), "no line break");
+    }
+
+    #[test]
+    fn edits_match_a_string() {
+        let mut model: String = (0..600).map(line).collect();
+        let mut rope = Rope::from(model.as_str());
+        assert!(rope.chunks.len() > 3);
+        check(&rope, &model);
+        let mut rng = Lcg(1);
+        for n in 0..300 {
+            let start = boundary(&model, &mut rng);
+            // mostly small edits, with some spanning several chunks
+            let len = match rng.below(8) {
+                0 => rng.below(3 * CHUNK_SIZE),
+                _ => rng.below(40),
+            };
+            let mut end = (start + len).min(model.len());
+            while !model.is_char_boundary(end) {
+                end += 1;
+            }
+            let text = match rng.below(20) {
+                // a line longer than a chunk
+                0 => "🦀".repeat(CHUNK_SIZE / 4 + 1) + "\n",
+                1 | 2 => (n..n + rng.below(80)).map(line).collect(),
+                3..=5 => String::new(),
+                6 | 7 => "\n".to_string(),
+                _ => line(n).chars().take(rng.below(16)).collect(),
+            };
+            let changed_line = model[..start].matches('\n').count();
+            let revision = rope.revision();
+            rope.replace(start..end, &text);
+            model.replace_range(start..end, &text);
+            if start != end || !text.is_empty() {
+                assert_eq!(rope.changed_since(revision), Some(changed_line));
+            }
+            if n % 10 == 0 {
+                check(&rope, &model);
+            }
+        }
+        check(&rope, &model);
+
+        // deleting everything leaves an empty text, and a trailing line break is a line
+        let len = model.len();
+        rope.replace(0..len, "");
+        check(&rope, "");
+        rope.replace(0..0, "a\nb\n");
+        check(&rope, "a\nb\n");
+        assert_eq!(rope.len_lines(), 3);
+    }
+
+    #[test]
+    fn replace_changed_edits_the_difference() {
+        let before = "fn main() {\n    let x = 1;\n    println!(\"{}\", x);\n}\n";
+        let after = "fn main() {\n    let y = 1;\n    println!(\"{}\", y);\n}\n";
+        let mut rope = Rope::from(format!("// é\n{}", before).as_str());
+        let revision = rope.revision();
+        rope.replace_changed("// é\n".len(), before, after);
+        check(&rope, &format!("// é\n{}", after));
+        assert_eq!(rope.changed_since(revision), Some(2));
+
+        // replacing text with itself is no edit at all
+        let revision = rope.revision();
+        rope.replace_changed("// é\n".len(), after, after);
+        assert_eq!(rope.changed_since(revision), None);
+
+        // the common start and end are found on char boundaries
+        let mut rope = Rope::from("aé");
+        rope.replace_changed(0, "aé", "aè");
+        check(&rope, "aè");
+        rope.replace_changed(0, "aè", "");
+        check(&rope, "");
+    }
+
+    #[test]
+    fn changed_since_falls_back_to_the_start() {
+        let mut rope = Rope::from((0..20).map(line).collect::<String>().as_str());
+        let first = rope.revision();
+        assert_eq!(rope.changed_since(first), None);
+        let at_line = |rope: &Rope, line: usize| rope.line_to_byte(line);
+        rope.replace(at_line(&rope, 5)..at_line(&rope, 5), "x");
+        let second = rope.revision();
+        rope.replace(at_line(&rope, 9)..at_line(&rope, 9), "y");
+        assert_eq!(rope.changed_since(first), Some(5));
+        assert_eq!(rope.changed_since(second), Some(9));
+        // a revision that isn't in the log is from another text
+        assert_eq!(rope.changed_since(Rope::from("other").revision()), Some(0));
+
+        for _ in 0..CHANGE_LOG_SIZE {
+            rope.replace(at_line(&rope, 15)..at_line(&rope, 15), "z");
+        }
+        let recent = rope.revision();
+        rope.replace(at_line(&rope, 12)..at_line(&rope, 12), "z");
+        // the log no longer goes back to the first edits
+        assert_eq!(rope.changed_since(first), Some(0));
+        assert_eq!(rope.changed_since(second), Some(0));
+        assert_eq!(rope.changed_since(recent), Some(12));
+    }
+}
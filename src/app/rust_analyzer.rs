@@ -0,0 +1,704 @@
+//! This module runs rust-analyzer in-process to provide the code editor with
+//! completions, hover info, go-to-definition and find-references. The project's
+//! Cargo workspace is loaded into an `AnalysisHost` on a background thread, which
+//! is then kept up to date with the contents of the editor's tabs. The queries made
+//! while typing (completions and hover info) run on a snapshot of the analysis on
+//! a background thread too, and an edit made in the meantime cancels them.
+
+use log::{info, warn};
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use ra_ap_ide::{
+    Analysis, AnalysisHost, AssistConfig, AssistKind, AssistResolveStrategy, Change,
+    CompletionConfig, DiagnosticsConfig, ExprFillDefaultMode, FileId, FilePosition, FileRange,
+    FileSystemEdit, HoverConfig, HoverDocFormat, SingleResolve, SourceChange, StructureNodeKind,
+    SymbolKind, TextRange, TextSize,
+};
+use ra_ap_ide_db::imports::insert_use::{ImportGranularity, InsertUseConfig, PrefixKind};
+use ra_ap_project_model::{CargoConfig, RustLibSource};
+use ra_ap_rust_analyzer::cli::load_cargo::{load_workspace_at, LoadCargoConfig, ProcMacroServerChoice};
+use ra_ap_vfs::{AbsPathBuf, AnchoredPathBuf, Vfs, VfsPath};
+
+/// The maximum number of completions to show
+const COMPLETION_LIMIT: usize = 100;
+
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum AnalysisError {
+    /// The workspace hasn't finished loading (or failed to load)
+    NotLoaded,
+    FileNotInWorkspace,
+    /// The query was cancelled by a concurrent change
+    Cancelled,
+    /// A refactoring couldn't be done, i.e. it doesn't apply at the cursor
+    RefactorError(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WorkspaceStatus {
+    Unloaded,
+    Loading,
+    Loaded,
+    Failed(String),
+}
+
+impl fmt::Display for WorkspaceStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WorkspaceStatus::Unloaded  => write!(f, "no workspace"),
+            WorkspaceStatus::Loading   => write!(f, "loading workspace..."),
+            WorkspaceStatus::Loaded    => write!(f, "ready"),
+            WorkspaceStatus::Failed(_) => write!(f, "failed to load workspace"),
+        }
+    }
+}
+
+/// A completion that can be applied to the file it was requested in
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub label: String,
+    pub detail: Option<String>,
+    text_edit: ra_ap_ide::TextEdit,
+}
+
+impl Completion {
+    /// Apply the completion to `code`, returning the byte offset the cursor should
+    /// be moved to (the end of the inserted text).
+    pub fn apply(&self, code: &mut String, cursor: usize) -> usize {
+        let mut new_cursor = cursor;
+        let mut delta: isize = 0;
+        for indel in self.text_edit.iter() {
+            let start = usize::from(indel.delete.start());
+            let end = usize::from(indel.delete.end());
+            if start <= cursor && cursor <= end {
+                new_cursor = (start as isize + delta) as usize + indel.insert.len();
+            }
+            if end <= cursor {
+                delta += indel.insert.len() as isize - (end - start) as isize;
+            }
+        }
+        self.text_edit.apply(code);
+        new_cursor.min(code.len())
+    }
+}
+
+/// A place in the workspace, i.e. a definition or a reference
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub path: PathBuf,
+    /// The 1-based line number
+    pub line: usize,
+    /// The (trimmed) text of the line
+    pub preview: String,
+}
+
+/// An item in the structure of a file, i.e. a module, struct, impl or function
+#[derive(Debug, Clone)]
+pub struct OutlineItem {
+    pub label: String,
+    /// A short name for the kind of item, like `fn` or `struct`
+    pub kind: &'static str,
+    /// The signature of functions, or the type of fields and constants
+    pub detail: Option<String>,
+    /// The nesting depth of the item, where 0 is the top level
+    pub depth: usize,
+    /// The 1-based line number of the item's name
+    pub line: usize,
+}
+
+/// Compute the outline of a Rust source file. This only parses the file, so it
+/// doesn't need a loaded workspace.
+pub fn file_outline(code: &str) -> Vec<OutlineItem> {
+    let (analysis, file_id) = Analysis::from_single_file(code.to_string());
+    let nodes = match analysis.file_structure(file_id) {
+        Ok(nodes) => nodes,
+        Err(_) => return Vec::new(),
+    };
+    let mut depths: Vec<usize> = Vec::with_capacity(nodes.len());
+    let mut items = Vec::new();
+    for node in nodes.iter() {
+        // parents always come before their children
+        let depth = node.parent.map(|p| depths[p] + 1).unwrap_or(0);
+        depths.push(depth);
+        let kind = match node.kind {
+            StructureNodeKind::SymbolKind(kind) => match kind {
+                SymbolKind::Module    => "mod",
+                SymbolKind::Struct    => "struct",
+                SymbolKind::Enum      => "enum",
+                SymbolKind::Union     => "union",
+                SymbolKind::Trait     => "trait",
+                SymbolKind::Function  => "fn",
+                SymbolKind::Const     => "const",
+                SymbolKind::Static    => "static",
+                SymbolKind::TypeAlias => "type",
+                SymbolKind::Macro     => "macro",
+                SymbolKind::Field     => "field",
+                SymbolKind::Variant   => "variant",
+                // impl labels already start with `impl`
+                _ => "",
+            },
+            StructureNodeKind::Region => "region",
+        };
+        let offset = usize::from(node.navigation_range.start()).min(code.len());
+        items.push(OutlineItem {
+            label: node.label.clone(),
+            kind,
+            detail: node.detail.clone(),
+            depth,
+            line: code.as_bytes()[..offset].iter().filter(|b| **b == b'\n').count() + 1,
+        });
+    }
+    items
+}
+
+/// The byte ranges of a Rust source file that can be folded: items, blocks, runs of
+/// `use` items and comments, and the like. This only parses the file, and the parser
+/// recovers from errors, so code that is being typed still has its ranges.
+pub fn folding_ranges(code: &str) -> Vec<std::ops::Range<usize>> {
+    let (analysis, file_id) = Analysis::from_single_file(code.to_string());
+    match analysis.folding_ranges(file_id) {
+        Ok(folds) => folds.into_iter()
+            .map(|fold| usize::from(fold.range.start())..usize::from(fold.range.end()))
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// The assist-based refactorings offered in the editor's context menu
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Refactoring {
+    ExtractFunction,
+    ExtractVariable,
+    InlineVariable,
+    FillMatchArms,
+}
+
+impl Refactoring {
+    pub const ALL: [Refactoring; 4] = [
+        Refactoring::ExtractFunction,
+        Refactoring::ExtractVariable,
+        Refactoring::InlineVariable,
+        Refactoring::FillMatchArms,
+    ];
+
+    /// The id and kind of the rust-analyzer assist that does the refactoring
+    fn assist(&self) -> (&'static str, AssistKind) {
+        match self {
+            Refactoring::ExtractFunction => ("extract_function", AssistKind::RefactorExtract),
+            Refactoring::ExtractVariable => ("extract_variable", AssistKind::RefactorExtract),
+            Refactoring::InlineVariable  => ("inline_local_variable", AssistKind::RefactorInline),
+            Refactoring::FillMatchArms   => ("add_missing_match_arms", AssistKind::QuickFix),
+        }
+    }
+}
+
+impl fmt::Display for Refactoring {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Refactoring::ExtractFunction => write!(f, "extract function"),
+            Refactoring::ExtractVariable => write!(f, "extract variable"),
+            Refactoring::InlineVariable  => write!(f, "inline variable"),
+            Refactoring::FillMatchArms   => write!(f, "fill match arms"),
+        }
+    }
+}
+
+/// A single replaced span of a file, for previewing a SourceEdit
+#[derive(Debug, Clone)]
+pub struct EditPreview {
+    /// The 1-based line number that the span starts on
+    pub line: usize,
+    pub old: String,
+    pub new: String,
+}
+
+/// A change to the files of the workspace, ready to be previewed and applied
+#[derive(Debug, Clone)]
+pub struct SourceEdit {
+    pub title: String,
+    file_edits: Vec<(PathBuf, ra_ap_ide::TextEdit)>,
+    /// The replaced spans of each edited file
+    pub previews: Vec<(PathBuf, Vec<EditPreview>)>,
+    /// Files to create, with their contents
+    pub created_files: Vec<(PathBuf, String)>,
+    /// Files to move, as (from, to)
+    pub moved_files: Vec<(PathBuf, PathBuf)>,
+}
+
+impl SourceEdit {
+    /// The files whose contents are changed
+    pub fn edited_files(&self) -> impl Iterator<Item = &Path> {
+        self.file_edits.iter().map(|(path, _)| path.as_path())
+    }
+
+    /// Apply the edit for the file at `path` to its contents
+    pub fn apply_to(&self, path: &Path, code: &mut String) {
+        for (_, edit) in self.file_edits.iter().filter(|(p, _)| p == path) {
+            edit.apply(code);
+        }
+    }
+}
+
+fn insert_use_config() -> InsertUseConfig {
+    InsertUseConfig {
+        granularity: ImportGranularity::Crate,
+        enforce_granularity: false,
+        prefix_kind: PrefixKind::Plain,
+        group: true,
+        skip_glob_imports: true,
+    }
+}
+
+type LoadResult = Result<(AnalysisHost, Vfs), String>;
+
+/// The result of a query that runs in the background, to be polled with `try_recv`
+pub type Pending<T> = mpsc::Receiver<Result<T, AnalysisError>>;
+
+/// Run a query on a snapshot of the analysis on a background thread
+fn spawn_query<T, F>(analysis: Analysis, query: F) -> Pending<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Analysis) -> Result<T, AnalysisError> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        // the receiver is gone if the result isn't wanted anymore
+        let _ = tx.send(query(&analysis));
+    });
+    rx
+}
+
+/// The completions at a position, most relevant first
+fn completions_at(analysis: &Analysis, position: FilePosition) -> Result<Vec<Completion>, AnalysisError> {
+    let config = CompletionConfig {
+        enable_postfix_completions: true,
+        enable_imports_on_the_fly: false,
+        enable_self_on_the_fly: true,
+        enable_private_editable: false,
+        callable: None,
+        snippet_cap: None,
+        insert_use: insert_use_config(),
+        prefer_no_std: false,
+        snippets: Vec::new(),
+        limit: Some(COMPLETION_LIMIT),
+    };
+    let mut items = analysis.completions(&config, position, None)
+        .map_err(|_| AnalysisError::Cancelled)?
+        .unwrap_or_default();
+    items.sort_by(|a, b| {
+        b.relevance.score().cmp(&a.relevance.score()).then(a.label.cmp(&b.label))
+    });
+    Ok(items.into_iter().map(|item| Completion {
+        label: item.label.to_string(),
+        detail: item.detail,
+        text_edit: item.text_edit,
+    }).collect())
+}
+
+/// The type and documentation of the item at a position
+fn hover_at(analysis: &Analysis, position: FilePosition) -> Result<Option<String>, AnalysisError> {
+    let config = HoverConfig {
+        links_in_hover: false,
+        memory_layout: None,
+        documentation: true,
+        keywords: true,
+        format: HoverDocFormat::PlainText,
+    };
+    let range = FileRange {
+        file_id: position.file_id,
+        range: TextRange::empty(position.offset),
+    };
+    let hover = analysis.hover(&config, range).map_err(|_| AnalysisError::Cancelled)?;
+    Ok(hover.map(|h| h.info.markup.to_string()))
+}
+
+pub struct RustAnalyzer {
+    host: Option<AnalysisHost>,
+    vfs: Vfs,
+    root: Option<PathBuf>,
+    receiver: Option<mpsc::Receiver<LoadResult>>,
+    status: WorkspaceStatus,
+}
+
+impl Default for RustAnalyzer {
+    fn default() -> Self {
+        Self {
+            host: None,
+            vfs: Vfs::default(),
+            root: None,
+            receiver: None,
+            status: WorkspaceStatus::Unloaded,
+        }
+    }
+}
+
+impl RustAnalyzer {
+
+    /// Start loading the Cargo workspace at `root` on a background thread,
+    /// replacing any workspace that is currently loaded.
+    pub fn load_workspace(&mut self, root: &Path) {
+        self.host = None;
+        self.vfs = Vfs::default();
+        self.root = Some(root.to_path_buf());
+        if !root.join("Cargo.toml").exists() {
+            self.receiver = None;
+            self.status = WorkspaceStatus::Unloaded;
+            return;
+        }
+        info!("loading rust-analyzer workspace at {}", root.display());
+        let (tx, rx) = mpsc::channel();
+        let root = root.to_path_buf();
+        thread::spawn(move || {
+            let cargo_config = CargoConfig {
+                sysroot: Some(RustLibSource::Discover),
+                ..Default::default()
+            };
+            let load_config = LoadCargoConfig {
+                load_out_dirs_from_check: true,
+                with_proc_macro_server: ProcMacroServerChoice::Sysroot,
+                prefill_caches: true,
+            };
+            let result = load_workspace_at(&root, &cargo_config, &load_config, &|_| {})
+                .map(|(host, vfs, _proc_macro_server)| (host, vfs))
+                .map_err(|e| e.to_string());
+            // the receiver is gone if another workspace was loaded in the meantime
+            let _ = tx.send(result);
+        });
+        self.receiver = Some(rx);
+        self.status = WorkspaceStatus::Loading;
+    }
+
+    /// Reload the current workspace, i.e. after its dependencies changed
+    pub fn reload_workspace(&mut self) {
+        if let Some(root) = self.root.clone() {
+            self.load_workspace(&root);
+        }
+    }
+
+    /// Check on a workspace that is loading. Returns true if it just finished loading.
+    pub fn update(&mut self) -> bool {
+        let result = match self.receiver.as_ref().map(|rx| rx.try_recv()) {
+            Some(Ok(result)) => result,
+            Some(Err(mpsc::TryRecvError::Empty)) | None => return false,
+            Some(Err(mpsc::TryRecvError::Disconnected)) => {
+                Err("workspace loading thread panicked".to_string())
+            },
+        };
+        self.receiver = None;
+        match result {
+            Ok((host, vfs)) => {
+                info!("rust-analyzer workspace loaded");
+                self.host = Some(host);
+                self.vfs = vfs;
+                self.status = WorkspaceStatus::Loaded;
+                true
+            },
+            Err(e) => {
+                warn!("couldn't load rust-analyzer workspace: {}", e);
+                self.status = WorkspaceStatus::Failed(e);
+                false
+            },
+        }
+    }
+
+    pub fn get_status(&self) -> &WorkspaceStatus {
+        &self.status
+    }
+
+    fn vfs_path(path: &Path) -> Option<VfsPath> {
+        AbsPathBuf::try_from(path.to_path_buf()).ok().map(VfsPath::from)
+    }
+
+    fn file_id(&self, path: &Path) -> Result<FileId, AnalysisError> {
+        if self.host.is_none() {
+            return Err(AnalysisError::NotLoaded);
+        }
+        Self::vfs_path(path)
+            .and_then(|p| self.vfs.file_id(&p))
+            .ok_or(AnalysisError::FileNotInWorkspace)
+    }
+
+    /// Update the analysis with new contents of the file at `path`
+    pub fn set_file_contents(&mut self, path: &Path, text: &str) {
+        let file_id = match self.file_id(path) {
+            Ok(file_id) => file_id,
+            Err(_) => return,
+        };
+        let vfs_path = self.vfs.file_path(file_id);
+        self.vfs.set_file_contents(vfs_path, Some(text.as_bytes().to_vec()));
+        let mut change = Change::new();
+        change.change_file(file_id, Some(text.into()));
+        if let Some(host) = &mut self.host {
+            host.apply_change(change);
+        }
+    }
+
+    fn position(&self, path: &Path, offset: usize) -> Result<FilePosition, AnalysisError> {
+        Ok(FilePosition {
+            file_id: self.file_id(path)?,
+            offset: TextSize::from(offset as u32),
+        })
+    }
+
+    /// Turn a range in a file into a Location
+    fn location(&self, file_id: FileId, range: TextRange) -> Result<Location, AnalysisError> {
+        let analysis = self.host.as_ref().ok_or(AnalysisError::NotLoaded)?.analysis();
+        let line_index = analysis.file_line_index(file_id).map_err(|_| AnalysisError::Cancelled)?;
+        let line = line_index.line_col(range.start()).line as usize;
+        let path = self.vfs.file_path(file_id);
+        let path = path.as_path().ok_or(AnalysisError::FileNotInWorkspace)?;
+        let contents = String::from_utf8_lossy(self.vfs.file_contents(file_id));
+        let preview = contents.lines().nth(line).unwrap_or("").trim().to_string();
+        Ok(Location {
+            path: AsRef::<Path>::as_ref(path).to_path_buf(),
+            line: line + 1,
+            preview,
+        })
+    }
+
+    /// Start getting the completions at the byte `offset` of a file, most relevant first
+    pub fn completions(&self, path: &Path, offset: usize) -> Result<Pending<Vec<Completion>>, AnalysisError> {
+        let position = self.position(path, offset)?;
+        let analysis = self.host.as_ref().ok_or(AnalysisError::NotLoaded)?.analysis();
+        Ok(spawn_query(analysis, move |analysis| completions_at(analysis, position)))
+    }
+
+    /// Start getting the type and documentation of the item at the byte `offset` of a file
+    pub fn hover(&self, path: &Path, offset: usize) -> Result<Pending<Option<String>>, AnalysisError> {
+        let position = self.position(path, offset)?;
+        let analysis = self.host.as_ref().ok_or(AnalysisError::NotLoaded)?.analysis();
+        Ok(spawn_query(analysis, move |analysis| hover_at(analysis, position)))
+    }
+
+    /// Find the definition(s) of the item at the byte `offset` of a file
+    pub fn goto_definition(&self, path: &Path, offset: usize) -> Result<Vec<Location>, AnalysisError> {
+        let position = self.position(path, offset)?;
+        let analysis = self.host.as_ref().ok_or(AnalysisError::NotLoaded)?.analysis();
+        let targets = analysis.goto_definition(position)
+            .map_err(|_| AnalysisError::Cancelled)?
+            .map(|r| r.info)
+            .unwrap_or_default();
+        targets.iter()
+            .map(|nav| self.location(nav.file_id, nav.focus_or_full_range()))
+            .collect()
+    }
+
+    /// Find all references to the item at the byte `offset` of a file, including
+    /// its declaration.
+    pub fn find_references(&self, path: &Path, offset: usize) -> Result<Vec<Location>, AnalysisError> {
+        let position = self.position(path, offset)?;
+        let analysis = self.host.as_ref().ok_or(AnalysisError::NotLoaded)?.analysis();
+        let results = analysis.find_all_refs(position, None)
+            .map_err(|_| AnalysisError::Cancelled)?
+            .unwrap_or_default();
+        let mut locations = Vec::new();
+        for result in results {
+            if let Some(decl) = result.declaration {
+                locations.push(self.location(decl.nav.file_id, decl.nav.focus_or_full_range())?);
+            }
+            for (file_id, refs) in result.references {
+                for (range, _) in refs {
+                    locations.push(self.location(file_id, range)?);
+                }
+            }
+        }
+        locations.sort_by(|a, b| a.path.cmp(&b.path).then(a.line.cmp(&b.line)));
+        locations.dedup_by(|a, b| a.path == b.path && a.line == b.line);
+        Ok(locations)
+    }
+
+    fn real_path(&self, file_id: FileId) -> Result<PathBuf, AnalysisError> {
+        let path = self.vfs.file_path(file_id);
+        let path = path.as_path().ok_or(AnalysisError::FileNotInWorkspace)?;
+        Ok(AsRef::<Path>::as_ref(path).to_path_buf())
+    }
+
+    /// Resolve a path that is relative to the directory of an anchor file
+    fn anchored_path(&self, path: &AnchoredPathBuf) -> Result<PathBuf, AnalysisError> {
+        let anchor = self.real_path(path.anchor)?;
+        let dir = anchor.parent().ok_or(AnalysisError::FileNotInWorkspace)?;
+        Ok(dir.join(&path.path))
+    }
+
+    /// Convert a SourceChange from rust-analyzer into a SourceEdit
+    fn source_edit(&self, title: String, change: SourceChange) -> Result<SourceEdit, AnalysisError> {
+        let mut edit = SourceEdit {
+            title,
+            file_edits: Vec::new(),
+            previews: Vec::new(),
+            created_files: Vec::new(),
+            moved_files: Vec::new(),
+        };
+        for (file_id, text_edit) in change.source_file_edits {
+            let path = self.real_path(file_id)?;
+            let contents = String::from_utf8_lossy(self.vfs.file_contents(file_id)).to_string();
+            let previews = text_edit.iter().map(|indel| {
+                let start = usize::from(indel.delete.start()).min(contents.len());
+                let end = usize::from(indel.delete.end()).min(contents.len());
+                EditPreview {
+                    line: contents[..start].matches('\n').count() + 1,
+                    old: contents[start..end].to_string(),
+                    new: indel.insert.clone(),
+                }
+            }).collect();
+            edit.previews.push((path.clone(), previews));
+            edit.file_edits.push((path, text_edit));
+        }
+        edit.previews.sort_by(|a, b| a.0.cmp(&b.0));
+        for fs_edit in change.file_system_edits {
+            match fs_edit {
+                FileSystemEdit::CreateFile { dst, initial_contents } => {
+                    edit.created_files.push((self.anchored_path(&dst)?, initial_contents));
+                },
+                FileSystemEdit::MoveFile { src, dst } => {
+                    edit.moved_files.push((self.real_path(src)?, self.anchored_path(&dst)?));
+                },
+                FileSystemEdit::MoveDir { src, dst, .. } => {
+                    edit.moved_files.push((self.anchored_path(&src)?, self.anchored_path(&dst)?));
+                },
+            }
+        }
+        Ok(edit)
+    }
+
+    /// Rename the symbol at the byte `offset` of a file, everywhere it is used
+    pub fn rename(&self, path: &Path, offset: usize, new_name: &str) -> Result<SourceEdit, AnalysisError> {
+        let position = self.position(path, offset)?;
+        let analysis = self.host.as_ref().ok_or(AnalysisError::NotLoaded)?.analysis();
+        let change = analysis.rename(position, new_name)
+            .map_err(|_| AnalysisError::Cancelled)?
+            .map_err(|e| AnalysisError::RefactorError(e.to_string()))?;
+        self.source_edit(format!("rename to `{}`", new_name), change)
+    }
+
+    /// Do a refactoring on the selected byte range of a file
+    pub fn refactor(&self, path: &Path, range: std::ops::Range<usize>, refactoring: Refactoring) -> Result<SourceEdit, AnalysisError> {
+        let file_id = self.file_id(path)?;
+        let analysis = self.host.as_ref().ok_or(AnalysisError::NotLoaded)?.analysis();
+        let (assist_id, assist_kind) = refactoring.assist();
+        let assist_config = AssistConfig {
+            snippet_cap: None,
+            allowed: Some(vec![assist_kind]),
+            insert_use: insert_use_config(),
+            prefer_no_std: false,
+            assist_emit_must_use: false,
+        };
+        let diagnostics_config = DiagnosticsConfig {
+            proc_macros_enabled: true,
+            proc_attr_macros_enabled: true,
+            disable_experimental: true,
+            disabled: Default::default(),
+            expr_fill_default: ExprFillDefaultMode::Todo,
+            insert_use: insert_use_config(),
+            prefer_no_std: false,
+        };
+        let resolve = AssistResolveStrategy::Single(SingleResolve {
+            assist_id: assist_id.to_string(),
+            assist_kind,
+        });
+        let frange = FileRange {
+            file_id,
+            range: TextRange::new(TextSize::from(range.start as u32), TextSize::from(range.end as u32)),
+        };
+        let assists = analysis.assists_with_fixes(&assist_config, &diagnostics_config, resolve, frange)
+            .map_err(|_| AnalysisError::Cancelled)?;
+        let change = assists.into_iter()
+            .filter(|assist| assist.id.0 == assist_id)
+            .find_map(|assist| assist.source_change)
+            .ok_or_else(|| AnalysisError::RefactorError(format!("can't {} here", refactoring)))?;
+        self.source_edit(refactoring.to_string(), change)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SNIPPET: &str = "\
+mod geometry {
+    pub struct Point {
+        pub x: f32,
+    }
+
+    impl Point {
+        pub fn norm(&self) -> f32 {
+            let x = self.x;
+            x * x
+        }
+    }
+}
+
+const ORIGIN: f32 = 0.0;
+";
+
+    #[test]
+    fn outline_has_the_items_of_the_file() {
+        let outline: Vec<_> = file_outline(SNIPPET).into_iter()
+            .map(|item| (item.label, item.kind, item.depth, item.line))
+            .collect();
+        assert_eq!(outline, [
+            ("geometry".to_string(), "mod", 0, 1),
+            ("Point".to_string(), "struct", 1, 2),
+            ("x".to_string(), "field", 2, 3),
+            ("impl Point".to_string(), "", 1, 6),
+            ("norm".to_string(), "fn", 2, 7),
+            ("ORIGIN".to_string(), "const", 0, 14),
+        ]);
+        let norm = file_outline(SNIPPET).into_iter().find(|item| item.label == "norm").unwrap();
+        assert_eq!(norm.detail.as_deref(), Some("fn(&self) -> f32"));
+        assert!(file_outline("").is_empty());
+    }
+
+    /// Rename the local variable at a byte offset of a snippet, without a workspace
+    fn rename_local(code: &str, offset: usize, new_name: &str) -> SourceEdit {
+        let (analysis, file_id) = Analysis::from_single_file(code.to_string());
+        let position = FilePosition { file_id, offset: TextSize::from(offset as u32) };
+        let change = analysis.rename(position, new_name).unwrap().unwrap();
+        SourceEdit {
+            title: format!("rename to `{}`", new_name),
+            file_edits: change.source_file_edits.into_values()
+                .map(|edit| (PathBuf::from("lib.rs"), edit))
+                .collect(),
+            previews: Vec::new(),
+            created_files: Vec::new(),
+            moved_files: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn source_edit_is_applied_to_its_file() {
+        let offset = SNIPPET.find("let x").unwrap() + 4;
+        let edit = rename_local(SNIPPET, offset, "value");
+        assert_eq!(edit.edited_files().collect::<Vec<_>>(), [Path::new("lib.rs")]);
+        let mut code = SNIPPET.to_string();
+        edit.apply_to(Path::new("lib.rs"), &mut code);
+        let expected = SNIPPET.replace("let x = self.x;\n            x * x", "let value = self.x;\n            value * value");
+        assert_eq!(code, expected);
+        // the edits of other files leave the code be
+        let mut code = SNIPPET.to_string();
+        edit.apply_to(Path::new("main.rs"), &mut code);
+        assert_eq!(code, SNIPPET);
+    }
+
+    #[test]
+    fn completions_and_hover_without_a_workspace() {
+        let code = "struct S { field: u32 }\n/// Makes a number\nfn f(s: S) -> u32 { s.fi }\n";
+        let (analysis, file_id) = Analysis::from_single_file(code.to_string());
+        let offset = code.find("s.fi").unwrap() + 4;
+        let position = FilePosition { file_id, offset: TextSize::from(offset as u32) };
+        let completions = completions_at(&analysis, position).unwrap();
+        let field = completions.iter().find(|c| c.label == "field").unwrap();
+        let mut completed = code.to_string();
+        assert_eq!(field.apply(&mut completed, offset), offset + 3);
+        assert!(completed.contains("{ s.field }"));
+        let position = FilePosition { file_id, offset: TextSize::from(code.find("fn f").unwrap() as u32 + 3) };
+        let hover = hover_at(&analysis, position).unwrap().unwrap();
+        assert!(hover.contains("fn f(s: S) -> u32"), "{}", hover);
+        assert!(hover.contains("Makes a number"), "{}", hover);
+    }
+}
@@ -48,6 +48,28 @@ impl fmt::Display for BoardStandards {
     }
 }
 
+/// The ways that firmware can be loaded onto a main board
+#[non_exhaustive]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum FlashMethod {
+    /// Copy a UF2 file to the bootloader's mass storage drive (built in to Iron Coder)
+    UF2,
+    /// Use a debug probe, via the runner configured for `cargo run`
+    Probe,
+    /// Use `cargo espflash` for Espressif chips
+    Esptool,
+}
+
+impl fmt::Display for FlashMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FlashMethod::UF2 => write!(f, "UF2"),
+            FlashMethod::Probe => write!(f, "Probe"),
+            FlashMethod::Esptool => write!(f, "Esptool"),
+        }
+    }
+}
+
 /// The board struct defines a board type
 #[derive(Serialize, Deserialize, Clone, Default)]
 #[serde(default)]
@@ -61,8 +83,14 @@ pub struct Board {
     /// A possible form factor that the board adheres to
     standard: Option<BoardStandards>,
     cpu: Option<String>,
+    /// The amount of RAM, in kB
     ram: Option<isize>,
+    /// The amount of flash memory, in kB
     flash: Option<isize>,
+    /// The supported ways of loading firmware, in order of preference
+    flash_methods: Option<Vec<FlashMethod>>,
+    /// The UF2 family ID of the processor, required for the UF2 flash method
+    uf2_family_id: Option<u32>,
     /// A list of the interfaces available on the board
     pub pinout: Pinout,
     /// A list of the Syntax Nodes of the BSP calculated by Rust Analyzer
@@ -191,6 +219,25 @@ impl Board {
         return self.template_dir.clone();
     }
 
+    /// Returns the amount of RAM in kB, if the board manifest specifies it
+    pub fn get_ram(&self) -> Option<isize> {
+        self.ram
+    }
+
+    /// Returns the amount of flash memory in kB, if the board manifest specifies it
+    pub fn get_flash(&self) -> Option<isize> {
+        self.flash
+    }
+
+    /// Returns the supported flash methods, most preferred first
+    pub fn get_flash_methods(&self) -> Vec<FlashMethod> {
+        self.flash_methods.clone().unwrap_or_default()
+    }
+
+    pub fn get_uf2_family_id(&self) -> Option<u32> {
+        self.uf2_family_id
+    }
+
 }
 
 /// Iteratively gather the Boards from the filesystem.
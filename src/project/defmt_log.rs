@@ -0,0 +1,131 @@
+//! This module decodes `defmt` log frames received by the serial monitor. defmt frames
+//! only hold an index into a table of format strings, which lives in the `.defmt`
+//! section of the firmware ELF, so a decoder is built from the most recently built ELF.
+
+use log::{info, warn};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use defmt_decoder::{DecodeError, Encoding, Frame, Locations, Table};
+
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum DefmtError {
+    FilesystemError,
+    /// The ELF has no `.defmt` section, i.e. the firmware doesn't use defmt
+    NoDefmtTable,
+    TableParseError,
+}
+
+/// A decoded log frame
+#[derive(Debug, Clone)]
+pub struct DefmtFrame {
+    pub level: Option<&'static str>,
+    /// The timestamp reported by the device, if the firmware defines one
+    pub timestamp: Option<String>,
+    pub message: String,
+    /// The source file and line of the log statement, if debug info is available
+    pub location: Option<(PathBuf, usize)>,
+}
+
+pub struct DefmtDecoder {
+    table: Table,
+    locations: Option<Locations>,
+    elf_path: PathBuf,
+    /// Received bytes that don't yet make up a complete frame
+    buffer: Vec<u8>,
+}
+
+impl DefmtDecoder {
+
+    /// Load the defmt table (and source locations) from an ELF
+    pub fn from_elf(elf_path: &Path) -> Result<Self, DefmtError> {
+        let elf = fs::read(elf_path).map_err(|_| DefmtError::FilesystemError)?;
+        let table = match Table::parse(&elf) {
+            Ok(Some(table)) => table,
+            Ok(None) => return Err(DefmtError::NoDefmtTable),
+            Err(e) => {
+                warn!("error parsing defmt table from {:?}: {:?}", elf_path, e);
+                return Err(DefmtError::TableParseError);
+            },
+        };
+        let locations = match table.get_locations(&elf) {
+            Ok(locations) if !locations.is_empty() => Some(locations),
+            Ok(_) => None,
+            Err(e) => {
+                warn!("couldn't load defmt source locations: {:?}", e);
+                None
+            },
+        };
+        info!("loaded defmt table from {}", elf_path.display());
+        Ok(Self {
+            table,
+            locations,
+            elf_path: elf_path.to_path_buf(),
+            buffer: Vec::new(),
+        })
+    }
+
+    pub fn get_elf_path(&self) -> &Path {
+        self.elf_path.as_path()
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
+
+    /// Convert a frame into an owned DefmtFrame, looking up its source location
+    fn convert(frame: &Frame, locations: &Option<Locations>) -> DefmtFrame {
+        let location = locations.as_ref()
+            .and_then(|l| l.get(&frame.index()))
+            .map(|l| (l.file.clone(), l.line as usize));
+        DefmtFrame {
+            level: frame.level().map(|l| l.as_str()),
+            timestamp: frame.display_timestamp().map(|t| t.to_string()),
+            message: frame.display_message().to_string(),
+            location,
+        }
+    }
+
+    /// Feed received bytes to the decoder, returning any frames that were completed.
+    pub fn push_bytes(&mut self, data: &[u8]) -> Vec<DefmtFrame> {
+        self.buffer.extend_from_slice(data);
+        let mut frames = Vec::new();
+        match self.table.encoding() {
+            // rzcobs frames are delimited by zero bytes, so decode each complete frame
+            // with a fresh stream decoder.
+            Encoding::Rzcobs => {
+                while let Some(end) = self.buffer.iter().position(|b| *b == 0) {
+                    let raw_frame: Vec<u8> = self.buffer.drain(..=end).collect();
+                    let mut decoder = self.table.new_stream_decoder();
+                    decoder.received(&raw_frame);
+                    match decoder.decode() {
+                        Ok(frame) => frames.push(Self::convert(&frame, &self.locations)),
+                        Err(e) => warn!("discarding malformed defmt frame: {:?}", e),
+                    };
+                }
+            },
+            // raw frames have no delimiter, so the stream can't be resynchronized
+            // after an error.
+            _ => {
+                loop {
+                    let consumed = match self.table.decode(&self.buffer) {
+                        Ok((frame, consumed)) => {
+                            frames.push(Self::convert(&frame, &self.locations));
+                            consumed
+                        },
+                        Err(DecodeError::UnexpectedEof) => break,
+                        Err(DecodeError::Malformed) => {
+                            warn!("malformed defmt data, discarding buffer");
+                            self.buffer.len()
+                        },
+                    };
+                    self.buffer.drain(..consumed);
+                }
+            },
+        }
+        frames
+    }
+
+}
@@ -12,7 +12,13 @@ use egui::widgets::Button;
 use crate::board::Board;
 use crate::{project::Project, board};
 use crate::app::icons::IconSet;
+use crate::app::colorscheme;
 use crate::app::Mode;
+use crate::project::tasks::{TaskId, TaskStatus};
+use crate::project::LoggingOption;
+use crate::project::project_search::SearchAction;
+use crate::app::edit_history::EditKind;
+use crate::app::keymap::{self, Command};
 
 use serde::{Serialize, Deserialize};
 
@@ -22,6 +28,10 @@ pub enum ProjectViewType {
     BoardsView,
     FileTree,
     CrateView(String),
+    /// The structure of the file in the active editor tab
+    Outline,
+    /// Search (and replace) across the files of the project
+    Search,
 }
 
 // this block contains the display related
@@ -64,31 +74,103 @@ impl Project {
     }
 
     /// show the terminal pane
-    pub fn display_terminal(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
-        // If there is an open channel, see if we can get some data from it
-        if let Some(rx) = &self.receiver {
-            while let Ok(s) = rx.try_recv() {
-                self.terminal_buffer += s.as_str();
+    pub fn display_terminal(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
+        // Collect any new output from the background tasks and the serial port
+        self.update_tasks(ctx);
+        self.serial_monitor.update();
+        // if the selected task was removed, go back to showing all output
+        if let Some(id) = self.terminal_task {
+            if self.tasks.get(id).is_none() {
+                self.terminal_task = None;
             }
         }
         egui::CollapsingHeader::new("Terminal").show(ui, |ui| {
+            // select which output stream to show
+            ui.horizontal(|ui| {
+                if ui.selectable_label(self.terminal_task.is_none(), "all output").clicked() {
+                    self.terminal_task = None;
+                }
+                for task in self.tasks.tasks() {
+                    let selected = self.terminal_task == Some(task.get_id());
+                    if ui.selectable_label(selected, task.get_name()).clicked() {
+                        self.terminal_task = Some(task.get_id());
+                    }
+                }
+            });
+            let Project { tasks, terminal_task, terminal_buffer, .. } = self;
+            let buffer = match terminal_task.and_then(|id| tasks.get_mut(id)) {
+                Some(task) => &mut task.output,
+                None => terminal_buffer,
+            };
             egui::ScrollArea::both()
             .auto_shrink([false; 2])
             .stick_to_bottom(true)
             .show(ui, |ui| {
                 if ui.add(
-                    egui::TextEdit::multiline(&mut self.terminal_buffer)
+                    egui::TextEdit::multiline(buffer)
                     .code_editor()
                     .interactive(false)
                     .desired_width(f32::INFINITY)
                     .frame(false)
                 ).clicked() {
-                    self.terminal_buffer.clear();
+                    buffer.clear();
                 }
             });
         });
     }
 
+    /// Show the serial monitor, and open the source of any log statement that was clicked
+    pub fn display_serial_monitor(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
+        if let Some((file, line)) = self.serial_monitor.display(ctx, ui) {
+            // relative paths in the debug info are relative to the project directory
+            let path = match &self.location {
+                Some(location) if file.is_relative() => location.join(file),
+                _ => file,
+            };
+            self.code_editor.goto_line(&path, line).unwrap_or_else(|e| {
+                warn!("couldn't open {}: {:?}", path.display(), e);
+            });
+        }
+    }
+
+    /// Show the project search, and carry out whatever the user asked it to do
+    fn display_project_search(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
+        if self.location.is_none() {
+            ui.label("There is currently no folder associated with this project. Please save it somewhere.");
+            return;
+        }
+        let code_editor = &self.code_editor;
+        let open_code = |path: &Path| code_editor.get_open_code(path);
+        match self.search.display(ctx, ui) {
+            SearchAction::None => (),
+            SearchAction::Search => {
+                let roots = self.get_search_roots();
+                self.search.search(&roots, open_code);
+            },
+            SearchAction::Open(path, line) => {
+                self.code_editor.goto_line(&path, line).unwrap_or_else(|e| {
+                    warn!("couldn't open {}: {:?}", path.display(), e);
+                });
+            },
+            SearchAction::PreviewReplace => {
+                self.search.preview_replace(open_code);
+            },
+            SearchAction::Replace => {
+                let code_editor = &self.code_editor;
+                let replacements = self.search.take_replacements(|path| code_editor.get_open_code(path));
+                for (path, contents) in replacements {
+                    self.code_editor.replace_file_contents(&path, contents, EditKind::Replace).unwrap_or_else(|e| {
+                        warn!("couldn't replace in {}: {:?}", path.display(), e);
+                    });
+                }
+                // show what is left of the matches
+                let roots = self.get_search_roots();
+                let code_editor = &self.code_editor;
+                self.search.search(&roots, |path| code_editor.get_open_code(path));
+            },
+        }
+    }
+
     /// show the project tree in a Ui
     fn display_project_tree(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
         let project_folder = match &self.location {
@@ -132,6 +214,13 @@ impl Project {
 
             ui.separator();
             // GENERATE PROJECT TEMPLATE
+            egui::ComboBox::from_id_source("logging_option_selector")
+            .selected_text(format!("logging: {:?}", self.logging))
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut self.logging, LoggingOption::None, "None");
+                ui.selectable_value(&mut self.logging, LoggingOption::Defmt, "Defmt")
+                    .on_hover_text("defmt over a serial port (defmt-serial), which the serial monitor decodes");
+            }).response.on_hover_text("logging framework to add when generating the template");
             if ui.button("Gen Template").clicked() {
                 info!("generating project template");
                 match self.generate_cargo_template(ctx) {
@@ -165,13 +254,111 @@ impl Project {
                 }
             }
 
+            ui.separator();
+            // SERIAL MONITOR
+            let text = if self.serial_monitor.visible { "hide serial monitor" } else { "serial monitor" };
+            if ui.button(text).clicked() {
+                self.serial_monitor.visible = !self.serial_monitor.visible;
+            }
+
+            // FIRMWARE SIZE
+            self.display_size_report(ui);
+
+            // BACKGROUND TASK STATUS
+            self.display_task_status(ctx, ui, &icons);
+
         });
     }
 
+    /// Show the flash and RAM usage of the last build relative to the main board's
+    /// capacity. Hovering shows the section totals and the largest symbols.
+    fn display_size_report(&mut self, ui: &mut egui::Ui) {
+        let report = match &self.size_report {
+            Some(report) => report,
+            None => return,
+        };
+        let (flash_budget, ram_budget) = self.memory_budget();
+        let usage_text = |label: &str, used: u64, budget: Option<u64>| {
+            match budget {
+                Some(budget) => {
+                    let percent = 100.0 * used as f32 / budget as f32;
+                    let text = RichText::new(format!("{}: {:.1}/{} kB ({:.0}%)", label, used as f32 / 1024.0, budget / 1024, percent));
+                    if used > budget {
+                        text.color(egui::Color32::RED)
+                    } else {
+                        text
+                    }
+                },
+                None => RichText::new(format!("{}: {:.1} kB", label, used as f32 / 1024.0)),
+            }
+        };
+        ui.separator();
+        let resp = ui.label(usage_text("flash", report.flash_used(), flash_budget));
+        let resp = resp.union(ui.label(usage_text("ram", report.ram_used(), ram_budget)));
+        resp.on_hover_ui(|ui| {
+            ui.label(report.elf_path.display().to_string());
+            egui::Grid::new("size_report_sections").striped(true).show(ui, |ui| {
+                for (section, size) in [(".text", report.text), (".rodata", report.rodata), (".data", report.data), (".bss", report.bss)] {
+                    ui.label(section);
+                    ui.label(format!("{} B", size));
+                    ui.end_row();
+                }
+            });
+            ui.separator();
+            ui.label(RichText::new("Largest Symbols").underline());
+            egui::Grid::new("size_report_symbols").striped(true).show(ui, |ui| {
+                for symbol in report.largest_symbols.iter() {
+                    ui.label(format!("{:?}", symbol.category).to_lowercase());
+                    ui.label(format!("{} B", symbol.size));
+                    ui.label(symbol.name.as_str());
+                    ui.end_row();
+                }
+            });
+        });
+    }
+
+    /// Show the status of each background task, with a button to stop it if it's
+    /// still active, or to dismiss it otherwise.
+    fn display_task_status(&mut self, ctx: &egui::Context, ui: &mut egui::Ui, icons: &IconSet) {
+        let mut to_kill: Option<TaskId> = None;
+        let mut to_remove: Option<TaskId> = None;
+        for task in self.tasks.tasks() {
+            ui.separator();
+            let status = task.get_status();
+            let color = match status {
+                TaskStatus::Queued | TaskStatus::Running => egui::Color32::YELLOW,
+                TaskStatus::Finished => egui::Color32::GREEN,
+                TaskStatus::Failed(_) | TaskStatus::Killed => egui::Color32::RED,
+            };
+            let text = RichText::new(format!("{}: {}", task.get_name(), status)).color(color);
+            let label = egui::Label::new(text).sense(egui::Sense::click());
+            if ui.add(label).on_hover_text("show task output").clicked() {
+                self.terminal_task = Some(task.get_id());
+            }
+            let button = egui::widgets::ImageButton::new(
+                icons.get("quit_icon").unwrap().texture_id(ctx),
+                egui::Vec2::new(6.0, 6.0),
+            ).frame(true);
+            if status.is_active() {
+                if ui.add(button).on_hover_text("stop").clicked() {
+                    to_kill = Some(task.get_id());
+                }
+            } else if ui.add(button).on_hover_text("dismiss").clicked() {
+                to_remove = Some(task.get_id());
+            }
+        }
+        if let Some(id) = to_kill {
+            self.tasks.kill(id);
+        }
+        if let Some(id) = to_remove {
+            self.tasks.remove(id);
+        }
+    }
+
     /// In the provided Ui, create a multi-column layout (tabs) that switches the current view state.
     fn display_sidebar_tabs(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
         // show the tabs to switch between view modes
-        ui.columns(2, |columns| {
+        ui.columns(4, |columns| {
             let mut new_view: ProjectViewType;
             let button = Button::new("File Explorer").frame(false);
             if columns[0].add(button).clicked() {
@@ -184,6 +371,16 @@ impl Project {
                 new_view = ProjectViewType::BoardsView;
                 self.current_view = new_view;
             };
+            let button = Button::new("Outline").frame(false);
+            if columns[2].add(button).clicked() {
+                new_view = ProjectViewType::Outline;
+                self.current_view = new_view;
+            };
+            let button = Button::new("Search").frame(false);
+            if columns[3].add(button).clicked() {
+                new_view = ProjectViewType::Search;
+                self.current_view = new_view;
+            };
         });
     }
 
@@ -216,7 +413,7 @@ impl Project {
                                     if ui.link(rc).clicked() {
                                         if let Some(path) = &self.location {
                                             let cmd = duct::cmd!("cargo", "-Z", "unstable-options", "-C", path.as_path().to_str().unwrap(), "add", rc.as_str());
-                                            self.run_background_commands(&[cmd], ctx);
+                                            self.run_background_commands("add crate", &[cmd], ctx);
                                         } else {
                                             self.terminal_buffer += "save project first!\n";
                                         }
@@ -272,6 +469,12 @@ impl Project {
                     // show the project tree
                     self.display_project_tree(ctx, ui);
                 },
+                ProjectViewType::Outline => {
+                    self.code_editor.display_outline(ui);
+                },
+                ProjectViewType::Search => {
+                    self.display_project_search(ctx, ui);
+                },
             }
         });
     }
@@ -321,21 +524,33 @@ impl Project {
 
         let mut pin_locations: HashMap<(Board, String), egui::Pos2> = HashMap::new();
 
+        // the shortcuts of the system editor are left alone while a text field has the
+        // keyboard, so that typing i.e. a project name doesn't zoom
+        let keymap = keymap::get_keymap(ctx);
+        let (zoom_in, zoom_out, cancel_connection) = match ctx.memory(|mem| mem.focus().is_some()) {
+            true => (false, false, false),
+            false => ctx.input_mut(|io| (
+                keymap.consume(io, Command::ZoomIn),
+                keymap.consume(io, Command::ZoomOut),
+                keymap.consume(io, Command::CancelConnection),
+            )),
+        };
+        let scale_id = egui::Id::new("system_editor_scale_factor");
+        ctx.data_mut(|data| {
+            let scale = data.get_temp_mut_or(scale_id, 5.0_f32);
+            if zoom_in {
+                *scale += 0.5;
+            } else if zoom_out {
+                *scale = (*scale - 0.5).max(0.5);
+            }
+        });
+
         // iterate through the system boards and draw them on the screen
         for (board_idx, board) in self.system.get_all_boards().iter_mut().enumerate() {
 
-            let scale_id = egui::Id::new("system_editor_scale_factor");
-            // set the editor scale factor in memory:
-            let mut scale = ctx.data_mut(|data| {
-                data.get_temp_mut_or(scale_id, 5.0).clone()
-            });
-            if ctx.input(|io| io.key_pressed(egui::Key::Q)) {
-                scale += 0.5;
-            } else if ctx.input(|io| io.key_pressed(egui::Key::W)) {
-                scale -= 0.5;
-            }
-            ctx.data_mut(|data| {
-                data.insert_temp(scale_id, scale);
+            // get the editor scale factor from memory:
+            let scale = ctx.data_mut(|data| {
+                *data.get_temp_mut_or(scale_id, 5.0_f32)
             });
 
             // Get the response of the board/pin Ui
@@ -452,15 +667,9 @@ impl Project {
             
         } // for each Board
 
-        // check for any key presses that might end the current in-progress connection.
+        // the cancel shortcut ends the current in-progress connection.
         // be careful to avoid deadlocks in the ctx access closure!
-        if let Some(_) = ctx.input(|io| {
-            if io.key_pressed(egui::Key::Escape) {
-                return Some(());
-            } else {
-                return None;
-            }
-        }) {
+        if cancel_connection {
             ctx.data_mut(|data| {
                 let id = egui::Id::new("connection_in_progress");
                 data.remove::<egui::Pos2>(id);
@@ -475,11 +684,12 @@ impl Project {
             data.get_temp::<egui::Pos2>(id)
         }) {
             if let Some(ep) = ctx.pointer_latest_pos() {
-                draw_connection(ctx, ui, sp, ep, egui::Color32::GREEN);
+                draw_connection(ctx, ui, sp, ep, colorscheme::get_colorscheme(ctx).wire_in_progress);
             }
         }
 
         // go through the system connections and see if this pin is a part of any of them
+        let wire_color = colorscheme::get_colorscheme(ctx).wire;
         for connection in self.system.connections.iter() {
             // get the start and end pin locations. If they're not in the map (which they should be...), just skip
             let start_loc: egui::Pos2 = match pin_locations.get(&(connection.start_board.clone(), connection.start_pin.clone())) {
@@ -491,7 +701,7 @@ impl Project {
                 None => continue,
             };
 
-            let resp = draw_connection(ctx, ui, start_loc, end_loc, egui::Color32::RED);
+            let resp = draw_connection(ctx, ui, start_loc, end_loc, wire_color);
             resp.on_hover_ui(|ui| {
                 ui.label("connection");
                 ui.label(connection.start_board.get_name().to_string() + ":" + connection.start_pin.as_str());
@@ -0,0 +1,244 @@
+//! This module contains the built-in flashing backends of Iron Coder. For now this
+//! is an ELF to UF2 converter, along with a way to find and write to a mounted UF2
+//! bootloader drive (such as the one the RP2040 presents when BOOTSEL is held).
+
+use log::{info, warn};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use object::elf::PT_LOAD;
+use object::read::elf::{ElfFile32, ProgramHeader};
+use object::Endianness;
+
+const UF2_MAGIC_START0: u32 = 0x0A32_4655;
+const UF2_MAGIC_START1: u32 = 0x9E5D_5157;
+const UF2_MAGIC_END: u32 = 0x0AB1_6F30;
+/// Indicates that the file size field of a block holds a family ID
+const UF2_FLAG_FAMILY_ID_PRESENT: u32 = 0x0000_2000;
+/// The number of payload bytes per block. 256 is what all known bootloaders accept.
+const UF2_PAYLOAD_SIZE: usize = 256;
+const UF2_BLOCK_SIZE: usize = 512;
+/// The size of the data area of a block, of which the payload is the first part
+const UF2_DATA_SIZE: usize = 476;
+
+/// The file that a UF2 bootloader drive exposes in its root directory
+const UF2_INFO_FILE_NAME: &str = "INFO_UF2.TXT";
+/// The name of the file to write to the drive. Bootloaders accept any name ending in .uf2
+const UF2_OUTPUT_FILE_NAME: &str = "firmware.uf2";
+
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum FlashError {
+    ElfParseError,
+    /// The ELF has no loadable segments
+    EmptyImage,
+    /// The board manifest doesn't specify a UF2 family ID
+    NoFamilyId,
+    /// No mounted drive contains an INFO_UF2.TXT file
+    NoUf2Drive,
+    FilesystemError,
+}
+
+/// Convert the loadable segments of a 32-bit ELF into a UF2 image for the given family.
+/// Segments are placed at their physical (load) addresses, and any gaps within a
+/// 256-byte page are padded with zeros.
+pub fn elf_to_uf2(elf_data: &[u8], family_id: u32) -> Result<Vec<u8>, FlashError> {
+    let elf = ElfFile32::<Endianness>::parse(elf_data).map_err(|e| {
+        warn!("couldn't parse elf for uf2 conversion: {:?}", e);
+        FlashError::ElfParseError
+    })?;
+    let endian = elf.endian();
+
+    // gather the image into 256-byte pages, keyed by their start address
+    let mut pages: BTreeMap<u32, [u8; UF2_PAYLOAD_SIZE]> = BTreeMap::new();
+    for segment in elf.raw_segments().iter() {
+        if segment.p_type(endian) != PT_LOAD || segment.p_filesz(endian) == 0 {
+            continue;
+        }
+        let data = segment.data(endian, elf_data).map_err(|_| FlashError::ElfParseError)?;
+        let mut addr = segment.p_paddr(endian);
+        let mut remaining = data;
+        while !remaining.is_empty() {
+            let page_addr = addr & !(UF2_PAYLOAD_SIZE as u32 - 1);
+            let offset = (addr - page_addr) as usize;
+            let len = (UF2_PAYLOAD_SIZE - offset).min(remaining.len());
+            let page = pages.entry(page_addr).or_insert([0; UF2_PAYLOAD_SIZE]);
+            page[offset..offset + len].copy_from_slice(&remaining[..len]);
+            remaining = &remaining[len..];
+            addr += len as u32;
+        }
+    }
+    if pages.is_empty() {
+        return Err(FlashError::EmptyImage);
+    }
+
+    let num_blocks = pages.len() as u32;
+    let mut uf2 = Vec::with_capacity(pages.len() * UF2_BLOCK_SIZE);
+    for (block_no, (page_addr, payload)) in pages.iter().enumerate() {
+        let header = [
+            UF2_MAGIC_START0,
+            UF2_MAGIC_START1,
+            UF2_FLAG_FAMILY_ID_PRESENT,
+            *page_addr,
+            UF2_PAYLOAD_SIZE as u32,
+            block_no as u32,
+            num_blocks,
+            family_id,
+        ];
+        for word in header {
+            uf2.extend_from_slice(&word.to_le_bytes());
+        }
+        uf2.extend_from_slice(payload);
+        uf2.extend_from_slice(&[0; UF2_DATA_SIZE - UF2_PAYLOAD_SIZE]);
+        uf2.extend_from_slice(&UF2_MAGIC_END.to_le_bytes());
+    }
+    Ok(uf2)
+}
+
+/// The directories under which removable drives are typically mounted on this platform.
+pub fn default_mount_roots() -> Vec<PathBuf> {
+    let mut roots = Vec::new();
+    if cfg!(target_os = "windows") {
+        // each drive letter is itself a candidate drive, which find_uf2_drive
+        // checks before looking at its subdirectories.
+        for letter in b'A'..=b'Z' {
+            roots.push(PathBuf::from(format!("{}:\\", letter as char)));
+        }
+    } else if cfg!(target_os = "macos") {
+        roots.push(PathBuf::from("/Volumes"));
+    } else {
+        if let Ok(user) = std::env::var("USER") {
+            roots.push(Path::new("/media").join(&user));
+            roots.push(Path::new("/run/media").join(&user));
+        }
+        roots.push(PathBuf::from("/media"));
+        roots.push(PathBuf::from("/mnt"));
+    }
+    roots
+}
+
+/// Look for a mounted UF2 bootloader drive, i.e. a directory containing INFO_UF2.TXT,
+/// either at one of the provided roots or directly inside of one.
+pub fn find_uf2_drive(mount_roots: &[PathBuf]) -> Option<PathBuf> {
+    for root in mount_roots.iter() {
+        if root.join(UF2_INFO_FILE_NAME).is_file() {
+            return Some(root.clone());
+        }
+        let entries = match root.read_dir() {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.join(UF2_INFO_FILE_NAME).is_file() {
+                return Some(path);
+            }
+        }
+    }
+    None
+}
+
+/// Convert the ELF at `elf_path` and copy it to the first UF2 drive found under
+/// `mount_roots`. Returns the path of the written file.
+pub fn flash_uf2(elf_path: &Path, family_id: u32, mount_roots: &[PathBuf]) -> Result<PathBuf, FlashError> {
+    let elf_data = fs::read(elf_path).map_err(|_| FlashError::FilesystemError)?;
+    let uf2 = elf_to_uf2(&elf_data, family_id)?;
+    let drive = find_uf2_drive(mount_roots).ok_or(FlashError::NoUf2Drive)?;
+    let destination = drive.join(UF2_OUTPUT_FILE_NAME);
+    info!("writing {} bytes of uf2 to {}", uf2.len(), destination.display());
+    fs::write(&destination, uf2).map_err(|e| {
+        warn!("error writing uf2 file: {:?}", e);
+        FlashError::FilesystemError
+    })?;
+    Ok(destination)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A little-endian ELF32 with a program header for each (address, data, memory size)
+    fn build_elf(segments: &[(u32, &[u8], u32)]) -> Vec<u8> {
+        const EHDR_SIZE: usize = 52;
+        const PHDR_SIZE: usize = 32;
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 1, 1, 1];
+        elf.resize(16, 0);
+        for half in [2_u16, 40] {
+            elf.extend_from_slice(&half.to_le_bytes());
+        }
+        for word in [1_u32, 0, EHDR_SIZE as u32, 0, 0] {
+            elf.extend_from_slice(&word.to_le_bytes());
+        }
+        for half in [EHDR_SIZE as u16, PHDR_SIZE as u16, segments.len() as u16, 0, 0, 0] {
+            elf.extend_from_slice(&half.to_le_bytes());
+        }
+        let mut offset = EHDR_SIZE + PHDR_SIZE * segments.len();
+        for (addr, data, mem_size) in segments {
+            let header = [PT_LOAD, offset as u32, *addr, *addr, data.len() as u32, *mem_size, 0, 4];
+            for word in header {
+                elf.extend_from_slice(&word.to_le_bytes());
+            }
+            offset += data.len();
+        }
+        for (_, data, _) in segments {
+            elf.extend_from_slice(data);
+        }
+        elf
+    }
+
+    fn word(block: &[u8], n: usize) -> u32 {
+        u32::from_le_bytes(block[n * 4..n * 4 + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn elf_to_uf2_pages_the_loadable_segments() {
+        let code: Vec<u8> = (0..300).map(|n| n as u8).collect();
+        let data = [0xaa; 4];
+        // the zero-sized segment is memory to clear (like .bss), which isn't flashed
+        let elf = build_elf(&[(0x1000_0000, &code, 300), (0x2000_0010, &data, 4), (0x2000_1000, &[], 64)]);
+        let uf2 = elf_to_uf2(&elf, 0xe48b_ff56).unwrap();
+        assert_eq!(uf2.len(), 3 * UF2_BLOCK_SIZE);
+        let addresses = [0x1000_0000, 0x1000_0100, 0x2000_0000];
+        for (n, block) in uf2.chunks(UF2_BLOCK_SIZE).enumerate() {
+            assert_eq!(word(block, 0), UF2_MAGIC_START0);
+            assert_eq!(word(block, 1), UF2_MAGIC_START1);
+            assert_eq!(word(block, 2), UF2_FLAG_FAMILY_ID_PRESENT);
+            assert_eq!(word(block, 3), addresses[n]);
+            assert_eq!(word(block, 4), 256);
+            assert_eq!(word(block, 5), n as u32);
+            assert_eq!(word(block, 6), 3);
+            assert_eq!(word(block, 7), 0xe48b_ff56);
+            assert_eq!(word(block, 127), UF2_MAGIC_END);
+            assert!(block[32 + UF2_PAYLOAD_SIZE..508].iter().all(|b| *b == 0));
+        }
+        let payload = |n: usize| &uf2[n * UF2_BLOCK_SIZE + 32..n * UF2_BLOCK_SIZE + 32 + UF2_PAYLOAD_SIZE];
+        assert_eq!(payload(0), &code[..256]);
+        assert_eq!(&payload(1)[..44], &code[256..]);
+        assert!(payload(1)[44..].iter().all(|b| *b == 0));
+        assert!(payload(2)[..16].iter().all(|b| *b == 0));
+        assert_eq!(&payload(2)[16..20], &data);
+        assert!(payload(2)[20..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn elf_to_uf2_rejects_bad_input() {
+        assert!(matches!(elf_to_uf2(b"not an elf", 0), Err(FlashError::ElfParseError)));
+        let elf = build_elf(&[(0x2000_0000, &[], 64)]);
+        assert!(matches!(elf_to_uf2(&elf, 0), Err(FlashError::EmptyImage)));
+    }
+
+    #[test]
+    fn find_uf2_drive_needs_the_info_file() {
+        let root = std::env::temp_dir().join(format!("iron_coder_uf2_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("usb_stick")).unwrap();
+        assert_eq!(find_uf2_drive(&[root.clone()]), None);
+        fs::create_dir_all(root.join("RPI-RP2")).unwrap();
+        fs::write(root.join("RPI-RP2").join(UF2_INFO_FILE_NAME), "UF2 Bootloader v3.0").unwrap();
+        assert_eq!(find_uf2_drive(&[root.join("missing"), root.clone()]), Some(root.join("RPI-RP2")));
+        assert_eq!(find_uf2_drive(&[root.join("RPI-RP2")]), Some(root.join("RPI-RP2")));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
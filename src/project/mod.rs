@@ -1,9 +1,8 @@
 //! This module describes an Iron Coder project.
 
-use log::{info, warn, debug};
+use log::{info, warn};
 
 // use std::error::Error;
-use std::io::BufRead;
 use std::io;
 use std::fs;
 use std::path::{Path, PathBuf};
@@ -15,8 +14,8 @@ use toml;
 
 use serde::{Serialize, Deserialize};
 
-use crate::board::Board;
-use crate::app::code_editor::CodeEditor;
+use crate::board::{Board, FlashMethod};
+use crate::app::code_editor::{CodeEditor, EditorState};
 
 pub mod display;
 use display::ProjectViewType;
@@ -26,8 +25,29 @@ pub mod egui_helpers;
 mod system;
 use system::System;
 
+pub mod tasks;
+use tasks::{TaskManager, TaskId, TaskStatus};
+
+pub mod size_report;
+use size_report::SizeReport;
+
+pub mod flashing;
+
+pub mod serial_monitor;
+use serial_monitor::SerialMonitor;
+
+pub mod serial_plotter;
+
+pub mod defmt_log;
+
+pub mod project_search;
+use project_search::ProjectSearch;
+
 const PROJECT_FILE_NAME: &'static str = ".ironcoder.toml";
 
+/// The file next to the project file that keeps what was open in the project
+const STATE_FILE_NAME: &str = ".ironcoder.state.toml";
+
 pub type Result = core::result::Result<(), ProjectIOError>;
 
 #[non_exhaustive]
@@ -41,6 +61,25 @@ pub enum ProjectIOError {
     LoadToTomlError,
 }
 
+/// The logging framework that is set up when generating the project template
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum LoggingOption {
+    #[default]
+    None,
+    /// defmt frames sent over a serial port with `defmt-serial`, so the serial monitor
+    /// can decode them without a debug probe. It also needs the defmt linker script.
+    Defmt,
+}
+
+/// What the user had open in a project. It changes all the time, so it is kept out of
+/// the project file, and written whenever it changes.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct ProjectState {
+    sidebar_view: ProjectViewType,
+    editor: EditorState,
+}
+
 /// A Project represents the highest level of Iron Coder, which contains
 /// a main, programmable development board, a set of peripheral development boards,
 /// and the project/source code directory
@@ -55,10 +94,25 @@ pub struct Project {
     #[serde(skip)]
     terminal_buffer: String,
     #[serde(skip)]
-    receiver: Option<std::sync::mpsc::Receiver<String>>,
+    tasks: TaskManager,
+    /// The task whose output is shown in the terminal, or None to show all output
+    #[serde(skip)]
+    terminal_task: Option<TaskId>,
+    /// The memory footprint of the most recent successful build
+    #[serde(skip)]
+    size_report: Option<SizeReport>,
+    #[serde(skip)]
+    pub serial_monitor: SerialMonitor,
+    #[serde(skip)]
     current_view: ProjectViewType,
+    /// The contents of the state file when it was last read or written
+    #[serde(skip)]
+    saved_state: String,
+    #[serde(skip)]
+    search: ProjectSearch,
     #[serde(skip)]
     pub known_boards: Vec<Board>,
+    logging: LoggingOption,
 }
 
 // backend functionality for Project struct
@@ -92,6 +146,25 @@ impl Project {
         }
     }
 
+    /// The directories the project search looks in: the project folder, and the local
+    /// BSP crates of the boards if the search includes them. The paths are canonical,
+    /// so they match the paths of the open editor tabs.
+    fn get_search_roots(&self) -> Vec<PathBuf> {
+        let mut roots: Vec<PathBuf> = self.location.iter().cloned().collect();
+        if self.search.include_bsps {
+            roots.extend(self.system.get_all_boards().into_iter().filter_map(|b| b.bsp_path));
+        }
+        let mut canonical_roots: Vec<PathBuf> = Vec::new();
+        for root in roots {
+            match root.canonicalize() {
+                Ok(root) if !canonical_roots.iter().any(|r| root.starts_with(r)) => canonical_roots.push(root),
+                Ok(_) => (),
+                Err(e) => warn!("couldn't search {}: {:?}", root.display(), e),
+            }
+        }
+        canonical_roots
+    }
+
     pub fn add_board(&mut self, board: Board) {
         match board.is_main_board() {
             true => {
@@ -159,16 +232,72 @@ impl Project {
                 return Err(ProjectIOError::LoadToTomlError)
             }
         };
+        // a different project starts with a fresh code editor, rather than adding its
+        // tabs to the ones of the project that was open
+        let same_project = self.location.as_ref()
+            .and_then(|location| location.canonicalize().ok())
+            .is_some_and(|location| project_directory.canonicalize().is_ok_and(|dir| dir == location));
+        if !same_project {
+            self.code_editor = CodeEditor::default();
+        }
         // Now load in certain fields without overwriting others:
         self.name = p.name;
         self.location = Some(project_directory.to_path_buf());
         self.system = p.system;
-        self.current_view = p.current_view;
+        self.logging = p.logging;
         // sync the assets with the global ones
         self.load_board_resources();
+        self.code_editor.load_workspace(project_directory);
+        self.restore_state(project_directory);
         Ok(())
     }
 
+    /// Reopen the tabs and sidebar view that were open when the project was last used
+    fn restore_state(&mut self, project_directory: &Path) {
+        self.current_view = ProjectViewType::default();
+        let contents = match fs::read_to_string(project_directory.join(STATE_FILE_NAME)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+            Err(e) => {
+                warn!("error reading project state file: {:?}", e);
+                return;
+            },
+        };
+        match toml::from_str::<ProjectState>(&contents) {
+            Ok(state) => {
+                self.current_view = state.sidebar_view;
+                self.code_editor.restore_state(&state.editor, project_directory);
+                self.saved_state = contents;
+            },
+            Err(e) => warn!("couldn't parse project state file: {:?}", e),
+        }
+    }
+
+    /// Write the open tabs and sidebar view to the state file, if they changed
+    pub fn save_state(&mut self) {
+        let Some(location) = &self.location else {
+            return;
+        };
+        let state = ProjectState {
+            sidebar_view: self.current_view.clone(),
+            editor: self.code_editor.get_state(location),
+        };
+        let contents = match toml::to_string(&state) {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!("couldn't serialize project state: {:?}", e);
+                return;
+            },
+        };
+        if contents == self.saved_state {
+            return;
+        }
+        match fs::write(location.join(STATE_FILE_NAME), &contents) {
+            Ok(()) => self.saved_state = contents,
+            Err(e) => warn!("couldn't write project state file: {:?}", e),
+        }
+    }
+
     /// Prompt the user to select project directory to open
     pub fn open(&mut self) -> Result {
         if let Some(project_directory) = FileDialog::new().pick_folder() {
@@ -196,7 +325,8 @@ impl Project {
                     return Ok(());
                 }
             }
-            self.location = Some(project_folder);
+            self.location = Some(project_folder.clone());
+            self.code_editor.load_workspace(&project_folder);
             // TODo: find template directory based on "programmable board" (for now just use board 0)
             // if let Some(template_dir) = self.system.boards[0].get_template_dir() {
             //     // copy_recursive(template_dir, project_dir)
@@ -232,6 +362,7 @@ impl Project {
                     warn!("couldn't save project to toml file!! {:?}", e);
                 }
             }
+            self.save_state();
             Ok(())
         }
     }
@@ -243,22 +374,51 @@ impl Project {
             info!("building project at {}", path.display().to_string());
             self.code_editor.save_all().unwrap_or_else(|_| warn!("error saving tabs!"));
             let cmd = duct::cmd!("cargo", "-Z", "unstable-options", "-C", path.as_path().to_str().unwrap(), "build");
-            self.run_background_commands(&[cmd], ctx);
+            self.run_background_commands("build", &[cmd], ctx);
         } else {
             self.info_logger("project needs a valid working directory before building");
         }
     }
 
-    /// Load the code (for now using 'cargo run')
+    /// Returns the preferred flash method of the main board. Boards that don't specify
+    /// one are assumed to have a `cargo run` runner configured, i.e. a debug probe.
+    fn flash_method(&self) -> FlashMethod {
+        self.system.main_board.as_ref()
+            .and_then(|mb| mb.get_flash_methods().first().cloned())
+            .unwrap_or(FlashMethod::Probe)
+    }
+
+    /// Load the code onto the main board, using its preferred flash method
     fn load_to_board(&mut self, ctx: &egui::Context) {
         if let Some(path) = &self.location {
-            let cmd = duct::cmd!("cargo", "-Z", "unstable-options", "-C", path.as_path().to_str().unwrap(), "run");
-            self.run_background_commands(&[cmd], ctx);
+            let path = path.as_path().to_str().unwrap();
+            let cmd = match self.flash_method() {
+                // build here, and copy the UF2 to the drive once the task completes
+                FlashMethod::UF2 => duct::cmd!("cargo", "-Z", "unstable-options", "-C", path, "build"),
+                FlashMethod::Esptool => {
+                    // espflash talks to the chip over the same serial port as the monitor
+                    self.serial_monitor.suspend();
+                    duct::cmd!("cargo", "-Z", "unstable-options", "-C", path, "espflash", "flash")
+                },
+                _ => duct::cmd!("cargo", "-Z", "unstable-options", "-C", path, "run"),
+            };
+            self.code_editor.save_all().unwrap_or_else(|_| warn!("error saving tabs!"));
+            self.run_background_commands("load", &[cmd], ctx);
         } else {
             self.info_logger("project needs a valid working directory before building");
         }
     }
 
+    /// Convert the built firmware to UF2 and copy it onto a mounted bootloader drive
+    fn load_uf2(&mut self) -> core::result::Result<PathBuf, flashing::FlashError> {
+        let location = self.location.clone().ok_or(flashing::FlashError::FilesystemError)?;
+        let family_id = self.system.main_board.as_ref()
+            .and_then(|mb| mb.get_uf2_family_id())
+            .ok_or(flashing::FlashError::NoFamilyId)?;
+        let elf_path = size_report::find_elf(&location).map_err(|_| flashing::FlashError::FilesystemError)?;
+        flashing::flash_uf2(&elf_path, family_id, &flashing::default_mount_roots())
+    }
+
     pub fn new_file(&mut self) -> io::Result<()> {
         if self.location == None {
             self.info_logger("must save project before adding files/directories");
@@ -272,30 +432,132 @@ impl Project {
         Ok(())
     }
 
-    /// This method will run a series of command sequentially on a separate
-    /// thread, sending their output through the channel to the project's terminal buffer
-    /// TODO - fix bug that calling this command again before a former call's thread is 
-    ///   complete will overwrite the rx channel in the Project object. Possible solution
-    ///   might be to add a command to a queue to be evaluated.
-    fn run_background_commands(&mut self, cmds: &[duct::Expression], ctx: &egui::Context) {
-        // create comms channel
-        let context = ctx.clone();
-        let commands = cmds.to_owned();
-        let (tx, rx) = std::sync::mpsc::channel();
-        self.receiver = Some(rx);
-        let _ = std::thread::spawn(move || {
-            for cmd in commands.iter() {
-                let reader = cmd.stderr_to_stdout().unchecked().reader().unwrap();
-                let mut lines = std::io::BufReader::new(reader).lines();
-                while let Some(line) = lines.next() {
-                    let line = line.unwrap() + "\n";
-                    debug!("sending line through channel");
-                    tx.send(line).unwrap();
-                    context.request_repaint();
+    /// This method will run a series of commands sequentially as a named background task,
+    /// sending their output to the project's terminal buffer. If a task with the same name
+    /// is still running, the new one is queued behind it.
+    fn run_background_commands(&mut self, task_name: &str, cmds: &[duct::Expression], ctx: &egui::Context) {
+        let id = self.tasks.spawn(task_name, cmds, ctx);
+        // keep the terminal following the newest task, if it was following an older one
+        if self.terminal_task.is_some() {
+            self.terminal_task = Some(id);
+        }
+    }
+
+    /// Collect output from the background tasks, and react to any that have completed.
+    fn update_tasks(&mut self, ctx: &egui::Context) {
+        let completed = self.tasks.update(&mut self.terminal_buffer, ctx);
+        for (name, status) in completed {
+            if name == "load" {
+                self.serial_monitor.resume(ctx);
+            }
+            if status != TaskStatus::Finished {
+                self.info_logger(&format!("task <{}> {}", name, status));
+                continue;
+            }
+            // the project's dependencies changed, so the code analysis must be reloaded
+            if name == "generate template" || name == "add crate" {
+                self.code_editor.reload_workspace();
+            }
+            if name == "build" {
+                self.update_size_report();
+                self.update_defmt_table();
+            }
+            if name == "generate template" && self.logging == LoggingOption::Defmt {
+                if let Err(e) = self.add_defmt_linker_args() {
+                    self.info_logger(&format!("couldn't add defmt linker arguments: {:?}", e));
+                }
+                self.info_logger("defmt logs go out over a serial port: pass the board's UART to \
+                    defmt_serial::defmt_serial() in main, then open that port in the serial monitor");
+            }
+            if name == "load" && self.flash_method() == FlashMethod::UF2 {
+                self.update_size_report();
+                self.update_defmt_table();
+                match self.load_uf2() {
+                    Ok(path) => self.info_logger(&format!("wrote firmware to {}", path.display())),
+                    Err(flashing::FlashError::NoUf2Drive) => {
+                        self.info_logger("no UF2 drive found; hold BOOTSEL while connecting the board, then try again");
+                    },
+                    Err(e) => self.info_logger(&format!("error loading UF2 onto board: {:?}", e)),
                 }
             }
-            info!("leaving thread");
-        });
+        }
+    }
+
+    /// Compute the size report of the built firmware, and log a summary of it
+    /// against the main board's memory budget.
+    fn update_size_report(&mut self) {
+        let location = match &self.location {
+            Some(l) => l.clone(),
+            None => return,
+        };
+        let report = match SizeReport::from_project(&location) {
+            Ok(report) => report,
+            Err(e) => {
+                warn!("couldn't compute firmware size report: {:?}", e);
+                self.size_report = None;
+                return;
+            },
+        };
+        self.info_logger(&format!(
+            "firmware size: .text {} B, .rodata {} B, .data {} B, .bss {} B",
+            report.text, report.rodata, report.data, report.bss,
+        ));
+        let (flash_budget, ram_budget) = self.memory_budget();
+        if let Some(flash) = flash_budget {
+            if report.flash_used() > flash {
+                self.info_logger(&format!("firmware uses {} B of flash, which exceeds the board's {} B!", report.flash_used(), flash));
+            }
+        }
+        if let Some(ram) = ram_budget {
+            if report.ram_used() > ram {
+                self.info_logger(&format!("firmware uses {} B of static RAM, which exceeds the board's {} B!", report.ram_used(), ram));
+            }
+        }
+        self.size_report = Some(report);
+    }
+
+    /// Give the serial monitor the defmt table of the firmware that was just built
+    fn update_defmt_table(&mut self) {
+        if let Some(location) = &self.location {
+            if let Ok(elf_path) = size_report::find_elf(location) {
+                self.serial_monitor.load_defmt_table(&elf_path);
+            }
+        }
+    }
+
+    /// Add the defmt linker script and log level to the project's cargo config. The
+    /// config is edited in place, so the template's comments and tables are kept, and
+    /// settings that are already there are left alone.
+    fn add_defmt_linker_args(&mut self) -> io::Result<()> {
+        let location = match &self.location {
+            Some(l) => l.clone(),
+            None => return Err(io::Error::other("project has no location")),
+        };
+        let cargo_dir = location.join(".cargo");
+        // prefer the legacy file name if the template uses it
+        let config_path = if cargo_dir.join("config").exists() {
+            cargo_dir.join("config")
+        } else {
+            cargo_dir.join("config.toml")
+        };
+        let contents = fs::read_to_string(&config_path).unwrap_or_default();
+        let mut config: toml_edit::Document = contents.parse().map_err(io::Error::other)?;
+        add_defmt_config(&mut config)?;
+        fs::create_dir_all(&cargo_dir)?;
+        fs::write(&config_path, config.to_string())?;
+        self.info_logger(&format!("added defmt linker arguments to {}", config_path.display()));
+        Ok(())
+    }
+
+    /// Returns the (flash, ram) sizes of the main board in bytes, where known.
+    fn memory_budget(&self) -> (Option<u64>, Option<u64>) {
+        match &self.system.main_board {
+            Some(mb) => (
+                mb.get_flash().map(|kb| kb as u64 * 1024),
+                mb.get_ram().map(|kb| kb as u64 * 1024),
+            ),
+            None => (None, None),
+        }
     }
 
     /// Generate the Cargo project template based on the main board template (if it has one).
@@ -338,7 +600,21 @@ impl Project {
                     cmds.push(cmd);
                 }
             }
-            self.run_background_commands(&cmds, ctx);
+            // add the crates for the chosen logging framework
+            if self.logging == LoggingOption::Defmt {
+                let cmd = duct::cmd!(
+                    "cargo",
+                    "-Z",
+                    "unstable-options",
+                    "-C",
+                    self.location.clone().unwrap(),
+                    "add",
+                    "defmt",
+                    "defmt-serial",
+                );
+                cmds.push(cmd);
+            }
+            self.run_background_commands("generate template", &cmds, ctx);
         } else {
             return Err(ProjectIOError::NoMainBoard);
         }
@@ -360,4 +636,99 @@ impl Project {
         Ok("".to_string())
     }
 
-}
\ No newline at end of file
+}
+
+/// The target table that the defmt linker script is added to, which matches the
+/// bare-metal ARM targets of the boards
+const DEFMT_TARGET: &str = "cfg(all(target_arch = \"arm\", target_os = \"none\"))";
+
+const DEFMT_LINK_ARG: &str = "link-arg=-Tdefmt.x";
+
+/// Merge the defmt linker script and log level into a cargo config. cargo joins the
+/// rustflags of all the target tables that match, so the script gets its own table
+/// unless one of the tables already links it.
+fn add_defmt_config(config: &mut toml_edit::Document) -> io::Result<()> {
+    let linked = config.get("target")
+        .and_then(|targets| targets.as_table_like())
+        .is_some_and(|targets| targets.iter().any(|(_, target)| {
+            target.get("rustflags")
+                .and_then(|flags| flags.as_array())
+                .is_some_and(|flags| flags.iter().any(|flag| flag.as_str() == Some(DEFMT_LINK_ARG)))
+        }));
+    if !linked {
+        let targets = config["target"].or_insert(toml_edit::table());
+        if let Some(targets) = targets.as_table_mut() {
+            targets.set_implicit(true);
+        }
+        let target = targets[DEFMT_TARGET].or_insert(toml_edit::table());
+        if let Some(target) = target.as_table_mut() {
+            if target.is_empty() {
+                target.decor_mut().set_prefix("\n# Added by Iron Coder: use the defmt linker script\n");
+            }
+        }
+        let flags = target["rustflags"].or_insert(toml_edit::value(toml_edit::Array::new()));
+        let Some(flags) = flags.as_array_mut() else {
+            return Err(io::Error::other(format!("the rustflags of target.'{}' aren't an array", DEFMT_TARGET)));
+        };
+        flags.push("-C");
+        flags.push(DEFMT_LINK_ARG);
+    }
+    // a log level the template already sets is kept
+    let env = config["env"].or_insert(toml_edit::table());
+    if env.get("DEFMT_LOG").is_none() {
+        env["DEFMT_LOG"] = toml_edit::value("debug");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merged(config: &str) -> String {
+        let mut config: toml_edit::Document = config.parse().unwrap();
+        add_defmt_config(&mut config).unwrap();
+        config.to_string()
+    }
+
+    #[test]
+    fn defmt_config_merges_into_existing_tables() {
+        let config = merged(concat!(
+            "# the template's comment\n",
+            "[target.thumbv6m-none-eabi]\n",
+            "runner = \"elf2uf2-rs -d\"\n",
+            "\n",
+            "[env]\n",
+            "FOO = \"bar\"\n",
+        ));
+        // cargo rejects a config with a table defined twice, which parsing checks too
+        let parsed: toml::Table = toml::from_str(&config).unwrap();
+        assert!(config.contains("# the template's comment"));
+        assert_eq!(parsed["env"]["FOO"].as_str(), Some("bar"));
+        assert_eq!(parsed["env"]["DEFMT_LOG"].as_str(), Some("debug"));
+        assert_eq!(parsed["target"]["thumbv6m-none-eabi"]["runner"].as_str(), Some("elf2uf2-rs -d"));
+        let flags = parsed["target"][DEFMT_TARGET]["rustflags"].as_array().unwrap();
+        assert_eq!(flags, &vec![toml::Value::from("-C"), toml::Value::from(DEFMT_LINK_ARG)]);
+    }
+
+    #[test]
+    fn defmt_config_is_only_added_once() {
+        let once = merged("");
+        assert_eq!(merged(&once), once);
+        let parsed: toml::Table = toml::from_str(&once).unwrap();
+        assert_eq!(parsed["env"]["DEFMT_LOG"].as_str(), Some("debug"));
+    }
+
+    #[test]
+    fn defmt_config_keeps_existing_settings() {
+        let config = merged(concat!(
+            "[target.thumbv7em-none-eabihf]\n",
+            "rustflags = [\"-C\", \"link-arg=-Tlink.x\", \"-C\", \"link-arg=-Tdefmt.x\"]\n",
+            "[env]\n",
+            "DEFMT_LOG = \"trace\"\n",
+        ));
+        let parsed: toml::Table = toml::from_str(&config).unwrap();
+        assert_eq!(parsed["env"]["DEFMT_LOG"].as_str(), Some("trace"));
+        assert!(parsed["target"].get(DEFMT_TARGET).is_none());
+    }
+}
@@ -0,0 +1,512 @@
+//! This module contains the project search, which finds a query in every text file
+//! of the project directory (and, optionally, in the local BSP crates of its boards).
+//! Matches are found line by line, and a bulk replace is previewed line by line
+//! before anything is written.
+
+use log::{info, warn};
+
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use egui::text::{LayoutJob, TextFormat};
+use egui::{Key, RichText};
+use onig::Regex;
+
+use crate::app::find_replace::SearchQuery;
+
+/// Directories that are never searched
+const SKIPPED_DIRS: [&str; 2] = ["target", ".git"];
+/// Line previews are cut off after this many chars
+const MAX_PREVIEW_CHARS: usize = 160;
+
+/// A line of a file with at least one match in it
+#[derive(Debug, Clone)]
+pub struct LineMatch {
+    /// The line number, 1-based
+    pub line: usize,
+    pub text: String,
+    /// The byte ranges of the matches within the line
+    pub ranges: Vec<Range<usize>>,
+}
+
+/// The matching lines of one file
+#[derive(Debug, Clone)]
+pub struct FileMatches {
+    pub path: PathBuf,
+    /// The path shown to the user, relative to the directory it was found in
+    pub display_path: String,
+    pub lines: Vec<LineMatch>,
+}
+
+/// A line that a bulk replace would change
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineChange {
+    pub line: usize,
+    pub old: String,
+    pub new: String,
+}
+
+/// The changes a bulk replace would make to one file
+#[derive(Debug, Clone)]
+pub struct FileChanges {
+    pub path: PathBuf,
+    pub display_path: String,
+    pub changes: Vec<LineChange>,
+    /// Whether the user wants the changes made to this file
+    pub selected: bool,
+}
+
+/// What the user asked the search view to do this frame
+#[derive(Debug, Clone, PartialEq)]
+pub enum SearchAction {
+    None,
+    /// Run the search again with the current query
+    Search,
+    /// Open a file at a (1-based) line
+    Open(PathBuf, usize),
+    /// Work out the changes of a bulk replace, to preview them
+    PreviewReplace,
+    /// Make the previewed changes
+    Replace,
+}
+
+#[derive(Default)]
+pub struct ProjectSearch {
+    query: SearchQuery,
+    replacement: String,
+    /// Whether to also search the local BSP crates of the project's boards
+    pub include_bsps: bool,
+    results: Vec<FileMatches>,
+    /// Why the query couldn't be compiled, if it couldn't
+    error: Option<String>,
+    /// The changes of a bulk replace, waiting to be confirmed
+    preview: Option<Vec<FileChanges>>,
+    /// The files that were left out of the last bulk replace
+    not_replaced: Vec<String>,
+}
+
+impl ProjectSearch {
+
+    /// Search every text file under each of the `roots`. The contents of a file are
+    /// taken from `open_code` if it has them (i.e. the file is open in the editor),
+    /// and otherwise read from disk.
+    pub fn search(&mut self, roots: &[PathBuf], open_code: impl Fn(&Path) -> Option<String>) {
+        self.results.clear();
+        self.error = None;
+        if self.query.text.is_empty() {
+            return;
+        }
+        let regex = match self.query.build_regex() {
+            Ok(regex) => regex,
+            Err(e) => {
+                self.error = Some(e);
+                return;
+            },
+        };
+        for root in roots.iter() {
+            let mut files = Vec::new();
+            collect_files(root, &mut files);
+            files.sort();
+            for path in files {
+                let contents = match open_code(&path).or_else(|| read_text_file(&path)) {
+                    Some(contents) => contents,
+                    None => continue,
+                };
+                let lines = find_lines(&regex, &contents);
+                if !lines.is_empty() {
+                    self.results.push(FileMatches {
+                        display_path: display_path(root, &path),
+                        path,
+                        lines,
+                    });
+                }
+            }
+        }
+        info!("found {} in {} files", self.query.text, self.results.len());
+    }
+
+    /// Work out the changes that replacing every match in the results would make,
+    /// and keep them to be previewed
+    pub fn preview_replace(&mut self, open_code: impl Fn(&Path) -> Option<String>) {
+        let regex = match self.query.build_regex() {
+            Ok(regex) => regex,
+            Err(e) => {
+                self.error = Some(e);
+                return;
+            },
+        };
+        self.not_replaced.clear();
+        let mut preview = Vec::new();
+        for file in self.results.iter() {
+            let contents = match open_code(&file.path).or_else(|| read_text_file(&file.path)) {
+                Some(contents) => contents,
+                None => {
+                    warn!("couldn't read {} to replace in it", file.path.display());
+                    continue;
+                },
+            };
+            let (changes, _) = replace_lines(&self.query, &regex, &contents, &self.replacement);
+            if !changes.is_empty() {
+                preview.push(FileChanges {
+                    path: file.path.clone(),
+                    display_path: file.display_path.clone(),
+                    changes,
+                    selected: true,
+                });
+            }
+        }
+        self.preview = Some(preview);
+    }
+
+    /// Take the new contents of the files the user chose in the replace preview. The
+    /// preview window doesn't stop the files from being edited in the meantime, so the
+    /// replacement is made again on their current contents, and a file whose changes
+    /// no longer match the preview is left alone.
+    pub fn take_replacements(&mut self, open_code: impl Fn(&Path) -> Option<String>) -> Vec<(PathBuf, String)> {
+        let preview = self.preview.take().unwrap_or_default();
+        let regex = match self.query.build_regex() {
+            Ok(regex) => regex,
+            Err(e) => {
+                self.error = Some(e);
+                return Vec::new();
+            },
+        };
+        let mut replacements = Vec::new();
+        let mut skipped = Vec::new();
+        for file in preview.into_iter().filter(|file| file.selected) {
+            let contents = open_code(&file.path).or_else(|| read_text_file(&file.path));
+            let replaced = contents.map(|contents| replace_lines(&self.query, &regex, &contents, &self.replacement));
+            match replaced {
+                Some((changes, new_contents)) if changes == file.changes => replacements.push((file.path, new_contents)),
+                _ => {
+                    warn!("{} changed since the replace was previewed, leaving it alone", file.path.display());
+                    skipped.push(file.display_path);
+                },
+            }
+        }
+        self.not_replaced = skipped;
+        replacements
+    }
+
+    /// Show the search view, and return what the user asked for
+    pub fn display(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) -> SearchAction {
+        let mut action = SearchAction::None;
+        ui.horizontal(|ui| {
+            let query = egui::TextEdit::singleline(&mut self.query.text)
+                .hint_text("search the project")
+                .desired_width(160.0);
+            let response = ui.add(query);
+            if response.lost_focus() && ui.input(|input| input.key_pressed(Key::Enter)) {
+                action = SearchAction::Search;
+            }
+            if ui.button("search").clicked() {
+                action = SearchAction::Search;
+            }
+        });
+        ui.horizontal(|ui| {
+            self.query.display_options(ui);
+            ui.checkbox(&mut self.include_bsps, "BSP crates")
+                .on_hover_text("also search the local BSP crates of the project's boards");
+        });
+        ui.horizontal(|ui| {
+            let replacement = egui::TextEdit::singleline(&mut self.replacement)
+                .hint_text("replace")
+                .desired_width(160.0);
+            ui.add(replacement).on_hover_text("$1, $2... insert the groups of a regular expression");
+            let enabled = !self.results.is_empty();
+            if ui.add_enabled(enabled, egui::Button::new("replace all")).clicked() {
+                action = SearchAction::PreviewReplace;
+            }
+        });
+        if let Some(e) = &self.error {
+            ui.label(RichText::new(e.as_str()).color(ui.visuals().error_fg_color));
+        }
+        if !self.not_replaced.is_empty() {
+            let text = format!("not replaced in {}, which changed after the preview", self.not_replaced.join(", "));
+            ui.label(RichText::new(text).color(ui.visuals().warn_fg_color));
+        }
+        if !self.results.is_empty() {
+            let num_matches: usize = self.results.iter()
+                .flat_map(|file| file.lines.iter())
+                .map(|line| line.ranges.len())
+                .sum();
+            ui.label(RichText::new(format!("{} matches in {} files", num_matches, self.results.len())).weak());
+        }
+        ui.separator();
+        for file in self.results.iter() {
+            let num_matches: usize = file.lines.iter().map(|line| line.ranges.len()).sum();
+            egui::CollapsingHeader::new(format!("{} ({})", file.display_path, num_matches))
+                .id_source(&file.path)
+                .default_open(true)
+                .show(ui, |ui| {
+                    for line in file.lines.iter() {
+                        let label = egui::Label::new(preview_job(ui, line)).sense(egui::Sense::click());
+                        if ui.add(label).on_hover_text(file.path.display().to_string()).clicked() {
+                            action = SearchAction::Open(file.path.clone(), line.line);
+                        }
+                    }
+                });
+        }
+        if self.display_replace_preview(ctx) {
+            action = SearchAction::Replace;
+        }
+        action
+    }
+
+    /// Show the changes of a bulk replace in a window, returning true if they were confirmed
+    fn display_replace_preview(&mut self, ctx: &egui::Context) -> bool {
+        let mut open = self.preview.is_some();
+        let mut apply = false;
+        if let Some(preview) = &mut self.preview {
+            egui::Window::new("replace in project")
+                .id(egui::Id::new("project_replace_preview"))
+                .open(&mut open)
+                .collapsible(false)
+                .show(ctx, |ui| {
+                    if preview.is_empty() {
+                        ui.label("nothing to replace");
+                        return;
+                    }
+                    egui::ScrollArea::vertical().max_height(400.0).show(ui, |ui| {
+                        for file in preview.iter_mut() {
+                            let title = RichText::new(file.display_path.as_str()).underline();
+                            ui.checkbox(&mut file.selected, title);
+                            for change in file.changes.iter() {
+                                ui.label(RichText::new(format!("line {}", change.line)).weak());
+                                ui.label(RichText::new(change.old.as_str()).monospace().strikethrough().color(egui::Color32::RED));
+                                ui.label(RichText::new(change.new.as_str()).monospace().color(egui::Color32::GREEN));
+                            }
+                            ui.separator();
+                        }
+                    });
+                    let num_files = preview.iter().filter(|file| file.selected).count();
+                    if ui.add_enabled(num_files > 0, egui::Button::new(format!("replace in {} files", num_files))).clicked() {
+                        apply = true;
+                    }
+                });
+        }
+        if !open {
+            self.preview = None;
+        }
+        apply
+    }
+}
+
+/// Collect every file under `dir`, skipping build output and version control. Symlinks
+/// aren't followed, so a link back up the tree can't loop forever.
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("couldn't read {}: {:?}", dir.display(), e);
+            return;
+        },
+    };
+    for entry in entries.flatten() {
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(_) => continue,
+        };
+        if file_type.is_dir() {
+            if !SKIPPED_DIRS.iter().any(|skipped| entry.file_name() == *skipped) {
+                collect_files(&entry.path(), files);
+            }
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+}
+
+/// Read a file, if it is text (valid UTF-8 without any NUL bytes)
+fn read_text_file(path: &Path) -> Option<String> {
+    let bytes = fs::read(path).ok()?;
+    if bytes.contains(&0) {
+        return None;
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Split text into lines, each with its line ending (which is empty for the last line
+/// if the text doesn't end with a newline)
+fn split_lines(text: &str) -> impl Iterator<Item = (&str, &str)> {
+    text.split_inclusive('\n').map(|line| {
+        let content = line.trim_end_matches('\n').trim_end_matches('\r');
+        line.split_at(content.len())
+    })
+}
+
+/// Replace every match in each line of `contents`, returning the changed lines and
+/// the whole text with the changes made
+fn replace_lines(query: &SearchQuery, regex: &Regex, contents: &str, replacement: &str) -> (Vec<LineChange>, String) {
+    let mut changes = Vec::new();
+    let mut new_contents = String::with_capacity(contents.len());
+    for (i, (line, ending)) in split_lines(contents).enumerate() {
+        let (new_line, count) = query.replace_all(regex, line, replacement);
+        if count > 0 {
+            changes.push(LineChange {
+                line: i + 1,
+                old: line.to_string(),
+                new: new_line.clone(),
+            });
+        }
+        new_contents += &new_line;
+        new_contents += ending;
+    }
+    (changes, new_contents)
+}
+
+/// Find the (non-empty) matches of `regex` in each line of `contents`
+fn find_lines(regex: &Regex, contents: &str) -> Vec<LineMatch> {
+    split_lines(contents).enumerate()
+        .filter_map(|(i, (line, _))| {
+            let ranges: Vec<Range<usize>> = regex.find_iter(line)
+                .filter(|(start, end)| start != end)
+                .map(|(start, end)| start..end)
+                .collect();
+            match ranges.is_empty() {
+                true => None,
+                false => Some(LineMatch { line: i + 1, text: line.to_string(), ranges }),
+            }
+        })
+        .collect()
+}
+
+/// The path of a file relative to the directory that was searched, prefixed with the
+/// directory's name so files of different roots can be told apart
+fn display_path(root: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    match root.file_name() {
+        Some(name) => Path::new(name).join(relative).display().to_string(),
+        None => relative.display().to_string(),
+    }
+}
+
+/// Lay out the preview of a matching line, with its line number and the matches
+/// highlighted. Leading whitespace is dropped, and long lines are cut off.
+fn preview_job(ui: &egui::Ui, line: &LineMatch) -> LayoutJob {
+    let visuals = ui.visuals();
+    let font_id = egui::TextStyle::Monospace.resolve(ui.style());
+    let plain = TextFormat::simple(font_id.clone(), visuals.text_color());
+    let highlighted = TextFormat {
+        background: visuals.selection.bg_fill,
+        ..plain.clone()
+    };
+    let weak = TextFormat::simple(font_id, visuals.weak_text_color());
+    let mut job = LayoutJob::default();
+    job.append(&format!("{}: ", line.line), 0.0, weak.clone());
+    let start = line.text.len() - line.text.trim_start().len();
+    let end = line.text[start..].char_indices()
+        .nth(MAX_PREVIEW_CHARS)
+        .map_or(line.text.len(), |(i, _)| start + i);
+    let mut last = start;
+    for range in line.ranges.iter() {
+        let (range_start, range_end) = (range.start.clamp(last, end), range.end.clamp(last, end));
+        job.append(&line.text[last..range_start], 0.0, plain.clone());
+        job.append(&line.text[range_start..range_end], 0.0, highlighted.clone());
+        last = range_end;
+    }
+    job.append(&line.text[last..end], 0.0, plain);
+    if end < line.text.len() {
+        job.append("…", 0.0, weak);
+    }
+    job
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(text: &str, replacement: &str) -> ProjectSearch {
+        let mut search = ProjectSearch::default();
+        search.query = SearchQuery {
+            text: text.to_string(),
+            case_sensitive: true,
+            use_regex: true,
+            ..Default::default()
+        };
+        search.replacement = replacement.to_string();
+        search
+    }
+
+    #[test]
+    fn lines_keep_their_endings() {
+        let lines: Vec<(&str, &str)> = split_lines("a\r\nb\n\nc").collect();
+        assert_eq!(lines, [("a", "\r\n"), ("b", "\n"), ("", "\n"), ("c", "")]);
+        let lines: Vec<(&str, &str)> = split_lines("a\n").collect();
+        assert_eq!(lines, [("a", "\n")]);
+        assert_eq!(split_lines("").count(), 0);
+        // a lone carriage return isn't a line break
+        let lines: Vec<(&str, &str)> = split_lines("a\rb").collect();
+        assert_eq!(lines, [("a\rb", "")]);
+    }
+
+    #[test]
+    fn matches_are_found_per_line() {
+        let regex = search(r"\w+\(", "").query.build_regex().unwrap();
+        let lines = find_lines(&regex, "fn main() {\r\n    é(); foo(1)\n}\nbar(");
+        let found: Vec<(usize, &str, Vec<Range<usize>>)> = lines.iter()
+            .map(|line| (line.line, line.text.as_str(), line.ranges.clone()))
+            .collect();
+        assert_eq!(found, [
+            (1, "fn main() {", vec![3..8]),
+            (2, "    é(); foo(1)", vec![4..7, 10..14]),
+            (4, "bar(", vec![0..4]),
+        ]);
+        // empty matches aren't matches
+        let regex = search("x*", "").query.build_regex().unwrap();
+        assert!(find_lines(&regex, "abc\ndef").is_empty());
+    }
+
+    #[test]
+    fn replace_is_made_on_the_current_contents() {
+        let dir = std::env::temp_dir().join(format!("iron_coder_search_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (closed, open) = (dir.join("closed.rs"), dir.join("open.rs"));
+        fs::write(&closed, "let a = old;\r\nlet b = old(old);").unwrap();
+        fs::write(&open, "old on disk").unwrap();
+        let open_code = |code: &'static str| {
+            let open = open.clone();
+            move |path: &Path| (path == open).then(|| code.to_string())
+        };
+
+        let mut search = search("o(l)d", "new$1");
+        search.search(&[dir.clone()], open_code("// old\nfn f() {}\n"));
+        assert_eq!(search.results.len(), 2);
+        search.preview_replace(open_code("// old\nfn f() {}\n"));
+        let preview = search.preview.as_ref().unwrap();
+        let changes: Vec<(usize, &str, &str)> = preview.iter()
+            .flat_map(|file| file.changes.iter().map(|c| (c.line, c.old.as_str(), c.new.as_str())))
+            .collect();
+        assert_eq!(changes, [
+            (1, "let a = old;", "let a = newl;"),
+            (2, "let b = old(old);", "let b = newl(newl);"),
+            (1, "// old", "// newl"),
+        ]);
+
+        // the open file was edited after the preview, in a line the replace doesn't change
+        let replacements = search.take_replacements(open_code("// old\nfn f() { edited(); }\n"));
+        assert_eq!(replacements, [
+            (closed.clone(), "let a = newl;\r\nlet b = newl(newl);".to_string()),
+            (open.clone(), "// newl\nfn f() { edited(); }\n".to_string()),
+        ]);
+        assert!(search.not_replaced.is_empty());
+        assert!(search.preview.is_none());
+
+        // a file whose matches changed after the preview is left alone
+        search.preview_replace(open_code("// old\n"));
+        fs::write(&closed, "let a = old; old").unwrap();
+        let replacements = search.take_replacements(open_code("// old\n"));
+        assert_eq!(replacements, [(open.clone(), "// newl\n".to_string())]);
+        assert_eq!(search.not_replaced, [display_path(&dir, &closed)]);
+
+        // and so is one that was deselected
+        search.preview_replace(open_code("// old\n"));
+        search.preview.as_mut().unwrap()[1].selected = false;
+        let replacements = search.take_replacements(open_code("// old\n"));
+        assert_eq!(replacements.len(), 1);
+        assert_eq!(replacements[0].0, closed);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
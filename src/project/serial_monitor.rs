@@ -0,0 +1,605 @@
+//! This module contains the serial monitor, which shows the text that the main board
+//! prints over a serial port (i.e. USB CDC or a UART adapter), and can send lines back.
+//! The port is read on a background thread, which reconnects automatically if the port
+//! disappears (e.g. when the board resets after being re-flashed).
+
+use log::{info, warn};
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::time::Duration;
+
+use egui::RichText;
+use rfd::FileDialog;
+use serialport::SerialPort;
+
+use super::serial_plotter::SerialPlotter;
+use super::defmt_log::{DefmtDecoder, DefmtFrame};
+
+/// Common baud rates to offer in the selector
+const BAUD_RATES: [u32; 8] = [9600, 19200, 38400, 57600, 115200, 230400, 460800, 921600];
+const DEFAULT_BAUD_RATE: u32 = 115200;
+/// How long a read blocks before the thread checks for outgoing data or a stop request
+const READ_TIMEOUT: Duration = Duration::from_millis(50);
+/// How long to wait between attempts to reopen a port that went away
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+/// Older lines are dropped once the monitor holds this many
+const MAX_LINES: usize = 10_000;
+
+/// Messages from the port thread to the GUI thread
+enum PortEvent {
+    Connected,
+    Data(Vec<u8>),
+    Disconnected(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionStatus {
+    Closed,
+    Connected,
+    /// The port went away, and the thread is trying to reopen it
+    Reconnecting(String),
+}
+
+/// A line of text received from the board, along with the local time it arrived.
+/// Lines decoded from defmt frames also carry the frame's metadata.
+#[derive(Debug, Clone, Default)]
+pub struct MonitorLine {
+    pub timestamp: chrono::DateTime<chrono::Local>,
+    pub text: String,
+    pub level: Option<&'static str>,
+    pub device_timestamp: Option<String>,
+    pub location: Option<(PathBuf, usize)>,
+}
+
+/// The GUI side of a running port thread
+struct Connection {
+    outgoing: Sender<Vec<u8>>,
+    incoming: Receiver<PortEvent>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+pub struct SerialMonitor {
+    /// Whether the monitor panel is shown
+    pub visible: bool,
+    port_name: String,
+    baud_rate: u32,
+    available_ports: Vec<String>,
+    connection: Option<Connection>,
+    status: ConnectionStatus,
+    /// Set when the port was closed for flashing, so it can be reopened afterwards
+    suspended: bool,
+    lines: Vec<MonitorLine>,
+    /// Bytes received after the last newline
+    partial_line: Vec<u8>,
+    send_buffer: String,
+    log_file: Option<(PathBuf, fs::File)>,
+    /// Parses numeric telemetry out of the received lines
+    plotter: SerialPlotter,
+    /// Whether to show the plot instead of the received text
+    show_plot: bool,
+    /// A decoder built from the defmt table of the last built firmware
+    defmt: Option<DefmtDecoder>,
+    /// Whether to decode the received bytes as defmt frames instead of text
+    decode_defmt: bool,
+    /// The far end of a pseudo-terminal pair, kept open while the monitor uses the near end
+    #[cfg(unix)]
+    pty_slave: Option<serialport::TTYPort>,
+}
+
+impl Default for SerialMonitor {
+    fn default() -> Self {
+        Self {
+            visible: false,
+            port_name: String::new(),
+            baud_rate: DEFAULT_BAUD_RATE,
+            available_ports: Vec::new(),
+            connection: None,
+            status: ConnectionStatus::Closed,
+            suspended: false,
+            lines: Vec::new(),
+            partial_line: Vec::new(),
+            send_buffer: String::new(),
+            log_file: None,
+            plotter: SerialPlotter::default(),
+            show_plot: false,
+            defmt: None,
+            decode_defmt: false,
+            #[cfg(unix)]
+            pty_slave: None,
+        }
+    }
+}
+
+impl SerialMonitor {
+
+    /// Refresh the list of serial ports on the system
+    pub fn refresh_ports(&mut self) {
+        self.available_ports = match serialport::available_ports() {
+            Ok(ports) => ports.into_iter().map(|p| p.port_name).collect(),
+            Err(e) => {
+                warn!("couldn't list serial ports: {:?}", e);
+                Vec::new()
+            },
+        };
+        if self.port_name.is_empty() {
+            if let Some(first) = self.available_ports.first() {
+                self.port_name = first.clone();
+            }
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    /// Open the currently selected port
+    pub fn connect(&mut self, ctx: &egui::Context) {
+        if self.port_name.is_empty() {
+            warn!("no serial port selected");
+            return;
+        }
+        info!("opening serial port {} at {} baud", self.port_name, self.baud_rate);
+        self.start_thread(Some(self.port_name.clone()), None, ctx);
+    }
+
+    /// Create a pseudo-terminal pair and connect the monitor to one end of it. The
+    /// other end can be opened by any program (e.g. `echo hello > /dev/pts/N`) to
+    /// simulate a board without any hardware.
+    #[cfg(unix)]
+    pub fn connect_pty(&mut self, ctx: &egui::Context) {
+        match serialport::TTYPort::pair() {
+            Ok((master, slave)) => {
+                let slave_name = slave.name().unwrap_or_default();
+                info!("created pseudo-terminal pair, far end is {}", slave_name);
+                self.port_name = slave_name;
+                self.pty_slave = Some(slave);
+                self.start_thread(None, Some(Box::new(master)), ctx);
+            },
+            Err(e) => warn!("couldn't create pseudo-terminal pair: {:?}", e),
+        }
+    }
+
+    /// Close the port, if it's open
+    pub fn disconnect(&mut self) {
+        self.connection = None;
+        self.status = ConnectionStatus::Closed;
+        #[cfg(unix)]
+        {
+            self.pty_slave = None;
+        }
+    }
+
+    /// Release the port so that a flashing tool can use it. `resume` reopens it.
+    pub fn suspend(&mut self) {
+        if self.connection.is_some() && self.status != ConnectionStatus::Closed {
+            info!("suspending serial monitor for flashing");
+            self.connection = None;
+            self.status = ConnectionStatus::Closed;
+            self.suspended = true;
+        }
+    }
+
+    /// Reopen a port that was released with `suspend`
+    pub fn resume(&mut self, ctx: &egui::Context) {
+        if self.suspended {
+            self.suspended = false;
+            self.connect(ctx);
+        }
+    }
+
+    /// Queue some bytes to be written to the port
+    pub fn send(&mut self, data: &[u8]) {
+        if let Some(connection) = &self.connection {
+            if connection.outgoing.send(data.to_vec()).is_err() {
+                warn!("serial port thread has exited");
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.partial_line.clear();
+        if let Some(defmt) = &mut self.defmt {
+            defmt.clear();
+        }
+    }
+
+    /// Load the defmt table of a newly built firmware image. Firmware that doesn't
+    /// use defmt simply leaves the monitor without a decoder.
+    pub fn load_defmt_table(&mut self, elf_path: &Path) {
+        self.defmt = match DefmtDecoder::from_elf(elf_path) {
+            Ok(decoder) => Some(decoder),
+            Err(e) => {
+                info!("no defmt decoder for {}: {:?}", elf_path.display(), e);
+                None
+            },
+        };
+    }
+
+    /// Start logging received lines to a file, chosen with a file dialog
+    fn start_logging(&mut self) {
+        if let Some(path) = FileDialog::new().set_file_name("serial.log").save_file() {
+            match fs::OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(file) => self.log_file = Some((path, file)),
+                Err(e) => warn!("couldn't open log file {:?}: {:?}", path, e),
+            }
+        }
+    }
+
+    fn start_thread(&mut self, path: Option<String>, port: Option<Box<dyn SerialPort>>, ctx: &egui::Context) {
+        let (out_tx, out_rx) = mpsc::channel();
+        let (in_tx, in_rx) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let baud_rate = self.baud_rate;
+        let context = ctx.clone();
+        // replacing the connection stops any previous thread
+        self.connection = Some(Connection {
+            outgoing: out_tx,
+            incoming: in_rx,
+            stop,
+        });
+        self.suspended = false;
+        self.status = ConnectionStatus::Reconnecting(String::from("connecting..."));
+        let _ = std::thread::spawn(move || {
+            run_port(path, baud_rate, port, out_rx, in_tx, thread_stop, &context);
+            info!("leaving serial port thread");
+        });
+    }
+
+    /// Process any events from the port thread. Returns the raw bytes received since
+    /// the last call, so that other views can decode the stream.
+    pub fn update(&mut self) -> Vec<u8> {
+        let mut received = Vec::new();
+        let mut thread_exited = false;
+        if let Some(connection) = &self.connection {
+            loop {
+                match connection.incoming.try_recv() {
+                    Ok(PortEvent::Connected) => self.status = ConnectionStatus::Connected,
+                    Ok(PortEvent::Data(data)) => received.extend_from_slice(&data),
+                    Ok(PortEvent::Disconnected(reason)) => self.status = ConnectionStatus::Reconnecting(reason),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        thread_exited = true;
+                        break;
+                    },
+                }
+            }
+        }
+        if thread_exited {
+            self.connection = None;
+            self.status = ConnectionStatus::Closed;
+        }
+        match &mut self.defmt {
+            Some(defmt) if self.decode_defmt => {
+                for frame in defmt.push_bytes(&received) {
+                    self.push_frame(frame);
+                }
+            },
+            _ => self.push_bytes(&received),
+        }
+        received
+    }
+
+    /// Split received bytes into timestamped lines
+    fn push_bytes(&mut self, data: &[u8]) {
+        for &byte in data {
+            match byte {
+                b'\n' => {
+                    let text = String::from_utf8_lossy(&self.partial_line).trim_end_matches('\r').to_string();
+                    self.partial_line.clear();
+                    self.push_text(text);
+                },
+                _ => self.partial_line.push(byte),
+            }
+        }
+    }
+
+    fn push_frame(&mut self, frame: DefmtFrame) {
+        self.push_line(MonitorLine {
+            timestamp: chrono::Local::now(),
+            text: frame.message,
+            level: frame.level,
+            device_timestamp: frame.timestamp,
+            location: frame.location,
+        });
+    }
+
+    fn push_text(&mut self, text: String) {
+        self.push_line(MonitorLine {
+            timestamp: chrono::Local::now(),
+            text,
+            ..Default::default()
+        });
+    }
+
+    fn push_line(&mut self, line: MonitorLine) {
+        if let Some((path, file)) = &mut self.log_file {
+            let level = line.level.map(|l| format!("[{}] ", l.to_uppercase())).unwrap_or_default();
+            if let Err(e) = writeln!(file, "{} {}{}", line.timestamp.format("%H:%M:%S%.3f"), level, line.text) {
+                warn!("error writing to serial log {:?}: {:?}", path, e);
+                self.log_file = None;
+            }
+        }
+        self.plotter.push_line(line.timestamp, &line.text);
+        self.lines.push(line);
+        if self.lines.len() > MAX_LINES {
+            let excess = self.lines.len() - MAX_LINES;
+            self.lines.drain(0..excess);
+        }
+    }
+
+    /// Show the port settings and connection controls
+    fn display_controls(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_source("serial_port_selector")
+            .selected_text(self.port_name.as_str())
+            .show_ui(ui, |ui| {
+                for port in self.available_ports.clone().into_iter() {
+                    ui.selectable_value(&mut self.port_name, port.clone(), port);
+                }
+            });
+            if ui.button("refresh").clicked() {
+                self.refresh_ports();
+            }
+            egui::ComboBox::from_id_source("serial_baud_selector")
+            .selected_text(format!("{} baud", self.baud_rate))
+            .show_ui(ui, |ui| {
+                for baud in BAUD_RATES.iter() {
+                    ui.selectable_value(&mut self.baud_rate, *baud, baud.to_string());
+                }
+            });
+            if self.is_connected() {
+                if ui.button("disconnect").clicked() {
+                    self.disconnect();
+                }
+            } else {
+                if ui.button("connect").clicked() {
+                    self.connect(ctx);
+                }
+                #[cfg(unix)]
+                if ui.button("pseudo-terminal").on_hover_text("connect to a new pseudo-terminal pair, for testing without hardware").clicked() {
+                    self.connect_pty(ctx);
+                }
+            }
+            let status = match &self.status {
+                ConnectionStatus::Closed => RichText::new("closed"),
+                ConnectionStatus::Connected => RichText::new("connected").color(egui::Color32::GREEN),
+                ConnectionStatus::Reconnecting(reason) => RichText::new(format!("waiting for port ({})", reason)).color(egui::Color32::YELLOW),
+            };
+            ui.label(status);
+            ui.separator();
+            let mut logging = self.log_file.is_some();
+            let log_text = match &self.log_file {
+                Some((path, _)) => format!("log to {}", path.display()),
+                None => String::from("log to file"),
+            };
+            if ui.checkbox(&mut logging, log_text).changed() {
+                if logging {
+                    self.start_logging();
+                } else {
+                    self.log_file = None;
+                }
+            }
+            if ui.button("clear").clicked() {
+                self.clear();
+            }
+            ui.separator();
+            let hover_text = match &self.defmt {
+                Some(defmt) => format!("decoding frames sent with defmt-serial, using the defmt table from {}", defmt.get_elf_path().display()),
+                None => String::from("build firmware that logs with defmt-serial to enable decoding"),
+            };
+            ui.add_enabled_ui(self.defmt.is_some(), |ui| {
+                ui.checkbox(&mut self.decode_defmt, "decode defmt")
+            }).response.on_hover_text(hover_text);
+            ui.separator();
+            ui.selectable_value(&mut self.show_plot, false, "text");
+            ui.selectable_value(&mut self.show_plot, true, "plot");
+        });
+    }
+
+    /// Show the received lines. Returns a source location if one was clicked.
+    fn display_lines(&mut self, ui: &mut egui::Ui) -> Option<(PathBuf, usize)> {
+        let mut clicked_location = None;
+        let row_height = ui.text_style_height(&egui::TextStyle::Monospace);
+        egui::ScrollArea::both()
+        .id_source("serial_monitor_lines")
+        .auto_shrink([false; 2])
+        .stick_to_bottom(true)
+        .max_height(ui.available_height() - 2.0 * row_height)
+        .show_rows(ui, row_height, self.lines.len(), |ui, row_range| {
+            for line in self.lines[row_range].iter() {
+                ui.horizontal(|ui| {
+                    let timestamp = RichText::new(line.timestamp.format("%H:%M:%S%.3f").to_string())
+                        .monospace()
+                        .color(ui.style().visuals.weak_text_color());
+                    ui.label(timestamp);
+                    if let Some(device_timestamp) = &line.device_timestamp {
+                        ui.label(RichText::new(device_timestamp.as_str()).monospace());
+                    }
+                    if let Some(level) = line.level {
+                        let color = match level {
+                            "error" => egui::Color32::RED,
+                            "warn" => egui::Color32::YELLOW,
+                            "info" => egui::Color32::GREEN,
+                            _ => ui.style().visuals.weak_text_color(),
+                        };
+                        ui.label(RichText::new(format!("{:5}", level.to_uppercase())).monospace().color(color));
+                    }
+                    ui.label(RichText::new(line.text.as_str()).monospace());
+                    if let Some((file, line_number)) = &line.location {
+                        let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+                        let link = ui.link(RichText::new(format!("{}:{}", file_name, line_number)).monospace().small());
+                        if link.on_hover_text(file.display().to_string()).clicked() {
+                            clicked_location = Some((file.clone(), *line_number));
+                        }
+                    }
+                });
+            }
+        });
+        clicked_location
+    }
+
+    /// Show the line entry for sending text to the board
+    fn display_send_line(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            let te = egui::TextEdit::singleline(&mut self.send_buffer)
+                .code_editor()
+                .hint_text("send a line");
+            let resp = ui.add(te);
+            let enter_pressed = resp.lost_focus() && ui.input(|io| io.key_pressed(egui::Key::Enter));
+            if ui.button("send").clicked() || enter_pressed {
+                let line = std::mem::take(&mut self.send_buffer) + "\n";
+                self.send(line.as_bytes());
+                resp.request_focus();
+            }
+        });
+    }
+
+    /// Show the serial monitor in the provided Ui. Returns the source location of a
+    /// defmt log statement if one was clicked.
+    pub fn display(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) -> Option<(PathBuf, usize)> {
+        let mut clicked_location = None;
+        if self.available_ports.is_empty() {
+            self.refresh_ports();
+        }
+        self.display_controls(ctx, ui);
+        ui.separator();
+        if self.show_plot {
+            self.plotter.display(ui);
+        } else {
+            clicked_location = self.display_lines(ui);
+            self.display_send_line(ui);
+        }
+        clicked_location
+    }
+
+}
+
+/// The body of the port thread. Opens the port (or uses the one provided), forwards
+/// received data to the GUI and outgoing data to the port, and reopens the port if it
+/// goes away. Ports that were provided already open can't be reopened, so the thread
+/// exits when they fail.
+fn run_port(path: Option<String>, baud_rate: u32, mut port: Option<Box<dyn SerialPort>>, outgoing: Receiver<Vec<u8>>, incoming: Sender<PortEvent>, stop: Arc<AtomicBool>, ctx: &egui::Context) {
+    if port.is_some() {
+        let _ = incoming.send(PortEvent::Connected);
+    }
+    let mut buf = [0u8; 1024];
+    while !stop.load(Ordering::SeqCst) {
+        let p = match port.as_mut() {
+            Some(p) => p,
+            None => {
+                let path = match &path {
+                    Some(path) => path,
+                    None => return,
+                };
+                match serialport::new(path.as_str(), baud_rate).timeout(READ_TIMEOUT).open() {
+                    Ok(p) => {
+                        let _ = incoming.send(PortEvent::Connected);
+                        ctx.request_repaint();
+                        port = Some(p);
+                    },
+                    Err(e) => {
+                        let _ = incoming.send(PortEvent::Disconnected(e.description));
+                        ctx.request_repaint();
+                        std::thread::sleep(RECONNECT_INTERVAL);
+                    },
+                }
+                continue;
+            },
+        };
+        // write anything the GUI has queued
+        let mut write_error = None;
+        while let Ok(data) = outgoing.try_recv() {
+            if let Err(e) = p.write_all(&data) {
+                write_error = Some(e);
+                break;
+            }
+        }
+        let result = match write_error {
+            Some(e) => Err(e),
+            None => p.read(&mut buf),
+        };
+        match result {
+            Ok(0) => (),
+            Ok(n) => {
+                if incoming.send(PortEvent::Data(buf[..n].to_vec())).is_err() {
+                    return;
+                }
+                ctx.request_repaint();
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => (),
+            Err(e) => {
+                info!("serial port error, will try to reconnect: {:?}", e);
+                let _ = incoming.send(PortEvent::Disconnected(e.to_string()));
+                ctx.request_repaint();
+                port = None;
+                std::thread::sleep(RECONNECT_INTERVAL);
+            },
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    /// Call `poll` until it returns true, failing the test after a few seconds
+    fn wait_for(mut poll: impl FnMut() -> bool) {
+        let start = Instant::now();
+        while !poll() {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn pty_lines_are_received_and_sent() {
+        let ctx = egui::Context::default();
+        let mut monitor = SerialMonitor::default();
+        monitor.connect_pty(&ctx);
+        wait_for(|| {
+            monitor.update();
+            monitor.status == ConnectionStatus::Connected
+        });
+
+        let before = chrono::Local::now();
+        let far_end = monitor.pty_slave.as_mut().unwrap();
+        far_end.write_all(b"hello\nwor").unwrap();
+        far_end.write_all(b"ld\r\npartial").unwrap();
+        far_end.flush().unwrap();
+        wait_for(|| {
+            monitor.update();
+            monitor.lines.len() >= 2
+        });
+        let texts: Vec<&str> = monitor.lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(texts, ["hello", "world"]);
+        assert!(monitor.lines.iter().all(|line| line.timestamp >= before && line.timestamp <= chrono::Local::now()));
+
+        monitor.send(b"ping\n");
+        let far_end = monitor.pty_slave.as_mut().unwrap();
+        far_end.set_timeout(Duration::from_millis(50)).unwrap();
+        let mut received = Vec::new();
+        wait_for(|| {
+            let mut buf = [0; 64];
+            if let Ok(n) = far_end.read(&mut buf) {
+                received.extend_from_slice(&buf[..n]);
+            }
+            received.ends_with(b"\n")
+        });
+        assert_eq!(String::from_utf8_lossy(&received).trim_end(), "ping");
+        monitor.disconnect();
+    }
+}
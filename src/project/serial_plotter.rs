@@ -0,0 +1,315 @@
+//! This module contains the serial plotter, which parses numeric telemetry from the
+//! lines of the serial monitor and draws them as live time-series charts. Lines can
+//! hold comma- or space-separated numbers (`1.0, 2.5, -3`), or named values
+//! (`x:1.0 y:2.5`, `x:1.0,y:2.5` or `temp: 21.5, hum: 40`).
+
+use log::{info, warn};
+
+use std::fs;
+use std::io::Write;
+
+use egui::{Align2, Color32, FontId, Pos2, Rect, Sense, Shape, Stroke, Vec2};
+use rfd::FileDialog;
+
+/// Older samples are dropped once the plotter holds this many
+const MAX_SAMPLES: usize = 100_000;
+const DEFAULT_WINDOW_SECONDS: f64 = 10.0;
+const MIN_WINDOW_SECONDS: f64 = 0.1;
+const MAX_WINDOW_SECONDS: f64 = 3600.0;
+/// Line colors for the series, reused in order if there are more series than colors
+const SERIES_COLORS: [Color32; 8] = [
+    Color32::from_rgb(220,  50,  47),
+    Color32::from_rgb(133, 153,   0),
+    Color32::from_rgb( 38, 139, 210),
+    Color32::from_rgb(181, 137,   0),
+    Color32::from_rgb(211,  54, 130),
+    Color32::from_rgb( 42, 161, 152),
+    Color32::from_rgb(203,  75,  22),
+    Color32::from_rgb(108, 113, 196),
+];
+
+/// A single parsed line: the time it arrived (in seconds since the plotter started),
+/// and a value for each series that appeared on that line.
+struct Sample {
+    time: f64,
+    values: Vec<Option<f64>>,
+}
+
+pub struct SerialPlotter {
+    series_names: Vec<String>,
+    samples: Vec<Sample>,
+    start_time: Option<chrono::DateTime<chrono::Local>>,
+    paused: bool,
+    /// The time at the right edge of the plot while paused
+    paused_at: f64,
+    /// The width of the visible time window, in seconds
+    window_seconds: f64,
+}
+
+impl Default for SerialPlotter {
+    fn default() -> Self {
+        Self {
+            series_names: Vec::new(),
+            samples: Vec::new(),
+            start_time: None,
+            paused: false,
+            paused_at: 0.0,
+            window_seconds: DEFAULT_WINDOW_SECONDS,
+        }
+    }
+}
+
+/// Parse a line of telemetry into (name, value) pairs. Unnamed values get their
+/// column index as a name. Returns None if any field isn't a finite number.
+///
+/// The line is split into fields at commas, semicolons and tabs. A field without
+/// names may hold several numbers separated by spaces; in a field with names, each
+/// value ends at the first space, and the rest up to the next colon is the next name.
+fn parse_line(line: &str) -> Option<Vec<(String, f64)>> {
+    let mut values = Vec::new();
+    for field in line.split([',', ';', '\t']).map(str::trim).filter(|f| !f.is_empty()) {
+        let mut parts = field.split(':').peekable();
+        let mut name = parts.next().unwrap_or_default().trim();
+        if parts.peek().is_none() {
+            for value in field.split_whitespace() {
+                values.push((values.len().to_string(), parse_value(value)?));
+            }
+            continue;
+        }
+        while let Some(part) = parts.next() {
+            let (value, next_name) = match parts.peek() {
+                Some(_) => part.trim().split_once(char::is_whitespace)?,
+                None => (part.trim(), ""),
+            };
+            if name.is_empty() {
+                return None;
+            }
+            values.push((name.to_string(), parse_value(value)?));
+            name = next_name.trim();
+        }
+    }
+    match values.is_empty() {
+        true => None,
+        false => Some(values),
+    }
+}
+
+/// Parse a value of telemetry; words like `inf` and `NaN` aren't taken as numbers
+fn parse_value(value: &str) -> Option<f64> {
+    value.parse::<f64>().ok().filter(|v| v.is_finite())
+}
+
+impl SerialPlotter {
+
+    /// Parse a line from the serial monitor, and record it if it holds telemetry
+    pub fn push_line(&mut self, timestamp: chrono::DateTime<chrono::Local>, line: &str) {
+        let values = match parse_line(line) {
+            Some(values) => values,
+            None => return,
+        };
+        let start_time = *self.start_time.get_or_insert(timestamp);
+        let time = (timestamp - start_time).num_microseconds().unwrap_or(0) as f64 / 1e6;
+        let mut sample = Sample {
+            time,
+            values: vec![None; self.series_names.len()],
+        };
+        for (name, value) in values {
+            let idx = match self.series_names.iter().position(|n| *n == name) {
+                Some(idx) => idx,
+                None => {
+                    self.series_names.push(name);
+                    sample.values.push(None);
+                    self.series_names.len() - 1
+                },
+            };
+            sample.values[idx] = Some(value);
+        }
+        self.samples.push(sample);
+        if self.samples.len() > MAX_SAMPLES {
+            let excess = self.samples.len() - MAX_SAMPLES;
+            self.samples.drain(0..excess);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.series_names.clear();
+        self.samples.clear();
+        self.start_time = None;
+        self.paused_at = 0.0;
+    }
+
+    /// The time of the most recent sample
+    fn latest_time(&self) -> f64 {
+        self.samples.last().map(|s| s.time).unwrap_or(0.0)
+    }
+
+    /// Write all samples to a CSV file chosen with a file dialog
+    fn export_csv(&self) {
+        let path = match FileDialog::new().set_file_name("telemetry.csv").save_file() {
+            Some(path) => path,
+            None => return,
+        };
+        let mut csv = String::from("time");
+        for name in self.series_names.iter() {
+            csv += ",";
+            csv += name;
+        }
+        csv += "\n";
+        for sample in self.samples.iter() {
+            csv += &format!("{:.6}", sample.time);
+            for i in 0..self.series_names.len() {
+                csv += ",";
+                if let Some(Some(value)) = sample.values.get(i) {
+                    csv += &value.to_string();
+                }
+            }
+            csv += "\n";
+        }
+        match fs::File::create(&path).and_then(|mut f| f.write_all(csv.as_bytes())) {
+            Ok(()) => info!("exported {} samples to {:?}", self.samples.len(), path),
+            Err(e) => warn!("error exporting csv to {:?}: {:?}", path, e),
+        }
+    }
+
+    /// Show the plot controls
+    fn display_controls(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            let text = if self.paused { "resume" } else { "pause" };
+            if ui.button(text).clicked() {
+                self.paused = !self.paused;
+                self.paused_at = self.latest_time();
+            }
+            ui.label("window:");
+            let slider = egui::Slider::new(&mut self.window_seconds, MIN_WINDOW_SECONDS..=MAX_WINDOW_SECONDS)
+                .logarithmic(true)
+                .suffix(" s");
+            ui.add(slider);
+            if ui.button("export csv").clicked() {
+                self.export_csv();
+            }
+            if ui.button("clear").clicked() {
+                self.clear();
+            }
+            ui.label(format!("{} samples", self.samples.len()));
+        });
+    }
+
+    /// Draw the visible window of each series. Scrolling over the plot zooms the time axis.
+    fn display_plot(&mut self, ui: &mut egui::Ui) {
+        let (response, painter) = ui.allocate_painter(ui.available_size(), Sense::hover());
+        let rect = response.rect;
+        let visuals = ui.style().visuals.clone();
+        painter.rect_filled(rect, 0.0, visuals.extreme_bg_color);
+
+        if response.hovered() {
+            let scroll = ui.input(|io| io.scroll_delta.y);
+            if scroll != 0.0 {
+                let factor = (-scroll as f64 / 200.0).exp();
+                self.window_seconds = (self.window_seconds * factor).clamp(MIN_WINDOW_SECONDS, MAX_WINDOW_SECONDS);
+            }
+        }
+
+        let t_max = if self.paused { self.paused_at } else { self.latest_time() };
+        let t_min = t_max - self.window_seconds;
+        let visible: Vec<&Sample> = self.samples.iter()
+            .filter(|s| s.time >= t_min && s.time <= t_max)
+            .collect();
+
+        // scale the y axis to the visible data
+        let (mut y_min, mut y_max) = (f64::INFINITY, f64::NEG_INFINITY);
+        for value in visible.iter().flat_map(|s| s.values.iter().flatten()) {
+            y_min = y_min.min(*value);
+            y_max = y_max.max(*value);
+        }
+        if !y_min.is_finite() {
+            painter.text(rect.center(), Align2::CENTER_CENTER, "no numeric data received", FontId::monospace(12.0), visuals.weak_text_color());
+            return;
+        }
+        if (y_max - y_min).abs() < f64::EPSILON {
+            y_min -= 1.0;
+            y_max += 1.0;
+        }
+        let margin = (y_max - y_min) * 0.05;
+        let (y_min, y_max) = (y_min - margin, y_max + margin);
+
+        let to_screen = |t: f64, y: f64| -> Pos2 {
+            let x = rect.left() + ((t - t_min) / (t_max - t_min)) as f32 * rect.width();
+            let y = rect.bottom() - ((y - y_min) / (y_max - y_min)) as f32 * rect.height();
+            Pos2::new(x, y)
+        };
+
+        // horizontal grid lines with value labels
+        let grid_stroke = Stroke::new(1.0, visuals.faint_bg_color);
+        let font = FontId::monospace(10.0);
+        for i in 0..=4 {
+            let y = y_min + (y_max - y_min) * i as f64 / 4.0;
+            let p = to_screen(t_min, y);
+            painter.line_segment([Pos2::new(rect.left(), p.y), Pos2::new(rect.right(), p.y)], grid_stroke);
+            painter.text(Pos2::new(rect.left() + 2.0, p.y), Align2::LEFT_BOTTOM, format!("{:.3}", y), font.clone(), visuals.weak_text_color());
+        }
+        painter.text(rect.right_bottom() - Vec2::new(2.0, 2.0), Align2::RIGHT_BOTTOM, format!("{:.1} s", t_max), font.clone(), visuals.weak_text_color());
+
+        // the series themselves, along with a legend
+        let mut legend_pos = rect.right_top() + Vec2::new(-4.0, 4.0);
+        for (i, name) in self.series_names.iter().enumerate() {
+            let color = SERIES_COLORS[i % SERIES_COLORS.len()];
+            let points: Vec<Pos2> = visible.iter()
+                .filter_map(|s| s.values.get(i).copied().flatten().map(|v| to_screen(s.time, v)))
+                .collect();
+            if points.len() > 1 {
+                painter.add(Shape::line(points, Stroke::new(1.5, color)));
+            }
+            let legend_rect: Rect = painter.text(legend_pos, Align2::RIGHT_TOP, name, font.clone(), color);
+            legend_pos.y = legend_rect.bottom() + 2.0;
+        }
+    }
+
+    /// Show the serial plotter in the provided Ui
+    pub fn display(&mut self, ui: &mut egui::Ui) {
+        self.display_controls(ui);
+        self.display_plot(ui);
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(values: &[(&str, f64)]) -> Option<Vec<(String, f64)>> {
+        Some(values.iter().map(|(name, value)| (name.to_string(), *value)).collect())
+    }
+
+    #[test]
+    fn parse_unnamed_values() {
+        assert_eq!(parse_line("1.0, 2.5, -3"), values(&[("0", 1.0), ("1", 2.5), ("2", -3.0)]));
+        assert_eq!(parse_line("1 2\t3;4"), values(&[("0", 1.0), ("1", 2.0), ("2", 3.0), ("3", 4.0)]));
+        assert_eq!(parse_line("  42\r"), values(&[("0", 42.0)]));
+    }
+
+    #[test]
+    fn parse_named_values() {
+        let expected = values(&[("temp", 21.5), ("hum", 40.0)]);
+        assert_eq!(parse_line("temp: 21.5, hum: 40"), expected);
+        assert_eq!(parse_line("temp:21.5 hum:40"), expected);
+        assert_eq!(parse_line("temp: 21.5 hum: 40"), expected);
+        assert_eq!(parse_line("temp:21.5;hum:40"), expected);
+        assert_eq!(parse_line("air temp: 21.5, 3"), values(&[("air temp", 21.5), ("1", 3.0)]));
+    }
+
+    #[test]
+    fn parse_rejects_text() {
+        assert_eq!(parse_line(""), None);
+        assert_eq!(parse_line(" , ;"), None);
+        assert_eq!(parse_line("hello world"), None);
+        assert_eq!(parse_line("temp: warm"), None);
+        assert_eq!(parse_line(": 1"), None);
+        assert_eq!(parse_line("temp:"), None);
+        assert_eq!(parse_line("12:30:45"), None);
+        assert_eq!(parse_line("booting, 1"), None);
+        assert_eq!(parse_line("inf"), None);
+        assert_eq!(parse_line("-inf 1"), None);
+        assert_eq!(parse_line("temp: NaN"), None);
+        assert_eq!(parse_line("a: 1 b: infinity"), None);
+    }
+}
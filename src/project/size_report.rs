@@ -0,0 +1,393 @@
+//! This module computes the memory footprint of a built firmware image, by parsing
+//! the ELF produced by cargo and summing up the sizes of its allocated sections.
+
+use log::{info, warn};
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use object::{Object, ObjectSection, ObjectSymbol, SectionKind, SymbolKind, SymbolSection};
+
+/// The number of symbols to keep in the per-symbol breakdown
+const NUM_LARGEST_SYMBOLS: usize = 20;
+
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum SizeReportError {
+    NoCargoManifest,
+    NoElfFound,
+    FilesystemError,
+    ElfParseError,
+}
+
+/// The output section category that a symbol or section is placed in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SectionCategory {
+    Text,
+    Rodata,
+    Data,
+    Bss,
+}
+
+/// A single function or static in the firmware image
+#[derive(Debug, Clone)]
+pub struct SymbolSize {
+    pub name: String,
+    pub category: SectionCategory,
+    pub size: u64,
+}
+
+/// The section totals of a firmware image, in bytes, along with the largest symbols.
+#[derive(Debug, Clone, Default)]
+pub struct SizeReport {
+    pub elf_path: PathBuf,
+    pub text: u64,
+    pub rodata: u64,
+    pub data: u64,
+    pub bss: u64,
+    /// The largest functions and statics, sorted from largest to smallest
+    pub largest_symbols: Vec<SymbolSize>,
+}
+
+impl SizeReport {
+
+    /// Parse the ELF at the given path and compute its section totals.
+    pub fn from_elf(elf_path: &Path) -> Result<Self, SizeReportError> {
+        let data = fs::read(elf_path).map_err(|e| {
+            warn!("couldn't read elf file {:?}: {:?}", elf_path, e);
+            SizeReportError::FilesystemError
+        })?;
+        let file = object::File::parse(&*data).map_err(|e| {
+            warn!("couldn't parse elf file {:?}: {:?}", elf_path, e);
+            SizeReportError::ElfParseError
+        })?;
+
+        let mut report = SizeReport {
+            elf_path: elf_path.to_path_buf(),
+            ..Default::default()
+        };
+        for section in file.sections() {
+            let size = section.size();
+            match categorize(section.kind()) {
+                Some(SectionCategory::Text) => report.text += size,
+                Some(SectionCategory::Rodata) => report.rodata += size,
+                Some(SectionCategory::Data) => report.data += size,
+                Some(SectionCategory::Bss) => report.bss += size,
+                None => (),
+            }
+        }
+
+        let mut symbols: Vec<SymbolSize> = file.symbols().filter_map(|symbol| {
+            match symbol.kind() {
+                SymbolKind::Text | SymbolKind::Data => (),
+                _ => return None,
+            }
+            if symbol.size() == 0 {
+                return None;
+            }
+            let section_index = match symbol.section() {
+                SymbolSection::Section(index) => index,
+                _ => return None,
+            };
+            let category = categorize(file.section_by_index(section_index).ok()?.kind())?;
+            let name = symbol.name().ok()?;
+            Some(SymbolSize {
+                name: format!("{:#}", rustc_demangle::demangle(name)),
+                category,
+                size: symbol.size(),
+            })
+        }).collect();
+        symbols.sort_by_key(|symbol| std::cmp::Reverse(symbol.size));
+        symbols.truncate(NUM_LARGEST_SYMBOLS);
+        report.largest_symbols = symbols;
+
+        Ok(report)
+    }
+
+    /// Find the most recently built ELF for the cargo project at `project_dir` and
+    /// compute its report.
+    pub fn from_project(project_dir: &Path) -> Result<Self, SizeReportError> {
+        let elf_path = find_elf(project_dir)?;
+        info!("computing size report for {}", elf_path.display());
+        Self::from_elf(&elf_path)
+    }
+
+    /// Bytes stored in flash: code, constants, and the initial values of statics
+    pub fn flash_used(&self) -> u64 {
+        self.text + self.rodata + self.data
+    }
+
+    /// Bytes of statically allocated RAM
+    pub fn ram_used(&self) -> u64 {
+        self.data + self.bss
+    }
+
+}
+
+/// Map an object section kind to the output section category it is counted towards.
+/// Sections that don't take up space on the target (i.e. debug info) return None.
+fn categorize(kind: SectionKind) -> Option<SectionCategory> {
+    match kind {
+        SectionKind::Text => Some(SectionCategory::Text),
+        SectionKind::ReadOnlyData | SectionKind::ReadOnlyString => Some(SectionCategory::Rodata),
+        SectionKind::Data | SectionKind::Tls => Some(SectionCategory::Data),
+        SectionKind::UninitializedData | SectionKind::UninitializedTls => Some(SectionCategory::Bss),
+        _ => None,
+    }
+}
+
+/// Look for the binaries named in the project's Cargo.toml, in each of the target
+/// directories, returning the most recently modified one.
+pub fn find_elf(project_dir: &Path) -> Result<PathBuf, SizeReportError> {
+    let manifest = fs::read_to_string(project_dir.join("Cargo.toml"))
+        .map_err(|_| SizeReportError::NoCargoManifest)?;
+    let manifest: toml::Value = toml::from_str(&manifest)
+        .map_err(|_| SizeReportError::NoCargoManifest)?;
+    let bin_names = bin_names(&manifest);
+    if bin_names.is_empty() {
+        return Err(SizeReportError::NoCargoManifest);
+    }
+    let env_target_dir = env::var_os("CARGO_TARGET_DIR").map(PathBuf::from);
+    newest_binary(&target_dir(project_dir, env_target_dir), &bin_names)
+}
+
+/// The names of the binaries that a Cargo.toml builds: its `[[bin]]` targets, and the
+/// package's own binary
+fn bin_names(manifest: &toml::Value) -> Vec<String> {
+    let bins = manifest.get("bin").and_then(|bins| bins.as_array());
+    let package_name = manifest.get("package").and_then(|p| p.get("name"));
+    bins.into_iter()
+        .flatten()
+        .filter_map(|bin| bin.get("name"))
+        .chain(package_name)
+        .filter_map(|name| name.as_str().map(String::from))
+        .collect()
+}
+
+/// The directory that cargo builds a project in: `CARGO_TARGET_DIR` if it's set,
+/// else the `build.target-dir` of the project's .cargo/config.toml, else `target`
+fn target_dir(project_dir: &Path, env_target_dir: Option<PathBuf>) -> PathBuf {
+    // cargo is run in the project folder, so a relative directory is relative to it
+    if let Some(dir) = env_target_dir {
+        return project_dir.join(dir);
+    }
+    let config_dir = project_dir.join(".cargo");
+    let configured = ["config.toml", "config"].iter()
+        .filter_map(|name| fs::read_to_string(config_dir.join(name)).ok())
+        .find_map(|config| {
+            let config: toml::Value = toml::from_str(&config).ok()?;
+            config.get("build")?.get("target-dir")?.as_str().map(PathBuf::from)
+        });
+    // a relative target-dir is relative to the directory that holds .cargo
+    match configured {
+        Some(dir) => project_dir.join(dir),
+        None => project_dir.join("target"),
+    }
+}
+
+/// The most recently modified of the named binaries, which are either at
+/// <target>/debug/<name>, or <target>/<triple>/debug/<name>
+fn newest_binary(target_dir: &Path, bin_names: &[String]) -> Result<PathBuf, SizeReportError> {
+    let mut profile_dirs = vec![target_dir.join("debug")];
+    if let Ok(entries) = target_dir.read_dir() {
+        for entry in entries.flatten() {
+            profile_dirs.push(entry.path().join("debug"));
+        }
+    }
+    profile_dirs.iter()
+        .flat_map(|dir| bin_names.iter().map(move |name| dir.join(name)))
+        .filter_map(|path| {
+            let modified = path.metadata().ok()?.modified().ok()?;
+            Some((modified, path))
+        })
+        .max_by_key(|(modified, _)| *modified)
+        .map(|(_, path)| path)
+        .ok_or(SizeReportError::NoElfFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use object::elf::{
+        SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, SHT_NOBITS, SHT_PROGBITS, SHT_STRTAB, SHT_SYMTAB,
+        STB_GLOBAL, STT_FUNC, STT_OBJECT,
+    };
+
+    /// A little-endian ELF32 with a section for each (name, type, flags, size), followed
+    /// by a symbol table with a symbol for each (name, type, section index, size)
+    fn build_elf(sections: &[(&str, u32, u32, u32)], symbols: &[(&str, u8, u16, u32)]) -> Vec<u8> {
+        const EHDR_SIZE: usize = 52;
+        fn add_name(table: &mut Vec<u8>, name: &str) -> u32 {
+            let offset = table.len() as u32;
+            table.extend_from_slice(name.as_bytes());
+            table.push(0);
+            offset
+        }
+        let mut shstrtab = vec![0];
+        let mut strtab = vec![0];
+        let mut symtab = vec![0; 16];
+        for (name, kind, section, size) in symbols {
+            let name = add_name(&mut strtab, name);
+            for word in [name, 0, *size] {
+                symtab.extend_from_slice(&word.to_le_bytes());
+            }
+            symtab.extend_from_slice(&[(STB_GLOBAL << 4) | kind, 0]);
+            symtab.extend_from_slice(&section.to_le_bytes());
+        }
+
+        // the contents of the sections, after the header
+        let mut contents = Vec::new();
+        let mut headers = vec![[0; 10]];
+        let num_sections = sections.len() as u32 + 4;
+        let mut add_section = |contents: &mut Vec<u8>, name: u32, kind: u32, flags: u32, data: &[u8], size: u32, link: u32| {
+            let offset = (EHDR_SIZE + contents.len()) as u32;
+            contents.extend_from_slice(data);
+            let (info, entsize) = match kind {
+                SHT_SYMTAB => (1, 16),
+                _ => (0, 0),
+            };
+            headers.push([name, kind, flags, 0, offset, size, link, info, 1, entsize]);
+        };
+        for (name, kind, flags, size) in sections {
+            let name = add_name(&mut shstrtab, name);
+            let data = match *kind {
+                SHT_NOBITS => Vec::new(),
+                _ => vec![0xaa; *size as usize],
+            };
+            add_section(&mut contents, name, *kind, *flags, &data, *size, 0);
+        }
+        let name = add_name(&mut shstrtab, ".symtab");
+        add_section(&mut contents, name, SHT_SYMTAB, 0, &symtab, symtab.len() as u32, num_sections - 2);
+        let name = add_name(&mut shstrtab, ".strtab");
+        add_section(&mut contents, name, SHT_STRTAB, 0, &strtab, strtab.len() as u32, 0);
+        let name = add_name(&mut shstrtab, ".shstrtab");
+        shstrtab.extend_from_slice(&[0; 4]);
+        let shstrtab_len = shstrtab.len() as u32;
+        add_section(&mut contents, name, SHT_STRTAB, 0, &shstrtab, shstrtab_len, 0);
+        contents.resize(contents.len().next_multiple_of(4), 0);
+
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 1, 1, 1];
+        elf.resize(16, 0);
+        for half in [2_u16, 40] {
+            elf.extend_from_slice(&half.to_le_bytes());
+        }
+        for word in [1_u32, 0, 0, (EHDR_SIZE + contents.len()) as u32, 0] {
+            elf.extend_from_slice(&word.to_le_bytes());
+        }
+        for half in [EHDR_SIZE as u16, 32, 0, 40, num_sections as u16, num_sections as u16 - 1] {
+            elf.extend_from_slice(&half.to_le_bytes());
+        }
+        elf.extend_from_slice(&contents);
+        for header in headers {
+            for word in header {
+                elf.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+        elf
+    }
+
+    /// A directory of its own for a test, which starts out empty
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("iron_coder_size_report_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn report_sums_up_the_sections_and_finds_the_largest_symbols() {
+        let sections = [
+            (".text", SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR, 100),
+            (".rodata", SHT_PROGBITS, SHF_ALLOC, 20),
+            (".data", SHT_PROGBITS, SHF_ALLOC | SHF_WRITE, 8),
+            (".bss", SHT_NOBITS, SHF_ALLOC | SHF_WRITE, 64),
+            // debug info doesn't take up space on the target
+            (".debug_info", SHT_PROGBITS, 0, 30),
+        ];
+        let mut symbols = vec![
+            ("_ZN4demo4main17h0123456789abcdefE", STT_FUNC, 1, 60),
+            ("helper", STT_FUNC, 1, 40),
+            ("TABLE", STT_OBJECT, 2, 20),
+            ("COUNTER", STT_OBJECT, 3, 8),
+            ("BUFFER", STT_OBJECT, 4, 64),
+            // symbols without a size or a section are left out
+            ("empty", STT_FUNC, 1, 0),
+            ("external", STT_FUNC, 0, 12),
+        ];
+        let names: Vec<String> = (0..20).map(|n| format!("f{}", n)).collect();
+        symbols.extend(names.iter().map(|name| (name.as_str(), STT_FUNC, 1, 1)));
+        let dir = test_dir("sections");
+        let elf_path = dir.join("firmware");
+        fs::write(&elf_path, build_elf(&sections, &symbols)).unwrap();
+
+        let report = SizeReport::from_elf(&elf_path).unwrap();
+        assert_eq!((report.text, report.rodata, report.data, report.bss), (100, 20, 8, 64));
+        assert_eq!(report.flash_used(), 128);
+        assert_eq!(report.ram_used(), 72);
+        assert_eq!(report.largest_symbols.len(), NUM_LARGEST_SYMBOLS);
+        let largest: Vec<_> = report.largest_symbols.iter()
+            .take(6)
+            .map(|symbol| (symbol.name.as_str(), symbol.category, symbol.size))
+            .collect();
+        assert_eq!(largest, [
+            ("BUFFER", SectionCategory::Bss, 64),
+            ("demo::main", SectionCategory::Text, 60),
+            ("helper", SectionCategory::Text, 40),
+            ("TABLE", SectionCategory::Rodata, 20),
+            ("COUNTER", SectionCategory::Data, 8),
+            ("f0", SectionCategory::Text, 1),
+        ]);
+
+        fs::write(&elf_path, b"not an elf").unwrap();
+        assert!(matches!(SizeReport::from_elf(&elf_path), Err(SizeReportError::ElfParseError)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn binaries_are_named_by_the_manifest() {
+        let manifest: toml::Value = toml::from_str("[package]\nname = \"blinky\"\n").unwrap();
+        assert_eq!(bin_names(&manifest), ["blinky"]);
+        let manifest: toml::Value = toml::from_str(
+            "[package]\nname = \"demo\"\n[[bin]]\nname = \"fw-a\"\npath = \"src/a.rs\"\n[[bin]]\nname = \"fw-b\"\n"
+        ).unwrap();
+        assert_eq!(bin_names(&manifest), ["fw-a", "fw-b", "demo"]);
+        let manifest: toml::Value = toml::from_str("[workspace]\nmembers = []\n").unwrap();
+        assert!(bin_names(&manifest).is_empty());
+    }
+
+    #[test]
+    fn target_dir_follows_the_environment_and_cargo_config() {
+        let dir = test_dir("target_dir");
+        assert_eq!(target_dir(&dir, None), dir.join("target"));
+        fs::create_dir_all(dir.join(".cargo")).unwrap();
+        fs::write(dir.join(".cargo/config.toml"), "[build]\ntarget-dir = \"build\"\n").unwrap();
+        assert_eq!(target_dir(&dir, None), dir.join("build"));
+        assert_eq!(target_dir(&dir, Some(PathBuf::from("out"))), dir.join("out"));
+        let shared = std::env::temp_dir().join("shared_target");
+        assert_eq!(target_dir(&dir, Some(shared.clone())), shared);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn newest_binary_is_found() {
+        let dir = test_dir("newest");
+        let names = ["fw-a".to_string(), "demo".to_string()];
+        assert!(matches!(newest_binary(&dir, &names), Err(SizeReportError::NoElfFound)));
+        let host = dir.join("debug/demo");
+        let cross = dir.join("thumbv6m-none-eabi/debug/fw-a");
+        for path in [&host, &cross] {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+        let now = std::time::SystemTime::now();
+        fs::File::options().write(true).open(&host).unwrap()
+            .set_modified(now - std::time::Duration::from_secs(60)).unwrap();
+        fs::File::options().write(true).open(&cross).unwrap().set_modified(now).unwrap();
+        assert_eq!(newest_binary(&dir, &names).unwrap(), cross);
+        // binaries that the manifest doesn't name are left out
+        assert_eq!(newest_binary(&dir, &names[1..]).unwrap(), host);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
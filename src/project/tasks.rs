@@ -0,0 +1,328 @@
+//! This module contains the background task subsystem of a Project. A Task is a
+//! named sequence of commands (i.e. cargo invocations) that runs on its own thread,
+//! with its own output stream and exit status. Tasks with the same name are queued
+//! behind each other, while differently named tasks run concurrently.
+
+use log::{info, warn, debug};
+
+use std::io::{BufRead, BufReader};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::fmt;
+
+/// A unique identifier for a task, which stays valid after other tasks are removed.
+pub type TaskId = usize;
+
+/// The current state of a Task
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TaskStatus {
+    /// Waiting for an earlier task with the same name to complete
+    Queued,
+    Running,
+    /// All commands exited successfully
+    Finished,
+    /// A command returned a non-zero exit code (or none, if it couldn't be started
+    /// or was terminated by a signal)
+    Failed(Option<i32>),
+    /// The task was stopped by the user
+    Killed,
+}
+
+impl TaskStatus {
+    pub fn is_active(&self) -> bool {
+        matches!(self, TaskStatus::Queued | TaskStatus::Running)
+    }
+}
+
+impl fmt::Display for TaskStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TaskStatus::Queued => write!(f, "queued"),
+            TaskStatus::Running => write!(f, "running"),
+            TaskStatus::Finished => write!(f, "finished"),
+            TaskStatus::Failed(Some(code)) => write!(f, "failed ({})", code),
+            TaskStatus::Failed(None) => write!(f, "failed"),
+            TaskStatus::Killed => write!(f, "stopped"),
+        }
+    }
+}
+
+/// Messages sent from a task thread back to the GUI thread
+enum TaskMessage {
+    Output(String),
+    /// Sent once when the thread exits, with the exit code of the last command run
+    Exited(Option<i32>),
+}
+
+/// The child process that a task is currently reading from, shared with the GUI
+/// thread so that it can be killed.
+type SharedHandle = Arc<Mutex<Option<Arc<duct::ReaderHandle>>>>;
+
+pub struct Task {
+    id: TaskId,
+    name: String,
+    status: TaskStatus,
+    /// The output of all commands run so far, with stderr merged into stdout
+    pub output: String,
+    /// The commands that are yet to be started (only non-empty while queued)
+    commands: Vec<duct::Expression>,
+    receiver: Option<Receiver<TaskMessage>>,
+    handle: SharedHandle,
+    killed: Arc<AtomicBool>,
+}
+
+impl fmt::Debug for Task {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Task {} <{}>: {}", self.id, self.name, self.status)
+    }
+}
+
+impl Task {
+
+    pub fn get_id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn get_name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn get_status(&self) -> TaskStatus {
+        self.status
+    }
+
+    /// Start the task's commands on a new thread
+    fn start(&mut self, ctx: &egui::Context) {
+        let (tx, rx) = mpsc::channel();
+        self.receiver = Some(rx);
+        self.status = TaskStatus::Running;
+        let commands = std::mem::take(&mut self.commands);
+        let handle = self.handle.clone();
+        let killed = self.killed.clone();
+        let context = ctx.clone();
+        let name = self.name.clone();
+        let _ = std::thread::spawn(move || {
+            run_commands(commands, tx, handle, killed, &context);
+            context.request_repaint();
+            info!("leaving thread for task <{}>", name);
+        });
+    }
+
+    /// Stop the task. A queued task is simply never started, and a running task
+    /// has its current child process killed.
+    fn kill(&mut self) {
+        self.killed.store(true, Ordering::SeqCst);
+        match self.status {
+            TaskStatus::Queued => {
+                self.commands.clear();
+                self.status = TaskStatus::Killed;
+            },
+            TaskStatus::Running => {
+                // clone the handle out of the mutex so that the lock isn't held while
+                // waiting on the child to exit.
+                let handle = self.handle.lock().unwrap().clone();
+                if let Some(handle) = handle {
+                    if let Err(e) = handle.kill() {
+                        warn!("error killing task <{}>: {:?}", self.name, e);
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+
+}
+
+/// The body of a task thread. Run each command in sequence, stopping at the first failure.
+fn run_commands(commands: Vec<duct::Expression>, tx: Sender<TaskMessage>, handle: SharedHandle, killed: Arc<AtomicBool>, ctx: &egui::Context) {
+    let mut exit_code: Option<i32> = Some(0);
+    for cmd in commands.iter() {
+        if killed.load(Ordering::SeqCst) {
+            exit_code = None;
+            break;
+        }
+        let reader = match cmd.stderr_to_stdout().unchecked().reader() {
+            Ok(reader) => Arc::new(reader),
+            Err(e) => {
+                let _ = tx.send(TaskMessage::Output(format!("couldn't start command: {}\n", e)));
+                exit_code = None;
+                break;
+            },
+        };
+        *handle.lock().unwrap() = Some(reader.clone());
+        // output that isn't valid UTF-8 is shown lossily, rather than ending the task's
+        // output at the first bad line
+        let mut output = BufReader::new(&*reader);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match output.read_until(b'\n', &mut line) {
+                Ok(0) => break,
+                Ok(_) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("error reading command output: {:?}", e);
+                    break;
+                },
+            }
+            // every line ends in a single newline, like the last one (which may not have one)
+            while line.last().is_some_and(|byte| *byte == b'\n' || *byte == b'\r') {
+                line.pop();
+            }
+            line.push(b'\n');
+            debug!("sending line through channel");
+            if tx.send(TaskMessage::Output(String::from_utf8_lossy(&line).into_owned())).is_err() {
+                break;
+            }
+            ctx.request_repaint();
+        }
+        *handle.lock().unwrap() = None;
+        exit_code = match reader.try_wait() {
+            Ok(Some(output)) => output.status.code(),
+            _ => None,
+        };
+        if exit_code != Some(0) {
+            break;
+        }
+    }
+    let _ = tx.send(TaskMessage::Exited(exit_code));
+}
+
+/// The TaskManager owns all of the Project's tasks, and is responsible for starting
+/// queued tasks and collecting task output.
+#[derive(Default, Debug)]
+pub struct TaskManager {
+    tasks: Vec<Task>,
+    next_id: TaskId,
+}
+
+impl TaskManager {
+
+    /// Add a new task. It will start right away, unless a task with the same name
+    /// is already active, in which case it is queued. Any completed tasks with the
+    /// same name are removed.
+    pub fn spawn(&mut self, name: &str, cmds: &[duct::Expression], ctx: &egui::Context) -> TaskId {
+        self.tasks.retain(|t| t.name != name || t.status.is_active());
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut task = Task {
+            id,
+            name: name.to_string(),
+            status: TaskStatus::Queued,
+            output: String::new(),
+            commands: cmds.to_owned(),
+            receiver: None,
+            handle: Arc::new(Mutex::new(None)),
+            killed: Arc::new(AtomicBool::new(false)),
+        };
+        if self.is_active(name) {
+            info!("queueing task <{}>", name);
+        } else {
+            info!("starting task <{}>", name);
+            task.start(ctx);
+        }
+        self.tasks.push(task);
+        id
+    }
+
+    /// Returns true if a task with the given name is queued or running
+    pub fn is_active(&self, name: &str) -> bool {
+        self.tasks.iter().any(|t| t.name == name && t.status.is_active())
+    }
+
+    /// Stop the task with the provided id
+    pub fn kill(&mut self, id: TaskId) {
+        if let Some(task) = self.get_mut(id) {
+            info!("stopping task <{}>", task.name);
+            task.kill();
+        }
+    }
+
+    pub fn get(&self, id: TaskId) -> Option<&Task> {
+        self.tasks.iter().find(|t| t.id == id)
+    }
+
+    pub fn get_mut(&mut self, id: TaskId) -> Option<&mut Task> {
+        self.tasks.iter_mut().find(|t| t.id == id)
+    }
+
+    pub fn tasks(&self) -> &[Task] {
+        &self.tasks
+    }
+
+    /// Remove a task that is no longer active
+    pub fn remove(&mut self, id: TaskId) {
+        self.tasks.retain(|t| t.id != id || t.status.is_active());
+    }
+
+    /// Collect output from running tasks, and start queued tasks whose predecessors
+    /// have completed. All new output is appended to `sink` in addition to each task's
+    /// own buffer. Returns the tasks that completed since the last call.
+    pub fn update(&mut self, sink: &mut String, ctx: &egui::Context) -> Vec<(String, TaskStatus)> {
+        let mut completed = Vec::new();
+        for task in self.tasks.iter_mut() {
+            let rx = match &task.receiver {
+                Some(rx) => rx,
+                None => continue,
+            };
+            while let Ok(msg) = rx.try_recv() {
+                match msg {
+                    TaskMessage::Output(s) => {
+                        *sink += s.as_str();
+                        task.output += s.as_str();
+                    },
+                    TaskMessage::Exited(code) => {
+                        task.status = if task.killed.load(Ordering::SeqCst) {
+                            TaskStatus::Killed
+                        } else if code == Some(0) {
+                            TaskStatus::Finished
+                        } else {
+                            TaskStatus::Failed(code)
+                        };
+                        info!("task <{}> completed with status: {}", task.name, task.status);
+                        completed.push((task.name.clone(), task.status));
+                    },
+                }
+            }
+            if !task.status.is_active() {
+                task.receiver = None;
+            }
+        }
+        // start the first queued task of each name, if nothing of that name is running
+        for i in 0..self.tasks.len() {
+            if self.tasks[i].status != TaskStatus::Queued {
+                continue;
+            }
+            let name = self.tasks[i].name.clone();
+            let blocked = self.tasks[..i].iter().any(|t| t.name == name && t.status.is_active());
+            if !blocked {
+                info!("starting queued task <{}>", name);
+                self.tasks[i].start(ctx);
+            }
+        }
+        completed
+    }
+
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_is_read_lossily_to_the_end() {
+        let (tx, rx) = mpsc::channel();
+        let commands = vec![duct::cmd!("printf", "caf\\351\\r\\nnext\\nlast")];
+        run_commands(commands, tx, SharedHandle::default(), Arc::new(AtomicBool::new(false)), &egui::Context::default());
+        let messages: Vec<TaskMessage> = rx.iter().collect();
+        let output: Vec<&str> = messages.iter()
+            .filter_map(|message| match message {
+                TaskMessage::Output(line) => Some(line.as_str()),
+                TaskMessage::Exited(_) => None,
+            })
+            .collect();
+        assert_eq!(output, ["caf\u{fffd}\n", "next\n", "last\n"]);
+        assert!(matches!(messages.last(), Some(TaskMessage::Exited(Some(0)))));
+    }
+}